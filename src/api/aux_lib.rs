@@ -0,0 +1,58 @@
+use std::cell::RefCell;
+use std::io;
+use std::process::ExitStatus;
+use std::rc::Rc;
+
+use crate::api::{LuaAPI, LuaError, LuaResult, RustFn};
+use crate::state::lua_userdata::LuaUserData;
+
+/// Lua Auxiliary Library
+pub trait LuaAuxLib: LuaAPI {
+    /* error functions */
+    fn error2(&self, msg: &str) -> LuaError;
+    fn arg_error(&self, arg: isize, extra_msg: &str) -> LuaError;
+    fn type_error(&self, arg: isize, tname: &str) -> LuaError;
+
+    /* argument check functions */
+    fn arg_check(&self, cond: bool, arg: isize, extra_msg: &str) -> LuaResult<()>;
+    fn check_type(&self, arg: isize, t: i8) -> LuaResult<()>;
+    fn check_any(&self, arg: isize) -> LuaResult<()>;
+    fn check_number(&self, arg: isize) -> LuaResult<f64>;
+    fn opt_number(&self, arg: isize, def: f64) -> LuaResult<f64>;
+    fn check_integer(&self, arg: isize) -> LuaResult<i64>;
+    fn opt_integer(&self, arg: isize, def: i64) -> LuaResult<i64>;
+    fn check_string(&self, arg: isize) -> LuaResult<String>;
+    fn opt_string(&self, arg: isize, def: &str) -> LuaResult<String>;
+    fn check_bytes(&self, arg: isize) -> LuaResult<Vec<u8>>;
+    fn opt_bytes(&self, arg: isize, def: &[u8]) -> LuaResult<Vec<u8>>;
+    fn type_name2(&self, idx: isize) -> &str;
+
+    /* metatable functions */
+    fn get_metafield(&mut self, obj: isize, e: &str) -> LuaResult<i8>;
+    fn call_meta(&mut self, obj: isize, e: &str) -> LuaResult<bool>;
+    fn new_metatable(&mut self, tname: &str) -> LuaResult<bool>;
+    fn get_metatable2(&mut self, tname: &str) -> LuaResult<i8>;
+    fn set_metatable2(&mut self, tname: &str) -> LuaResult<()>;
+    fn test_udata(&mut self, arg: isize, tname: &str) -> LuaResult<Option<Rc<RefCell<LuaUserData>>>>;
+    fn check_udata(&mut self, arg: isize, tname: &str) -> LuaResult<Rc<RefCell<LuaUserData>>>;
+
+    /* conversion functions */
+    fn to_string2(&mut self, idx: isize) -> LuaResult<String>;
+    fn len2(&mut self, idx: isize) -> LuaResult<i64>;
+
+    /* results of system calls */
+    fn file_result(&mut self, res: io::Result<()>, fname: Option<&str>) -> usize;
+    fn exec_result(&mut self, res: io::Result<ExitStatus>) -> usize;
+
+    /* load functions */
+    fn load_file(&mut self, filename: Option<&str>, mode: &str) -> i8;
+
+    /* debug functions */
+    fn traceback(&mut self, msg: Option<&str>, level: usize) -> LuaResult<()>;
+
+    /* library functions */
+    fn new_lib(&mut self, l: &[(&str, RustFn)]) -> LuaResult<()>;
+    fn get_subtable(&mut self, idx: isize, fname: &str) -> LuaResult<bool>;
+    fn require_f(&mut self, modname: &str, open_f: RustFn, glb: bool) -> LuaResult<()>;
+    fn open_libs(&mut self) -> LuaResult<()>;
+}
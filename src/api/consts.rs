@@ -1,3 +1,67 @@
+/// version string of the implemented Lua language
+pub const LUA_VERSION: &str = "Lua 5.3";
+
+/// option for multiple returns in `LuaAPI::call`
+pub const LUA_MULTRET: isize = -1;
+
+/// minimum Lua stack available to a function
+pub const LUA_MINSTACK: usize = 20;
+
+/// limit for the size of Lua stack
+pub const LUAI_MAXSTACK: isize = 1000000;
+
+/// limit for the nested calls from Rust functions
+pub const LUAI_MAXCCALLS: usize = 200;
+
+/// pseudo-index of the registry, the up values are indexed below it
+pub const LUA_REGISTRYINDEX: isize = -LUAI_MAXSTACK - 1000;
+
+/* predefined values in the registry */
+pub const LUA_RIDX_MAINTHREAD: i64 = 1;
+pub const LUA_RIDX_GLOBALS: i64 = 2;
+
+/// key of the table of loaded modules in the registry
+pub const LUA_LOADED_TABLE: &str = "_LOADED";
+/// key of the table of module loaders in the registry
+pub const LUA_PRELOAD_TABLE: &str = "_PRELOAD";
+
+/* thread status */
+pub const LUA_OK: i8 = 0;
+pub const LUA_YIELD: i8 = 1;
+pub const LUA_ERRRUN: i8 = 2;
+pub const LUA_ERRSYNTAX: i8 = 3;
+pub const LUA_ERRMEM: i8 = 4;
+pub const LUA_ERRGCMM: i8 = 5;
+pub const LUA_ERRERR: i8 = 6;
+/// status of `os.exit` intercepted by the host, which is not caught by protected calls
+pub const LUA_ERREXIT: i8 = 7;
+/// status of a file which can not be opened or read by `LuaAuxLib::load_file`
+pub const LUA_ERRFILE: i8 = 8;
+
+/* event codes of hooks */
+pub const LUA_HOOKCALL: i32 = 0;
+pub const LUA_HOOKRET: i32 = 1;
+pub const LUA_HOOKLINE: i32 = 2;
+pub const LUA_HOOKCOUNT: i32 = 3;
+pub const LUA_HOOKTAILCALL: i32 = 4;
+
+/* event masks of hooks */
+pub const LUA_MASKCALL: i32 = 1 << LUA_HOOKCALL;
+pub const LUA_MASKRET: i32 = 1 << LUA_HOOKRET;
+pub const LUA_MASKLINE: i32 = 1 << LUA_HOOKLINE;
+pub const LUA_MASKCOUNT: i32 = 1 << LUA_HOOKCOUNT;
+
+/* garbage-collection options */
+pub const LUA_GCSTOP: i32 = 0;
+pub const LUA_GCRESTART: i32 = 1;
+pub const LUA_GCCOLLECT: i32 = 2;
+pub const LUA_GCCOUNT: i32 = 3;
+pub const LUA_GCCOUNTB: i32 = 4;
+pub const LUA_GCSTEP: i32 = 5;
+pub const LUA_GCSETPAUSE: i32 = 6;
+pub const LUA_GCSETSTEPMUL: i32 = 7;
+pub const LUA_GCISRUNNING: i32 = 9;
+
 /* basic types */
 pub const LUA_TNONE: i8 = -1;
 pub const LUA_TNIL: i8 = 0;
@@ -1,5 +1,37 @@
+pub mod aux_lib;
 pub mod consts;
 
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use self::consts::LUA_REGISTRYINDEX;
+use crate::state::lua_state::LuaState;
+use crate::state::lua_userdata::LuaUserData;
+
+pub use crate::state::lua_debug::{LuaDebug, LuaHook};
+pub use crate::state::lua_error::{LuaError, LuaResult};
+pub use crate::state::lua_state::InterruptHandle;
+
+/// Rust function called with its arguments on the stack,
+/// which returns the number of results left on the top of stack or raises an error
+pub type RustFn = fn(&mut LuaState) -> LuaResult<usize>;
+
+/// Continuation of a Rust function whose protected call by `pcallk` yielded,
+/// called with the status of the call and the context given to `pcallk`
+pub type RustKFn = fn(&mut LuaState, i8, isize) -> LuaResult<usize>;
+
+/// Rust closure which may capture Rust states
+pub type RustClosure = Box<dyn Fn(&mut LuaState) -> LuaResult<usize>>;
+
+/// Host handler of `os.exit` called with the exit status and the `close` argument
+pub type ExitHandler = Box<dyn Fn(i32, bool)>;
+
+/// Pseudo-index of the `i`-th up value of the running function, which starts from 1
+#[inline]
+pub fn upvalue_index(i: isize) -> isize {
+    LUA_REGISTRYINDEX - i
+}
+
 /// Lua State API
 pub trait LuaAPI {
     /* basic stack manipulation */
@@ -7,13 +39,13 @@ pub trait LuaAPI {
     fn abs_index(&self, idx: isize) -> isize;
     fn check_stack(&mut self, n: usize) -> bool;
     fn pop(&mut self, n: usize);
-    fn copy(&mut self, from_idx: isize, to_idx: isize);
+    fn copy(&mut self, from_idx: isize, to_idx: isize) -> LuaResult<()>;
     fn push_value(&mut self, idx: isize);
-    fn replace(&mut self, idx: isize);
-    fn insert(&mut self, idx: isize);
-    fn remove(&mut self, idx: isize);
-    fn rotate(&mut self, idx: isize, n: isize);
-    fn set_top(&mut self, idx: isize);
+    fn replace(&mut self, idx: isize) -> LuaResult<()>;
+    fn insert(&mut self, idx: isize) -> LuaResult<()>;
+    fn remove(&mut self, idx: isize) -> LuaResult<()>;
+    fn rotate(&mut self, idx: isize, n: isize) -> LuaResult<()>;
+    fn set_top(&mut self, idx: isize) -> LuaResult<()>;
 
     /* access functions (stack -> rust) */
     fn type_name(&self, tp: i8) -> &str;
@@ -30,6 +62,8 @@ pub trait LuaAPI {
     fn is_table(&self, idx: isize) -> bool;
     fn is_thread(&self, idx: isize) -> bool;
     fn is_function(&self, idx: isize) -> bool;
+    fn is_rust_function(&self, idx: isize) -> bool;
+    fn is_userdata(&self, idx: isize) -> bool;
 
     fn to_boolean(&self, idx: isize) -> bool;
     fn to_integer(&self, idx: isize) -> i64;
@@ -38,6 +72,10 @@ pub trait LuaAPI {
     fn to_numberx(&self, idx: isize) -> Option<f64>;
     fn to_string(&self, idx: isize) -> String;
     fn to_stringx(&self, idx: isize) -> Option<String>;
+    fn to_bytes(&self, idx: isize) -> Vec<u8>;
+    fn to_bytesx(&self, idx: isize) -> Option<Vec<u8>>;
+    fn to_pointer(&self, idx: isize) -> *const ();
+    fn to_userdata(&self, idx: isize) -> Option<Rc<RefCell<LuaUserData>>>;
 
     /* push functions (rust -> stack) */
     fn push_nil(&mut self);
@@ -45,13 +83,74 @@ pub trait LuaAPI {
     fn push_integer(&mut self, n: i64);
     fn push_number(&mut self, n: f64);
     fn push_string(&mut self, s: String);
+    fn push_bytes(&mut self, s: Vec<u8>);
+    fn push_rust_function(&mut self, f: RustFn);
+    fn push_rust_closure(&mut self, f: RustClosure, n: usize);
+    fn push_global_table(&mut self);
+
+    /* get functions (Lua -> stack) */
+    fn create_table(&mut self, narr: usize, nrec: usize);
+    fn new_table(&mut self);
+    fn new_userdata(&mut self, data: Box<dyn std::any::Any>);
+    fn get_table(&mut self, idx: isize) -> LuaResult<i8>;
+    fn get_field(&mut self, idx: isize, k: &str) -> LuaResult<i8>;
+    fn get_i(&mut self, idx: isize, i: i64) -> LuaResult<i8>;
+    fn raw_get(&mut self, idx: isize) -> LuaResult<i8>;
+    fn get_global(&mut self, name: &str) -> LuaResult<i8>;
+    fn get_metatable(&mut self, idx: isize) -> bool;
+
+    /* set functions (stack -> Lua) */
+    fn set_table(&mut self, idx: isize) -> LuaResult<()>;
+    fn set_field(&mut self, idx: isize, k: &str) -> LuaResult<()>;
+    fn set_i(&mut self, idx: isize, i: i64) -> LuaResult<()>;
+    fn raw_set(&mut self, idx: isize) -> LuaResult<()>;
+    fn set_global(&mut self, name: &str) -> LuaResult<()>;
+    fn set_metatable(&mut self, idx: isize) -> LuaResult<()>;
+    fn register(&mut self, name: &str, f: RustFn) -> LuaResult<()>;
+
+    /* 'load' and 'call' functions (load and run Lua code) */
+    fn load(&mut self, chunk: Vec<u8>, chunk_name: &str, mode: &str) -> i8;
+    fn call(&mut self, nargs: isize, nresults: isize) -> LuaResult<()>;
+    fn pcall(&mut self, nargs: isize, nresults: isize, msgh: isize) -> i8;
+    fn pcallk(&mut self, nargs: isize, nresults: isize, msgh: isize, ctx: isize, k: RustKFn) -> i8;
+
+    /* coroutine functions */
+    fn new_thread(&mut self);
+    fn push_thread(&mut self) -> bool;
+    fn xmove(&mut self, idx: isize, n: usize) -> LuaResult<()>;
+    fn resume(&mut self, idx: isize, nargs: isize) -> (i8, usize);
+    fn yield_(&mut self, n: isize) -> LuaResult<usize>;
+    fn status(&self, idx: isize) -> i8;
+    fn is_yieldable(&self) -> bool;
 
     /* comparison and arithmetic functions */
-    fn arith(&mut self, op: u8);
-    fn compare(&self, idx1: isize, idx2: isize, op: u8) -> bool;
+    fn arith(&mut self, op: u8) -> LuaResult<()>;
+    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> LuaResult<bool>;
     /* miscellaneous functions */
-    fn len(&mut self, idx: isize);
-    fn concat(&mut self, n: isize);
+    fn error(&mut self) -> LuaError;
+    fn len(&mut self, idx: isize) -> LuaResult<()>;
+    fn raw_len(&self, idx: isize) -> usize;
+    fn raw_equal(&self, idx1: isize, idx2: isize) -> bool;
+    fn concat(&mut self, n: isize) -> LuaResult<()>;
+    fn next(&mut self, idx: isize) -> LuaResult<bool>;
+    fn string_to_number(&mut self, s: &str) -> bool;
+
+    /* garbage-collection function */
+    fn gc(&mut self, what: i32, data: i32) -> LuaResult<i32>;
+
+    /* debug API */
+    fn get_stack(&self, level: usize) -> Option<LuaDebug>;
+    fn get_info(&mut self, what: &str, ar: &mut LuaDebug) -> bool;
+    fn get_local(&mut self, ar: Option<&LuaDebug>, n: isize) -> Option<String>;
+    fn set_local(&mut self, ar: &LuaDebug, n: isize) -> Option<String>;
+    fn get_upvalue(&mut self, func_idx: isize, n: usize) -> Option<String>;
+    fn set_upvalue(&mut self, func_idx: isize, n: usize) -> Option<String>;
+    fn upvalue_id(&self, func_idx: isize, n: usize) -> Option<*const ()>;
+    fn upvalue_join(&mut self, func_idx1: isize, n1: usize, func_idx2: isize, n2: usize);
+    fn set_hook(&mut self, f: Option<LuaHook>, mask: i32, count: i32);
+    fn get_hook(&self) -> Option<LuaHook>;
+    fn get_hook_mask(&self) -> i32;
+    fn get_hook_count(&self) -> i32;
 }
 
 /// Lua VM API
@@ -61,4 +160,17 @@ pub trait LuaVM: LuaAPI {
     fn fetch(&mut self) -> u32;
     fn get_const(&mut self, idx: isize);
     fn get_rk(&mut self, rk: isize);
+    fn close_upvalues(&mut self, a: isize);
+    fn register_count(&self) -> isize;
+    fn load_vararg(&mut self, n: isize);
+    fn load_proto(&mut self, idx: usize);
+    /// Error with the message prefixed by the position of the running function
+    fn runtime_error(&self, msg: &str) -> LuaError;
+    /// Call the function below the `nargs` arguments on the top of stack without waiting for it,
+    /// a Lua function runs in a new frame until it returns by `post_call`
+    fn pre_call(&mut self, nargs: isize, nresults: isize) -> LuaResult<()>;
+    /// Return the `n` values on the top of stack from the running function
+    fn post_call(&mut self, n: isize) -> LuaResult<()>;
+    /// Replace the running function with the call to the function below the `nargs` arguments
+    fn tail_call(&mut self, nargs: isize) -> LuaResult<()>;
 }
@@ -52,9 +52,8 @@ fn main() {
         let proto = gen_prototype(Box::new(block)).unwrap();
         let file_name = file_name.to_str().unwrap().to_string();
         let bytecode = encode(proto, Some("@".to_string() + &file_name));
-        let s = unsafe { String::from_utf8_unchecked(bytecode) };
         println!("{:?}\n", file_name);
-        fs::write(path.file_stem().unwrap().to_str().unwrap().to_string() + ".out", s);
+        fs::write(path.file_stem().unwrap().to_str().unwrap().to_string() + ".out", bytecode);
     } else {
         println!("not a legal command")
     }
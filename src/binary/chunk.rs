@@ -70,28 +70,13 @@ pub struct Prototype {
     pub up_value_names: Vec<String>,
 }
 
-impl Prototype {
-    fn to_bytes(self) -> Vec<u8> {
-        unimplemented!()
-    }
-}
-
 /// Lua Up Value
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Default)]
 pub struct UpValue {
     pub instack: u8,
     pub idx: u8,
 }
 
-impl Default for UpValue {
-    fn default() -> Self {
-        Self {
-            instack: 0,
-            idx: 0,
-        }
-    }
-}
-
 impl UpValue {
     pub fn new(instack: u8, idx: u8) -> Self {
         Self {
@@ -115,7 +100,7 @@ pub enum Constant {
     Boolean(bool),
     Number(f64),
     Integer(i64),
-    String(String),
+    String(Vec<u8>),
 }
 
 impl Hash for Constant {
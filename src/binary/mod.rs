@@ -5,11 +5,11 @@ pub mod reader;
 pub mod writer;
 
 
-/// decode Lua binary chunk to prototype structure
-pub fn decode(data: Vec<u8>) -> Rc<chunk::Prototype> {
+/// decode Lua binary chunk to prototype structure, which fails if it is truncated or malformed
+pub fn decode(data: Vec<u8>) -> reader::Result<Rc<chunk::Prototype>> {
     let mut r = reader::Reader::new(data);
-    r.check_header();
-    r.read_byte();
+    r.check_header()?;
+    r.read_byte()?; // the number of up values
     r.read_proto()
 }
 
@@ -21,6 +21,7 @@ pub fn encode(proto: Rc<chunk::Prototype>, src: Option<String>) -> Vec<u8> {
     writer.as_bytes()
 }
 
+#[cfg(test)]
 mod tests {
     use std::fs;
 
@@ -31,20 +32,51 @@ mod tests {
         let mut writer = writer::Writer::new();
         writer.write_header();
         let mut reader = reader::Reader::new(writer.as_bytes());
-        reader.check_header();
+        reader.check_header().unwrap();
     }
 
 
     #[test]
     fn test_decode() {
         let s = fs::read("./tests/luac.out").expect("error");
-        let proto = decode(s);
+        let proto = decode(s.clone()).unwrap();
+        assert_eq!(decode(encode(proto, None)).unwrap().code.len(), decode(s.clone()).unwrap().code.len());
+
+        // every truncated chunk is an error
+        for n in 0..s.len() {
+            assert_eq!(decode(s[..n].to_vec()).unwrap_err(), reader::Error::Truncated);
+        }
+    }
+
+    #[test]
+    fn test_decode_corrupted() {
+        let s = fs::read("./tests/luac.out").expect("error");
+        let mut bad = s.clone();
+        bad[4] = 0x52;
+        assert_eq!(decode(bad).unwrap_err().to_string(), "bad binary format (version mismatch)");
+        let mut bad = s.clone();
+        bad[17] = 0;
+        assert_eq!(decode(bad).unwrap_err().to_string(), "bad binary format (endianness mismatch)");
+
+        // a huge size of a vector or a string is truncated
+        let mut bad = s[..33].to_vec();
+        bad.extend_from_slice(&[1, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(decode(bad).unwrap_err(), reader::Error::Truncated);
+
+        // a corrupted byte never panics
+        for i in 33..s.len() {
+            for &b in [0u8, 0x7F, 0xFF].iter() {
+                let mut bad = s.clone();
+                bad[i] = b;
+                let _ = decode(bad);
+            }
+        }
     }
 
+    #[allow(dead_code)]
     fn test_encode() {
         let chunk = fs::read("./tests/luac.out").expect("error");
-        let proto = encode(decode(chunk.clone()), Some("@hello.lua".to_string()));
-        let s = unsafe { String::from_utf8_unchecked(proto.clone()) };
-        fs::write("./tests/test.out", s);
+        let proto = encode(decode(chunk.clone()).unwrap(), Some("@hello.lua".to_string()));
+        fs::write("./tests/test.out", proto).unwrap();
     }
 }
@@ -1,159 +1,230 @@
+use std::fmt::{self, Display, Formatter};
 use std::rc::Rc;
+use std::result;
 
 use crate::binary::chunk::*;
 
+/// Wrapped for the errors of malformed binary chunks
+pub type Result<T> = result::Result<T, Error>;
+
+/// Errors of a binary chunk which is not produced by `luac` or `Writer`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Error {
+    /// The chunk ends before all of its data
+    Truncated,
+    /// The header or a value does not match the format
+    BadFormat(&'static str),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "truncated precompiled chunk"),
+            Error::BadFormat(why) => write!(f, "bad binary format ({})", why),
+        }
+    }
+}
+
+/// Limit of the nested functions, which are read recursively
+const MAX_NESTED_PROTOS: usize = 200;
+
 #[derive(Debug, Clone)]
 pub struct Reader {
     data: Vec<u8>,
     pos: usize,
+    /// The depth of the function being read
+    depth: usize,
 }
 
 impl Reader {
     #[inline]
     pub fn new(data: Vec<u8>) -> Self {
-        Self { data, pos: 0 }
+        Self { data, pos: 0, depth: 0 }
     }
 
     #[inline]
-    pub fn read_byte(&mut self) -> u8 {
-        let b = self.data[self.pos];
+    pub fn read_byte(&mut self) -> Result<u8> {
+        let b = *self.data.get(self.pos).ok_or(Error::Truncated)?;
         self.pos += 1;
-        b
+        Ok(b)
     }
 
     #[inline]
-    fn read_u32(&mut self) -> u32 {
-        let a0 = self.read_byte() as u32;
-        let a1 = self.read_byte() as u32;
-        let a2 = self.read_byte() as u32;
-        let a3 = self.read_byte() as u32;
-        (a3 << 24) | (a2 << 16) | (a1 << 8) | a0
+    pub fn pos(&self) -> usize {
+        self.pos
     }
 
     #[inline]
-    fn read_u64(&mut self) -> u64 {
-        let a0 = self.read_u32() as u64;
-        let a1 = self.read_u32() as u64;
-        dbg!(a1 << 32 | a0);
-        (a1 << 32) | a0
+    pub fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    /// Take the next `n` bytes
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.data.len()).ok_or(Error::Truncated)?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Read an integer of `size` bytes, which is sign extended if `signed`,
+    /// return None if it does not fit into 64 bits or there are not enough bytes
+    pub fn read_int(&mut self, size: usize, little: bool, signed: bool) -> Option<i64> {
+        let bytes = self.take(size).ok()?;
+        let byte = |i: usize| if little { bytes[i] } else { bytes[size - 1 - i] };
+        let limit = size.min(8);
+        let mut res: u64 = 0;
+        for i in (0..limit).rev() {
+            res = (res << 8) | byte(i) as u64;
+        }
+        if size < 8 {
+            if signed {
+                // sign extension
+                let mask = 1u64 << (size * 8 - 1);
+                res = (res ^ mask).wrapping_sub(mask);
+            }
+        } else if size > 8 {
+            // the other bytes must be the sign extension
+            let mask = if !signed || (res as i64) >= 0 { 0 } else { 0xFF };
+            if (limit..size).any(|i| byte(i) != mask) {
+                return None;
+            }
+        }
+        Some(res as i64)
     }
 
     #[inline]
-    fn read_lua_integer(&mut self) -> i64 {
-        self.read_u64() as i64
+    fn read_u32(&mut self) -> Result<u32> {
+        self.read_int(4, true, false).map(|i| i as u32).ok_or(Error::Truncated)
     }
 
     #[inline]
-    fn read_lua_number(&mut self) -> f64 {
-        use std::f64; // TODO
-        f64::from_bits(self.read_u64())
+    fn read_u64(&mut self) -> Result<u64> {
+        self.read_int(8, true, false).map(|i| i as u64).ok_or(Error::Truncated)
     }
 
-    fn read_bytes(&mut self, n: usize) -> Vec<u8> {
-        let mut vec = Vec::new();
-        for _ in 0..n {
-            vec.push(self.read_byte());
-        }
-        vec
+    #[inline]
+    fn read_lua_integer(&mut self) -> Result<i64> {
+        self.read_u64().map(|i| i as i64)
     }
 
     #[inline]
-    fn read_string(&mut self) -> String {
-        self.read_string0().unwrap_or_default()
+    fn read_lua_number(&mut self) -> Result<f64> {
+        self.read_u64().map(f64::from_bits)
     }
 
-    fn read_string0(&mut self) -> Option<String> {
-        let mut size = self.read_byte() as usize;
-        dbg!(&size);
-        if size == 0 {
-            return None;
-        }
+    pub fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        self.take(n).map(|bytes| bytes.to_vec())
+    }
+
+    #[inline]
+    fn read_string(&mut self) -> Result<Vec<u8>> {
+        Ok(self.read_string0()?.unwrap_or_default())
+    }
+
+    /// A source or a name for debug information
+    fn read_name(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.read_string()?).into_owned())
+    }
+
+    fn read_string0(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut size = self.read_byte()? as usize;
         if size == 0xFF {
-            size = self.read_u32() as usize; // size_t
+            size = self.read_u32()? as usize; // size_t
         }
-        let bytes = self.read_bytes(size - 1);
-        let string = String::from_utf8(bytes);
-        string.ok()
+        if size == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.read_bytes(size - 1)?))
     }
 
-    fn read_vec<T, F>(&mut self, f: F) -> Vec<T>
-        where
-            F: Fn(&mut Reader) -> T,
+    fn read_vec<T, F>(&mut self, f: F) -> Result<Vec<T>>
+    where
+        F: Fn(&mut Reader) -> Result<T>,
     {
-        let n = self.read_u32() as usize;
-        let mut vec = Vec::with_capacity(n);
+        let n = self.read_u32()? as usize;
+        // every element takes a byte at least, so a wrong size can not allocate too much
+        let mut vec = Vec::with_capacity(n.min(self.data.len() - self.pos));
         for _i in 0..n {
-            vec.push(f(self));
+            vec.push(f(self)?);
         }
-        vec
+        Ok(vec)
     }
 
-    pub fn check_header(&mut self) {
+    /// Check that the header matches the one written by `Writer::write_header`
+    pub fn check_header(&mut self) -> Result<()> {
         // 17 + 16 = 33
-        assert_eq!(self.read_bytes(4), LUA_SIGNATURE, "not a precompiled chunk!");
-        assert_eq!(self.read_byte(), LUAC_VERSION, "version mismatch!");
-        assert_eq!(self.read_byte(), LUAC_FORMAT, "format mismatch!");
-        assert_eq!(self.read_bytes(6), LUAC_DATA, "corrupted!");
-        assert_eq!(self.read_byte(), CINT_SIZE, "int size mismatch!");
-        assert_eq!(self.read_byte(), CSIZET_SIZE, "size_t size mismatch!");
-        assert_eq!(self.read_byte(), INSTRUCTION_SIZE, "instruction size mismatch!");
-        assert_eq!(self.read_byte(), LUA_INTEGER_SIZE, "lua_Integer size mismatch!");
-        assert_eq!(self.read_byte(), LUA_NUMBER_SIZE, "lua_Number size mismatch!");
-        assert_eq!(self.read_lua_integer(), LUAC_INT, "endianness mismatch!");
-        assert_eq!(self.read_lua_number(), LUAC_NUM, "float format mismatch!");
+        let check = |ok: bool, why: &'static str| if ok { Ok(()) } else { Err(Error::BadFormat(why)) };
+        check(self.read_bytes(4)? == LUA_SIGNATURE, "not a precompiled chunk")?;
+        check(self.read_byte()? == LUAC_VERSION, "version mismatch")?;
+        check(self.read_byte()? == LUAC_FORMAT, "format mismatch")?;
+        check(self.read_bytes(6)? == LUAC_DATA, "corrupted")?;
+        check(self.read_byte()? == CINT_SIZE, "int size mismatch")?;
+        check(self.read_byte()? == CSIZET_SIZE, "size_t size mismatch")?;
+        check(self.read_byte()? == INSTRUCTION_SIZE, "instruction size mismatch")?;
+        check(self.read_byte()? == LUA_INTEGER_SIZE, "lua_Integer size mismatch")?;
+        check(self.read_byte()? == LUA_NUMBER_SIZE, "lua_Number size mismatch")?;
+        check(self.read_lua_integer()? == LUAC_INT, "endianness mismatch")?;
+        check(self.read_lua_number()? == LUAC_NUM, "float format mismatch")
     }
 
     #[inline]
-    pub fn read_proto(&mut self) -> Rc<Prototype> {
+    pub fn read_proto(&mut self) -> Result<Rc<Prototype>> {
         self.read_proto0(None)
     }
 
-    fn read_proto0(&mut self, parent_source: Option<String>) -> Rc<Prototype> {
-        let source = self.read_string0().or(parent_source);
-        Rc::new(Prototype {
+    fn read_proto0(&mut self, parent_source: Option<String>) -> Result<Rc<Prototype>> {
+        if self.depth >= MAX_NESTED_PROTOS {
+            return Err(Error::BadFormat("too many nested functions"));
+        }
+        self.depth += 1;
+        let source = self.read_string0()?.map(|s| String::from_utf8_lossy(&s).into_owned()).or(parent_source);
+        let proto = Prototype {
             source: source.clone(), // debug
-            line_defined: self.read_u32(),
-            last_line_defined: self.read_u32(),
-            num_params: self.read_byte(),
-            is_vararg: self.read_byte(),
-            max_stack_size: self.read_byte(),
-            code: self.read_vec(|r| r.read_u32()),
-            constants: self.read_vec(|r| r.read_constant()),
-            up_values: self.read_vec(|r| r.read_up_value()),
-            prototypes: self.read_vec(|r| r.read_proto0(source.clone())),
-            line_info: self.read_vec(|r| r.read_u32()),        // debug
-            local_vars: self.read_vec(|r| r.read_loc_var()),     // debug
-            up_value_names: self.read_vec(|r| r.read_string()), // debug
-        })
-    }
-
-    fn read_constant(&mut self) -> Constant {
-        let tag = self.read_byte();
-        match tag {
+            line_defined: self.read_u32()?,
+            last_line_defined: self.read_u32()?,
+            num_params: self.read_byte()?,
+            is_vararg: self.read_byte()?,
+            max_stack_size: self.read_byte()?,
+            code: self.read_vec(|r| r.read_u32())?,
+            constants: self.read_vec(|r| r.read_constant())?,
+            up_values: self.read_vec(|r| r.read_up_value())?,
+            prototypes: self.read_vec(|r| r.read_proto0(source.clone()))?,
+            line_info: self.read_vec(|r| r.read_u32())?,        // debug
+            local_vars: self.read_vec(|r| r.read_loc_var())?,     // debug
+            up_value_names: self.read_vec(|r| r.read_name())?,   // debug
+        };
+        self.depth -= 1;
+        Ok(Rc::new(proto))
+    }
+
+    fn read_constant(&mut self) -> Result<Constant> {
+        let tag = self.read_byte()?;
+        Ok(match tag {
             TAG_NIL => Constant::Nil,
-            TAG_BOOLEAN => Constant::Boolean(self.read_byte() != 0),
-            TAG_INTEGER => Constant::Integer(self.read_lua_integer()),
-            TAG_NUMBER => Constant::Number(self.read_lua_number()),
-            TAG_SHORT_STR => Constant::String(self.read_string()),
-            TAG_LONG_STR => Constant::String(self.read_string()),
-            _ => panic!("corrupted!"),
-        }
+            TAG_BOOLEAN => Constant::Boolean(self.read_byte()? != 0),
+            TAG_INTEGER => Constant::Integer(self.read_lua_integer()?),
+            TAG_NUMBER => Constant::Number(self.read_lua_number()?),
+            TAG_SHORT_STR => Constant::String(self.read_string()?),
+            TAG_LONG_STR => Constant::String(self.read_string()?),
+            _ => return Err(Error::BadFormat("corrupted")),
+        })
     }
 
     #[inline]
-    fn read_up_value(&mut self) -> UpValue {
-        UpValue {
-            instack: self.read_byte(),
-            idx: self.read_byte(),
-        }
+    fn read_up_value(&mut self) -> Result<UpValue> {
+        Ok(UpValue {
+            instack: self.read_byte()?,
+            idx: self.read_byte()?,
+        })
     }
 
     #[inline]
-    fn read_loc_var(&mut self) -> LocalVar {
-        LocalVar {
-            var_name: self.read_string(),
-            start_pc: self.read_u32(),
-            end_pc: self.read_u32(),
-        }
+    fn read_loc_var(&mut self) -> Result<LocalVar> {
+        Ok(LocalVar {
+            var_name: self.read_name()?,
+            start_pc: self.read_u32()?,
+            end_pc: self.read_u32()?,
+        })
     }
 }
@@ -7,6 +7,12 @@ pub struct Writer {
     data: Vec<u8>,
 }
 
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Writer {
     #[inline]
     pub fn new() -> Self {
@@ -23,24 +29,29 @@ impl Writer {
         self.data
     }
 
+    /// Write the `size` lower bytes of an integer,
+    /// the bytes beyond 8 are the sign extension of a negative integer if `neg`
+    pub fn write_int(&mut self, n: u64, size: usize, little: bool, neg: bool) {
+        let ext = if neg { 0xFF } else { 0 };
+        let k = size.min(8);
+        let len = self.data.len() + size;
+        if little {
+            self.data.extend_from_slice(&n.to_le_bytes()[..k]);
+            self.data.resize(len, ext);
+        } else {
+            self.data.resize(len - k, ext);
+            self.data.extend_from_slice(&n.to_be_bytes()[8 - k..]);
+        }
+    }
+
+    #[inline]
     fn write_u32(&mut self, b4: u32) {
-        let a0 = (b4 >> 24) as u8;
-        let a1 = (b4 >> 16) as u8;
-        let a2 = (b4 >> 8) as u8;
-        let a3 = b4 as u8;
-        self.write_byte(a3);
-        self.write_byte(a2);
-        self.write_byte(a1);
-        self.write_byte(a0);
+        self.write_int(b4 as u64, 4, true, false);
     }
 
     #[inline]
     fn write_u64(&mut self, b8: u64) {
-        let a0 = ((b8 & 0xFF_FF_FF_FF_00_00_00_00) >> 32) as u32;
-        let a1 = (b8 & 0xFF_FF_FF_FF) as u32;
-
-        self.write_u32(a1);
-        self.write_u32(a0);
+        self.write_int(b8, 8, true, false);
     }
 
     #[inline]
@@ -54,29 +65,29 @@ impl Writer {
     }
 
     #[inline]
-    fn write_bytes(&mut self, bytes: Vec<u8>) {
+    pub fn write_bytes(&mut self, bytes: Vec<u8>) {
         for b in bytes {
             self.write_byte(b);
         }
     }
 
     #[inline]
-    fn write_string(&mut self, s: &String) {
+    fn write_string(&mut self, s: &[u8]) {
         self.write_string0(s).unwrap_or_default()
     }
 
-    fn write_string0(&mut self, s: &String) -> Option<()> {
-        if s.len() == 0 {
+    fn write_string0(&mut self, s: &[u8]) -> Option<()> {
+        if s.is_empty() {
             self.write_byte(0);
-            None
+            return None;
         } else if s.len() < 0xFF {
             self.write_byte(s.len() as u8 + 1);
-            self.write_bytes(s.as_bytes().to_vec());
-            Some(())
         } else {
-            // todo
-            unimplemented!()
+            self.write_byte(0xFF);
+            self.write_u32(s.len() as u32 + 1); // size_t
         }
+        self.write_bytes(s.to_vec());
+        Some(())
     }
 
     pub fn write_header(&mut self) {
@@ -94,7 +105,7 @@ impl Writer {
     }
 
     pub fn write_proto(&mut self, proto: Rc<Prototype>, parent_source: Option<String>) {
-        self.write_string0(&parent_source.clone().unwrap_or_default());
+        self.write_string0(parent_source.unwrap_or_default().as_bytes());
 
         self.write_u32(proto.line_defined);
         self.write_u32(proto.last_line_defined);
@@ -134,7 +145,7 @@ impl Writer {
 
         self.write_u32(proto.up_value_names.len() as u32);
         for name in proto.up_value_names.iter() {
-            self.write_string(name);
+            self.write_string(name.as_bytes());
         }
     }
 
@@ -173,7 +184,7 @@ impl Writer {
 
     #[inline]
     fn write_loc_var(&mut self, local_var: &LocalVar) {
-        self.write_string(&local_var.var_name);
+        self.write_string(local_var.var_name.as_bytes());
         self.write_u32(local_var.start_pc);
         self.write_u32(local_var.end_pc);
     }
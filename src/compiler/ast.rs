@@ -28,8 +28,8 @@ impl Block {
 pub enum Stat {
     Empty,
     Break(Line),
-    Label(String),
-    Goto(String),
+    Label(String, Line),
+    Goto(String, Line),
     Do(Box<Block>),
     While(Exp, Box<Block>),
     Repeat(Exp, Box<Block>),
@@ -57,7 +57,7 @@ pub enum Exp {
     Vararg(Line),
     Integer(i64, Line),
     Float(f64, Line),
-    String(String, Line),
+    String(Vec<u8>, Line),
     Name(String, Line),
     Parens(Box<Exp>),
     Unop(Token, Box<Exp>, Line),
@@ -31,12 +31,9 @@
 //! - 'Bx' : 18 bits ('B' and 'C' together)
 //! - 'sBx' : signed Bx
 
-#![allow(dead_code)]
-#![allow(unused_variables)]
-#![allow(unused_mut)]
 #![allow(non_snake_case)]
 
-use std::cell::{Ref, RefCell};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::iter;
 use std::rc::Rc;
@@ -53,36 +50,44 @@ use crate::vm::opcode;
 const MAXARG_BX: isize = (1 << 18) - 1;
 /// 131071
 const MAXARG_SBX: isize = MAXARG_BX >> 1;
-
-
+/// 511
+const MAXARG_C: isize = (1 << 9) - 1;
+/// Number of list items to accumulate before a SETLIST instruction
+const LFIELDS_PER_FLUSH: isize = 50;
+
+/* kinds of operand that `exp_to_op_arg` may produce */
+const ARG_CONST: u8 = 1;
+const ARG_REG: u8 = 2;
+const ARG_UPVAL: u8 = 4;
+const ARG_RK: u8 = ARG_REG | ARG_CONST;
+const ARG_RU: u8 = ARG_REG | ARG_UPVAL;
+
+/// Generate the prototype of main function for a Lua chunk.
+///
+/// The chunk is compiled as a vararg function nested in a dummy function owning
+/// the local variable `_ENV`, so that `_ENV` is always the first up value of main function.
 pub fn gen_prototype(block: Box<Block>) -> Result<Rc<Prototype>> {
     let last_line = block.last_line;
-    let fn_def = FnDef::new(ParList::default(), block, 0, last_line);
-    let mut fn_info = FnInfo::new(None, ParList::default(), 0, last_line);
-//    fn_info.add_local_var("_ENV".to_string(), 0)?;
-    fn_info.up_values.insert(
-        "_ENV".to_string(),
-        UpValueInfo::new(Some(0), Some(0), 0),
-    );
-
-
-    let fn_info_ref = FnInfoRef(Rc::new(RefCell::new(fn_info)));
-    FnInfo::codegen_fn_def_exp2(&fn_info_ref, &fn_def, 0)?;
-    fn_info_ref.0.borrow_mut().emit_return(last_line, 0, 0);
-    let proto = fn_info_ref.0.borrow_mut().to_prototype();
-    Ok(proto)
-
-//    fn_info.codegen_fn_def_exp(&fn_def, 0)?;
-//    fn_info.emit_return(last_line, 0, 0);
-//    Ok(fn_info.to_prototype())
+    let mut env_fn = FnInfo::new(None, ParList::default(), 0, last_line);
+    env_fn.add_local_var("_ENV".to_string(), 0)?;
+
+    let mut main_fn = FnInfo::new(Some(&mut env_fn), ParList::default(), 0, last_line);
+    main_fn.up_value_index("_ENV");
+    main_fn.codegen_block(&block)?;
+    main_fn.exit_scope(main_fn.pc().wrapping_add(2))?;
+    main_fn.emit_return(last_line, 0, 0);
+    Ok(main_fn.to_prototype())
 }
 
 /// Local Variable Information Reference
 pub type LocalVarInfoRef = Rc<RefCell<LocalVarInfo>>;
 
 /// Local Variable Information
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct LocalVarInfo {
+    /// The variable with same name shadowed by this one
+    prev: Option<LocalVarInfoRef>,
+    name: String,
     scope_level: isize,
     slot: usize,
     is_captured: bool,
@@ -109,13 +114,38 @@ impl UpValueInfo {
     }
 }
 
-/// Function Information Table Reference
+/// A label, or a goto statement waiting for its label
 #[derive(Debug, Clone)]
-pub struct FnInfoRef(Rc<RefCell<FnInfo>>);
+struct LabelInfo {
+    name: String,
+    /// The pc of the label, or of the jump of the goto
+    pc: usize,
+    /// Num of active local variables
+    num_active: usize,
+    line: Line,
+}
+
+/// The labels declared in a block, and the pending gotos of the block and its nested ones
+#[derive(Debug, Default)]
+struct LabelScope {
+    /// Num of active local variables when entering the block
+    num_active: usize,
+    labels: Vec<LabelInfo>,
+    gotos: Vec<LabelInfo>,
+    /// The block of `repeat`, whose local variables are visible in `until`
+    in_repeat: bool,
+}
+
+/// The enclosing function seen by a nested function when resolving up values
+trait Enclosing {
+    /// Mark the visible local variable as captured and return its register
+    fn capture_local_var(&mut self, name: &str) -> Option<usize>;
+    /// Get (or create) up value's index
+    fn up_value_index(&mut self, name: &str) -> Option<usize>;
+}
 
 /// Function Information Table for Lua
-#[derive(Debug, Clone)]
-pub struct FnInfo {
+pub struct FnInfo<'a> {
     constants: HashMap<Constant, usize>,
     /// Num of used regs
     used_regs: usize,
@@ -123,22 +153,22 @@ pub struct FnInfo {
     max_regs: usize,
     /// Block scope level
     scope_level: isize,
-    /// Local variable of all scope
-    local_vars: Vec<HashMap<String, LocalVarInfoRef>>,
+    /// All local variables in declaration order
+    local_vars: Vec<LocalVarInfoRef>,
+    /// Visible local variables
+    local_names: HashMap<String, LocalVarInfoRef>,
     /// Record some breaks statements
     breaks: Vec<Option<Vec<usize>>>,
-    /// Parents' index
-//    parent_index: usize,
-    /// 当前节点的索引
-//    current_index: usize,
+    /// Labels and pending gotos of each block
+    label_scopes: Vec<LabelScope>,
     /// UpValues
     up_values: HashMap<String, UpValueInfo>,
     /// Store Lua instructions
     instructions: Vec<u32>,
     /// Nested Functions
-    sub_fns: Vec<FnInfoRef>,
+    sub_fns: Vec<Rc<Prototype>>,
     /// parent function
-    parent: Option<FnInfoRef>,
+    parent: Option<&'a mut dyn Enclosing>,
     /// The function's param num
     num_params: usize,
     /// Has `...`
@@ -151,75 +181,33 @@ pub struct FnInfo {
     last_line: Line,
 }
 
-static mut COUNT: usize = 0;
-
-fn count() -> usize {
-    unsafe {
-        COUNT += 1;
-        COUNT
-    }
-}
-
-impl FnInfoRef {
-    #[inline]
-    fn new(parent: Option<FnInfoRef>, par_list: ParList, line: Line, last_line: Line) -> Self {
-        let is_vararg = par_list.is_vararg;
-        let num_params = par_list.params.len();
-
-        Self(Rc::new(RefCell::new(FnInfo::new(
-            parent,
-            par_list,
-            line,
-            last_line,
-        ))))
-    }
-}
-
-impl Default for FnInfoRef {
-    fn default() -> Self {
-        Self(Rc::new(RefCell::new(FnInfo::default())))
-    }
-}
-
-
 /********************** keep function information ************************/
 
-impl Default for FnInfo {
-    fn default() -> Self {
-        Self::new(None, ParList::default(), 0, 0)
-    }
-}
-
-impl FnInfo {
+impl<'a> FnInfo<'a> {
     /// Create a FnInfo structure
     #[inline]
-    pub fn new(parent: Option<FnInfoRef>, par_list: ParList, line: Line, last_line: Line) -> Self {
-        let is_vararg = par_list.is_vararg;
-        let num_params = par_list.params.len();
-//        let parent_index = 0;
-//        let current_index = 0;
+    fn new(parent: Option<&'a mut dyn Enclosing>, par_list: ParList, line: Line, last_line: Line) -> Self {
         Self {
             constants: HashMap::new(),
             used_regs: 0,
             max_regs: 0,
             scope_level: 0,
-            local_vars: vec![HashMap::new()],
+            local_vars: vec![],
+            local_names: HashMap::new(),
             breaks: vec![None],
-//            parent_index,
-//            current_index,
+            label_scopes: vec![LabelScope::default()],
             up_values: HashMap::new(),
             instructions: Vec::new(),
             sub_fns: Vec::new(),
             parent,
-            num_params,
-            is_vararg,
+            num_params: par_list.params.len(),
+            is_vararg: par_list.is_vararg,
             line_nums: Vec::new(),
             line,
             last_line,
         }
     }
 
-
     fn constant_index(&mut self, k: &Constant) -> usize {
         match self.constants.get(k) {
             Some(v) => *v,
@@ -261,31 +249,20 @@ impl FnInfo {
         }
     }
 
-    #[inline]
-    fn get_current_scope(&self) -> &HashMap<String, LocalVarInfoRef> {
-        &self.local_vars[self.scope_level as usize]
-    }
-
-    #[inline]
-    fn get_current_scope_mut(&mut self) -> &mut HashMap<String, LocalVarInfoRef> {
-        &mut self.local_vars[self.scope_level as usize]
-    }
-
     /// Create a new scope for vars, true for breakable
     #[inline]
     fn enter_scope(&mut self, breakable: bool) {
         self.scope_level += 1;
-        self.local_vars.push(HashMap::new());
         if breakable {
             self.breaks.push(Some(vec![]));
         } else {
             self.breaks.push(None);
         }
+        self.label_scopes.push(LabelScope { num_active: self.used_regs, ..LabelScope::default() });
     }
 
-    /// Exit current scope
-    #[inline]
-    fn exit_scope(&mut self) -> Result<()> {
+    /// Exit current scope, the local variables of which are alive until `end_pc`
+    fn exit_scope(&mut self, end_pc: usize) -> Result<()> {
         let pending_break_jmps = self.breaks.pop().ok_or(Error::NoMoreScopes)?;
         let a = self.get_jump_arg_a() as usize;
 
@@ -297,26 +274,60 @@ impl FnInfo {
             }
         }
 
+        // the pending gotos leave the block, closing its up values
+        let scope = self.label_scopes.pop().ok_or(Error::NoMoreScopes)?;
+        match self.label_scopes.last_mut() {
+            Some(outer) => {
+                for mut goto in scope.gotos {
+                    if goto.num_active > scope.num_active {
+                        goto.num_active = scope.num_active;
+                        if a > 0 {
+                            let i = self.instructions[goto.pc];
+                            self.instructions[goto.pc] = i & !(0xFF << 6) | (a as u32) << 6;
+                        }
+                    }
+                    outer.gotos.push(goto);
+                }
+            }
+            None => {
+                if let Some(goto) = scope.gotos.into_iter().next() {
+                    return Err(Error::NoLabel { line: goto.line, name: goto.name });
+                }
+            }
+        }
+
         self.scope_level -= 1;
-        match self.local_vars.pop() {
-            Some(vars) => Ok(()),
-            None => Err(Error::NoMoreScopes)
+        let out_of_scope: Vec<_> = self.local_names.values()
+            .filter(|local_var| local_var.borrow().scope_level > self.scope_level)
+            .cloned()
+            .collect();
+        for local_var in out_of_scope {
+            local_var.borrow_mut().end_pc = end_pc;
+            self.remove_local_var(&local_var);
         }
+        Ok(())
     }
 
-    fn get_jump_arg_a(&mut self) -> isize {
+    /// The argument A of a JMP closing the up values captured in current scope
+    fn get_jump_arg_a(&self) -> isize {
         let mut has_captured_local_var = false;
         let mut min_local_var_slot = self.max_regs;
-        // fixme
-        let local_vars = self.get_current_scope_mut();
-        local_vars.iter().for_each(|(k, mut local_var)| {
-            if local_var.borrow_mut().is_captured {
-                has_captured_local_var = true;
-            }
-            if local_var.borrow_mut().slot < min_local_var_slot && k.starts_with('(') {
-                min_local_var_slot = local_var.borrow_mut().slot;
+        for local_var in self.local_names.values() {
+            let mut local_var = Some(local_var.clone());
+            while let Some(var) = local_var {
+                let var = var.borrow();
+                if var.scope_level != self.scope_level {
+                    break;
+                }
+                if var.is_captured {
+                    has_captured_local_var = true;
+                }
+                if var.slot < min_local_var_slot && !var.name.starts_with('(') {
+                    min_local_var_slot = var.slot;
+                }
+                local_var = var.prev.clone();
             }
-        });
+        }
 
         if has_captured_local_var {
             min_local_var_slot as isize + 1
@@ -327,97 +338,62 @@ impl FnInfo {
 
     /// Add a local variable and return register index
     fn add_local_var(&mut self, name: String, start_pc: usize) -> Result<usize> {
-        let mut new_var = Rc::new(RefCell::new(LocalVarInfo {
+        let new_var = Rc::new(RefCell::new(LocalVarInfo {
+            prev: self.local_names.get(&name).cloned(),
+            name: name.clone(),
             scope_level: self.scope_level,
             slot: self.alloc_register()?,
             is_captured: false,
             start_pc,
             end_pc: 0,
         }));
-        let slot = new_var.borrow_mut().slot;
-        if self.local_vars.len() as isize <= self.scope_level {
-            self.local_vars.resize(self.local_vars.len() * 2, HashMap::new());
-        }
-
-        self.local_vars[self.scope_level as usize].insert(name, new_var);
+        let slot = new_var.borrow().slot;
+        self.local_vars.push(new_var.clone());
+        self.local_names.insert(name, new_var);
         Ok(slot)
     }
 
     /// Get name's register number
-    fn local_var_slot(&self, name: &String) -> Result<usize> {
-        for local_var in self.local_vars.iter() {
-            match local_var.get(name) {
-                Some(mut local_var) => { return Ok(local_var.borrow_mut().slot) },
-                _ => {},
-            }
-        }
-        Err(Error::IllegalRegister)
+    fn local_var_slot(&self, name: &str) -> Option<usize> {
+        self.local_names.get(name).map(|local_var| local_var.borrow().slot)
     }
 
-    /// Remove a variable from current scope
-    #[inline]
-    fn remove_local_var(&mut self, name: &String) {
+    /// Remove a local variable, and the shadowed ones declared in the same scope
+    fn remove_local_var(&mut self, local_var: &LocalVarInfoRef) {
         self.free_register();
-        self.get_current_scope_mut().remove(name);
+        let local_var = local_var.borrow();
+        match local_var.prev {
+            None => {
+                self.local_names.remove(&local_var.name);
+            }
+            Some(ref prev) if prev.borrow().scope_level == local_var.scope_level => {
+                self.remove_local_var(prev);
+            }
+            Some(ref prev) => {
+                self.local_names.insert(local_var.name.clone(), prev.clone());
+            }
+        }
     }
 
     /// Create a jump instruction to a latest loop block
     fn add_break_jump(&mut self, pc: usize, line: Line) -> Result<()> {
-        for brk in &mut self.breaks.iter_mut().rev() {
-            match brk.as_mut() {
-                Some(arr) => {
-                    arr.push(pc);
-                    return Ok(());
-                }
-                None => {}
+        for brk in self.breaks.iter_mut().rev() {
+            if let Some(arr) = brk.as_mut() {
+                arr.push(pc);
+                return Ok(());
             }
         }
         Err(Error::NoLoop { line })
     }
 
-    /// Get up value's index
-    fn up_value_index(&mut self, name: &String) -> Option<usize> {
-        // 找出如 _ENV 的upvalue 会一直在外围函数查询
-        // todo: refactor scope lookup
-        if let Some(up_value) = self.up_values.get(name) {
-            return Some(up_value.index);
-            // fixme
-        } else if let Some(ref mut parent) = self.parent {
-            match parent.0.borrow_mut().get_local_var(name) {
-                Some(local_var) => {
-                    let idx = self.up_values.len();
-                    self.up_values.insert(name.clone(), UpValueInfo::new(Some(local_var.borrow_mut().slot), None, idx));
-                    local_var.borrow_mut().is_captured = true;
-                    return Some(idx);
-                }
-
-                None => {}
-            }
-            match parent.0.borrow_mut().up_value_index(name) {
-                Some(upval_idx) => {
-                    let idx = self.up_values.len();
-                    self.up_values.insert(name.clone(), UpValueInfo::new(None, Some(upval_idx), idx));
-                    return Some(idx);
-                }
-                None => {}
-            }
-        }
-
-        None
-    }
-
-    fn get_local_var(&mut self, name: &String) -> Option<&mut LocalVarInfoRef> {
-        for map in self.local_vars.iter_mut() {
-            match map.get_mut(name) {
-                Some(local_var) => { return Some(local_var); }
-                None => {}
-            }
-        }
-        None
+    /// The name of the active local variable at `slot`
+    fn local_var_name(&self, slot: usize) -> String {
+        self.local_names.values()
+            .find(|local_var| local_var.borrow().slot == slot)
+            .map_or_else(String::new, |local_var| local_var.borrow().name.clone())
     }
 
     fn close_open_up_values(&mut self, line: Line) {
-        // todo: understand
         let a = self.get_jump_arg_a();
         if a > 0 {
             self.emit_jmp(line, a, 0);
@@ -428,14 +404,14 @@ impl FnInfo {
         Rc::new(Prototype {
             source: None,
             line_defined: self.line as u32,
-            last_line_defined: self.last_line as u32,
+            last_line_defined: if self.line == 0 { 0 } else { self.last_line as u32 },
             num_params: self.num_params as u8,
             is_vararg: self.is_vararg as u8,
-            max_stack_size: self.max_regs as u8,
+            max_stack_size: self.max_regs.max(2) as u8,
             code: self.instructions.clone(),
             constants: self.get_constants(),
             up_values: self.get_up_values(),
-            prototypes: Self::to_prototypes(&self.sub_fns),
+            prototypes: self.sub_fns.clone(),
             line_info: self.line_nums.clone(),
             local_vars: self.get_local_vars(),
             up_value_names: self.get_up_value_names(),
@@ -443,39 +419,26 @@ impl FnInfo {
     }
 
     fn get_local_vars(&self) -> Vec<LocalVar> {
-        let mut res = vec![];
-        // todo: maybe refactor it to linear structure
-        for local_var in self.local_vars.iter() {
-            for (name, loc_var) in local_var.iter() {
-                let start_pc = loc_var.borrow_mut().start_pc as u32;
-                let end_pc = loc_var.borrow_mut().end_pc as u32;
-                res.push(LocalVar {
-                    var_name: name.clone(),
-                    start_pc,
-                    end_pc,
-                })
+        self.local_vars.iter().map(|local_var| {
+            let local_var = local_var.borrow();
+            LocalVar {
+                var_name: local_var.name.clone(),
+                start_pc: local_var.start_pc as u32,
+                end_pc: local_var.end_pc as u32,
             }
-        }
-
-        res
+        }).collect()
     }
 
     fn get_up_value_names(&self) -> Vec<String> {
-        let mut names: Vec<_> = iter::repeat(String::default()).take(self.up_values.len()).collect();
+        let mut names: Vec<_> = iter::repeat_n(String::default(), self.up_values.len()).collect();
         for (name, up_val) in self.up_values.iter() {
             names[up_val.index] = name.clone();
         }
         names
     }
 
-    fn to_prototypes(sub_fns: &Vec<FnInfoRef>) -> Vec<Rc<Prototype>> {
-        sub_fns.iter().map(|sub_fn| {
-            sub_fn.0.borrow_mut().to_prototype()
-        }).collect()
-    }
-
     fn get_constants(&self) -> Vec<Constant> {
-        let mut consts: Vec<Constant> = iter::repeat(Constant::Nil).take(self.constants.len()).collect();
+        let mut consts: Vec<Constant> = iter::repeat_n(Constant::Nil, self.constants.len()).collect();
         self.constants.iter().for_each(|(cst, &index)| {
             consts[index] = cst.clone();
         });
@@ -483,15 +446,15 @@ impl FnInfo {
     }
 
     fn get_up_values(&self) -> Vec<UpValue> {
-        let mut up_vals: Vec<UpValue> = iter::repeat(UpValue::default()).take(self.up_values.len()).collect();
-
-        self.up_values.iter().for_each(|(_, up_val)| {
-            // instack
-            // fixme
-            if up_val.local_var_slot.is_some() {
-                up_vals[up_val.index] = UpValue::new(1, up_val.local_var_slot.unwrap() as u8);
-            } else {
-                up_vals[up_val.index] = UpValue::new(0, up_val.up_value_index.unwrap() as u8);
+        let mut up_vals: Vec<UpValue> = iter::repeat_n(UpValue::default(), self.up_values.len()).collect();
+
+        self.up_values.values().for_each(|up_val| {
+            if let Some(slot) = up_val.local_var_slot {
+                // captures a local variable of the enclosing function
+                up_vals[up_val.index] = UpValue::new(1, slot as u8);
+            } else if let Some(idx) = up_val.up_value_index {
+                // captures an up value of the enclosing function
+                up_vals[up_val.index] = UpValue::new(0, idx as u8);
             }
         });
 
@@ -499,9 +462,35 @@ impl FnInfo {
     }
 }
 
+impl Enclosing for FnInfo<'_> {
+    fn capture_local_var(&mut self, name: &str) -> Option<usize> {
+        let local_var = self.local_names.get(name)?;
+        let mut local_var = local_var.borrow_mut();
+        local_var.is_captured = true;
+        Some(local_var.slot)
+    }
+
+    fn up_value_index(&mut self, name: &str) -> Option<usize> {
+        if let Some(up_value) = self.up_values.get(name) {
+            return Some(up_value.index);
+        }
+        let parent = self.parent.as_mut()?;
+        let idx = self.up_values.len();
+        if let Some(slot) = parent.capture_local_var(name) {
+            self.up_values.insert(name.to_string(), UpValueInfo::new(Some(slot), None, idx));
+            Some(idx)
+        } else if let Some(up_value_index) = parent.up_value_index(name) {
+            self.up_values.insert(name.to_string(), UpValueInfo::new(None, Some(up_value_index), idx));
+            Some(idx)
+        } else {
+            None
+        }
+    }
+}
+
 /********************** emit bytecode ************************/
 
-impl FnInfo {
+impl FnInfo<'_> {
     #[inline]
     fn emit_ABC(&mut self, line: Line, opcode: u8, a: isize, b: isize, c: isize) {
         let ins = b << 23 | c << 14 | a << 6 | opcode as isize;
@@ -559,6 +548,7 @@ impl FnInfo {
             self.emit_Ax(line, opcode::OP_EXTRAARG, idx);
         }
     }
+
     // r[a], r[a+1], ..., r[a+b-2] = vararg
     #[inline]
     fn emit_vararg(&mut self, line: Line, a: isize, n: isize) {
@@ -580,7 +570,12 @@ impl FnInfo {
     // r[a][(c-1)*FPF+i] := r[a+i], 1 <= i <= b
     #[inline]
     fn emit_set_list(&mut self, line: Line, a: isize, b: isize, c: isize) {
-        self.emit_ABC(line, opcode::OP_SETLIST, a, b, c);
+        if c <= MAXARG_C {
+            self.emit_ABC(line, opcode::OP_SETLIST, a, b, c);
+        } else {
+            self.emit_ABC(line, opcode::OP_SETLIST, a, b, 0);
+            self.emit_Ax(line, opcode::OP_EXTRAARG, c);
+        }
     }
 
     // r[a] := r[b][rk(c)]
@@ -659,27 +654,26 @@ impl FnInfo {
     // if (r[b] <==> c) then r[a] := r[b] else pc++
     #[inline]
     fn emit_test_set(&mut self, line: Line, a: isize, b: isize, c: isize) {
-        self.emit_ABC(line, opcode::OP_TEST, a, b, c);
+        self.emit_ABC(line, opcode::OP_TESTSET, a, b, c);
     }
 
     // R(A)-=R(A+2); pc+=sBx
     #[inline]
-    fn emit_for_prep(&mut self, line: Line, a: isize, sBx: isize) -> isize {
+    fn emit_for_prep(&mut self, line: Line, a: isize, sBx: isize) -> usize {
         self.emit_AsBx(line, opcode::OP_FORPREP, a, sBx);
-        self.instructions.len() as isize - 1
+        self.instructions.len() - 1
     }
 
     // R(A)+=R(A+2); if R(A) <?= R(A+1) then { pc+=sBx; R(A+3)=R(A) }
     #[inline]
-    fn emit_for_loop(&mut self, line: Line, a: isize, sBx: isize) -> isize {
+    fn emit_for_loop(&mut self, line: Line, a: isize, sBx: isize) -> usize {
         self.emit_AsBx(line, opcode::OP_FORLOOP, a, sBx);
-        self.instructions.len() as isize - 1
+        self.instructions.len() - 1
     }
 
     // r(a+3), ... ,r(a+2+c) := r(a)(r(a+1), r(a+2));
     #[inline]
     fn emit_t_for_call(&mut self, line: Line, a: isize, c: isize) {
-        // todo: understand it
         self.emit_ABC(line, opcode::OP_TFORCALL, a, 0, c);
     }
 
@@ -738,7 +732,7 @@ impl FnInfo {
     // return current pc
     #[inline]
     fn pc(&self) -> usize {
-        self.instructions.len() - 1
+        self.instructions.len().wrapping_sub(1)
     }
 
     // fix sbx for one instruction
@@ -747,17 +741,25 @@ impl FnInfo {
         // clear sBx Op
         ins = ins << 18 >> 18;
         // reset sBx op
-        ins = ins | ((sBx + MAXARG_SBX) as u32) << 14;
+        ins |= ((sBx + MAXARG_SBX) as u32) << 14;
         self.instructions[pc] = ins;
     }
 }
 
 /********************** statement code generation ************************/
 
-impl FnInfo {
+impl FnInfo<'_> {
     fn codegen_block(&mut self, block: &Block) -> Result<()> {
-        for stat in &block.stats {
-            self.codegen_stat(stat)?;
+        for (i, stat) in block.stats.iter().enumerate() {
+            match stat {
+                Stat::Label(name, line) => {
+                    // the local variables of the block are out of scope at its end
+                    let is_last = block.ret_exps.is_none() && block.stats[i + 1..].iter()
+                        .all(|stat| matches!(stat, Stat::Label(..) | Stat::Empty));
+                    self.codegen_label_stat(name, *line, is_last)?;
+                }
+                _ => self.codegen_stat(stat)?,
+            }
         }
         match &block.ret_exps {
             Some(ret_exps) => {
@@ -771,72 +773,72 @@ impl FnInfo {
         match stat {
             Stat::FnCall(fn_call) => self.codegen_fn_call_stat(fn_call),
             Stat::Break(line) => self.codegen_break_stat(*line),
-            Stat::Do(block) => self.codegen_do_stat(&*block),
-            Stat::Repeat(exp, block) => self.codegen_repeat_stat(exp, &*block),
-            Stat::While(exp, block) => self.codegen_while_stat(exp, &*block),
+            Stat::Do(block) => self.codegen_do_stat(block),
+            Stat::Repeat(exp, block) => self.codegen_repeat_stat(exp, block),
+            Stat::While(exp, block) => self.codegen_while_stat(exp, block),
             Stat::Condition(exps, blocks) => self.codegen_condition_stat(exps, blocks),
-            Stat::ForNum(for_num) => self.codegen_for_num_stat(&*for_num),
-            Stat::ForIn(for_in, line) => self.codegen_for_in_stat(&*for_in, *line),
+            Stat::ForNum(for_num) => self.codegen_for_num_stat(for_num),
+            Stat::ForIn(for_in, line) => self.codegen_for_in_stat(for_in, *line),
             Stat::Assign(names, vals, line) => self.codegen_assign_stat(names, vals, *line),
             Stat::LocalVarDecl(names, exps, line) => self.codegen_local_var_decl_stat(names, exps, *line),
             Stat::LocalFnDef(name, fn_def) => self.codegen_local_fn_def_stat(name, fn_def),
-
-            _ => { panic!("label and goto statements are not supported!"); }
+            Stat::Label(name, line) => self.codegen_label_stat(name, *line, false),
+            Stat::Goto(name, line) => self.codegen_goto_stat(name, *line),
+            Stat::Empty => Ok(()),
         }
     }
 
-    fn codegen_ret_stat(&mut self, exps: &Vec<Exp>, last_line: Line) -> Result<()> {
+    fn codegen_ret_stat(&mut self, exps: &[Exp], last_line: Line) -> Result<()> {
         if exps.is_empty() {
             self.emit_return(last_line, 0, 0);
-            Ok(())
-        } else {
-            if exps.len() == 1 {
-                match &exps[0] {
-                    Exp::Name(name, line) => {
-                        if let Ok(reg) = self.local_var_slot(name) {
-                            self.emit_return(*line, reg as isize, 1);
-                            return Ok(());
-                        }
-                    }
-
-//                    Exp::FnCall(fn_call) => {
-//                        let reg = self.alloc_register()? as isize;
-//                        self.codegen_tail_call_exp(fn_call, reg)?;
-//                        self.free_register();
-//                        self.emit_return(last_line, reg, -1);
-//                    }
+            return Ok(());
+        }
 
-                    _ => {}
+        if exps.len() == 1 {
+            match &exps[0] {
+                Exp::Name(name, line) => {
+                    if let Some(reg) = self.local_var_slot(name) {
+                        self.emit_return(*line, reg as isize, 1);
+                        return Ok(());
+                    }
                 }
-            }
-
-            let mult_ret = is_vararg_or_fn_call(exps.last().unwrap());
-            let num = exps.len() - 1;
-            for (i, exp) in exps.iter().enumerate() {
-                let reg = self.alloc_register()? as isize;
-                // has `...` or function call
-                if i == num && mult_ret {
-                    self.codegen_exp(exp, reg, -1)?;
-                } else {
-                    self.codegen_exp(exp, reg, 1)?;
+                Exp::FnCall(fn_call) => {
+                    let reg = self.alloc_register()? as isize;
+                    self.codegen_tail_call_exp(fn_call, reg)?;
+                    self.free_register();
+                    self.emit_return(last_line, reg, -1);
+                    return Ok(());
                 }
+                _ => {}
             }
-            self.free_registers(num);
+        }
 
-            let a = self.used_regs;
-            if mult_ret {
-                self.emit_return(last_line, a as isize, -1);
+        let mult_ret = is_vararg_or_fn_call(exps.last().unwrap());
+        let num = exps.len() - 1;
+        for (i, exp) in exps.iter().enumerate() {
+            let reg = self.alloc_register()? as isize;
+            // has `...` or function call
+            if i == num && mult_ret {
+                self.codegen_exp(exp, reg, -1)?;
             } else {
-                self.emit_return(last_line, a as isize, exps.len() as isize);
+                self.codegen_exp(exp, reg, 1)?;
             }
+        }
+        self.free_registers(exps.len());
 
-            Ok(())
+        let a = self.used_regs as isize;
+        if mult_ret {
+            self.emit_return(last_line, a, -1);
+        } else {
+            self.emit_return(last_line, a, exps.len() as isize);
         }
+
+        Ok(())
     }
 
     // local function f() end => local f; f = function() end
-    fn codegen_local_fn_def_stat(&mut self, name: &String, fn_def: &FnDef) -> Result<()> {
-        let reg = self.add_local_var(name.clone(), self.pc() + 2)?;
+    fn codegen_local_fn_def_stat(&mut self, name: &str, fn_def: &FnDef) -> Result<()> {
+        let reg = self.add_local_var(name.to_string(), self.pc().wrapping_add(2))?;
         self.codegen_fn_def_exp(fn_def, reg as isize)
     }
 
@@ -852,12 +854,53 @@ impl FnInfo {
         self.add_break_jump(pc, line)
     }
 
+    fn codegen_label_stat(&mut self, name: &str, line: Line, is_last: bool) -> Result<()> {
+        let visible = self.label_scopes.iter().flat_map(|scope| scope.labels.iter()).find(|label| label.name == name);
+        if let Some(label) = visible {
+            return Err(Error::DuplicateLabel { line, name: name.to_string(), first: label.line });
+        }
+
+        let pc = self.instructions.len();
+        let scope = self.label_scopes.last_mut().ok_or(Error::NoMoreScopes)?;
+        let num_active = if is_last && !scope.in_repeat { scope.num_active } else { self.used_regs };
+        let (gotos, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut scope.gotos).into_iter().partition(|goto| goto.name == name);
+        scope.gotos = pending;
+        scope.labels.push(LabelInfo { name: name.to_string(), pc, num_active, line });
+
+        for goto in gotos {
+            if goto.num_active < num_active {
+                let local = self.local_var_name(goto.num_active);
+                return Err(Error::JumpIntoScope { line: goto.line, name: goto.name, local });
+            }
+            self.fix_sbx(goto.pc, pc as isize - goto.pc as isize - 1);
+        }
+        Ok(())
+    }
+
+    fn codegen_goto_stat(&mut self, name: &str, line: Line) -> Result<()> {
+        let visible = self.label_scopes.iter().flat_map(|scope| scope.labels.iter()).find(|label| label.name == name);
+        match visible.cloned() {
+            Some(label) => {
+                // jump backward, closing the local variables declared after the label
+                let a = if self.used_regs > label.num_active { label.num_active as isize + 1 } else { 0 };
+                let sBx = label.pc as isize - self.instructions.len() as isize - 1;
+                self.emit_jmp(line, a, sBx);
+            }
+            None => {
+                let pc = self.emit_jmp(line, 0, 0);
+                let goto = LabelInfo { name: name.to_string(), pc, num_active: self.used_regs, line };
+                self.label_scopes.last_mut().ok_or(Error::NoMoreScopes)?.gotos.push(goto);
+            }
+        }
+        Ok(())
+    }
+
     fn codegen_do_stat(&mut self, block: &Block) -> Result<()> {
         // not a loop block
         self.enter_scope(false);
         self.codegen_block(block)?;
         self.close_open_up_values(block.last_line);
-        self.exit_scope()
+        self.exit_scope(self.pc().wrapping_add(1))
     }
 
     /*
@@ -868,20 +911,25 @@ impl FnInfo {
     */
     fn codegen_repeat_stat(&mut self, exp: &Exp, block: &Block) -> Result<()> {
         self.enter_scope(true);
+        // the local variables of the block are visible in `until`
+        if let Some(scope) = self.label_scopes.last_mut() {
+            scope.in_repeat = true;
+        }
 
         let pc_before_block = self.pc();
         self.codegen_block(block)?;
 
-        let reg = self.alloc_register()?;
-        self.codegen_exp(exp, reg as isize, 1)?;
-        self.free_register();
+        let old_regs = self.used_regs;
+        let (a, _) = self.exp_to_op_arg(exp, ARG_REG)?;
+        self.used_regs = old_regs;
 
-        self.emit_test(block.last_line, reg as isize, 0);
+        let line = line_of(exp);
+        self.emit_test(line, a, 0);
         let a = self.get_jump_arg_a();
-        self.emit_jmp(block.last_line, a, (pc_before_block as isize - self.pc() as isize - 1));
-        self.close_open_up_values(block.last_line);
+        self.emit_jmp(line, a, pc_before_block as isize - self.pc() as isize - 1);
+        self.close_open_up_values(line);
 
-        self.exit_scope()
+        self.exit_scope(self.pc().wrapping_add(1))
     }
 
     /*
@@ -896,18 +944,19 @@ impl FnInfo {
     fn codegen_while_stat(&mut self, exp: &Exp, block: &Block) -> Result<()> {
         let pc_before_exp = self.pc();
 
-        let reg = self.alloc_register()?;
-        self.codegen_exp(exp, reg as isize, 1)?;
-        self.free_register();
+        let old_regs = self.used_regs;
+        let (a, _) = self.exp_to_op_arg(exp, ARG_REG)?;
+        self.used_regs = old_regs;
 
-        self.emit_test(block.last_line, reg as isize, 0);
-        let pc_jmp_to_end = self.emit_jmp(block.last_line, 0, 0);
+        let line = line_of(exp);
+        self.emit_test(line, a, 0);
+        let pc_jmp_to_end = self.emit_jmp(line, 0, 0);
 
         self.enter_scope(true);
         self.codegen_block(block)?;
         self.close_open_up_values(block.last_line);
-        self.emit_jmp(block.last_line, 0, (pc_before_exp as isize - self.pc() as isize - 1));
-        self.exit_scope()?;
+        self.emit_jmp(block.last_line, 0, pc_before_exp as isize - self.pc() as isize - 1);
+        self.exit_scope(self.pc())?;
 
         self.fix_sbx(pc_jmp_to_end, (self.pc() - pc_jmp_to_end) as isize);
 
@@ -923,32 +972,34 @@ impl FnInfo {
                         \_______________________\_______________________\_____|
                         jmp                     jmp                     jmp
     */
-    fn codegen_condition_stat(&mut self, exps: &Vec<Exp>, blocks: &Vec<Block>) -> Result<()> {
+    fn codegen_condition_stat(&mut self, exps: &[Exp], blocks: &[Block]) -> Result<()> {
         let mut pc_jmp_to_ends = vec![];
-        let mut pc_jmp_to_next_exp: isize = -1;
+        let mut pc_jmp_to_next_exp: Option<usize> = None;
 
         for (i, exp) in exps.iter().enumerate() {
-            if pc_jmp_to_next_exp >= 0 {
-                self.fix_sbx(pc_jmp_to_next_exp as usize, self.pc() as isize - pc_jmp_to_next_exp as isize);
+            if let Some(pc) = pc_jmp_to_next_exp {
+                self.fix_sbx(pc, self.pc() as isize - pc as isize);
             }
 
-            let reg = self.alloc_register()? as isize;
-            self.codegen_exp(exp, reg, 1)?;
-            self.free_register();
+            let old_regs = self.used_regs;
+            let (a, _) = self.exp_to_op_arg(exp, ARG_REG)?;
+            self.used_regs = old_regs;
 
-            self.emit_test(0, reg, 0);
-            pc_jmp_to_next_exp = self.emit_jmp(0, 0, 0) as isize;
+            let line = line_of(exp);
+            self.emit_test(line, a, 0);
+            let pc = self.emit_jmp(line, 0, 0);
+            pc_jmp_to_next_exp = Some(pc);
 
             self.enter_scope(false);
             let block = &blocks[i];
             self.codegen_block(block)?;
             self.close_open_up_values(block.last_line);
-            self.exit_scope()?;
+            self.exit_scope(self.pc().wrapping_add(1))?;
 
             if i < exps.len() - 1 {
                 pc_jmp_to_ends.push(self.emit_jmp(block.last_line, 0, 0));
             } else {
-                pc_jmp_to_ends.push(pc_jmp_to_next_exp as usize);
+                pc_jmp_to_ends.push(pc);
             }
         }
 
@@ -963,32 +1014,31 @@ impl FnInfo {
         // need OP_FORPREP and OP_FORLOOP
         self.enter_scope(true);
 
-        let names = vec![
+        let names = [
             "(for index)".to_string(),
             "(for limit)".to_string(),
             "(for step)".to_string(),
         ];
 
-        self.codegen_local_var_decl_stat_borrow(&names, &vec![&for_num.init, &for_num.limit, &for_num.step], for_num.line_of_do)?;
-        self.add_local_var(for_num.name.clone(), self.pc() + 2)?;
+        self.codegen_local_var_decl_stat_borrow(&names, &[&for_num.init, &for_num.limit, &for_num.step], for_num.line_of_do)?;
+        self.add_local_var(for_num.name.clone(), self.pc().wrapping_add(2))?;
 
         let a = self.used_regs as isize - 4;
         let pc_for_prep = self.emit_for_prep(for_num.line_of_do, a, 0);
-        self.codegen_block(&*for_num.block)?;
+        self.codegen_block(&for_num.block)?;
         self.close_open_up_values(for_num.block.last_line);
         let pc_for_loop = self.emit_for_loop(for_num.line_of_for, a, 0);
 
+        self.fix_sbx(pc_for_prep, pc_for_loop as isize - pc_for_prep as isize - 1);
+        self.fix_sbx(pc_for_loop, pc_for_prep as isize - pc_for_loop as isize);
 
-        self.fix_sbx(pc_for_prep as usize, pc_for_loop - pc_for_prep - 1);
-        self.fix_sbx(pc_for_loop as usize, pc_for_prep - pc_for_loop);
-
-        self.exit_scope()
+        self.exit_scope(self.pc())
     }
 
     fn codegen_for_in_stat(&mut self, for_in: &ForIn, line: Line) -> Result<()> {
         self.enter_scope(true);
 
-        let names = vec![
+        let names = [
             "(for generator)".to_string(),
             "(for state)".to_string(),
             "(for control)".to_string(),
@@ -996,51 +1046,53 @@ impl FnInfo {
         self.codegen_local_var_decl_stat(&names, &for_in.exp_list, line)?;
 
         for name in for_in.name_list.iter() {
-            self.add_local_var(name.clone(), self.pc() + 2)?;
+            self.add_local_var(name.clone(), self.pc().wrapping_add(2))?;
         }
 
         let pc_jmp_to_tfc = self.emit_jmp(line, 0, 0);
-        self.codegen_block(&*for_in.block)?;
+        self.codegen_block(&for_in.block)?;
         self.close_open_up_values(for_in.block.last_line);
         self.fix_sbx(pc_jmp_to_tfc, self.pc() as isize - pc_jmp_to_tfc as isize);
 
-        let reg_gen = self.local_var_slot(&"(for generator)".to_string())?;
+        let line = line_of(&for_in.exp_list[0]);
+        let reg_gen = self.local_var_slot("(for generator)").ok_or(Error::IllegalRegister)?;
         self.emit_t_for_call(line, reg_gen as isize, for_in.name_list.len() as isize);
         self.emit_t_for_loop(line, reg_gen as isize + 2, pc_jmp_to_tfc as isize - self.pc() as isize - 1);
 
-        self.exit_scope()
+        self.exit_scope(self.pc() - 1)
     }
 
-
-    fn codegen_assign_stat(&mut self, names: &Vec<Exp>, vals: &Vec<Exp>, line: Line) -> Result<()> {
+    fn codegen_assign_stat(&mut self, names: &[Exp], vals: &[Exp], line: Line) -> Result<()> {
+        let vals = remove_tail_nils(vals);
         let old_regs = self.used_regs;
-        let mut t_regs = iter::repeat(isize::default()).take(names.len()).collect::<Vec<_>>();
-        let mut k_regs = iter::repeat(isize::default()).take(names.len()).collect::<Vec<_>>();
-        let mut v_regs = iter::repeat(isize::default()).take(names.len()).collect::<Vec<_>>();
+        let mut t_regs = iter::repeat_n(isize::default(), names.len()).collect::<Vec<_>>();
+        let mut k_regs = iter::repeat_n(isize::default(), names.len()).collect::<Vec<_>>();
+        let mut v_regs = iter::repeat_n(isize::default(), names.len()).collect::<Vec<_>>();
 
         for (i, name_exp) in names.iter().enumerate() {
-            if let Exp::TableAccess(prefix_exp, key_exp, line) = name_exp {
-                t_regs[i] = self.alloc_register()? as isize;
-                self.codegen_exp(&*prefix_exp, t_regs[i], 1)?;
-                k_regs[i] = self.alloc_register()? as isize;
-                self.codegen_exp(&*key_exp, k_regs[i], 1)?;
-            } else {
-                match name_exp {
-                    Exp::Name(name, line) => {
-                        if self.local_var_slot(name).is_err() && self.up_value_index(name).is_none() {
-                            // global variable
-                            k_regs[i] = -1;
-                            if self.constant_index(&Constant::String(name.clone())) > 0xFF {
-                                k_regs[i] = self.alloc_register()? as isize;
-                            }
+            match name_exp {
+                Exp::TableAccess(prefix_exp, key_exp, _) => {
+                    t_regs[i] = self.alloc_register()? as isize;
+                    self.codegen_exp(prefix_exp, t_regs[i], 1)?;
+                    k_regs[i] = self.alloc_register()? as isize;
+                    self.codegen_exp(key_exp, k_regs[i], 1)?;
+                }
+                Exp::Name(name, line) => {
+                    if self.local_var_slot(name).is_none() && self.up_value_index(name).is_none() {
+                        // global variable
+                        k_regs[i] = -1;
+                        let k = Constant::String(name.clone().into_bytes());
+                        if self.constant_index(&k) > 0xFF {
+                            k_regs[i] = self.alloc_register()? as isize;
+                            self.emit_load_k(*line, k_regs[i], k);
                         }
                     }
-                    _ => unreachable!(),
                 }
+                _ => unreachable!(),
             }
         }
-        for i in 0..names.len() {
-            v_regs[i] = (self.used_regs + i) as isize;
+        for (i, v_reg) in v_regs.iter_mut().enumerate() {
+            *v_reg = (self.used_regs + i) as isize;
         }
 
         if vals.len() >= names.len() {
@@ -1073,29 +1125,30 @@ impl FnInfo {
                 self.emit_load_nil(line, a, n as isize);
             }
         }
+
         let last_line = line;
         for (i, exp) in names.iter().enumerate() {
             match exp {
-                Exp::Name(name, line) => {
-                    if let Ok(a) = self.local_var_slot(name) {
+                Exp::Name(name, _) => {
+                    if let Some(a) = self.local_var_slot(name) {
                         self.emit_move(last_line, a as isize, v_regs[i]);
                     } else if let Some(b) = self.up_value_index(name) {
                         self.emit_set_up_value(last_line, v_regs[i], b as isize);
-                    } else if let Ok(a) = self.local_var_slot(&"_ENV".to_string()) {
+                    } else if let Some(a) = self.local_var_slot("_ENV") {
                         if k_regs[i] < 0 {
-                            let b = 0x100 + self.constant_index(&Constant::String(name.clone())) as isize;
+                            let b = 0x100 + self.constant_index(&Constant::String(name.clone().into_bytes())) as isize;
                             self.emit_set_table(last_line, a as isize, b, v_regs[i]);
                         } else {
                             self.emit_set_table(last_line, a as isize, k_regs[i], v_regs[i]);
                         }
                     } else {
-                        let a = self.up_value_index(&"_ENV".to_string())
+                        let a = self.up_value_index("_ENV")
                             .ok_or(Error::NotUpValue {
                                 line: last_line,
                             })? as isize;
 
                         if k_regs[i] < 0 {
-                            let b = 0x100 + self.constant_index(&Constant::String(name.clone())) as isize;
+                            let b = 0x100 + self.constant_index(&Constant::String(name.clone().into_bytes())) as isize;
                             self.emit_set_table_up(last_line, a, b, v_regs[i]);
                         } else {
                             self.emit_set_table_up(last_line, a, k_regs[i], v_regs[i]);
@@ -1113,53 +1166,12 @@ impl FnInfo {
         Ok(())
     }
 
-    fn codegen_local_var_decl_stat(&mut self, names: &Vec<String>, exps: &Vec<Exp>, last_line: Line) -> Result<()> {
-        let old_regs = self.used_regs;
-        if exps.len() == names.len() {
-            for exp in exps.iter() {
-                let a = self.alloc_register()?;
-                self.codegen_exp(exp, a as isize, 1)?;
-            }
-        } else if exps.len() > names.len() {
-            for (i, exp) in exps.iter().enumerate() {
-                let a = self.alloc_register()?;
-                if i == exps.len() - 1 && is_vararg_or_fn_call(exp) {
-                    self.codegen_exp(exp, a as isize, 0)?;
-                } else {
-                    self.codegen_exp(exp, a as isize, 1)?;
-                }
-            }
-        } else {
-            let mut mult_ret = false;
-            for (i, exp) in exps.iter().enumerate() {
-                let a = self.alloc_register()?;
-                if i == exps.len() - 1 && is_vararg_or_fn_call(exp) {
-                    mult_ret = true;
-                    let n = names.len() - exps.len() + 1;
-                    self.codegen_exp(exp, a as isize, n as isize)?;
-                    self.alloc_registers(n - 1)?;
-                } else {
-                    self.codegen_exp(exp, a as isize, 1)?;
-                }
-            }
-
-            if !mult_ret {
-                let n = names.len() - exps.len();
-                let a = self.alloc_registers(n)?;
-                self.emit_load_nil(last_line, a as isize, n as isize);
-            }
-        }
-
-        self.used_regs = old_regs;
-        let start_pc = self.pc() + 1;
-        for name in names {
-            self.add_local_var(name.clone(), start_pc)?;
-        }
-
-        Ok(())
+    fn codegen_local_var_decl_stat(&mut self, names: &[String], exps: &[Exp], last_line: Line) -> Result<()> {
+        let exps: Vec<&Exp> = remove_tail_nils(exps).iter().collect();
+        self.codegen_local_var_decl_stat_borrow(names, &exps, last_line)
     }
 
-    fn codegen_local_var_decl_stat_borrow(&mut self, names: &Vec<String>, exps: &Vec<&Exp>, last_line: Line) -> Result<()> {
+    fn codegen_local_var_decl_stat_borrow(&mut self, names: &[String], exps: &[&Exp], last_line: Line) -> Result<()> {
         let old_regs = self.used_regs;
         if exps.len() == names.len() {
             for exp in exps.iter() {
@@ -1195,8 +1207,9 @@ impl FnInfo {
                 self.emit_load_nil(last_line, a as isize, n as isize);
             }
         }
+
         self.used_regs = old_regs;
-        let start_pc = self.pc() + 1;
+        let start_pc = self.pc().wrapping_add(1);
         for name in names {
             self.add_local_var(name.clone(), start_pc)?;
         }
@@ -1207,7 +1220,7 @@ impl FnInfo {
 
 /********************** expression code generation ***********************/
 
-impl FnInfo {
+impl FnInfo<'_> {
     pub fn codegen_exp(&mut self, exp: &Exp, a: isize, n: isize) -> Result<()> {
         match exp {
             Exp::Nil(line) => {
@@ -1234,23 +1247,60 @@ impl FnInfo {
                 self.emit_load_k(*line, a, Constant::String(s.clone()));
                 Ok(())
             }
-            Exp::Name(name, line) => self.codegen_name_exp(name, a, n, *line),
-            Exp::Parens(exp) => self.codegen_exp(&*exp, a, n),
+            Exp::Name(name, line) => self.codegen_name_exp(name, a, *line),
+            Exp::Parens(exp) => self.codegen_exp(exp, a, 1),
             Exp::Vararg(line) => self.codegen_vararg_exp(a, n, *line),
             Exp::Unop(op, exp, line) => self.codegen_unop_exp(op, exp, a, *line),
-            Exp::Binop(exp1, op, exp2, line) => self.codegen_binop_exp(&*exp1, op, &*exp2, a, n, *line),
-            Exp::Concat(exps, line) => self.codegen_concat_exp(exps, a, n, *line),
+            Exp::Binop(exp1, op, exp2, line) => self.codegen_binop_exp(exp1, op, exp2, a, *line),
+            Exp::Concat(exps, line) => self.codegen_concat_exp(exps, a, *line),
             Exp::TableConstructor(fields, line) => self.codegen_table_constructor_exp(fields, a, *line),
-            Exp::TableAccess(obj, key, line) => self.codegen_table_access_exp(&*obj, &*key, a, n, *line),
+            Exp::TableAccess(obj, key, line) => self.codegen_table_access_exp(obj, key, a, *line),
             Exp::FnDef(fn_def) => self.codegen_fn_def_exp(fn_def, a),
             Exp::FnCall(fn_call) => self.codegen_fn_call_exp(fn_call, a, n),
         }
     }
 
-    fn codegen_name_exp(&mut self, name: &String, a: isize, n: isize, line: Line) -> Result<()> {
+    /// Get a operand for `exp` of the allowed kinds, which is a register, constant index (RK) or up value index.
+    /// Expressions not fitting any allowed kind are evaluated into a newly allocated register.
+    fn exp_to_op_arg(&mut self, exp: &Exp, arg_kinds: u8) -> Result<(isize, u8)> {
+        if arg_kinds & ARG_CONST > 0 {
+            let k = match exp {
+                Exp::Nil(_) => Some(Constant::Nil),
+                Exp::False(_) => Some(Constant::Boolean(false)),
+                Exp::True(_) => Some(Constant::Boolean(true)),
+                Exp::Integer(num, _) => Some(Constant::Integer(*num)),
+                Exp::Float(num, _) => Some(Constant::Number(*num)),
+                Exp::String(s, _) => Some(Constant::String(s.clone())),
+                _ => None,
+            };
+            if let Some(k) = k {
+                let idx = self.constant_index(&k);
+                if idx <= 0xFF {
+                    return Ok((0x100 + idx as isize, ARG_CONST));
+                }
+            }
+        }
+
+        if let Exp::Name(name, _) = exp {
+            if arg_kinds & ARG_REG > 0 {
+                if let Some(reg) = self.local_var_slot(name) {
+                    return Ok((reg as isize, ARG_REG));
+                }
+            }
+            if arg_kinds & ARG_UPVAL > 0 {
+                if let Some(idx) = self.up_value_index(name) {
+                    return Ok((idx as isize, ARG_UPVAL));
+                }
+            }
+        }
+
+        let a = self.alloc_register()? as isize;
+        self.codegen_exp(exp, a, 1)?;
+        Ok((a, ARG_REG))
+    }
 
-        let reg = self.local_var_slot(name);
-        if let Ok(reg) = reg {
+    fn codegen_name_exp(&mut self, name: &str, a: isize, line: Line) -> Result<()> {
+        if let Some(reg) = self.local_var_slot(name) {
             self.emit_move(line, a, reg as isize);
             Ok(())
         } else if let Some(idx) = self.up_value_index(name) {
@@ -1258,47 +1308,26 @@ impl FnInfo {
             Ok(())
         } else {
             // x => _Env['x']
-            self.codegen_table_access_exp(&Exp::Name("_ENV".to_string(), 0), &Exp::String(name.clone(), 0), a, 0, line)
+            self.codegen_table_access_exp(&Exp::Name("_ENV".to_string(), line), &Exp::String(name.as_bytes().to_vec(), line), a, line)
         }
     }
 
     // f[a] := function(args) body end
     fn codegen_fn_def_exp(&mut self, fn_def: &FnDef, a: isize) -> Result<()> {
-        // fixme
-        let parent = FnInfoRef(Rc::new(RefCell::new(self.clone())));
-        let sub_fn = Self::new(Some(parent), fn_def.par_list.clone(), fn_def.line, fn_def.last_line);
-        let mut sub_fn = FnInfoRef(Rc::new(RefCell::new(sub_fn)));
-        self.sub_fns.push(sub_fn.clone());
-
-        for param in &fn_def.par_list.params {
-            sub_fn.0.borrow_mut().add_local_var(param.clone(), 0)?;
-        }
-        sub_fn.0.borrow_mut().codegen_block(&*fn_def.block)?;
-//        self.codegen_block(&*fn_def.block)?;
-        sub_fn.0.borrow_mut().exit_scope()?;
-        sub_fn.0.borrow_mut().emit_return(fn_def.block.last_line, 0, 0);
+        let proto = {
+            let mut sub_fn = FnInfo::new(Some(self), fn_def.par_list.clone(), fn_def.line, fn_def.last_line);
+            for param in &fn_def.par_list.params {
+                sub_fn.add_local_var(param.clone(), 0)?;
+            }
+            sub_fn.codegen_block(&fn_def.block)?;
+            sub_fn.exit_scope(sub_fn.pc().wrapping_add(2))?;
+            sub_fn.emit_return(fn_def.last_line, 0, 0);
+            sub_fn.to_prototype()
+        };
+        self.sub_fns.push(proto);
 
         let bx = self.sub_fns.len() - 1;
-        self.emit_closure(fn_def.block.last_line, a, bx as isize);
-        Ok(())
-    }
-
-    // f[a] := function(args) body end
-    fn codegen_fn_def_exp2(parent: &FnInfoRef, fn_def: &FnDef, a: isize) -> Result<()> {
-        // fixme
-        let sub_fn = Self::new(Some(parent.clone()), fn_def.par_list.clone(), fn_def.line, fn_def.last_line);
-        let mut sub_fn = FnInfoRef(Rc::new(RefCell::new(sub_fn)));
-        parent.0.borrow_mut().sub_fns.push(sub_fn.clone());
-
-        for param in &fn_def.par_list.params {
-            sub_fn.0.borrow_mut().add_local_var(param.clone(), 0)?;
-        }
-        parent.0.borrow_mut().codegen_block(&*fn_def.block)?;
-        sub_fn.0.borrow_mut().exit_scope()?;
-        sub_fn.0.borrow_mut().emit_return(fn_def.block.last_line, 0, 0);
-
-        let bx = parent.0.borrow_mut().sub_fns.len() - 1;
-        parent.0.borrow_mut().emit_closure(fn_def.block.last_line, a, bx as isize);
+        self.emit_closure(fn_def.last_line, a, bx as isize);
         Ok(())
     }
 
@@ -1312,19 +1341,19 @@ impl FnInfo {
     }
 
     fn codegen_unop_exp(&mut self, op: &Token, exp: &Exp, a: isize, line: Line) -> Result<()> {
-        let b = self.alloc_register()? as isize;
-        self.codegen_exp(exp, b, 1)?;
+        let old_regs = self.used_regs;
+        let (b, _) = self.exp_to_op_arg(exp, ARG_REG)?;
         self.emit_unary_op(line, op, a, b);
-        self.free_register();
+        self.used_regs = old_regs;
         Ok(())
     }
 
-    fn codegen_binop_exp(&mut self, exp1: &Exp, op: &Token, exp2: &Exp, a: isize, n: isize, line: Line) -> Result<()> {
+    fn codegen_binop_exp(&mut self, exp1: &Exp, op: &Token, exp2: &Exp, a: isize, line: Line) -> Result<()> {
         match op {
             Token::OpAnd | Token::OpOr => {
-                let b = self.alloc_register()? as isize;
-                self.codegen_exp(exp1, b, 1)?;
-                self.free_register();
+                let old_regs = self.used_regs;
+                let (b, _) = self.exp_to_op_arg(exp1, ARG_REG)?;
+                self.used_regs = old_regs;
                 if *op == Token::OpAnd {
                     self.emit_test_set(line, a, b, 0);
                 } else {
@@ -1332,86 +1361,78 @@ impl FnInfo {
                 }
                 let jmp_pc = self.emit_jmp(line, 0, 0);
 
-                let b = self.alloc_register()? as isize;
-                self.codegen_exp(exp2, b, 1)?;
-                self.free_register();
+                let (b, _) = self.exp_to_op_arg(exp2, ARG_REG)?;
+                self.used_regs = old_regs;
                 self.emit_move(line, a, b);
                 self.fix_sbx(jmp_pc, self.pc() as isize - jmp_pc as isize);
             }
 
             _ => {
-                let b = self.alloc_register()? as isize;
-                self.codegen_exp(exp1, b, 1)?;
-                let c = self.alloc_register()? as isize;
-                self.codegen_exp(exp2, c, 1)?;
+                let old_regs = self.used_regs;
+                let (b, _) = self.exp_to_op_arg(exp1, ARG_RK)?;
+                let (c, _) = self.exp_to_op_arg(exp2, ARG_RK)?;
                 self.emit_binary_op(line, op, a, b, c);
-                self.free_registers(2);
+                self.used_regs = old_regs;
             }
         }
         Ok(())
     }
 
-    fn codegen_concat_exp(&mut self, exps: &Vec<Exp>, a: isize, n: isize, line: Line) -> Result<()> {
+    fn codegen_concat_exp(&mut self, exps: &[Exp], a: isize, line: Line) -> Result<()> {
         for exp in exps {
             let a = self.alloc_register()? as isize;
             self.codegen_exp(exp, a, 1)?;
         }
 
         let c = self.used_regs - 1;
-        let b = c - exps.len() + 1;
+        let b = c + 1 - exps.len();
         self.free_registers(c + 1 - b);
         self.emit_ABC(line, opcode::OP_CONCAT, a, b as isize, c as isize);
         Ok(())
     }
 
-    fn codegen_table_constructor_exp(&mut self, fields: &Vec<Field>, a: isize, line: Line) -> Result<()> {
-        // 有多少个是没有下标的
-        let mut n_arr = 0;
-        for field in fields {
-            if field.key.is_none() {
-                n_arr += 1;
-            }
-        }
-
+    fn codegen_table_constructor_exp(&mut self, fields: &[Field], a: isize, line: Line) -> Result<()> {
+        // the number of fields without key
+        let n_arr = fields.iter().filter(|field| field.key.is_none()).count() as isize;
         let mult_ret = !fields.is_empty() && is_vararg_or_fn_call(&fields.last().unwrap().val);
 
         self.emit_new_table(line, a, n_arr, fields.len() as isize - n_arr);
         let mut arr_idx = 0;
 
         for (i, field) in fields.iter().enumerate() {
-            // 先处理数组
             match field.key {
                 Some(ref key) => {
-                    let b = self.alloc_register()? as isize;
-                    self.codegen_exp(key, b, 1)?;
-                    let c = self.alloc_register()? as isize;
-                    self.codegen_exp(&field.val, c, 1)?;
-
-                    self.free_registers(2);
-
-                    let line = 0;
-                    self.emit_set_table(line, a, b, c);
+                    let old_regs = self.used_regs;
+                    let (b, _) = self.exp_to_op_arg(key, ARG_RK)?;
+                    let (c, _) = self.exp_to_op_arg(&field.val, ARG_RK)?;
+                    self.used_regs = old_regs;
+                    self.emit_set_table(line_of(&field.val), a, b, c);
                 }
 
                 None => {
                     arr_idx += 1;
                     let tmp = self.alloc_register()? as isize;
-                    if i == fields.len() - 1 && mult_ret {
+                    let is_mult_ret = i == fields.len() - 1 && mult_ret;
+                    if is_mult_ret {
                         self.codegen_exp(&field.val, tmp, -1)?;
                     } else {
                         self.codegen_exp(&field.val, tmp, 1)?;
                     }
 
-                    // LFIELDS_PER_FLUSH
-                    if arr_idx % 50 == 0 || arr_idx == fields.len() {
-                        let n = if arr_idx % 50 == 0 {
-                            50
-                        } else {
-                            arr_idx % 50
+                    if arr_idx % LFIELDS_PER_FLUSH == 0 || arr_idx == n_arr {
+                        let n = match arr_idx % LFIELDS_PER_FLUSH {
+                            0 => LFIELDS_PER_FLUSH,
+                            n => n,
                         };
+                        self.free_registers(n as usize);
 
-                        let c = (arr_idx - 1) / 50 + 1;
-                        self.free_registers(n);
+                        let c = (arr_idx - 1) / LFIELDS_PER_FLUSH + 1;
+                        let line = line_of(&field.val);
+                        if is_mult_ret {
+                            self.emit_set_list(line, a, 0, c);
+                        } else {
+                            self.emit_set_list(line, a, n, c);
+                        }
                     }
                 }
             }
@@ -1419,50 +1440,52 @@ impl FnInfo {
         Ok(())
     }
 
-    fn codegen_table_access_exp(&mut self, obj: &Exp, key: &Exp, a: isize, n: isize, line: Line) -> Result<()> {
-        let old_Regs = self.used_regs;
-        let b = self.alloc_register()? as isize;
-        self.codegen_exp(obj, b, 1)?;
-        let c = self.alloc_register()? as isize;
-        self.codegen_exp(key, c, 1)?;
-        self.emit_get_table(line, a, b, c);
-        self.free_registers(2);
+    fn codegen_table_access_exp(&mut self, obj: &Exp, key: &Exp, a: isize, line: Line) -> Result<()> {
+        let old_regs = self.used_regs;
+        let (b, kind_b) = self.exp_to_op_arg(obj, ARG_RU)?;
+        let (c, _) = self.exp_to_op_arg(key, ARG_RK)?;
+        self.used_regs = old_regs;
+
+        if kind_b == ARG_UPVAL {
+            self.emit_get_table_up(line, a, b, c);
+        } else {
+            self.emit_get_table(line, a, b, c);
+        }
         Ok(())
     }
 
     fn codegen_fn_call_exp(&mut self, fn_call: &FnCall, a: isize, n: isize) -> Result<()> {
-        let n_args = self.prep_fn_call(fn_call, a)? as isize;
+        let n_args = self.prep_fn_call(fn_call, a)?;
         self.emit_call(fn_call.line, a, n_args, n);
         Ok(())
     }
 
     fn codegen_tail_call_exp(&mut self, fn_call: &FnCall, a: isize) -> Result<()> {
-        unimplemented!();
+        let n_args = self.prep_fn_call(fn_call, a)?;
+        self.emit_tail_call(fn_call.line, a, n_args);
+        Ok(())
     }
 
-    fn prep_fn_call(&mut self, fn_call: &FnCall, a: isize) -> Result<usize> {
-        let mut n_args = fn_call.args.len();
+    /// Put the function and its arguments in registers from `a`, return the number of arguments
+    /// or -1 if it is decided at runtime.
+    fn prep_fn_call(&mut self, fn_call: &FnCall, a: isize) -> Result<isize> {
+        let mut n_args = fn_call.args.len() as isize;
         let mut last_arg_is_vararg_or_fn_call = false;
-        self.codegen_exp(&*fn_call.prefix, a, 1)?;
-
-        // fixme
-        match fn_call.name {
-            Some(ref name) => {
-                match **name {
-                    Exp::String(ref s, line) => {
-                        let constant = Constant::String(s.clone());
-                        let c = 0x100 + self.constant_index(&constant) as isize;
-                        self.emit_self(line, a, a, c)
-                    }
-                    _ => unreachable!()
-                };
+        self.codegen_exp(&fn_call.prefix, a, 1)?;
+
+        if let Some(ref name) = fn_call.name {
+            // obj:name(args) => obj.name(obj, args)
+            self.alloc_register()?;
+            let (c, kind_c) = self.exp_to_op_arg(name, ARG_RK)?;
+            self.emit_self(fn_call.line, a, a, c);
+            if kind_c == ARG_REG {
+                self.free_register();
             }
-            _ => {}
-        };
+        }
 
         for (i, arg) in fn_call.args.iter().enumerate() {
             let tmp = self.alloc_register()? as isize;
-            if i == n_args - 1 && is_vararg_or_fn_call(arg) {
+            if i == fn_call.args.len() - 1 && is_vararg_or_fn_call(arg) {
                 last_arg_is_vararg_or_fn_call = true;
                 self.codegen_exp(arg, tmp, -1)?;
             } else {
@@ -1470,14 +1493,15 @@ impl FnInfo {
             }
         }
 
-        self.free_registers(n_args);
+        self.free_registers(fn_call.args.len());
 
-        if let Some(_) = fn_call.name {
+        if fn_call.name.is_some() {
+            self.free_register();
             n_args += 1;
         }
 
         if last_arg_is_vararg_or_fn_call {
-            n_args -= 1;
+            n_args = -1;
         }
 
         Ok(n_args)
@@ -1486,14 +1510,41 @@ impl FnInfo {
 
 #[inline]
 fn is_vararg_or_fn_call(exp: &Exp) -> bool {
-    match exp {
-        Exp::Vararg(_) => true,
-        Exp::FnCall(_) => true,
-        _ => false,
+    matches!(exp, Exp::Vararg(_) | Exp::FnCall(_))
+}
+
+/// Trailing `nil`s of expression list only need LOADNIL
+fn remove_tail_nils(exps: &[Exp]) -> &[Exp] {
+    let mut n = exps.len();
+    while n > 0 && matches!(exps[n - 1], Exp::Nil(_)) {
+        n -= 1;
     }
+    &exps[..n]
 }
 
+/// The line where an expression appears
+fn line_of(exp: &Exp) -> Line {
+    match exp {
+        Exp::Nil(line)
+        | Exp::True(line)
+        | Exp::False(line)
+        | Exp::Vararg(line)
+        | Exp::Integer(_, line)
+        | Exp::Float(_, line)
+        | Exp::String(_, line)
+        | Exp::Name(_, line)
+        | Exp::Unop(_, _, line)
+        | Exp::Binop(_, _, _, line)
+        | Exp::Concat(_, line)
+        | Exp::TableConstructor(_, line)
+        | Exp::TableAccess(_, _, line) => *line,
+        Exp::Parens(exp) => line_of(exp),
+        Exp::FnDef(fn_def) => fn_def.line,
+        Exp::FnCall(fn_call) => fn_call.line,
+    }
+}
 
+#[cfg(test)]
 mod tests {
     use std::fs;
 
@@ -1580,6 +1631,6 @@ mod tests {
         let proto = gen_prototype(Box::new(block));
 
         let bytes = encode(proto.unwrap(), Some("@hello2.lua".to_string()));
-        fs::write("./tests/test.out", bytes);
+        fs::write("./tests/test.out", bytes).unwrap();
     }
-}
\ No newline at end of file
+}
@@ -43,6 +43,8 @@ pub enum Error {
     IllegalFnCall { line: Line },
     /// Illegal Function definition
     IllegalFnDef { line: Line },
+    /// Tokens after the end of the chunk
+    ExpectedEOF { line: Line, near: String },
     /// No more Registers
     NoMoreRegisters,
     /// Illegal Register
@@ -55,6 +57,12 @@ pub enum Error {
     NotUpValue { line: Line },
     /// Not a vararg function
     NotVararg { line: Line },
+    /// No visible label for a goto
+    NoLabel { line: Line, name: String },
+    /// A label with the same name is visible
+    DuplicateLabel { line: Line, name: String, first: Line },
+    /// A goto jumps into the scope of a local variable
+    JumpIntoScope { line: Line, name: String, local: String },
     NoReturnValue,
 }
 
@@ -79,12 +87,20 @@ impl Display for Error {
             MissingAssignment { line } => write!(f, "line: {}, error: MissingAssignment", *line),
             IllegalFnCall { line } => write!(f, "line: {}, error: IllegalFnCall", *line),
             IllegalFnDef { line } => write!(f, "line: {}, error: IllegalFnDef", *line),
+            ExpectedEOF { line, near } => write!(f, "line: {}, error: '<eof>' expected near '{}'", *line, near),
             NoMoreRegisters => write!(f, "codegen error: NoMoreRegisters"),
             IllegalRegister => write!(f, "codegen error: IllegalRegister"),
             NoMoreScopes => write!(f, "codegen error: NoMoreScopes"),
             NoLoop { line } => write!(f, "line: {}, codegen error: NoLoop", *line),
             NotUpValue { line } => write!(f, "line: {}, codegen error: NotUpValue", *line),
             NotVararg { line } => write!(f, "line: {}, codegen error: NotVararg", *line),
+            NoLabel { line, name } => write!(f, "line: {}, error: no visible label '{}' for goto", *line, name),
+            DuplicateLabel { line, name, first } => {
+                write!(f, "line: {}, error: label '{}' already defined on line {}", *line, name, *first)
+            }
+            JumpIntoScope { line, name, local } => {
+                write!(f, "line: {}, error: <goto {}> jumps into the scope of local '{}'", *line, name, local)
+            }
             _ => unreachable!(),
         }
     }
@@ -39,10 +39,7 @@ pub trait Lex {
 
     /// 检查下一个token是否tok
     fn check_next_token(&mut self, tok: Token) -> bool {
-        match self.next_token() {
-            Ok(ref token) if tok == *token => true,
-            _ => false,
-        }
+        matches!(self.next_token(), Ok(ref token) if tok == *token)
     }
 }
 
@@ -109,13 +106,9 @@ lazy_static! {
         m.insert("nil", Token::KwNil);
         m
     };
-    static ref re_long_bracket: Regex = Regex::new(r##"^(?P<comment>\[=*\[(?P<string>.*?)\]=*\])"##).unwrap();
-    static ref re_short_str: Regex = Regex::new(r##"(^'(\\\\|\\' | \\\n|\\z\s*|[^'\n])*')|^"(\\\\|\\' | \\\n|\\z\s*|[^'\n])*""##).unwrap();
+    static ref re_long_bracket: Regex = Regex::new(r##"(?s)^(?P<comment>\[=*\[(?P<string>.*?)\]=*\])"##).unwrap();
     static ref re_number: Regex = Regex::new(r#"^0[xX][[:xdigit:]]*(\.[[:xdigit:]]*)?([pP][+\-]?[[:digit:]]+)?|^[[:digit:]]*(\.[[:digit:]]*)?([eE][+\-]?[[:digit:]]+)?"#).unwrap();
     static ref re_ident: Regex = Regex::new(r##"^[_\d\w]+"##).unwrap();
-    static ref re_dec_escaped_seq: Regex = Regex::new(r##"^\\[0-9]{1,3}"##).unwrap();
-    static ref re_hex_escaped_seq: Regex = Regex::new(r##"^\\[[:xdigit]]{2}"##).unwrap();
-    static ref re_unicode_escaped_seq: Regex = Regex::new(r##"^\\u\{[[:xdigit]]+\}"##).unwrap();
 }
 
 
@@ -257,7 +250,7 @@ impl Lex for Lexer {
                     }
                 } else {
                     let line = self.current_line();
-                    Err(Error::IllegalToken { line: line })
+                    Err(Error::IllegalToken { line })
                 }
             }
         }
@@ -299,9 +292,9 @@ impl Lexer {
         Ok(token)
     }
 
-    /// 转移字符串
-    fn escape_string(&self, s: &[u8]) -> Result<String> {
-        // todo: 转义字符串
+    /// 转义字符串
+    fn escape_string(&self, s: &[u8]) -> Result<Vec<u8>> {
+        let err = || Error::IllegalEscape { line: self.current_line() };
         let mut ret: Vec<u8> = vec![];
         let mut i = 0;
         while i < s.len() {
@@ -311,124 +304,134 @@ impl Lexer {
                 continue;
             }
 
-            if i + 1 == s.len() {
-                return Err(Error::IllegalEscape { line: self.current_line() });
-            } else {
-                match s[i + 1] {
-                    b'a' => {
-                        ret.push(0x07u8);
-                        i += 2;
-                    }
-                    b'b' => {
-                        ret.push(0x08u8);
-                        i += 2;
-                    }
-                    b'f' => {
-                        ret.push(0x0cu8);
-                        i += 2;
-                    }
-                    b'n' | b'\n' => {
-                        ret.push(b'\n');
-                        i += 2;
-                    }
-                    b'r' => {
-                        ret.push(b'\r');
-                        i += 2;
-                    }
-                    b't' => {
-                        ret.push(b'\t');
-                        i += 2;
+            let ch = *s.get(i + 1).ok_or_else(err)?;
+            i += 2;
+            match ch {
+                b'a' => ret.push(0x07u8),
+                b'b' => ret.push(0x08u8),
+                b'f' => ret.push(0x0cu8),
+                b'n' => ret.push(b'\n'),
+                b'r' => ret.push(b'\r'),
+                b't' => ret.push(b'\t'),
+                b'v' => ret.push(0x0bu8),
+                b'"' | b'\'' | b'\\' => ret.push(ch),
+                // \newline, \r\n 和 \n\r 视为一个换行
+                b'\n' | b'\r' => {
+                    ret.push(b'\n');
+                    if i < s.len() && is_new_line(s[i]) && s[i] != ch {
+                        i += 1;
                     }
-                    b'v' => {
-                        ret.push(0x0bu8);
-                        i += 2;
+                }
+                // \ddd
+                b'0'..=b'9' => {
+                    let start = i - 1;
+                    let mut end = start;
+                    while end < s.len() && end < start + 3 && s[end].is_ascii_digit() {
+                        end += 1;
                     }
-                    ch @ b'"' | ch @ b'\'' | ch @ b'\\' => {
-                        ret.push(ch);
-                        i += 2;
+                    let num = str::from_utf8(&s[start..end]).map_err(|_| err())?;
+                    let num = num.parse::<u8>().map_err(|_| err())?;
+                    ret.push(num);
+                    i = end;
+                }
+                // \xXX
+                b'x' => {
+                    let num = s.get(i..i + 2).filter(|h| h.iter().all(u8::is_ascii_hexdigit)).ok_or_else(err)?;
+                    let num = str::from_utf8(num).map_err(|_| err())?;
+                    ret.push(u8::from_str_radix(num, 16).map_err(|_| err())?);
+                    i += 2;
+                }
+                // \u{XXX}
+                b'u' => {
+                    if s.get(i) != Some(&b'{') {
+                        return Err(err());
                     }
-                    // todo: fix it
-                    // \ddd
-                    ch if ch.is_ascii_digit() => {
-                        let num = re_dec_escaped_seq
-                            .find(&s[i + 1..])
-                            .ok_or(Error::IllegalEscape { line: self.current_line() })?
-                            .as_bytes();
-                        let num = str::from_utf8(num).or(Err(Error::IllegalEscape { line: self.current_line() }))?;
-                        let len = num.len() + 1;
-                        let num = num.parse::<u8>().or(Err(Error::IllegalEscape { line: self.current_line() }))?;
-                        ret.push(num);
-                        i += len;
+                    let start = i + 1;
+                    let mut end = start;
+                    while end < s.len() && s[end].is_ascii_hexdigit() {
+                        end += 1;
                     }
-                    // todo: fix it
-                    // \xXX
-                    b'x' => {
-                        let num = re_hex_escaped_seq
-                            .find(&s[i + 2..])
-                            .ok_or(Error::IllegalEscape { line: self.current_line() })?
-                            .as_bytes();
-                        let num = str::from_utf8(num).or(Err(Error::IllegalEscape { line: self.current_line() }))?;
-                        let num = num.parse::<u8>().or(Err(Error::IllegalEscape { line: self.current_line() }))?;
-                        ret.push(num);
-                        i += 3;
+                    if end == start || s.get(end) != Some(&b'}') {
+                        return Err(err());
                     }
-                    // \u{XXX}
-                    b'u' => {
-                        let num = re_unicode_escaped_seq
-                            .find(&s[i + 3..])
-                            .ok_or(Error::IllegalEscape { line: self.current_line() })?
-                            .as_bytes();
-                        let num = str::from_utf8(num).or(Err(Error::IllegalEscape { line: self.current_line() }))?;
-                        let len = num.len();
-                        let num = num.parse::<u8>().or(Err(Error::IllegalEscape { line: self.current_line() }))?;
-                        ret.push(num);
-                        i += len;
+                    let num = str::from_utf8(&s[start..end]).map_err(|_| err())?;
+                    let num = u32::from_str_radix(num, 16).map_err(|_| err())?;
+                    if num > 0x7FFF_FFFF {
+                        return Err(err());
                     }
-                    // \z
-                    b'z' => {}
-                    _ => {
+                    ret.extend(utf8_encode(num));
+                    i = end + 1;
+                }
+                // \z 跳过之后的空白字符
+                b'z' => {
+                    while i < s.len() && s[i].is_ascii_whitespace() {
                         i += 1;
                     }
-                };
-            }
+                }
+                _ => return Err(err()),
+            };
         }
 
-        unsafe { Ok(String::from_utf8_unchecked(ret)) }
+        Ok(ret)
     }
 
     /// 扫描长字符串
-    fn scan_long_string(&mut self) -> Result<String> {
+    fn scan_long_string(&mut self) -> Result<Vec<u8>> {
         // long comment: -- [===[ ... ]===]
         let text = &self.chunk[self.index..];
         let caps = &re_long_bracket.captures(text).ok_or(Error::IllegalToken {
             line: self.current_line(),
         })?;
-        // todo: trim string
         self.index += caps["comment"].len();
-        for ch in caps["comment"].iter() {
-            if is_new_line(*ch) {
-                self.line += 1;
-            }
+        self.line += count_new_lines(&caps["comment"]);
+
+        // 忽略开头的第一个换行
+        let mut s = &caps["string"];
+        if s.starts_with(b"\r\n") || s.starts_with(b"\n\r") {
+            s = &s[2..];
+        } else if !s.is_empty() && is_new_line(s[0]) {
+            s = &s[1..];
         }
-
-        unsafe { Ok(String::from_utf8_unchecked(caps["string"].to_vec())) }
+        Ok(s.to_vec())
     }
 
     /// 扫描短字符串
-    fn scan_short_string(&mut self) -> Result<String> {
-        // todo: escape
-        let text = &self.chunk[self.index..];
-        let s = re_short_str
-            .find(text)
-            .ok_or(
-                Error::IllegalToken {
-                    line: self.current_line(),
+    fn scan_short_string(&mut self) -> Result<Vec<u8>> {
+        let quote = self.chunk[self.index];
+        let mut i = self.index + 1;
+        loop {
+            match self.chunk.get(i) {
+                None | Some(b'\n') | Some(b'\r') => {
+                    return Err(Error::IllegalString { line: self.current_line() });
                 }
-            )?
-            .as_bytes();
-        self.index += s.len();
-        let s = &s[1..s.len() - 1];
-        self.escape_string(s)
+                Some(&ch) if ch == quote => break,
+                Some(b'\\') => {
+                    i += 1;
+                    match self.chunk.get(i) {
+                        Some(b'z') => {
+                            i += 1;
+                            while i < self.chunk.len() && self.chunk[i].is_ascii_whitespace() {
+                                i += 1;
+                            }
+                        }
+                        Some(&ch) if is_new_line(ch) => {
+                            i += 1;
+                            if i < self.chunk.len() && is_new_line(self.chunk[i]) && self.chunk[i] != ch {
+                                i += 1;
+                            }
+                        }
+                        Some(_) => i += 1,
+                        None => return Err(Error::IllegalString { line: self.current_line() }),
+                    }
+                }
+                _ => i += 1,
+            }
+        }
+
+        let s = self.chunk[self.index + 1..i].to_vec();
+        self.index = i + 1;
+        self.line += count_new_lines(&s);
+        self.escape_string(&s)
     }
 
     /// 扫描数字
@@ -439,7 +442,7 @@ impl Lexer {
                 line: self.current_line(),
             })?.as_bytes();
         self.index += s.len();
-        unsafe { Ok(String::from_utf8_unchecked(s.to_vec())) }
+        Ok(String::from_utf8_lossy(s).into_owned())
     }
 
     /// 扫描标识符
@@ -451,7 +454,7 @@ impl Lexer {
 
             })?.as_bytes();
         self.index += s.len();
-        unsafe { Ok(String::from_utf8_unchecked(s.to_vec())) }
+        Ok(String::from_utf8_lossy(s).into_owned())
     }
 
     fn scan_unknow_identifier(&mut self) -> String {
@@ -462,7 +465,7 @@ impl Lexer {
         }
         let s = &text[..i];
         self.index += i;
-        unsafe { String::from_utf8_unchecked(s.to_vec()) }
+        String::from_utf8_lossy(s).into_owned()
     }
 
     /// 跳过空白符(总是跳过注释)
@@ -487,14 +490,7 @@ impl Lexer {
 
     /// 判断当前源码是否以一串字符串开头
     fn is_start_with(&self, s: &str) -> bool {
-        for (i, ch) in s.bytes().enumerate() {
-            if self.is_eof() {
-                return false;
-            } else if self.chunk[self.index + i] != ch {
-                return false;
-            }
-        }
-        return true;
+        self.chunk[self.index..].starts_with(s.as_bytes())
     }
 
     #[inline]
@@ -521,12 +517,9 @@ impl Lexer {
     /// 跳过注释
     fn skip_comment(&mut self) -> Result<()> {
         self.next(2);
-        match self.current() {
-            Some(b'[') => {
-                self.scan_long_string()?;
-                return Ok(());
-            }
-            _ => {}
+        if let Some(b'[') = self.current() {
+            self.scan_long_string()?;
+            return Ok(());
         }
         // short comment: --
         while let Some(ch) = self.current() {
@@ -546,10 +539,47 @@ fn is_new_line(c: u8) -> bool {
     c == b'\r' || c == b'\n'
 }
 
+/// 统计换行数，\r\n 和 \n\r 视为一个换行
+fn count_new_lines(s: &[u8]) -> usize {
+    let mut count = 0;
+    let mut i = 0;
+    while i < s.len() {
+        if is_new_line(s[i]) {
+            count += 1;
+            if i + 1 < s.len() && is_new_line(s[i + 1]) && s[i + 1] != s[i] {
+                i += 1;
+            }
+        }
+        i += 1;
+    }
+    count
+}
+
+/// 将码点编码为 UTF-8（与Lua一致，最大支持 0x7FFFFFFF）
+pub fn utf8_encode(mut x: u32) -> Vec<u8> {
+    if x < 0x80 {
+        return vec![x as u8];
+    }
+    let mut buf = vec![];
+    // 首字节能容纳的最大值
+    let mut mfb = 0x3fu32;
+    loop {
+        buf.push(0x80 | (x & 0x3f) as u8);
+        x >>= 6;
+        mfb >>= 1;
+        if x <= mfb {
+            break;
+        }
+    }
+    buf.push(((!mfb << 1) | x) as u8);
+    buf.reverse();
+    buf
+}
+
 /// 判断字符是否符合16进制
 #[inline]
 fn is_hexadecimal(c: u8) -> bool {
-    (b'0' <= c && c <= b'9') || (b'a' <= c && c <= b'f') || (b'A' <= c && c <= b'F')
+    c.is_ascii_hexdigit()
 }
 
 #[cfg(test)]
@@ -595,15 +625,15 @@ mod tests {
         assert_eq!(lexer.current_line(), 5);
 
         let res = lexer.next_token();
-        assert_eq!(res.unwrap(), Token::String(" 世界 ".to_string()));
+        assert_eq!(res.unwrap(), Token::String(" 世界 ".as_bytes().to_vec()));
         assert_eq!(lexer.current_line(), 6);
 
         let res = lexer.next_token();
-        assert_eq!(res.unwrap(), Token::String("string".to_string()));
+        assert_eq!(res.unwrap(), Token::String("string".as_bytes().to_vec()));
         assert_eq!(lexer.current_line(), 7);
 
         let res = lexer.next_token();
-        assert_eq!(res.unwrap(), Token::String("string".to_string()));
+        assert_eq!(res.unwrap(), Token::String("string".as_bytes().to_vec()));
         assert_eq!(lexer.current_line(), 8);
 
         let res = lexer.next_token();
@@ -624,4 +654,25 @@ mod tests {
 
         assert_eq!(lexer.next_token(), Err(Error::EOF { line: 13 }));
     }
+    #[test]
+    fn test_escape() {
+        let s = r##"'a\tb\65\x41\u{4E16}' "it's \"q\"" 'x\z
+            y' [[
+line]]"##.to_string();
+        let mut lexer = Lexer::from_iter(s.bytes(), "test".to_string());
+
+        assert_eq!(lexer.next_token().unwrap(), Token::String("a\tbAA世".as_bytes().to_vec()));
+        assert_eq!(lexer.next_token().unwrap(), Token::String("it's \"q\"".as_bytes().to_vec()));
+        assert_eq!(lexer.next_token().unwrap(), Token::String("xy".as_bytes().to_vec()));
+        assert_eq!(lexer.current_line(), 2);
+        assert_eq!(lexer.next_token().unwrap(), Token::String("line".as_bytes().to_vec()));
+        assert_eq!(lexer.current_line(), 3);
+
+        // escapes may produce bytes that are not valid UTF-8
+        let mut lexer = Lexer::from_iter(r"'\xff\0\200'".bytes(), "test".to_string());
+        assert_eq!(lexer.next_token().unwrap(), Token::String(vec![0xFF, 0, 200]));
+
+        let mut lexer = Lexer::from_iter("'abc\n'".bytes(), "test".to_string());
+        assert_eq!(lexer.next_token(), Err(Error::IllegalString { line: 1 }));
+    }
 }
@@ -4,3 +4,35 @@ pub mod lexer;
 pub mod parser;
 pub mod token;
 pub mod codegen;
+
+use std::rc::Rc;
+
+use crate::binary::chunk::Prototype;
+use crate::compiler::codegen::gen_prototype;
+use crate::compiler::error::{Error, Result};
+use crate::compiler::lexer::{Lex, Lexer};
+use crate::compiler::parser::parse_block;
+
+/// Compile the source code of a chunk to the prototype of its main function
+pub fn compile(chunk: Vec<u8>, chunk_name: String) -> Result<Rc<Prototype>> {
+    let mut lexer = Lexer::from_iter(chunk, chunk_name.clone());
+    let block = parse_block(&mut lexer)?;
+    match lexer.look_ahead() {
+        Err(Error::EOF { .. }) => {}
+        Ok(tok) => return Err(Error::ExpectedEOF { line: lexer.current_line(), near: tok.to_string() }),
+        Err(err) => return Err(err),
+    }
+    let mut proto = gen_prototype(Box::new(block))?;
+    set_source(&mut proto, &chunk_name);
+    Ok(proto)
+}
+
+/// Set the chunk name as the source of the function and its nested ones
+fn set_source(proto: &mut Rc<Prototype>, source: &str) {
+    if let Some(proto) = Rc::get_mut(proto) {
+        proto.source = Some(source.to_string());
+        for sub in proto.prototypes.iter_mut() {
+            set_source(sub, source);
+        }
+    }
+}
@@ -1,6 +1,5 @@
 #![allow(dead_code)]
 
-use std::process::exit;
 
 use crate::compiler::ast::*;
 use crate::compiler::error::*;
@@ -11,22 +10,13 @@ use crate::number::parser::*;
 /// parse gets a lexer and returns a Lua Block which is Lua AST
 pub fn parse_block(lexer: &mut impl Lex) -> Result<Block> {
     let stats = parse_stats(lexer)?;
-    let res = parse_ret_exps(lexer);
-    let ret_exps;
-
-    match res {
-        Ok(res) => {
-            ret_exps = Some(res);
-        }
-
-        Err(Error::NoReturnValue) => {
-            ret_exps = None;
-        }
-
+    let ret_exps = match parse_ret_exps(lexer) {
+        Ok(res) => Some(res),
+        Err(Error::NoReturnValue) => None,
         Err(err) => {
             return Err(err);
         }
-    }
+    };
 
     let last_line = lexer.current_line();
 
@@ -122,7 +112,7 @@ fn parse_label_stat(lexer: &mut impl Lex) -> Result<Stat> {
     // check `::`
 
     if lexer.check_next_token(Token::SepLabel) {
-        Ok(Stat::Label(name))
+        Ok(Stat::Label(name, lexer.current_line()))
     } else {
         Err(Error::IllegalStat { line: lexer.current_line() })
     }
@@ -132,7 +122,7 @@ fn parse_goto_stat(lexer: &mut impl Lex) -> Result<Stat> {
     // skip `goto`
     lexer.skip_next_token();
     let name = lexer.next_ident()?;
-    Ok(Stat::Goto(name))
+    Ok(Stat::Goto(name, lexer.current_line()))
 }
 
 fn parse_do_stat(lexer: &mut impl Lex) -> Result<Stat> {
@@ -203,7 +193,9 @@ fn parse_if_stat(lexer: &mut impl Lex) -> Result<Stat> {
         // demo: if false then elseif false then else end
         blocks.push(parse_block(lexer)?);
     }
-    lexer.skip_next_token();
+    if !lexer.check_next_token(Token::KwEnd) {
+        return Err(Error::IllegalStat { line: lexer.current_line() });
+    }
     Ok(Stat::Condition(exps, blocks))
 }
 
@@ -316,13 +308,12 @@ fn _parse_local_var_decl_stat(lexer: &mut impl Lex) -> Result<Stat> {
 }
 
 fn parse_assign_or_fn_call_stat(lexer: &mut impl Lex) -> Result<Stat> {
-    let prefix_exp = parse_prefix_exp(lexer);
-    match prefix_exp {
-        Ok(Exp::FnCall(fn_call)) => {
+    match parse_prefix_exp(lexer)? {
+        Exp::FnCall(fn_call) => {
             Ok(Stat::FnCall(fn_call))
         }
-        _ => {
-            parse_assign_stat(lexer, prefix_exp.unwrap())
+        prefix_exp => {
+            parse_assign_stat(lexer, prefix_exp)
         }
     }
 }
@@ -395,7 +386,7 @@ fn _parse_fn_name(lexer: &mut impl Lex, has_colon: &mut bool) -> Result<Exp> {
         lexer.skip_next_token();
         let name = lexer.next_ident()?;
         let line = lexer.current_line();
-        let key = Box::new(Exp::String(name, line));
+        let key = Box::new(Exp::String(name.into_bytes(), line));
         exp = Box::new(Exp::TableAccess(exp, key, line));
     }
 
@@ -405,7 +396,7 @@ fn _parse_fn_name(lexer: &mut impl Lex, has_colon: &mut bool) -> Result<Exp> {
         let name = lexer.next_ident()?;
         let line = lexer.current_line();
         *has_colon = true;
-        let key = Box::new(Exp::String(name, line));
+        let key = Box::new(Exp::String(name.into_bytes(), line));
         exp = Box::new(Exp::TableAccess(exp, key, line));
     }
 
@@ -445,15 +436,10 @@ fn parse_exp11(lexer: &mut impl Lex) -> Result<Exp> {
 fn parse_exp10(lexer: &mut impl Lex) -> Result<Exp> {
     // x `cmp` y
     let mut exp = Box::new(parse_exp9(lexer)?);
-    loop {
-        match lexer.look_ahead() {
-            Ok(Token::OpGe) | Ok(Token::OpGt) | Ok(Token::OpLe) | Ok(Token::OpLt) | Ok(Token::OpNe) | Ok(Token::OPEq) => {
-                let op = lexer.next_token()?;
-                let line = lexer.current_line();
-                exp = Box::new(Exp::Binop(exp, op, Box::new(parse_exp9(lexer)?), line));
-            }
-            _ => { break; }
-        }
+    while let Ok(Token::OpGe) | Ok(Token::OpGt) | Ok(Token::OpLe) | Ok(Token::OpLt) | Ok(Token::OpNe) | Ok(Token::OPEq) = lexer.look_ahead() {
+        let op = lexer.next_token()?;
+        let line = lexer.current_line();
+        exp = Box::new(Exp::Binop(exp, op, Box::new(parse_exp9(lexer)?), line));
     }
 
     Ok(*exp)
@@ -495,15 +481,10 @@ fn parse_exp7(lexer: &mut impl Lex) -> Result<Exp> {
 fn parse_exp6(lexer: &mut impl Lex) -> Result<Exp> {
     // x >>/<< y
     let mut exp = Box::new(parse_exp5(lexer)?);
-    loop {
-        match lexer.look_ahead() {
-            Ok(Token::OpShl) | Ok(Token::OpShr) => {
-                let op = lexer.next_token()?;
-                let line = lexer.current_line();
-                exp = Box::new(Exp::Binop(exp, op, Box::new(parse_exp5(lexer)?), line));
-            }
-            _ => { break; }
-        }
+    while let Ok(Token::OpShl) | Ok(Token::OpShr) = lexer.look_ahead() {
+        let op = lexer.next_token()?;
+        let line = lexer.current_line();
+        exp = Box::new(Exp::Binop(exp, op, Box::new(parse_exp5(lexer)?), line));
     }
 
     Ok(*exp)
@@ -515,7 +496,7 @@ fn parse_exp5(lexer: &mut impl Lex) -> Result<Exp> {
     match lexer.look_ahead() {
         Ok(Token::OpConcat) => {
             let mut line = 0;
-            let mut exps = vec![];
+            let mut exps = vec![exp];
 
             while let Ok(Token::OpConcat) = lexer.look_ahead() {
                 lexer.skip_next_token();
@@ -532,15 +513,10 @@ fn parse_exp5(lexer: &mut impl Lex) -> Result<Exp> {
 fn parse_exp4(lexer: &mut impl Lex) -> Result<Exp> {
     // x +/- y
     let mut exp = Box::new(parse_exp3(lexer)?);
-    loop {
-        match lexer.look_ahead() {
-            Ok(Token::OpAdd) | Ok(Token::OpMinus) => {
-                let op = lexer.next_token()?;
-                let line = lexer.current_line();
-                exp = Box::new(Exp::Binop(exp, op, Box::new(parse_exp3(lexer)?), line));
-            }
-            _ => { break; }
-        }
+    while let Ok(Token::OpAdd) | Ok(Token::OpMinus) = lexer.look_ahead() {
+        let op = lexer.next_token()?;
+        let line = lexer.current_line();
+        exp = Box::new(Exp::Binop(exp, op, Box::new(parse_exp3(lexer)?), line));
     }
 
     Ok(*exp)
@@ -549,15 +525,10 @@ fn parse_exp4(lexer: &mut impl Lex) -> Result<Exp> {
 fn parse_exp3(lexer: &mut impl Lex) -> Result<Exp> {
     // *  %  /  //
     let mut exp = Box::new(parse_exp2(lexer)?);
-    loop {
-        match lexer.look_ahead() {
-            Ok(Token::OpMul) | Ok(Token::OpDiv) | Ok(Token::OpIDiv) | Ok(Token::OpMod) => {
-                let op = lexer.next_token()?;
-                let line = lexer.current_line();
-                exp = Box::new(Exp::Binop(exp, op, Box::new(parse_exp2(lexer)?), line));
-            }
-            _ => { break; }
-        }
+    while let Ok(Token::OpMul) | Ok(Token::OpDiv) | Ok(Token::OpIDiv) | Ok(Token::OpMod) = lexer.look_ahead() {
+        let op = lexer.next_token()?;
+        let line = lexer.current_line();
+        exp = Box::new(Exp::Binop(exp, op, Box::new(parse_exp2(lexer)?), line));
     }
 
     Ok(*exp)
@@ -634,7 +605,7 @@ fn parse_number_exp(lexer: &mut impl Lex) -> Result<Exp> {
     if let Ok(Token::Number(val)) = num {
         match parse_integer(val.clone()) {
             Err(_) => {
-                let num = parse_float(val)?;
+                let num = parse_float(val).or(Err(Error::IllegalNumLiteral { line }))?;
                 Ok(Exp::Float(num, line))
             }
             Ok(num) => Ok(Exp::Integer(num, line))
@@ -671,18 +642,18 @@ fn parse_fn_def_exp(lexer: &mut impl Lex) -> Result<Exp> {
     }
     let mut is_vararg = false;
     let par_list = _parse_par_list(lexer, &mut is_vararg)?;
-    let line = lexer.current_line();
+    let rparen_line = lexer.current_line();
     if !lexer.check_next_token(Token::SepRparen) {
         return Err(Error::IllegalToken {
-            line,
+            line: rparen_line,
         });
     }
     let block = Box::new(parse_block(lexer)?);
-    let line = lexer.current_line();
+    let end_line = lexer.current_line();
 
     if !lexer.check_next_token(Token::KwEnd) {
         return Err(Error::IllegalToken {
-            line,
+            line: end_line,
         });
     }
     let last_line = lexer.current_line();
@@ -719,7 +690,7 @@ fn parse_prefix_exp(lexer: &mut impl Lex) -> Result<Exp> {
                 lexer.skip_next_token();
                 let name = lexer.next_ident()?;
                 let line = lexer.current_line();
-                let key = Box::new(Exp::String(name, line));
+                let key = Box::new(Exp::String(name.into_bytes(), line));
 
                 let last_line = line;
                 exp = Box::new(Exp::TableAccess(exp, key, last_line));
@@ -775,7 +746,7 @@ fn _parse_fn_name_exp(lexer: &mut impl Lex) -> Result<Box<Exp>> {
         lexer.skip_next_token();
         let val = lexer.next_ident()?;
         let line = lexer.current_line();
-        Ok(Box::new(Exp::String(val, line)))
+        Ok(Box::new(Exp::String(val.into_bytes(), line)))
     } else {
         // just represent a option token
         Err(Error::NoMoreTokens { line: lexer.current_line() })
@@ -869,7 +840,7 @@ fn _parse_field(lexer: &mut impl Lex) -> Result<(Option<Exp>, Exp)> {
         if let Exp::Name(ref val, line) = exp {
             if let Ok(Token::OpAssign) = lexer.look_ahead() {
                 lexer.skip_next_token();
-                let key = Exp::String(val.to_string(), line);
+                let key = Exp::String(val.clone().into_bytes(), line);
                 let val = parse_exp(lexer)?;
                 return Ok((Some(key), val));
             }
@@ -899,6 +870,7 @@ fn _parse_par_list(lexer: &mut impl Lex, is_vararg: &mut bool) -> Result<Vec<Str
                 lexer.skip_next_token();
             }
             Ok(Token::VarArg) => {
+                lexer.skip_next_token();
                 *is_vararg = true;
                 break;
             }
@@ -912,32 +884,25 @@ fn _parse_par_list(lexer: &mut impl Lex, is_vararg: &mut bool) -> Result<Vec<Str
 
 #[inline]
 fn _is_return_or_block_end(tok: Result<Token>) -> bool {
-    match tok {
+    matches!(
+        tok,
         Err(Error::EOF { line: _ })
-        | Ok(Token::KwReturn)
-        | Ok(Token::KwEnd)
-        | Ok(Token::KwElse)
-        | Ok(Token::KwElseIf)
-        | Ok(Token::KwUntil) => true,
-        _ => false,
-    }
+            | Ok(Token::KwReturn)
+            | Ok(Token::KwEnd)
+            | Ok(Token::KwElse)
+            | Ok(Token::KwElseIf)
+            | Ok(Token::KwUntil)
+    )
 }
 
 #[inline]
 fn _is_var_exp(exp: &Exp) -> bool {
-    match exp {
-        Exp::Name(_, _) => true,
-        Exp::TableAccess(_, _, _) => true,
-        _ => false,
-    }
+    matches!(exp, Exp::Name(_, _) | Exp::TableAccess(_, _, _))
 }
 
 #[inline]
 fn _is_field_sep(tok: Result<Token>) -> bool {
-    match tok {
-        Ok(Token::SepComma) | Ok(Token::SepSemi) => true,
-        _ => false,
-    }
+    matches!(tok, Ok(Token::SepComma) | Ok(Token::SepSemi))
 }
 
 #[cfg(test)]
@@ -990,7 +955,7 @@ mod tests {
             b = {}
         }"##.to_string();
         let mut lexer = Lexer::from_iter(s.into_bytes(), "test".to_string());
-        let block = parse_block(&mut lexer).expect("parse error");
+        let _block = parse_block(&mut lexer).expect("parse error");
 //        println!("{:#?}", block);
     }
 }
@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use std::fmt::{self, Display, Formatter};
+
 /// Lua Token
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
@@ -119,6 +121,73 @@ pub enum Token {
     Identifier(String),
     /// __number__
     Number(String),
-    /// __string__
-    String(String),
-}
\ No newline at end of file
+    /// __string__, which is not necessarily valid UTF-8
+    String(Vec<u8>),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use Token::*;
+        match self {
+            Eof => f.write_str("<eof>"),
+            VarArg => f.write_str("..."),
+            SepSemi => f.write_str(";"),
+            SepComma => f.write_str(","),
+            SepDot => f.write_str("."),
+            SepColon => f.write_str(":"),
+            SepLabel => f.write_str("::"),
+            SepLparen => f.write_str("("),
+            SepRparen => f.write_str(")"),
+            SepLbrack => f.write_str("["),
+            SepRbrack => f.write_str("]"),
+            SepLcurly => f.write_str("{"),
+            SepRcurly => f.write_str("}"),
+            OpAssign => f.write_str("="),
+            OpMinus => f.write_str("-"),
+            OpWave => f.write_str("~"),
+            OpAdd => f.write_str("+"),
+            OpMul => f.write_str("*"),
+            OpDiv => f.write_str("/"),
+            OpIDiv => f.write_str("//"),
+            OpPow => f.write_str("^"),
+            OpMod => f.write_str("%"),
+            OpBitAnd => f.write_str("&"),
+            OpBitOr => f.write_str("|"),
+            OpShr => f.write_str(">>"),
+            OpShl => f.write_str("<<"),
+            OpConcat => f.write_str(".."),
+            OpLt => f.write_str("<"),
+            OpLe => f.write_str("<="),
+            OpGt => f.write_str(">"),
+            OpGe => f.write_str(">="),
+            OPEq => f.write_str("=="),
+            OpNe => f.write_str("~="),
+            OpLen => f.write_str("#"),
+            OpAnd => f.write_str("and"),
+            OpOr => f.write_str("or"),
+            OpNot => f.write_str("not"),
+            KwBreak => f.write_str("break"),
+            KwDo => f.write_str("do"),
+            KwElse => f.write_str("else"),
+            KwElseIf => f.write_str("elseif"),
+            KwEnd => f.write_str("end"),
+            KwFalse => f.write_str("false"),
+            KwFor => f.write_str("for"),
+            KwFunction => f.write_str("function"),
+            KwGoto => f.write_str("goto"),
+            KwIf => f.write_str("if"),
+            KwIn => f.write_str("in"),
+            KwLocal => f.write_str("local"),
+            KwNil => f.write_str("nil"),
+            KwRepeat => f.write_str("repeat"),
+            KwReturn => f.write_str("return"),
+            KwThen => f.write_str("then"),
+            KwTrue => f.write_str("true"),
+            KwUntil => f.write_str("until"),
+            KwWhile => f.write_str("while"),
+            Identifier(s) => f.write_str(s),
+            Number(s) => f.write_str(s),
+            String(s) => f.write_str(&std::string::String::from_utf8_lossy(s)),
+        }
+    }
+}
@@ -7,4 +7,5 @@ pub mod number;
 pub mod binary;
 pub mod vm;
 pub mod api;
-pub mod state;
\ No newline at end of file
+pub mod state;
+pub mod stdlib;
\ No newline at end of file
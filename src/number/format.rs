@@ -0,0 +1,324 @@
+//! Formatting of numbers with the semantics of C `printf`
+
+/// Conversion specification like `%-+ #0<width>.<precision><conversion>`
+#[derive(Debug, Default, Clone)]
+pub struct FormatSpec {
+    /// '-' flag, left justify within the width
+    pub left: bool,
+    /// '+' flag, always write the sign
+    pub plus: bool,
+    /// ' ' flag, write a space in place of the plus sign
+    pub space: bool,
+    /// '#' flag, alternate form
+    pub alt: bool,
+    /// '0' flag, pad numbers with zeros
+    pub zero: bool,
+    pub width: usize,
+    pub precision: Option<usize>,
+    pub conversion: u8,
+}
+
+impl FormatSpec {
+    pub fn new(conversion: u8) -> Self {
+        FormatSpec {
+            conversion,
+            ..FormatSpec::default()
+        }
+    }
+
+    /// Pad the `prefix` (sign or base) followed by `body` up to the width,
+    /// zeros are inserted between them if `zero_ok`
+    pub fn pad(&self, prefix: &str, body: &str, zero_ok: bool) -> String {
+        let len = prefix.len() + body.len();
+        if len >= self.width {
+            return format!("{}{}", prefix, body);
+        }
+        let fill = self.width - len;
+        if self.left {
+            format!("{}{}{}", prefix, body, " ".repeat(fill))
+        } else if self.zero && zero_ok {
+            format!("{}{}{}", prefix, "0".repeat(fill), body)
+        } else {
+            format!("{}{}{}", " ".repeat(fill), prefix, body)
+        }
+    }
+
+    /// Pad the bytes of a string up to the width, for '%c' and '%s'
+    pub fn pad_bytes(&self, body: &[u8]) -> Vec<u8> {
+        let fill = vec![b' '; self.width.saturating_sub(body.len())];
+        if self.left {
+            [body, &fill].concat()
+        } else {
+            [&fill, body].concat()
+        }
+    }
+
+    fn sign(&self, neg: bool) -> &'static str {
+        if neg {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    /// Apply the precision, which is the minimum number of digits, to integer digits
+    fn integer_digits(&self, digits: String) -> String {
+        match self.precision {
+            Some(0) if digits == "0" => String::new(),
+            Some(p) if p > digits.len() => format!("{}{}", "0".repeat(p - digits.len()), digits),
+            _ => digits,
+        }
+    }
+
+    /// Conversions `d` and `i`
+    pub fn format_integer(&self, i: i64) -> String {
+        let digits = self.integer_digits(i.unsigned_abs().to_string());
+        self.pad(self.sign(i < 0), &digits, self.precision.is_none())
+    }
+
+    /// Conversions `o`, `u`, `x` and `X`
+    pub fn format_unsigned(&self, u: u64) -> String {
+        let (prefix, digits) = match self.conversion {
+            b'o' => {
+                let digits = self.integer_digits(format!("{:o}", u));
+                if self.alt && !digits.starts_with('0') {
+                    ("", format!("0{}", digits))
+                } else {
+                    ("", digits)
+                }
+            }
+            b'x' => (if self.alt && u != 0 { "0x" } else { "" }, self.integer_digits(format!("{:x}", u))),
+            b'X' => (if self.alt && u != 0 { "0X" } else { "" }, self.integer_digits(format!("{:X}", u))),
+            _ => ("", self.integer_digits(u.to_string())),
+        };
+        self.pad(prefix, &digits, self.precision.is_none())
+    }
+
+    /// Conversions `a`, `A`, `e`, `E`, `f`, `g` and `G`
+    pub fn format_float(&self, f: f64) -> String {
+        let upper = self.conversion.is_ascii_uppercase();
+        let x = f.abs();
+        let body = if f.is_nan() {
+            "nan".to_string()
+        } else if f.is_infinite() {
+            "inf".to_string()
+        } else {
+            match self.conversion.to_ascii_lowercase() {
+                b'a' => fmt_hex(x, self.precision, self.alt),
+                b'e' => fmt_exp(x, self.precision.unwrap_or(6), self.alt),
+                b'g' => fmt_general(x, self.precision.unwrap_or(6), self.alt),
+                _ => fmt_fixed(x, self.precision.unwrap_or(6), self.alt),
+            }
+        };
+        let body = if upper { body.to_ascii_uppercase() } else { body };
+        self.pad(self.sign(f.is_sign_negative()), &body, f.is_finite())
+    }
+}
+
+/// Convert a float to string like `%.14g`, which looks like a float if it has no dot nor exponent
+pub fn float_to_string(f: f64) -> String {
+    let mut s = FormatSpec {
+        precision: Some(14),
+        ..FormatSpec::new(b'g')
+    }
+    .format_float(f);
+    if s.bytes().all(|c| c == b'-' || c.is_ascii_digit()) {
+        s.push_str(".0"); // adds '.0' to result
+    }
+    s
+}
+
+/// `%f` of a non negative number
+fn fmt_fixed(x: f64, prec: usize, alt: bool) -> String {
+    let mut s = format!("{:.*}", prec, x);
+    if alt && prec == 0 {
+        s.push('.');
+    }
+    s
+}
+
+/// Split the scientific notation of a non negative number into the mantissa and exponent
+fn split_exp(x: f64, prec: usize) -> (String, i32) {
+    let s = format!("{:.*e}", prec, x);
+    let (mantissa, exp) = s.split_at(s.find('e').unwrap());
+    (mantissa.to_string(), exp[1..].parse().unwrap())
+}
+
+/// `%e` of a non negative number
+fn fmt_exp(x: f64, prec: usize, alt: bool) -> String {
+    let (mut mantissa, exp) = split_exp(x, prec);
+    if alt && prec == 0 {
+        mantissa.push('.');
+    }
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, exp.abs())
+}
+
+/// `%g` of a non negative number
+fn fmt_general(x: f64, prec: usize, alt: bool) -> String {
+    let p = prec.max(1);
+    let (_, exp) = split_exp(x, p - 1);
+    let s = if exp < -4 || exp >= p as i32 {
+        fmt_exp(x, p - 1, alt)
+    } else {
+        fmt_fixed(x, (p as i32 - 1 - exp) as usize, alt)
+    };
+    if alt {
+        return s;
+    }
+    // remove the trailing zeros of the fraction
+    let (num, exp) = s.split_at(s.find('e').unwrap_or(s.len()));
+    let num = if num.contains('.') {
+        num.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        num
+    };
+    format!("{}{}", num, exp)
+}
+
+/// `%a` of a non negative number, the exact value when there is no precision
+fn fmt_hex(x: f64, prec: Option<usize>, alt: bool) -> String {
+    let bits = x.to_bits();
+    let biased_exp = ((bits >> 52) & 0x7ff) as i32;
+    let mut frac = bits & ((1 << 52) - 1);
+    let (mut lead, exp) = match (biased_exp, frac) {
+        (0, 0) => (0, 0),        // zero
+        (0, _) => (0, -1022),    // subnormal
+        _ => (1, biased_exp - 1023), // normal
+    };
+    let mut ndigits = 13;
+    if let Some(p) = prec {
+        if p < 13 {
+            // round to nearest, ties to even
+            let shift = 4 * (13 - p) as u32;
+            let rest = frac & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+            frac >>= shift;
+            if rest > half || (rest == half && (frac & 1) == 1) {
+                frac += 1;
+                if frac >> (4 * p) != 0 {
+                    // carry into the leading digit
+                    frac &= (1 << (4 * p)) - 1;
+                    lead += 1;
+                }
+            }
+            ndigits = p;
+        }
+    }
+    let mut digits = if ndigits == 0 {
+        String::new()
+    } else {
+        format!("{:0width$x}", frac, width = ndigits)
+    };
+    match prec {
+        Some(p) if p > 13 => digits.push_str(&"0".repeat(p - 13)),
+        None => digits.truncate(digits.trim_end_matches('0').len()),
+        _ => {}
+    }
+    let dot = if !digits.is_empty() || alt { "." } else { "" };
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("0x{}{}{}p{}{}", lead, dot, digits, sign, exp.abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(s: &str) -> FormatSpec {
+        let s = s.as_bytes();
+        let mut spec = FormatSpec::new(s[s.len() - 1]);
+        let mut i = 0;
+        while let Some(&c) = s.get(i).filter(|c| b"-+ #0".contains(c)) {
+            match c {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alt = true,
+                _ => spec.zero = true,
+            }
+            i += 1;
+        }
+        while s[i].is_ascii_digit() {
+            spec.width = spec.width * 10 + (s[i] - b'0') as usize;
+            i += 1;
+        }
+        if s[i] == b'.' {
+            i += 1;
+            let mut p = 0;
+            while s[i].is_ascii_digit() {
+                p = p * 10 + (s[i] - b'0') as usize;
+                i += 1;
+            }
+            spec.precision = Some(p);
+        }
+        spec
+    }
+
+    #[test]
+    fn test_format_float() {
+        let cases: &[(&str, f64, &str)] = &[
+            ("f", 1.23456, "1.234560"),
+            (".2f", 2.675, "2.67"),
+            ("08.3f", -1.23456, "-001.235"),
+            ("+.0f", 2.5, "+2"),
+            ("#.0f", 3.0, "3."),
+            ("e", 12345.678, "1.234568e+04"),
+            (".2E", 0.000123, "1.23E-04"),
+            ("g", 100000.0, "100000"),
+            ("g", 1000000.0, "1e+06"),
+            ("g", 0.0001, "0.0001"),
+            ("g", 0.00001, "1e-05"),
+            ("#g", 1.5, "1.50000"),
+            (".14g", 0.1, "0.1"),
+            (".3G", 1e-10, "1E-10"),
+            ("10.3g", 1.23456, "      1.23"),
+            ("-10g", 1.5, "1.5       "),
+            ("a", 1.0, "0x1p+0"),
+            ("a", 0.1, "0x1.999999999999ap-4"),
+            ("A", -2.5, "-0X1.4P+1"),
+            (".1a", 1.95, "0x1.fp+0"),
+            (".1a", 1.97, "0x2.0p+0"),
+            (".0a", 1.97, "0x2p+0"),
+            ("a", 0.0, "0x0p+0"),
+            ("a", 5e-324, "0x0.0000000000001p-1022"),
+            ("05f", f64::INFINITY, "  inf"),
+            ("f", f64::NEG_INFINITY, "-inf"),
+            ("E", f64::NAN, "NAN"),
+        ];
+        for &(fmt, f, expected) in cases {
+            assert_eq!(spec(fmt).format_float(f), expected, "%{}", fmt);
+        }
+    }
+
+    #[test]
+    fn test_format_integer() {
+        assert_eq!(spec("d").format_integer(-42), "-42");
+        assert_eq!(spec("5d").format_integer(42), "   42");
+        assert_eq!(spec("-5d").format_integer(42), "42   ");
+        assert_eq!(spec("05d").format_integer(-42), "-0042");
+        assert_eq!(spec("+.4d").format_integer(42), "+0042");
+        assert_eq!(spec("08.4d").format_integer(42), "    0042");
+        assert_eq!(spec(".0d").format_integer(0), "");
+        assert_eq!(spec("d").format_integer(i64::MIN), "-9223372036854775808");
+        assert_eq!(spec("x").format_unsigned(255), "ff");
+        assert_eq!(spec("#X").format_unsigned(255), "0XFF");
+        assert_eq!(spec("#o").format_unsigned(8), "010");
+        assert_eq!(spec("u").format_unsigned(-1i64 as u64), "18446744073709551615");
+    }
+
+    #[test]
+    fn test_float_to_string() {
+        assert_eq!(float_to_string(1.0), "1.0");
+        assert_eq!(float_to_string(-0.0), "-0.0");
+        assert_eq!(float_to_string(0.1), "0.1");
+        assert_eq!(float_to_string(1e15), "1e+15");
+        assert_eq!(float_to_string(2f64.powi(53)), "9.007199254741e+15");
+        assert_eq!(float_to_string(1.0 / 3.0), "0.33333333333333");
+        assert_eq!(float_to_string(f64::INFINITY), "inf");
+        assert_eq!(float_to_string(-f64::NAN), "-nan");
+    }
+}
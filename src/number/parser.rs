@@ -3,23 +3,29 @@
 use regex::bytes::Regex;
 
 use crate::compiler::error::{Error, Result};
+use crate::state::lua_value::{string_to_number, LuaValue};
 
 lazy_static! {
     static ref re_integer: Regex = Regex::new(r#"^[+-]?[0-9]+$|^-?0x[0-9a-f]+$"#).unwrap();
     static ref re_hex_float: Regex = Regex::new(r#"^([0-9a-f]+(\.[0-9a-f]*)?|([0-9a-f]*\.[0-9a-f]+))(p[+\-]?[0-9]+)?$"#).unwrap();
 }
 
+/// Convert a numeral to a float, in the same way as `tonumber`
 pub fn parse_float(num: String) -> Result<f64> {
-    // todo: supports total syntax
-    num.parse::<f64>().or(Err(Error::IllegalToken {
-        line: 0,
-    }))
+    match string_to_number(&num) {
+        Some(LuaValue::Number(n)) => Ok(n),
+        Some(LuaValue::Integer(i)) => Ok(i as f64),
+        _ => Err(Error::IllegalNumLiteral { line: 0 }),
+    }
 }
 
+/// Convert a numeral to an integer, in the same way as `tonumber`,
+/// which fails for floats and decimal integers overflowing
 pub fn parse_integer(num: String) -> Result<i64> {
-    num.parse::<i64>().or(Err(Error::IllegalToken {
-        line: 0,
-    }))
+    match string_to_number(&num) {
+        Some(LuaValue::Integer(i)) => Ok(i),
+        _ => Err(Error::IllegalNumLiteral { line: 0 }),
+    }
 }
 
 pub fn int_to_float_byte(mut x: isize) -> isize {
@@ -38,4 +44,13 @@ pub fn int_to_float_byte(mut x: isize) -> isize {
     }
 
     ((e + 1) << 3) | (x - 8)
-}
\ No newline at end of file
+}
+
+/// The inverse of `int_to_float_byte`, (eeeeexxx) -> x
+pub fn float_byte_to_int(x: isize) -> isize {
+    if x < 8 {
+        x
+    } else {
+        ((x & 7) + 8) << ((x >> 3) - 1)
+    }
+}
@@ -0,0 +1,475 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Read};
+use std::process::ExitStatus;
+use std::rc::Rc;
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{LuaAPI, LuaDebug, LuaError, LuaResult, RustFn};
+use crate::state::lua_state::LuaState;
+use crate::state::lua_userdata::LuaUserData;
+use crate::stdlib;
+
+impl LuaAuxLib for LuaState {
+    /// Error with the message prefixed by the position of the function calling the Rust function
+    fn error2(&self, msg: &str) -> LuaError {
+        LuaError::runtime(msg, self.where_(1))
+    }
+
+    fn arg_error(&self, arg: isize, extra_msg: &str) -> LuaError {
+        self.error2(&format!("bad argument #{} ({})", arg, extra_msg))
+    }
+
+    fn type_error(&self, arg: isize, tname: &str) -> LuaError {
+        let msg = format!("{} expected, got {}", tname, self.type_name2(arg));
+        self.arg_error(arg, &msg)
+    }
+
+    #[inline]
+    fn arg_check(&self, cond: bool, arg: isize, extra_msg: &str) -> LuaResult<()> {
+        if !cond {
+            return Err(self.arg_error(arg, extra_msg));
+        }
+        Ok(())
+    }
+
+    fn check_type(&self, arg: isize, t: i8) -> LuaResult<()> {
+        if self.type_id(arg) != t {
+            return Err(self.type_error(arg, self.type_name(t)));
+        }
+        Ok(())
+    }
+
+    fn check_any(&self, arg: isize) -> LuaResult<()> {
+        if self.type_id(arg) == LUA_TNONE {
+            return Err(self.arg_error(arg, "value expected"));
+        }
+        Ok(())
+    }
+
+    fn check_number(&self, arg: isize) -> LuaResult<f64> {
+        match self.to_numberx(arg) {
+            Some(n) => Ok(n),
+            None => Err(self.type_error(arg, "number")),
+        }
+    }
+
+    fn opt_number(&self, arg: isize, def: f64) -> LuaResult<f64> {
+        if self.is_none_or_nil(arg) {
+            Ok(def)
+        } else {
+            self.check_number(arg)
+        }
+    }
+
+    fn check_integer(&self, arg: isize) -> LuaResult<i64> {
+        match self.to_integerx(arg) {
+            Some(i) => Ok(i),
+            None if self.is_number(arg) => Err(self.arg_error(arg, "number has no integer representation")),
+            None => Err(self.type_error(arg, "number")),
+        }
+    }
+
+    fn opt_integer(&self, arg: isize, def: i64) -> LuaResult<i64> {
+        if self.is_none_or_nil(arg) {
+            Ok(def)
+        } else {
+            self.check_integer(arg)
+        }
+    }
+
+    /// A string or a number converted to string, where invalid UTF-8 sequences are replaced
+    fn check_string(&self, arg: isize) -> LuaResult<String> {
+        match self.to_stringx(arg) {
+            Some(s) => Ok(s),
+            None => Err(self.type_error(arg, "string")),
+        }
+    }
+
+    fn opt_string(&self, arg: isize, def: &str) -> LuaResult<String> {
+        if self.is_none_or_nil(arg) {
+            Ok(def.to_string())
+        } else {
+            self.check_string(arg)
+        }
+    }
+
+    /// The bytes of a string or a number converted to string
+    fn check_bytes(&self, arg: isize) -> LuaResult<Vec<u8>> {
+        match self.to_bytesx(arg) {
+            Some(s) => Ok(s),
+            None => Err(self.type_error(arg, "string")),
+        }
+    }
+
+    fn opt_bytes(&self, arg: isize, def: &[u8]) -> LuaResult<Vec<u8>> {
+        if self.is_none_or_nil(arg) {
+            Ok(def.to_vec())
+        } else {
+            self.check_bytes(arg)
+        }
+    }
+
+    #[inline]
+    fn type_name2(&self, idx: isize) -> &str {
+        self.type_name(self.type_id(idx))
+    }
+
+    /// Push the field `e` of the metatable of the object at `obj` and return its type,
+    /// nothing is pushed if there is no such field
+    fn get_metafield(&mut self, obj: isize, e: &str) -> LuaResult<i8> {
+        if !self.get_metatable(obj) {
+            return Ok(LUA_TNIL);
+        }
+        self.push_string(e.to_string());
+        let tp = self.raw_get(-2)?;
+        if tp == LUA_TNIL {
+            self.pop(2);
+        } else {
+            self.remove(-2)?;
+        }
+        Ok(tp)
+    }
+
+    /// Call the metamethod `e` of the object at `obj` and push its result
+    fn call_meta(&mut self, obj: isize, e: &str) -> LuaResult<bool> {
+        let obj = self.abs_index(obj);
+        if self.get_metafield(obj, e)? == LUA_TNIL {
+            return Ok(false);
+        }
+        self.push_value(obj);
+        self.call(1, 1)?;
+        Ok(true)
+    }
+
+    /// Push the metatable registered as `tname`, a new one is created and registered
+    /// with `__name` if there is none, and return whether it is new
+    fn new_metatable(&mut self, tname: &str) -> LuaResult<bool> {
+        if self.get_metatable2(tname)? != LUA_TNIL {
+            return Ok(false); // name already in use
+        }
+        self.pop(1);
+        self.create_table(0, 2);
+        self.push_string(tname.to_string());
+        self.set_field(-2, "__name")?; // metatable.__name = tname
+        self.push_value(-1);
+        self.set_field(LUA_REGISTRYINDEX, tname)?; // registry.tname = metatable
+        Ok(true)
+    }
+
+    /// Push the metatable registered as `tname`
+    fn get_metatable2(&mut self, tname: &str) -> LuaResult<i8> {
+        self.get_field(LUA_REGISTRYINDEX, tname)
+    }
+
+    /// Set the metatable registered as `tname` to the object on the top
+    fn set_metatable2(&mut self, tname: &str) -> LuaResult<()> {
+        self.get_metatable2(tname)?;
+        self.set_metatable(-2)
+    }
+
+    /// The userdata at `arg` if its metatable is registered as `tname`
+    fn test_udata(&mut self, arg: isize, tname: &str) -> LuaResult<Option<Rc<RefCell<LuaUserData>>>> {
+        let u = match self.to_userdata(arg) {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+        if !self.get_metatable(arg) {
+            return Ok(None);
+        }
+        self.get_metatable2(tname)?;
+        let same = self.raw_equal(-1, -2);
+        self.pop(2); // remove both metatables
+        Ok(if same { Some(u) } else { None })
+    }
+
+    fn check_udata(&mut self, arg: isize, tname: &str) -> LuaResult<Rc<RefCell<LuaUserData>>> {
+        match self.test_udata(arg, tname)? {
+            Some(u) => Ok(u),
+            None => Err(self.type_error(arg, tname)),
+        }
+    }
+
+    /// Convert any value to a string in a reasonable format, which is pushed as well
+    fn to_string2(&mut self, idx: isize) -> LuaResult<String> {
+        if self.call_meta(idx, "__tostring")? {
+            if !self.is_string(-1) {
+                return Err(self.error2("'__tostring' must return a string"));
+            }
+        } else {
+            match self.type_id(idx) {
+                LUA_TNUMBER | LUA_TSTRING => self.push_value(idx),
+                LUA_TBOOLEAN => {
+                    let s = if self.to_boolean(idx) { "true" } else { "false" };
+                    self.push_string(s.to_string());
+                }
+                LUA_TNIL => self.push_string("nil".to_string()),
+                _ => {
+                    // the kind is `__name` of the metatable if it is a string
+                    let idx = self.abs_index(idx);
+                    let tt = self.get_metafield(idx, "__name")?;
+                    let kind = if tt == LUA_TSTRING { self.to_string(-1) } else { self.type_name2(idx).to_string() };
+                    if tt != LUA_TNIL {
+                        self.pop(1);
+                    }
+                    let s = format!("{}: {:p}", kind, self.to_pointer(idx));
+                    self.push_string(s);
+                }
+            }
+        }
+        Ok(self.to_string(-1))
+    }
+
+    /// The length of the value at `idx` as an integer, which may call `__len`
+    fn len2(&mut self, idx: isize) -> LuaResult<i64> {
+        self.len(idx)?;
+        let n = self.to_integerx(-1);
+        self.pop(1); // remove object
+        n.ok_or_else(|| self.error2("object length is not an integer"))
+    }
+
+    /// Push true, or nil, the message prefixed by `fname` and the error number
+    fn file_result(&mut self, res: io::Result<()>, fname: Option<&str>) -> usize {
+        match res {
+            Ok(()) => {
+                self.push_boolean(true);
+                1
+            }
+            Err(err) => {
+                self.push_nil();
+                let msg = match fname {
+                    Some(name) => format!("{}: {}", name, strerror(&err)),
+                    None => strerror(&err),
+                };
+                self.push_string(msg);
+                self.push_integer(err.raw_os_error().unwrap_or(0) as i64);
+                3
+            }
+        }
+    }
+
+    /// Push the results of `os.execute`: true or nil, "exit" or "signal" and the code
+    fn exec_result(&mut self, res: io::Result<ExitStatus>) -> usize {
+        let status = match res {
+            Ok(status) => status,
+            Err(err) => return self.file_result(Err(err), None),
+        };
+        let (what, code) = match status.code() {
+            Some(code) => ("exit", code),
+            None => ("signal", exit_signal(&status)),
+        };
+        if what == "exit" && code == 0 {
+            self.push_boolean(true);
+        } else {
+            self.push_nil();
+        }
+        self.push_string(what.to_string());
+        self.push_integer(code as i64);
+        3
+    }
+
+    /// Load a file as a chunk, or the standard input if `filename` is None,
+    /// skipping its first line if it starts with `#`
+    fn load_file(&mut self, filename: Option<&str>, mode: &str) -> i8 {
+        let chunk_name = match filename {
+            Some(name) => format!("@{}", name),
+            None => "=stdin".to_string(),
+        };
+        let mut data = Vec::new();
+        let res = match filename {
+            Some(name) => fs::File::open(name)
+                .map_err(|err| ("open", err))
+                .and_then(|mut f| f.read_to_end(&mut data).map_err(|err| ("read", err))),
+            None => io::stdin().read_to_end(&mut data).map_err(|err| ("read", err)),
+        };
+        if let Err((what, err)) = res {
+            let msg = format!("cannot {} {}: {}", what, &chunk_name[1..], strerror(&err));
+            self.push_string(msg);
+            return LUA_ERRFILE;
+        }
+        if data.first() == Some(&b'#') {
+            // skip the Unix exec. file comment, but keep its newline for the line numbers
+            let end = data.iter().position(|&b| b == b'\n').unwrap_or(data.len());
+            data.drain(..end);
+        }
+        self.load(data, &chunk_name, mode)
+    }
+
+    /// Push the traceback of the stack from `level` after the line of `msg`,
+    /// where the middle of a deep stack is elided
+    fn traceback(&mut self, msg: Option<&str>, level: usize) -> LuaResult<()> {
+        let mut buf = msg.map_or(String::new(), |msg| format!("{}\n", msg));
+        buf.push_str("stack traceback:");
+        let last = (level..).take_while(|&l| self.get_stack(l).is_some()).last().unwrap_or(level);
+        let mut n1 = if last.saturating_sub(level) > LEVELS1 + LEVELS2 { LEVELS1 as isize } else { -1 };
+        let mut level = level;
+        while let Some(mut ar) = self.get_stack(level) {
+            level += 1;
+            if n1 == 0 {
+                // too many levels, skip to the last ones
+                buf.push_str("\n\t...");
+                level = last - LEVELS2 + 1;
+            } else {
+                self.get_info("Slnt", &mut ar);
+                buf.push_str(&format!("\n\t{}:", ar.short_src));
+                if ar.current_line > 0 {
+                    buf.push_str(&format!("{}:", ar.current_line));
+                }
+                buf.push_str(" in ");
+                buf.push_str(&func_name(self, &mut ar)?);
+                if ar.is_tail_call {
+                    buf.push_str("\n\t(...tail calls...)");
+                }
+            }
+            n1 -= 1;
+        }
+        self.push_string(buf);
+        Ok(())
+    }
+
+    /// Push a new table with the functions of a library
+    fn new_lib(&mut self, l: &[(&str, RustFn)]) -> LuaResult<()> {
+        self.create_table(0, l.len());
+        for (name, f) in l {
+            self.push_rust_function(*f);
+            self.set_field(-2, name)?;
+        }
+        Ok(())
+    }
+
+    /// Push the table t[fname] where t is at `idx`, and return whether it exists
+    fn get_subtable(&mut self, idx: isize, fname: &str) -> LuaResult<bool> {
+        if self.get_field(idx, fname)? == LUA_TTABLE {
+            return Ok(true);
+        }
+        self.pop(1);
+        let idx = self.abs_index(idx);
+        self.new_table();
+        self.push_value(-1);
+        self.set_field(idx, fname)?;
+        Ok(false)
+    }
+
+    /// Call `open_f` to open the module unless it is in `_LOADED`,
+    /// and push the module which is also set as a global if `glb`
+    fn require_f(&mut self, modname: &str, open_f: RustFn, glb: bool) -> LuaResult<()> {
+        self.get_subtable(LUA_REGISTRYINDEX, LUA_LOADED_TABLE)?;
+        self.get_field(-1, modname)?;
+        if !self.to_boolean(-1) {
+            self.pop(1);
+            self.push_rust_function(open_f);
+            self.push_string(modname.to_string());
+            self.call(1, 1)?;
+            self.push_value(-1);
+            self.set_field(-3, modname)?;
+        }
+        self.remove(-2)?;
+        if glb {
+            self.push_value(-1);
+            self.set_global(modname)?;
+        }
+        Ok(())
+    }
+
+    /// Open all standard libraries
+    fn open_libs(&mut self) -> LuaResult<()> {
+        let libs: &[(&str, RustFn)] = &[
+            ("_G", stdlib::base::open_base),
+            ("package", stdlib::package::open_package),
+            ("coroutine", stdlib::coroutine::open_coroutine),
+            ("string", stdlib::string::open_string),
+            ("table", stdlib::table::open_table),
+            ("math", stdlib::math::open_math),
+            ("os", stdlib::os::open_os),
+            ("io", stdlib::io::open_io),
+            ("utf8", stdlib::utf8::open_utf8),
+            ("debug", stdlib::debug::open_debug),
+        ];
+        for (name, open_f) in libs {
+            self.require_f(name, *open_f, true)?;
+            self.pop(1);
+        }
+        Ok(())
+    }
+}
+
+/// The size of the first part of a traceback
+const LEVELS1: usize = 10;
+/// The size of the second part of a traceback
+const LEVELS2: usize = 11;
+
+/// A printable name of the function of an activation record filled by "Sn"
+fn func_name(ls: &mut LuaState, ar: &mut LuaDebug) -> LuaResult<String> {
+    if let Some(name) = global_func_name(ls, ar)? {
+        Ok(format!("function '{}'", name))
+    } else if !ar.namewhat.is_empty() {
+        // a name from code
+        Ok(format!("{} '{}'", ar.namewhat, ar.name.as_deref().unwrap_or("?")))
+    } else if ar.what == "main" {
+        Ok("main chunk".to_string())
+    } else if ar.what != "C" {
+        // a Lua function is known by its position
+        Ok(format!("function <{}:{}>", ar.short_src, ar.line_defined))
+    } else {
+        Ok("?".to_string())
+    }
+}
+
+/// The name of the function of an activation record as a field of a loaded module,
+/// where the functions of the base library have no prefix
+fn global_func_name(ls: &mut LuaState, ar: &mut LuaDebug) -> LuaResult<Option<String>> {
+    let top = ls.get_top();
+    ls.get_info("f", ar); // push function
+    ls.get_field(LUA_REGISTRYINDEX, LUA_LOADED_TABLE)?;
+    let name = find_field(ls, top + 1, 2)?;
+    ls.set_top(top)?;
+    Ok(name.map(|name| match name.strip_prefix("_G.") {
+        Some(name) => name.to_string(),
+        None => name,
+    }))
+}
+
+/// Search the table on the top of stack for the value at `obj_idx` by string keys,
+/// also in the tables it contains up to `level` deep, and return the dotted name
+fn find_field(ls: &mut LuaState, obj_idx: isize, level: usize) -> LuaResult<Option<String>> {
+    if level == 0 || !ls.is_table(-1) {
+        return Ok(None);
+    }
+    ls.push_nil(); // start 'next' loop
+    while ls.next(-2)? {
+        if ls.type_id(-2) == LUA_TSTRING {
+            let name = if ls.raw_equal(obj_idx, -1) {
+                Some(ls.to_string(-2))
+            } else {
+                find_field(ls, obj_idx, level - 1)?.map(|name| format!("{}.{}", ls.to_string(-2), name))
+            };
+            if name.is_some() {
+                ls.pop(2); // remove value and key
+                return Ok(name);
+            }
+        }
+        ls.pop(1); // remove value
+    }
+    Ok(None)
+}
+
+/// The message of an error without the " (os error N)" suffix, like C `strerror`
+fn strerror(err: &io::Error) -> String {
+    let msg = err.to_string();
+    match err.raw_os_error() {
+        Some(code) => msg.trim_end_matches(&format!(" (os error {})", code)).to_string(),
+        None => msg,
+    }
+}
+
+#[cfg(unix)]
+fn exit_signal(status: &ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().unwrap_or(0)
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: &ExitStatus) -> i32 {
+    0
+}
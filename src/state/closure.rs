@@ -0,0 +1,78 @@
+use std::cell::RefCell;
+use std::mem::size_of;
+use std::rc::Rc;
+
+use crate::api::RustClosure;
+use crate::binary::chunk::Prototype;
+use crate::state::lua_value::LuaValue;
+
+/// Lua Closure, or a Rust closure with up values
+pub struct Closure {
+    pub proto: Option<Rc<Prototype>>,
+    pub rust_fn: Option<RustClosure>,
+    /// Up values, which can be replaced by `LuaAPI::upvalue_join`
+    pub upvals: RefCell<Vec<UpValRef>>,
+}
+
+impl Closure {
+    /// Create a Lua closure whose up values are all closed nil
+    pub fn new(proto: Rc<Prototype>) -> Closure {
+        let upvals = RefCell::new((0..proto.up_values.len()).map(|_| new_upval(LuaValue::Nil)).collect());
+        Closure {
+            proto: Some(proto),
+            rust_fn: None,
+            upvals,
+        }
+    }
+
+    /// Create a Rust closure owning the values of its up values
+    pub fn new_rust(f: RustClosure, upvals: Vec<LuaValue>) -> Closure {
+        Closure {
+            proto: None,
+            rust_fn: Some(f),
+            upvals: RefCell::new(upvals.into_iter().map(new_upval).collect()),
+        }
+    }
+
+    /// Estimated bytes used by the closure
+    pub fn size_estimate(&self) -> usize {
+        size_of::<Closure>() + self.upvals.borrow().len() * size_of::<UpVal>()
+    }
+}
+
+pub type UpValRef = Rc<RefCell<UpVal>>;
+
+/// Up value shared by closures, which refers to a register of a running function
+/// until it is closed and owns the value
+pub enum UpVal {
+    Open(Rc<RefCell<Vec<LuaValue>>>, usize),
+    Closed(LuaValue),
+}
+
+impl UpVal {
+    pub fn get(&self) -> LuaValue {
+        match self {
+            UpVal::Open(regs, idx) => regs.borrow()[*idx].clone(),
+            UpVal::Closed(val) => val.clone(),
+        }
+    }
+
+    pub fn set(&mut self, val: LuaValue) {
+        match self {
+            UpVal::Open(regs, idx) => regs.borrow_mut()[*idx] = val,
+            UpVal::Closed(v) => *v = val,
+        }
+    }
+
+    /// Move the value out of the register
+    pub fn close(&mut self) {
+        if let UpVal::Open(..) = self {
+            *self = UpVal::Closed(self.get());
+        }
+    }
+}
+
+#[inline]
+fn new_upval(val: LuaValue) -> UpValRef {
+    Rc::new(RefCell::new(UpVal::Closed(val)))
+}
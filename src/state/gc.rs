@@ -0,0 +1,494 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::{Rc, Weak};
+
+use crate::state::closure::{Closure, UpVal, UpValRef};
+use crate::state::lua_table::LuaTable;
+use crate::state::lua_thread::LuaThread;
+use crate::state::lua_userdata::LuaUserData;
+use crate::state::lua_value::LuaValue;
+
+/// The amount of work of an incremental step, in bytes
+const GC_STEP_SIZE: usize = 8 * 1024;
+
+/// Heap object tracked by the collector
+enum GcObject {
+    Table(Weak<RefCell<LuaTable>>),
+    Closure(Weak<Closure>),
+    Thread(Weak<RefCell<LuaThread>>),
+    UserData(Weak<RefCell<LuaUserData>>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Phase {
+    /// Waiting for the next cycle
+    Pause,
+    /// Marking the reachable objects
+    Propagate,
+}
+
+/// Incremental mark and sweep collector.
+///
+/// Values are reference counted, so most objects are freed as soon as they are unused.
+/// The collector finds the unreachable ones left by reference cycles and clears
+/// the references inside them, which breaks the cycles.
+pub struct Gc {
+    objects: Vec<GcObject>,
+    /// Addresses of the objects marked in this cycle
+    marked: HashSet<usize>,
+    /// Marked objects whose references are not traversed yet
+    gray: Vec<LuaValue>,
+    /// Traversed objects and up values, which are kept alive until the sweep,
+    /// so their addresses are not reused during the cycle
+    black: Vec<LuaValue>,
+    black_upvals: Vec<UpValRef>,
+    /// Marked tables modified during marking, which are traversed again by the atomic step
+    gray_again: Vec<LuaValue>,
+    /// Addresses of the tables in `gray_again`
+    touched: HashSet<usize>,
+    /// Traversed weak tables with their modes (weak keys, weak values)
+    weak: Vec<(Rc<RefCell<LuaTable>>, bool, bool)>,
+    /// Weak-keyed tables with values not marked yet since their keys are not marked
+    ephemerons: Vec<Rc<RefCell<LuaTable>>>,
+    /// Objects with finalizers, in the order they were marked for finalization
+    finobj: Vec<LuaValue>,
+    /// Addresses of the objects in `finobj`
+    finobj_addrs: HashSet<usize>,
+    /// Unreachable objects whose finalizers are waiting to be called
+    tobefnz: Vec<LuaValue>,
+    phase: Phase,
+    pub running: bool,
+    /// Estimated bytes of the objects in use
+    total_bytes: usize,
+    /// A new cycle starts when `total_bytes` reaches it
+    threshold: usize,
+    /// Bytes allocated and not paid by the collector yet
+    debt: isize,
+    /// The limit of `total_bytes` checked by the state, which raises a memory error
+    pub limit: usize,
+    /// `total_bytes` left by a collection failing to free enough memory,
+    /// only more allocation raises the memory error again until the next cycle
+    pub(crate) over_bytes: usize,
+    /// How long to wait between cycles, in percentage of the memory in use
+    pub pause: usize,
+    /// The speed of the collector relative to allocation, in percentage
+    pub stepmul: usize,
+}
+
+impl Default for Gc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gc {
+    pub fn new() -> Gc {
+        Gc {
+            objects: Vec::new(),
+            marked: HashSet::new(),
+            gray: Vec::new(),
+            black: Vec::new(),
+            black_upvals: Vec::new(),
+            gray_again: Vec::new(),
+            touched: HashSet::new(),
+            weak: Vec::new(),
+            ephemerons: Vec::new(),
+            finobj: Vec::new(),
+            finobj_addrs: HashSet::new(),
+            tobefnz: Vec::new(),
+            phase: Phase::Pause,
+            running: true,
+            total_bytes: 0,
+            threshold: GC_STEP_SIZE,
+            debt: -(GC_STEP_SIZE as isize),
+            limit: usize::MAX,
+            over_bytes: 0,
+            pause: 200,
+            stepmul: 200,
+        }
+    }
+
+    /// Estimated bytes of the objects in use
+    #[inline]
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    /// Whether the estimated bytes in use exceed the limit
+    #[inline]
+    pub fn over_limit(&self, extra: usize) -> bool {
+        self.total_bytes.saturating_add(extra) > self.limit.max(self.over_bytes)
+    }
+
+    #[inline]
+    pub fn is_marking(&self) -> bool {
+        self.phase == Phase::Propagate
+    }
+
+    /// Whether a step should be performed at the next safe point
+    #[inline]
+    pub fn should_step(&self) -> bool {
+        self.running && self.debt > 0
+    }
+
+    /// Account for `n` bytes allocated
+    #[inline]
+    pub fn add_debt(&mut self, n: usize) {
+        self.total_bytes += n;
+        self.debt += n as isize;
+    }
+
+    pub fn track_table(&mut self, t: &Rc<RefCell<LuaTable>>) {
+        self.objects.push(GcObject::Table(Rc::downgrade(t)));
+        self.add_debt(t.borrow().size_estimate());
+    }
+
+    pub fn track_closure(&mut self, c: &Rc<Closure>) {
+        self.objects.push(GcObject::Closure(Rc::downgrade(c)));
+        self.add_debt(c.size_estimate());
+    }
+
+    pub fn track_thread(&mut self, t: &Rc<RefCell<LuaThread>>) {
+        self.objects.push(GcObject::Thread(Rc::downgrade(t)));
+        self.add_debt(t.borrow().size_estimate());
+    }
+
+    pub fn track_userdata(&mut self, u: &Rc<RefCell<LuaUserData>>) {
+        self.objects.push(GcObject::UserData(Rc::downgrade(u)));
+        self.add_debt(u.borrow().size_estimate());
+    }
+
+    /// Mark a table or userdata for finalization when its metatable with `__gc` is set
+    pub fn check_finalizer(&mut self, obj: &LuaValue) {
+        if let Some(addr) = object_addr(obj) {
+            if self.finobj_addrs.insert(addr) {
+                self.finobj.push(obj.clone());
+            }
+        }
+    }
+
+    /// Take the next object to finalize, the last marked one is the first
+    pub fn next_finalizer(&mut self) -> Option<LuaValue> {
+        self.tobefnz.pop()
+    }
+
+    /// Finalize all objects, which is used when the state is closed
+    pub fn separate_all(&mut self) {
+        let finobj = std::mem::take(&mut self.finobj);
+        self.finobj_addrs.clear();
+        self.tobefnz.extend(finobj);
+    }
+
+    /// Mark a value as reachable, the references inside it are traversed later
+    pub fn mark_value(&mut self, val: &LuaValue) {
+        if let Some(addr) = object_addr(val) {
+            if self.marked.insert(addr) {
+                self.gray.push(val.clone());
+            }
+        }
+    }
+
+    /// Mark an up value and its value, it is kept alive even if its closures are not
+    fn mark_upval(&mut self, uv: &UpValRef) {
+        if self.marked.insert(Rc::as_ptr(uv) as usize) {
+            let val = uv.borrow().get();
+            self.mark_value(&val);
+            self.black_upvals.push(uv.clone());
+        }
+    }
+
+    /// Barrier for a table modified during marking, which is traversed again
+    /// by the atomic step if it has been marked
+    pub fn barrier_table(&mut self, t: &Rc<RefCell<LuaTable>>) {
+        let addr = Rc::as_ptr(t) as usize;
+        if self.is_marking() && self.marked.contains(&addr) && self.touched.insert(addr) {
+            self.gray_again.push(LuaValue::Table(t.clone()));
+        }
+    }
+
+    /// Barrier for a value stored into an up value during marking
+    #[inline]
+    pub fn barrier_value(&mut self, val: &LuaValue) {
+        if self.is_marking() {
+            self.mark_value(val);
+        }
+    }
+
+    /// Barrier for an up value to be closed, whose value is moved out of the stack
+    pub fn barrier_upval(&mut self, uv: &UpValRef) {
+        if self.is_marking() && self.marked.contains(&(Rc::as_ptr(uv) as usize)) {
+            let val = uv.borrow().get();
+            self.mark_value(&val);
+        }
+    }
+
+    /// Barrier for an up value joined to a closure which may have been marked
+    pub fn barrier_join(&mut self, uv: &UpValRef) {
+        if self.is_marking() {
+            self.mark_upval(uv);
+        }
+    }
+
+    /// Start a new cycle, the roots should be marked after it
+    pub fn start_cycle(&mut self) {
+        self.phase = Phase::Propagate;
+        self.marked.clear();
+        self.gray.clear();
+        self.black.clear();
+        self.black_upvals.clear();
+        self.gray_again.clear();
+        self.touched.clear();
+        self.weak.clear();
+        self.ephemerons.clear();
+    }
+
+    /// Traverse gray objects for about `work` bytes, and return whether there is no gray object left
+    pub fn propagate(&mut self, work: usize) -> bool {
+        let mut done = 0;
+        while done < work {
+            match self.gray.pop() {
+                Some(val) => {
+                    done += self.traverse(&val);
+                    self.black.push(val);
+                }
+                None => return true,
+            }
+        }
+        self.gray.is_empty()
+    }
+
+    fn traverse(&mut self, val: &LuaValue) -> usize {
+        match val {
+            LuaValue::Table(rc) => {
+                let t = rc.borrow();
+                let (weak_k, weak_v) = weak_mode(&t);
+                if weak_k || weak_v {
+                    self.weak.push((rc.clone(), weak_k, weak_v));
+                }
+                if let Some(ref mt) = t.metatable {
+                    self.mark_value(&LuaValue::Table(mt.clone()));
+                }
+                if !weak_v {
+                    for v in t.array_part() {
+                        self.mark_value(v);
+                    }
+                }
+                let mut pending = false;
+                for (k, v) in t.hash_part() {
+                    if !weak_k {
+                        self.mark_value(k);
+                    }
+                    if weak_v {
+                        continue;
+                    }
+                    // an ephemeron value is reachable only if its key is
+                    if weak_k && self.is_dead(k) {
+                        pending = true;
+                    } else {
+                        self.mark_value(v);
+                    }
+                }
+                if pending {
+                    self.ephemerons.push(rc.clone());
+                }
+                t.size_estimate()
+            }
+            LuaValue::Function(c) => {
+                for uv in c.upvals.borrow().iter() {
+                    self.mark_upval(uv);
+                }
+                c.size_estimate()
+            }
+            LuaValue::Thread(t) => {
+                let t = t.borrow();
+                for frame in t.frames.iter() {
+                    frame.mark(self);
+                }
+                t.size_estimate()
+            }
+            LuaValue::UserData(u) => {
+                let u = u.borrow();
+                if let Some(ref mt) = u.metatable {
+                    self.mark_value(&LuaValue::Table(mt.clone()));
+                }
+                u.size_estimate()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Whether the value is a collectable object not marked
+    fn is_dead(&self, val: &LuaValue) -> bool {
+        object_addr(val).is_some_and(|addr| !self.marked.contains(&addr))
+    }
+
+    /// Mark the values of ephemerons whose keys are marked, until nothing more is marked
+    fn converge_ephemerons(&mut self) {
+        loop {
+            let n = self.marked.len();
+            for t in std::mem::take(&mut self.ephemerons) {
+                self.traverse(&LuaValue::Table(t));
+            }
+            self.propagate(usize::MAX);
+            if self.marked.len() == n {
+                break;
+            }
+        }
+    }
+
+    /// Remove the entries of weak tables whose keys (or values) are dead
+    fn clear_weak(&mut self, from: usize, keys: bool) {
+        for (t, weak_k, weak_v) in self.weak[from..].iter() {
+            if (keys && *weak_k) || (!keys && *weak_v) {
+                t.borrow_mut().retain(|k, v| !self.is_dead(if keys { k } else { v }));
+            }
+        }
+    }
+
+    /// Mark all reachable objects after the roots are marked again,
+    /// and resurrect the unreachable objects with finalizers
+    pub fn atomic(&mut self) {
+        // the stacks of threads are changed without barriers
+        let threads: Vec<LuaValue> = self.black.iter().filter(|v| matches!(v, LuaValue::Thread(_))).cloned().collect();
+        for t in threads.iter() {
+            self.traverse(t);
+        }
+        for t in std::mem::take(&mut self.gray_again) {
+            self.traverse(&t);
+        }
+        self.propagate(usize::MAX);
+        self.converge_ephemerons();
+        // the objects to be finalized are removed from weak values before resurrection
+        let n = self.weak.len();
+        self.clear_weak(0, false);
+
+        let (dead, live) = std::mem::take(&mut self.finobj)
+            .into_iter()
+            .partition(|obj| self.is_dead(obj));
+        self.finobj = live;
+        for obj in dead {
+            if let Some(addr) = object_addr(&obj) {
+                self.finobj_addrs.remove(&addr);
+            }
+            self.mark_value(&obj);
+            self.tobefnz.push(obj);
+        }
+        self.propagate(usize::MAX);
+        self.converge_ephemerons();
+
+        // but kept in weak keys until they are finalized
+        self.clear_weak(0, true);
+        self.clear_weak(n, false);
+    }
+
+    /// Finish the cycle after all reachable objects are marked,
+    /// the unreachable objects are cleared and forgotten.
+    /// `roots` is the estimated bytes of the roots which are not tracked objects.
+    pub fn sweep(&mut self, roots: usize) {
+        let marked = &self.marked;
+        let mut total = roots;
+        self.objects.retain(|obj| match obj {
+            GcObject::Table(t) => match t.upgrade() {
+                Some(t) if marked.contains(&(Rc::as_ptr(&t) as usize)) => {
+                    total += t.borrow().size_estimate();
+                    true
+                }
+                Some(t) => {
+                    t.borrow_mut().clear();
+                    false
+                }
+                None => false,
+            },
+            GcObject::Closure(c) => match c.upgrade() {
+                Some(c) if marked.contains(&(Rc::as_ptr(&c) as usize)) => {
+                    total += c.size_estimate();
+                    true
+                }
+                Some(c) => {
+                    for uv in c.upvals.borrow().iter() {
+                        // open up values are still referred by their frames
+                        if !marked.contains(&(Rc::as_ptr(uv) as usize)) {
+                            let mut uv = uv.borrow_mut();
+                            if let UpVal::Closed(_) = *uv {
+                                *uv = UpVal::Closed(LuaValue::Nil);
+                            }
+                        }
+                    }
+                    false
+                }
+                None => false,
+            },
+            GcObject::Thread(t) => match t.upgrade() {
+                Some(t) if marked.contains(&(Rc::as_ptr(&t) as usize)) => {
+                    total += t.borrow().size_estimate();
+                    true
+                }
+                Some(t) => {
+                    for frame in t.borrow_mut().frames.drain(..) {
+                        frame.clear();
+                    }
+                    false
+                }
+                None => false,
+            },
+            GcObject::UserData(u) => match u.upgrade() {
+                Some(u) if marked.contains(&(Rc::as_ptr(&u) as usize)) => {
+                    total += u.borrow().size_estimate();
+                    true
+                }
+                Some(u) => {
+                    u.borrow_mut().clear();
+                    false
+                }
+                None => false,
+            },
+        });
+
+        self.marked.clear();
+        self.black.clear();
+        self.black_upvals.clear();
+        self.gray_again.clear();
+        self.touched.clear();
+        self.weak.clear();
+        self.ephemerons.clear();
+        self.phase = Phase::Pause;
+        self.total_bytes = total;
+        self.over_bytes = 0;
+        self.threshold = (total / 100 * self.pause).max(GC_STEP_SIZE);
+        self.debt = total as isize - self.threshold as isize;
+    }
+
+    /// Request a step of at least `n` bytes at the next safe point
+    pub fn request_step(&mut self, n: usize) {
+        self.debt = self.debt.max(0) + n as isize;
+    }
+
+    /// The amount of work of a step
+    pub fn step_work(&mut self) -> usize {
+        let work = (self.debt.max(0) as usize).max(GC_STEP_SIZE) / 100 * self.stepmul;
+        self.debt = -(GC_STEP_SIZE as isize);
+        work
+    }
+}
+
+/// The address of a collectable object
+fn object_addr(val: &LuaValue) -> Option<usize> {
+    match val {
+        LuaValue::Table(t) => Some(Rc::as_ptr(t) as usize),
+        LuaValue::Function(c) => Some(Rc::as_ptr(c) as usize),
+        LuaValue::Thread(t) => Some(Rc::as_ptr(t) as usize),
+        LuaValue::UserData(u) => Some(Rc::as_ptr(u) as usize),
+        _ => None,
+    }
+}
+
+/// The weakness of keys and values of a table given by `__mode` of its metatable
+fn weak_mode(t: &LuaTable) -> (bool, bool) {
+    match t.metatable {
+        Some(ref mt) => match mt.borrow().get(&LuaValue::new_string(b"__mode".to_vec())) {
+            LuaValue::String(mode) => (mode.contains(&b'k'), mode.contains(&b'v')),
+            _ => (false, false),
+        },
+        None => (false, false),
+    }
+}
@@ -0,0 +1,242 @@
+use std::rc::Rc;
+
+use crate::binary::chunk::{Constant, Prototype};
+use crate::state::lua_error::LuaResult;
+use crate::state::lua_state::LuaState;
+use crate::vm::instruction::Instruction;
+use crate::vm::opcode::*;
+
+/// Hook called by the interpreter with the state and the activation record of the event,
+/// an error returned by it is raised in the hooked function
+pub type LuaHook = Rc<dyn Fn(&mut LuaState, &mut LuaDebug) -> LuaResult<()>>;
+
+/// The hook of a thread with its event mask and instruction count
+#[derive(Clone)]
+pub(crate) struct HookState {
+    pub hook: LuaHook,
+    pub mask: i32,
+    pub base_count: i32,
+    /// Instructions to execute before the next count event
+    pub count: i32,
+}
+
+/// Information about a function or an activation record,
+/// filled by `LuaAPI::get_stack` and the options of `LuaAPI::get_info`
+#[derive(Clone, Debug, Default)]
+pub struct LuaDebug {
+    /// The hook event, `LUA_HOOKCALL` and so on
+    pub event: i32,
+    /// 'n': a reasonable name of the function
+    pub name: Option<String>,
+    /// 'n': "global", "local", "method", "field", "upvalue", "constant", "for iterator" or ""
+    pub namewhat: &'static str,
+    /// 'S': "Lua", "main" or "C" for a Rust function
+    pub what: &'static str,
+    /// 'S': the chunk name where the function was defined
+    pub source: String,
+    /// 'S': the printable form of `source`
+    pub short_src: String,
+    /// 'l': the line being executed, -1 if it is unknown
+    pub current_line: i32,
+    /// 'S': the lines where the function starts and ends, -1 for a Rust function
+    pub line_defined: i32,
+    pub last_line_defined: i32,
+    /// 'u': the number of up values
+    pub nups: usize,
+    /// 'u': the number of fixed parameters, 0 for a Rust function
+    pub nparams: usize,
+    /// 'u': whether it is a vararg function, always true for a Rust function
+    pub is_vararg: bool,
+    /// 't': whether it was called by a tail call
+    pub is_tail_call: bool,
+    /// The index of the frame of the active function
+    pub(crate) frame: usize,
+}
+
+/// The index of the instruction being executed by a frame whose pc is `pc`
+#[inline]
+pub(crate) fn current_pc(pc: isize) -> usize {
+    (pc - 1).max(0) as usize
+}
+
+/// The line of the instruction at `pc`, -1 if there is no line information
+pub(crate) fn func_line(proto: &Prototype, pc: usize) -> i32 {
+    proto.line_info.get(pc).map_or(-1, |&line| line as i32)
+}
+
+/// The name of the `n`-th local variable active at `pc`, like `luaF_getlocalname`
+pub(crate) fn local_name(proto: &Prototype, mut n: isize, pc: usize) -> Option<&str> {
+    for var in proto.local_vars.iter().take_while(|var| var.start_pc as usize <= pc) {
+        if pc < var.end_pc as usize {
+            // is variable active?
+            n -= 1;
+            if n == 0 {
+                return Some(&var.var_name);
+            }
+        }
+    }
+    None // not found
+}
+
+/// The name and its kind of the function called by the instruction at `pc`
+pub(crate) fn func_name_from_code(proto: &Prototype, pc: usize) -> Option<(&'static str, String)> {
+    let i = *proto.code.get(pc)?;
+    match i.opcode() {
+        OP_CALL | OP_TAILCALL => obj_name(proto, pc, i.abc().0), // get function name
+        OP_TFORCALL => Some(("for iterator", "for iterator".to_string())),
+        _ => None,
+    }
+}
+
+/// The name and its kind of the value in register `reg` at `lastpc`, found by symbolic execution
+fn obj_name(proto: &Prototype, lastpc: usize, reg: isize) -> Option<(&'static str, String)> {
+    if let Some(name) = local_name(proto, reg + 1, lastpc) {
+        // is a local?
+        return Some(("local", name.to_string()));
+    }
+    // else try symbolic execution
+    let pc = find_set_reg(proto, lastpc, reg)?;
+    let i = proto.code[pc];
+    match i.opcode() {
+        OP_MOVE => {
+            let (a, b, _) = i.abc(); // move from 'b' to 'a'
+            if b < a {
+                return obj_name(proto, pc, b); // get name for 'b'
+            }
+            None
+        }
+        op @ (OP_GETTABUP | OP_GETTABLE) => {
+            let (_, t, k) = i.abc(); // table and key indices
+            // name of indexed variable
+            let vn = if op == OP_GETTABLE {
+                local_name(proto, t + 1, pc)
+            } else {
+                proto.up_value_names.get(t as usize).map(|s| s.as_str())
+            };
+            let name = key_name(proto, pc, k);
+            let what = if vn == Some("_ENV") { "global" } else { "field" };
+            Some((what, name))
+        }
+        OP_GETUPVAL => {
+            let (_, b, _) = i.abc();
+            let name = proto.up_value_names.get(b as usize).map_or("?", |s| s.as_str());
+            Some(("upvalue", name.to_string()))
+        }
+        op @ (OP_LOADK | OP_LOADKX) => {
+            let b = if op == OP_LOADK { i.a_bx().1 } else { proto.code.get(pc + 1)?.ax() };
+            match proto.constants.get(b as usize) {
+                Some(Constant::String(s)) => Some(("constant", String::from_utf8_lossy(s).into_owned())),
+                _ => None,
+            }
+        }
+        OP_SELF => {
+            let (_, _, k) = i.abc(); // key index
+            Some(("method", key_name(proto, pc, k)))
+        }
+        _ => None, // could not find reasonable name
+    }
+}
+
+/// The name of the key RK(C) at `pc`, "?" if it is not a string constant
+fn key_name(proto: &Prototype, pc: usize, c: isize) -> String {
+    if c > 0xFF {
+        // is 'c' a constant?
+        if let Some(Constant::String(s)) = proto.constants.get((c & 0xFF) as usize) {
+            return String::from_utf8_lossy(s).into_owned(); // literal constant is its own name
+        }
+    } else if let Some(("constant", name)) = obj_name(proto, pc, c) {
+        // 'c' is a register holding a constant
+        return name;
+    }
+    "?".to_string() // no reasonable name found
+}
+
+/// Whether the instruction sets register A
+fn sets_a(op: u8) -> bool {
+    !matches!(
+        op,
+        OP_SETTABUP
+            | OP_SETUPVAL
+            | OP_SETTABLE
+            | OP_JMP
+            | OP_EQ
+            | OP_LT
+            | OP_LE
+            | OP_TEST
+            | OP_TAILCALL
+            | OP_RETURN
+            | OP_TFORCALL
+            | OP_SETLIST
+            | OP_EXTRAARG
+    )
+}
+
+/// The last instruction before `lastpc` that modified register `reg`,
+/// None if it is unknown because the code is conditional
+fn find_set_reg(proto: &Prototype, lastpc: usize, reg: isize) -> Option<usize> {
+    let mut setreg = None; // keep last instruction that changed 'reg'
+    let mut jmptarget = 0; // any code before this address is conditional
+    // the current position sets that register unless the code is inside a jump
+    let filter = |pc: usize, jmptarget: usize| if pc < jmptarget { None } else { Some(pc) };
+    for (pc, &i) in proto.code.iter().enumerate().take(lastpc) {
+        let op = i.opcode();
+        let (a, b, _) = i.abc();
+        match op {
+            OP_LOADNIL => {
+                if a <= reg && reg <= a + b {
+                    // set registers from 'a' to 'a+b'
+                    setreg = filter(pc, jmptarget);
+                }
+            }
+            OP_TFORCALL => {
+                if reg >= a + 2 {
+                    // affect all regs above its base
+                    setreg = filter(pc, jmptarget);
+                }
+            }
+            OP_CALL | OP_TAILCALL => {
+                if reg >= a {
+                    // affect all registers above base
+                    setreg = filter(pc, jmptarget);
+                }
+            }
+            OP_JMP => {
+                let dest = pc as isize + 1 + i.a_sbx().1;
+                // jump is forward and do not skip 'lastpc'?
+                if (pc as isize) < dest && dest <= lastpc as isize && dest as usize > jmptarget {
+                    jmptarget = dest as usize; // update 'jmptarget'
+                }
+            }
+            _ => {
+                if sets_a(op) && reg == a {
+                    // any instruction that set A
+                    setreg = filter(pc, jmptarget);
+                }
+            }
+        }
+    }
+    setreg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler;
+
+    #[test]
+    fn test_names() {
+        let src = "local a = 1\nlocal b = g(a)\nlocal t = {}\nt.f(b)\nt:m()\nb()\nreturn x.y.z()";
+        let proto = compiler::compile(src.as_bytes().to_vec(), "=test".to_string()).unwrap();
+        assert_eq!(local_name(&proto, 1, 0), None);
+        assert_eq!(local_name(&proto, 1, 1), Some("a"));
+        assert_eq!(local_name(&proto, 2, 5), Some("b"));
+        let calls: Vec<_> = (0..proto.code.len())
+            .filter(|&pc| matches!(proto.code[pc].opcode(), OP_CALL | OP_TAILCALL))
+            .filter_map(|pc| func_name_from_code(&proto, pc))
+            .map(|(what, name)| format!("{} {}", what, name))
+            .collect();
+        assert_eq!(calls, ["global g", "field f", "method m", "local b", "field z"]);
+        assert_eq!(func_line(&proto, 0), 1);
+        assert_eq!(func_line(&proto, 1000), -1);
+    }
+}
@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::api::consts::LUA_ERRRUN;
+use crate::state::lua_value::{type_name, LuaValue};
+
+pub type LuaResult<T> = Result<T, LuaError>;
+
+/// Runtime error raised by the VM, `error` or a Rust function,
+/// which is propagated until it is caught by a protected call
+#[derive(Clone)]
+pub struct LuaError {
+    /// `LUA_ERRRUN`, `LUA_ERRMEM`, `LUA_ERRGCMM` or `LUA_ERRERR`
+    pub status: i8,
+    /// The error object, any Lua value
+    pub value: LuaValue,
+    /// "chunkname:currentline" of the Lua function raising the error,
+    /// which is also the prefix of the message
+    pub position: Option<String>,
+}
+
+impl LuaError {
+    /// Error with an arbitrary error object
+    pub fn new(value: LuaValue) -> LuaError {
+        LuaError {
+            status: LUA_ERRRUN,
+            value,
+            position: None,
+        }
+    }
+
+    /// Error with a message, prefixed with the position if any
+    pub fn runtime(msg: &str, position: Option<String>) -> LuaError {
+        let msg = match position {
+            Some(ref pos) => format!("{}: {}", pos, msg),
+            None => msg.to_string(),
+        };
+        LuaError {
+            status: LUA_ERRRUN,
+            value: LuaValue::new_string(msg.into_bytes()),
+            position,
+        }
+    }
+
+    /// Error of the given status with a message
+    pub fn with_status(status: i8, msg: &str) -> LuaError {
+        LuaError {
+            status,
+            value: LuaValue::new_string(msg.into()),
+            position: None,
+        }
+    }
+}
+
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            LuaValue::String(ref s) => write!(f, "{}", String::from_utf8_lossy(s)),
+            LuaValue::Integer(i) => write!(f, "{}", i),
+            LuaValue::Number(n) => write!(f, "{}", n),
+            ref v => write!(f, "(error object is a {} value)", type_name(v.type_id())),
+        }
+    }
+}
+
+impl fmt::Debug for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LuaError({}, {})", self.status, self)
+    }
+}
+
+impl Error for LuaError {}
+
+/// Max length of the chunk id in error messages
+const LUA_IDSIZE: usize = 60;
+
+/// The printable form of a chunk name, like `luaO_chunkid`:
+/// "=stdin" is "stdin", "@file.lua" is "file.lua" and other sources are `[string "source"]`
+pub fn chunk_id(source: &str) -> String {
+    if let Some(name) = source.strip_prefix('=') {
+        truncate(name, LUA_IDSIZE - 1).to_string()
+    } else if let Some(name) = source.strip_prefix('@') {
+        if name.len() < LUA_IDSIZE {
+            name.to_string()
+        } else {
+            // keep the end of a long file name
+            let mut start = name.len() - (LUA_IDSIZE - 4);
+            while !name.is_char_boundary(start) {
+                start += 1;
+            }
+            format!("...{}", &name[start..])
+        }
+    } else {
+        let max = LUA_IDSIZE - "[string \"...\"]".len() - 1;
+        let line = source.split('\n').next().unwrap_or_default();
+        if line.len() == source.len() && source.len() < max {
+            format!("[string \"{}\"]", source)
+        } else {
+            format!("[string \"{}...\"]", truncate(line, max))
+        }
+    }
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = max.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_id() {
+        assert_eq!(chunk_id("=stdin"), "stdin");
+        assert_eq!(chunk_id("@test.lua"), "test.lua");
+        assert_eq!(chunk_id("return 1"), "[string \"return 1\"]");
+        assert_eq!(chunk_id("x = 1\nreturn x"), "[string \"x = 1...\"]");
+        let long = format!("@{}.lua", "d/".repeat(40));
+        assert_eq!(chunk_id(&long).len(), LUA_IDSIZE - 1);
+        assert!(chunk_id(&long).starts_with("..."));
+    }
+}
@@ -1,41 +1,138 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use std::mem::size_of;
+
+use crate::api::consts::{LUAI_MAXSTACK, LUA_REGISTRYINDEX};
+use crate::api::{RustFn, RustKFn};
+use crate::state::closure::{Closure, UpVal, UpValRef};
+use crate::state::gc::Gc;
+use crate::state::lua_error::{LuaError, LuaResult};
 use crate::state::lua_value::LuaValue;
 
-/// Lua Stack
+/// Lua Stack, a call frame owning the registers of the running function
 pub struct LuaStack {
-    vec: Vec<LuaValue>,
+    /// Registers and temporary values, shared with the open up values
+    vec: Rc<RefCell<Vec<LuaValue>>>,
+    /// The running closure, `None` for the frame of host
+    pub closure: Option<Rc<Closure>>,
+    /// The running Rust function, which is not a closure
+    pub rust_fn: Option<RustFn>,
+    /// Extra arguments passed to a vararg function
+    pub varargs: Vec<LuaValue>,
+    /// Open up values indexed by the registers they refer to
+    pub openuvs: HashMap<usize, UpValRef>,
+    pub pc: isize,
+    /// The number of results wanted by the caller, -1 means all of them
+    pub n_results: isize,
+    /// Whether the caller is waiting in `LuaAPI::call`,
+    /// otherwise the results are moved to the registers of the caller
+    pub fresh: bool,
+    /// Whether it replaced the frame of its caller by a tail call
+    pub is_tail: bool,
+    /// One past the last instruction traced by the line hook, 0 if there is none
+    pub old_pc: isize,
+    /// The number of slots used by the frames below in the same thread,
+    /// which are limited by `LUAI_MAXSTACK` with this frame
+    pub base: usize,
+    /// The protected call of the Rust function suspended by a yield,
+    /// which is finished by its continuation after the thread is resumed
+    pub pcallk: Option<PCallK>,
+    /// Whether the running instruction is interrupted by a yield in a metamethod,
+    /// which is finished after the thread is resumed and the metamethod returns
+    pub finish_op: bool,
+    /// Whether the interrupted `<=` calls `__lt` for the lack of `__le`, whose result is negated
+    pub le_by_lt: bool,
+}
+
+/// A protected call suspended by a yield, see `LuaAPI::pcallk`
+pub struct PCallK {
+    pub k: RustKFn,
+    pub ctx: isize,
+    /// The top of the frame without the function and its arguments, where the error object is put
+    pub old_top: usize,
+    /// The index of the message handler in the frame, 0 if there is none
+    pub msgh: isize,
 }
 
 impl LuaStack {
     #[inline]
-    pub fn new(size: usize) -> LuaStack {
+    pub fn new(size: usize, closure: Option<Rc<Closure>>) -> LuaStack {
         LuaStack {
-            vec: Vec::with_capacity(size),
+            vec: Rc::new(RefCell::new(Vec::with_capacity(size))),
+            closure,
+            varargs: Vec::new(),
+            openuvs: HashMap::new(),
+            pc: 0,
+            n_results: -1,
+            fresh: true,
+            rust_fn: None,
+            is_tail: false,
+            old_pc: 0,
+            base: 0,
+            pcallk: None,
+            finish_op: false,
+            le_by_lt: false,
         }
     }
 
     #[inline]
     pub fn top(&self) -> isize {
-        self.vec.len() as isize
+        self.vec.borrow().len() as isize
     }
 
+    /// The number of slots allocated by the frames up to this one
     #[inline]
-    pub fn check(&mut self, n: usize) {
-        self.vec.reserve(n);
+    pub fn slots(&self) -> usize {
+        self.base + self.vec.borrow().capacity()
+    }
+
+    /// Ensure that there is space for `n` more values, false if it would exceed `LUAI_MAXSTACK`
+    #[inline]
+    pub fn check(&mut self, n: usize) -> bool {
+        if self.base + self.top() as usize + n > LUAI_MAXSTACK as usize {
+            return false;
+        }
+        self.vec.borrow_mut().reserve(n);
+        true
     }
 
     #[inline]
     pub fn push(&mut self, val: LuaValue) {
-        self.vec.push(val);
+        self.vec.borrow_mut().push(val);
     }
 
+    /// Pop the top value, or nil if the stack is empty
     #[inline]
     pub fn pop(&mut self) -> LuaValue {
-        self.vec.pop().unwrap()
+        self.vec.borrow_mut().pop().unwrap_or(LuaValue::Nil)
+    }
+
+    /// Pop `n` values in the order they were pushed, or all of them if there are fewer
+    pub fn pop_n(&mut self, n: usize) -> Vec<LuaValue> {
+        let mut vec = self.vec.borrow_mut();
+        let len = vec.len();
+        vec.split_off(len.saturating_sub(n))
+    }
+
+    /// Drop the values above `top` or fill with nil up to it
+    #[inline]
+    pub fn set_top(&mut self, top: usize) {
+        self.vec.borrow_mut().resize(top, LuaValue::Nil);
+    }
+
+    /// Push `n` values, filling with nil or dropping the extra ones, and `n < 0` pushes all of them
+    pub fn push_n(&mut self, mut vals: Vec<LuaValue>, n: isize) {
+        if n >= 0 {
+            vals.resize(n as usize, LuaValue::Nil);
+        }
+        self.vec.borrow_mut().append(&mut vals);
     }
 
     #[inline]
     pub fn abs_index(&self, idx: isize) -> isize {
-        if idx >= 0 {
+        if idx >= 0 || idx <= LUA_REGISTRYINDEX {
             idx
         } else {
             idx + self.top() + 1
@@ -44,36 +141,106 @@ impl LuaStack {
 
     #[inline]
     pub fn is_valid(&self, idx: isize) -> bool {
+        if idx < LUA_REGISTRYINDEX {
+            // up values
+            let uv_idx = (LUA_REGISTRYINDEX - idx - 1) as usize;
+            return matches!(self.closure, Some(ref c) if uv_idx < c.upvals.borrow().len());
+        }
         let abs_idx = self.abs_index(idx);
         abs_idx > 0 && abs_idx <= self.top()
     }
 
     pub fn get(&self, idx: isize) -> LuaValue {
+        if idx < LUA_REGISTRYINDEX {
+            // up values
+            let uv_idx = (LUA_REGISTRYINDEX - idx - 1) as usize;
+            return match self.closure {
+                Some(ref c) => c.upvals.borrow().get(uv_idx).map_or(LuaValue::Nil, |uv| uv.borrow().get()),
+                None => LuaValue::Nil,
+            };
+        }
+
         let abs_idx = self.abs_index(idx);
         if abs_idx > 0 && abs_idx <= self.top() {
             let idx = abs_idx as usize - 1;
-            self.vec[idx].clone() // TODO
+            self.vec.borrow()[idx].clone() // TODO
         } else {
             LuaValue::Nil
         }
     }
 
-    pub fn set(&mut self, idx: isize, val: LuaValue) {
+    pub fn set(&mut self, idx: isize, val: LuaValue) -> LuaResult<()> {
+        if idx < LUA_REGISTRYINDEX {
+            // up values
+            let uv_idx = (LUA_REGISTRYINDEX - idx - 1) as usize;
+            if let Some(ref c) = self.closure {
+                if let Some(uv) = c.upvals.borrow().get(uv_idx) {
+                    uv.borrow_mut().set(val);
+                }
+            }
+            return Ok(());
+        }
+
         let abs_idx = self.abs_index(idx);
         if abs_idx > 0 && abs_idx <= self.top() {
             let idx = abs_idx as usize - 1;
-            self.vec[idx] = val;
+            self.vec.borrow_mut()[idx] = val;
+            Ok(())
         } else {
-            // todo: result
-            panic!("invalid index!");
+            Err(LuaError::runtime(&format!("invalid index {}", idx), None))
         }
     }
 
     pub fn reverse(&mut self, mut from: usize, mut to: usize) {
+        let mut vec = self.vec.borrow_mut();
         while from < to {
-            self.vec.swap(from, to);
+            vec.swap(from, to);
             from += 1;
             to -= 1;
         }
     }
+
+    /// Return the up value referring to the register `idx`, which is shared by all closures capturing it
+    pub fn capture(&mut self, idx: usize) -> UpValRef {
+        let vec = &self.vec;
+        self.openuvs
+            .entry(idx)
+            .or_insert_with(|| Rc::new(RefCell::new(UpVal::Open(vec.clone(), idx))))
+            .clone()
+    }
+
+    /// Estimated bytes used by the values of the frame
+    pub fn size_estimate(&self) -> usize {
+        let vec = self.vec.borrow();
+        let strings: usize = vec.iter().chain(self.varargs.iter()).map(LuaValue::heap_size).sum();
+        (vec.len() + self.varargs.len()) * size_of::<LuaValue>() + strings
+    }
+
+    /// Mark the values referred by the frame, which are roots of the collector
+    pub fn mark(&self, gc: &mut Gc) {
+        if let Some(ref c) = self.closure {
+            gc.mark_value(&LuaValue::Function(c.clone()));
+        }
+        for val in self.vec.borrow().iter().chain(self.varargs.iter()) {
+            gc.mark_value(val);
+        }
+    }
+
+    /// Drop all values of a frame found unreachable, which may be referred by its open up values
+    pub fn clear(&self) {
+        self.vec.borrow_mut().clear();
+    }
+
+    /// Close the open up values referring to the registers from `idx`
+    pub fn close_upvalues(&mut self, idx: usize, gc: &mut Gc) {
+        self.openuvs.retain(|&i, uv| {
+            if i >= idx {
+                gc.barrier_upval(uv);
+                uv.borrow_mut().close();
+                false
+            } else {
+                true
+            }
+        });
+    }
 }
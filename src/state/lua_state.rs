@@ -1,18 +1,872 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use crate::api::consts::*;
-use crate::api::LuaAPI;
-use crate::state::lua_stack::LuaStack;
-use crate::state::lua_value::LuaValue;
+use crate::api::{ExitHandler, LuaAPI, LuaVM, RustClosure, RustFn, RustKFn};
+use crate::binary;
+use crate::binary::chunk::{Prototype, LUA_SIGNATURE};
+use crate::compiler;
+use crate::number::format::float_to_string;
+use crate::state::closure::{Closure, UpVal, UpValRef};
+use crate::state::gc::Gc;
+use crate::state::lua_debug::{current_pc, func_line, func_name_from_code, local_name, HookState, LuaDebug, LuaHook};
+use crate::state::lua_error::{chunk_id, LuaError, LuaResult};
+use crate::state::lua_stack::{LuaStack, PCallK};
+use crate::state::lua_table::LuaTable;
+use crate::state::lua_thread::LuaThread;
+use crate::state::lua_userdata::LuaUserData;
+use crate::state::lua_value::{bytes_to_string, string_to_number, type_name, LuaValue};
 use crate::state::ops;
+use crate::vm::instruction::Instruction;
+use crate::vm::opcode::*;
+
+/// limit for the chains of `__index` and `__newindex`
+const MAX_TAG_LOOP: usize = 2000;
+
+/// The bytes of a string, or of a number converted to a string
+fn value_bytes(val: &LuaValue) -> Option<Cow<'_, [u8]>> {
+    match val {
+        LuaValue::String(s) => Some(Cow::Borrowed(s)),
+        LuaValue::Number(n) => Some(Cow::Owned(float_to_string(*n).into_bytes())),
+        LuaValue::Integer(i) => Some(Cow::Owned(i.to_string().into_bytes())),
+        _ => None,
+    }
+}
 
-/// Lua State containing Lua Stack
+/// Lua State containing the call frames
 pub struct LuaState {
-    stack: LuaStack,
+    /// Call frames, the last one is running
+    frames: Vec<LuaStack>,
+    /// Registry table, which holds the global table
+    registry: LuaValue,
+    /// The running thread, whose frames are moved here
+    thread: Rc<RefCell<LuaThread>>,
+    /// The threads suspended by resuming another one, which are only referenced here
+    resumers: Vec<Rc<RefCell<LuaThread>>>,
+    /// The number of non-yieldable calls in the running thread
+    nny: usize,
+    /// Whether the running thread is yielding, the values are on the top of its last frame
+    yielded: Option<usize>,
+    /// The number of protected calls running, which unwind the frames of errors
+    protected: usize,
+    gc: Gc,
+    /// Handler of `os.exit`, which exits the process if there is none
+    exit_handler: Option<ExitHandler>,
+    /// The hook of the running thread
+    hook: Option<HookState>,
+    /// Whether hooks can be called, which is false while one is running
+    allow_hook: bool,
+    /// Set by `InterruptHandle` to stop the running Lua code
+    interrupted: Arc<AtomicBool>,
+    /// The number of nested calls from Rust functions, which is limited by `LUAI_MAXCCALLS`
+    n_ccalls: usize,
+}
+
+/// Handle to interrupt the Lua code running in a state, which can be sent to another thread
+#[derive(Clone, Debug)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Raise an "interrupted" error at the next instruction of the running Lua code.
+    /// It is raised again by every instruction until the call from the host returns,
+    /// so it can not be caught by `pcall`.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Default for LuaState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for LuaState {
+    /// Call the pending finalizers when the state is closed
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            self.gc.separate_all();
+            self.call_all_finalizers();
+        }
+    }
 }
 
 impl LuaState {
     pub fn new() -> LuaState {
+        let mut thread = LuaThread::new();
+        let frames = std::mem::take(&mut thread.frames);
+        let thread = Rc::new(RefCell::new(thread));
+        let registry = LuaValue::new_table(0, 0);
+        if let LuaValue::Table(ref t) = registry {
+            let mut t = t.borrow_mut();
+            t.put(LuaValue::Integer(LUA_RIDX_MAINTHREAD), LuaValue::Thread(thread.clone()));
+            t.put(LuaValue::Integer(LUA_RIDX_GLOBALS), LuaValue::new_table(0, 0));
+        }
+
         LuaState {
-            stack: LuaStack::new(20),
+            frames,
+            registry,
+            thread,
+            resumers: Vec::new(),
+            // the main thread is not yieldable
+            nny: 1,
+            yielded: None,
+            protected: 0,
+            gc: Gc::new(),
+            exit_handler: None,
+            hook: None,
+            allow_hook: true,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            n_ccalls: 0,
+        }
+    }
+
+    /// Limit the estimated bytes used by the values of the state, `None` for no limit.
+    /// Exceeding it raises an error of status `LUA_ERRMEM` after a full collection,
+    /// which can be caught by `pcall`.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.gc.limit = limit.unwrap_or(usize::MAX);
+    }
+
+    /// The estimated bytes used by the strings, tables, closures and stacks of the state,
+    /// which includes the garbage not collected yet
+    pub fn memory_used(&self) -> usize {
+        self.gc.total_bytes()
+    }
+
+    /// Raise a memory error if `extra` more bytes would exceed the memory limit,
+    /// the garbage is collected to free memory at first
+    pub(crate) fn check_memory(&mut self, extra: usize) -> LuaResult<()> {
+        if self.gc.over_limit(extra) {
+            // emergency collection, the finalizers are called later
+            self.full_gc();
+            if self.gc.over_limit(extra) {
+                self.gc.over_bytes = self.gc.total_bytes();
+                return Err(LuaError::with_status(LUA_ERRMEM, "not enough memory"));
+            }
+        }
+        Ok(())
+    }
+
+    /// A handle to interrupt the Lua code running in the state from another thread
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.interrupted.clone())
+    }
+
+    /// Intercept `os.exit` by a handler, then it raises an error of status `LUA_ERREXIT`
+    /// with the exit status as value, which unwinds the running chunk through protected calls
+    pub fn set_exit_handler(&mut self, handler: Option<ExitHandler>) {
+        self.exit_handler = handler;
+    }
+
+    #[inline]
+    pub(crate) fn exit_handler(&self) -> Option<&ExitHandler> {
+        self.exit_handler.as_ref()
+    }
+
+    /// The frame of the running function
+    #[inline]
+    fn stack(&self) -> &LuaStack {
+        self.frames.last().unwrap()
+    }
+
+    #[inline]
+    fn stack_mut(&mut self) -> &mut LuaStack {
+        self.frames.last_mut().unwrap()
+    }
+
+    #[inline]
+    fn proto(&self) -> &Prototype {
+        let c = self.stack().closure.as_ref().expect("no function is running!");
+        c.proto.as_ref().expect("not a Lua function!")
+    }
+
+    /// Get the value at `idx`, which may be a pseudo-index
+    fn get_value(&self, idx: isize) -> LuaValue {
+        if idx == LUA_REGISTRYINDEX {
+            self.registry.clone()
+        } else {
+            self.stack().get(idx)
+        }
+    }
+
+    /// Set the value at `idx`, which may be a pseudo-index
+    fn set_value(&mut self, idx: isize, val: LuaValue) -> LuaResult<()> {
+        if idx == LUA_REGISTRYINDEX {
+            self.registry = val;
+            Ok(())
+        } else {
+            if idx < LUA_REGISTRYINDEX {
+                // an up value may belong to a closure already marked
+                self.gc.barrier_value(&val);
+            }
+            self.stack_mut().set(idx, val)
+        }
+    }
+
+    /// Get registry[key] for the predefined values
+    fn registry_get(&self, key: i64) -> LuaValue {
+        match self.registry {
+            LuaValue::Table(ref t) => t.borrow().get_int(key),
+            _ => LuaValue::Nil,
+        }
+    }
+
+    /// Execute instructions until the frames above `depth` return or the thread yields
+    fn execute(&mut self, depth: usize) -> LuaResult<()> {
+        while self.frames.len() > depth && self.yielded.is_none() {
+            let frame = self.frames.last_mut().unwrap();
+            if let Some(p) = frame.pcallk.take() {
+                // the protected call suspended by a yield has returned
+                self.finish_pcallk(p.k, LUA_OK, p.ctx)?;
+                continue;
+            }
+            if std::mem::take(&mut frame.finish_op) {
+                // the metamethod suspended by a yield has returned
+                self.finish_op().or_else(|err| self.yielded_in_op(err))?;
+                continue;
+            }
+            if self.gc.should_step() && self.gc_step() {
+                self.call_finalizers()?;
+            }
+            let inst = self.fetch();
+            if self.hook_mask() & (LUA_MASKLINE | LUA_MASKCOUNT) != 0 {
+                self.trace_exec()?;
+            }
+            if self.interrupted.load(Ordering::Relaxed) {
+                return Err(self.runtime_error("interrupted"));
+            }
+            if self.gc.over_limit(0) {
+                self.check_memory(0)?;
+            }
+            inst.execute(self).or_else(|err| self.yielded_in_op(err))?;
+        }
+        Ok(())
+    }
+
+    /// Catch the error aborting an instruction for a yield in a metamethod, see `call_metamethod`
+    fn yielded_in_op(&mut self, err: LuaError) -> LuaResult<()> {
+        if err.status == LUA_YIELD && self.yielded.is_some() {
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+
+    /// Finish the instruction interrupted by a yield in a metamethod,
+    /// whose result is on the top of stack above the temporary values of the instruction
+    fn finish_op(&mut self) -> LuaResult<()> {
+        let inst = self.proto().code[self.stack().pc as usize - 1];
+        let (a, _, _) = inst.abc();
+        match inst.opcode() {
+            OP_ADD..=OP_SHR | OP_UNM | OP_BNOT | OP_LEN | OP_GETTABUP | OP_GETTABLE | OP_SELF => {
+                self.replace(a + 1)?;
+            }
+            op @ (OP_EQ | OP_LT | OP_LE) => {
+                let mut res = self.stack_mut().pop().to_boolean();
+                if op == OP_LE && std::mem::take(&mut self.stack_mut().le_by_lt) {
+                    res = !res;
+                }
+                if res != (a != 0) {
+                    self.add_pc(1);
+                }
+            }
+            OP_CONCAT => {
+                // the operands left are concatenated with the result
+                let n = self.get_top() - self.register_count();
+                self.concat(n)?;
+                self.replace(a + 1)?;
+            }
+            // OP_SETTABUP, OP_SETTABLE
+            _ => self.pop(1),
+        }
+        let n = self.register_count();
+        self.set_top(n)
+    }
+
+    /// Pop the frames above `depth` after an error, closing their up values
+    fn unwind(&mut self, depth: usize) {
+        while self.frames.len() > depth {
+            let mut frame = self.frames.pop().unwrap();
+            frame.close_upvalues(0, &mut self.gc);
+        }
+    }
+
+    /// Call a function like `LuaAPI::call`, which may yield if the running thread is yieldable,
+    /// then its frames are left to be resumed
+    fn call_yieldable(&mut self, nargs: isize, nresults: isize) -> LuaResult<()> {
+        if self.n_ccalls >= LUAI_MAXCCALLS {
+            return Err(self.runtime_error("C stack overflow"));
+        }
+        let depth = self.frames.len();
+        self.n_ccalls += 1;
+        let result = self.call_function(nargs, nresults, true, false).and_then(|_| self.execute(depth));
+        self.n_ccalls -= 1;
+        if depth == 1 {
+            // the call from the host is finished
+            self.interrupted.store(false, Ordering::Relaxed);
+        }
+        if result.is_err() && self.protected == 0 {
+            self.unwind(depth);
+        }
+        result
+    }
+
+    /// Catch the error raised by the frames above `depth` for a protected call, and put the error object
+    /// at `old_top` of the frame below, which is the result of the message handler if there is one
+    fn recover(&mut self, depth: usize, old_top: usize, handler: Option<LuaValue>, mut err: LuaError) -> i8 {
+        if let Some(h) = handler.filter(|_| err.status == LUA_ERRRUN) {
+            // the handler runs on top of the frames raising the error
+            self.stack_mut().push(h);
+            self.stack_mut().push(err.value.clone());
+            self.protected += 1;
+            let result = self.call(1, 1);
+            self.protected -= 1;
+            err = match result {
+                Ok(()) => LuaError { value: self.stack_mut().pop(), ..err },
+                Err(_) => LuaError::with_status(LUA_ERRERR, "error in error handling"),
+            };
+        }
+        self.unwind(depth);
+        let frame = self.frames.last_mut().unwrap();
+        frame.close_upvalues(old_top, &mut self.gc);
+        frame.set_top(old_top);
+        frame.push(err.value);
+        err.status
+    }
+
+    /// Return the results of the Rust function on the top, whose protected call suspended by a yield
+    /// is finished with `status`, by its continuation `k`
+    fn finish_pcallk(&mut self, k: RustKFn, status: i8, ctx: isize) -> LuaResult<()> {
+        let n = k(self, status, ctx)?;
+        // the frame is kept until the thread is resumed
+        if self.yielded.is_none() {
+            self.post_call(n as isize)?;
+        }
+        Ok(())
+    }
+
+    /// Execute the resumed thread until it returns or yields, where an error is caught
+    /// by the innermost protected call suspended by a yield, see `LuaAPI::pcallk`
+    fn execute_resumed(&mut self, mut result: LuaResult<()>) -> LuaResult<()> {
+        loop {
+            let err = match result.and_then(|_| self.execute(1)) {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            let i = match self.frames.iter().rposition(|f| f.pcallk.is_some()) {
+                Some(i) => i,
+                None => return Err(err),
+            };
+            let p = self.frames[i].pcallk.take().unwrap();
+            let handler = if p.msgh != 0 { Some(self.frames[i].get(p.msgh)) } else { None };
+            let status = self.recover(i + 1, p.old_top, handler, err);
+            result = self.finish_pcallk(p.k, status, p.ctx);
+        }
+    }
+
+    /// The position "chunkname:currentline" of the function at `level`,
+    /// 0 is the running one and there is none for a Rust function
+    pub(crate) fn where_(&self, level: usize) -> Option<String> {
+        let frame = &self.frames[self.frames.len().checked_sub(level + 1)?];
+        let proto = frame.closure.as_ref()?.proto.as_ref()?;
+        let line = proto.line_info.get((frame.pc as usize).checked_sub(1)?)?;
+        let source = proto.source.as_deref().unwrap_or("=?");
+        Some(format!("{}:{}", chunk_id(source), line))
+    }
+
+    #[inline]
+    fn hook_mask(&self) -> i32 {
+        self.hook.as_ref().map_or(0, |h| h.mask)
+    }
+
+    /// Call the hook for an event of the running function, hooks are disabled while it runs
+    fn call_hook(&mut self, event: i32, line: i32) -> LuaResult<()> {
+        let hook = match self.hook {
+            Some(ref h) if self.allow_hook => h.hook.clone(),
+            _ => return Ok(()),
+        };
+        let mut ar = LuaDebug { event, current_line: line, frame: self.frames.len() - 1, ..LuaDebug::default() };
+        self.allow_hook = false;
+        let result = hook(self, &mut ar);
+        self.allow_hook = true;
+        result
+    }
+
+    /// Call the count and line hooks before the instruction just fetched by the running Lua function
+    fn trace_exec(&mut self) -> LuaResult<()> {
+        let mask = self.hook_mask();
+        if mask & LUA_MASKCOUNT != 0 {
+            let h = self.hook.as_mut().unwrap();
+            h.count -= 1;
+            if h.count == 0 {
+                h.count = h.base_count;
+                self.call_hook(LUA_HOOKCOUNT, -1)?;
+            }
+        }
+        if mask & LUA_MASKLINE != 0 {
+            let (npc, old_pc) = (current_pc(self.stack().pc), self.stack().old_pc as usize);
+            let proto = self.proto();
+            let new_line = func_line(proto, npc);
+            // a new function, a jump back (loop) or entering a new line
+            let changed = npc == 0 || old_pc == 0 || npc < old_pc || new_line != func_line(proto, old_pc - 1);
+            self.stack_mut().old_pc = npc as isize + 1;
+            if changed {
+                self.call_hook(LUA_HOOKLINE, new_line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The function running in the frame `idx`
+    fn frame_function(&self, idx: usize) -> LuaValue {
+        let frame = &self.frames[idx];
+        match (&frame.closure, frame.rust_fn) {
+            (Some(c), _) => LuaValue::Function(c.clone()),
+            (None, Some(f)) => LuaValue::RustFunction(f),
+            (None, None) => LuaValue::Nil,
+        }
+    }
+
+    /// The name and its kind of the function running in the frame `idx`,
+    /// which is known from the calling instruction of a Lua caller
+    fn frame_func_name(&self, idx: usize) -> Option<(&'static str, String)> {
+        let frame = &self.frames[idx];
+        if frame.is_tail || frame.fresh {
+            return None;
+        }
+        let caller = &self.frames[idx - 1];
+        let proto = caller.closure.as_ref()?.proto.as_ref()?;
+        func_name_from_code(proto, current_pc(caller.pc))
+    }
+
+    /// The name of the local `n` of the frame `idx` and its index in the registers or varargs
+    fn find_local(&self, idx: usize, n: isize) -> Option<(String, Result<isize, usize>)> {
+        let frame = &self.frames[idx];
+        let proto = frame.closure.as_ref().and_then(|c| c.proto.as_ref());
+        if n < 0 {
+            // access to vararg values
+            let i = (-n) as usize - 1;
+            return match proto {
+                Some(p) if p.is_vararg != 0 && i < frame.varargs.len() => Some(("(*vararg)".to_string(), Err(i))),
+                _ => None,
+            };
+        }
+        if let Some(name) = proto.and_then(|p| local_name(p, n, current_pc(frame.pc))) {
+            return Some((name.to_string(), Ok(n)));
+        }
+        if n > 0 && n <= frame.top() {
+            let name = if proto.is_some() { "(*temporary)" } else { "(*C temporary)" };
+            return Some((name.to_string(), Ok(n)));
+        }
+        None
+    }
+
+    /// The up value `n` of the closure at `func_idx` and its name,
+    /// which is empty for Rust closures
+    fn find_upvalue(&self, func_idx: isize, n: usize) -> Option<(UpValRef, String)> {
+        let c = match self.get_value(func_idx) {
+            LuaValue::Function(c) => c,
+            _ => return None,
+        };
+        let uv = c.upvals.borrow().get(n.checked_sub(1)?)?.clone();
+        let name = match c.proto {
+            Some(ref proto) => proto.up_value_names.get(n - 1).map_or("(*no name)", |s| s.as_str()),
+            None => "",
+        };
+        Some((uv, name.to_string()))
+    }
+
+    /// Run `f` with the thread at `idx` as the running one, moving the values it leaves
+    /// on the top of stack back, which is used to inspect suspended coroutines
+    pub(crate) fn with_thread<T>(&mut self, idx: isize, f: impl FnOnce(&mut LuaState) -> T) -> T {
+        let co = match self.get_value(idx) {
+            LuaValue::Thread(t) => t,
+            _ => panic!("thread expected!"),
+        };
+        if Rc::ptr_eq(&co, &self.thread) || co.borrow().frames.is_empty() {
+            return f(self); // the running thread or one resuming another
+        }
+        let swap = |ls: &mut LuaState| {
+            let mut t = co.borrow_mut();
+            std::mem::swap(&mut ls.frames, &mut t.frames);
+            std::mem::swap(&mut ls.hook, &mut t.hook);
+        };
+        let prev = std::mem::replace(&mut self.thread, co.clone());
+        self.resumers.push(prev);
+        swap(self);
+        let top = self.get_top() as usize;
+        let result = f(self);
+        let n = (self.get_top() as usize).saturating_sub(top);
+        let vals = self.stack_mut().pop_n(n);
+        swap(self);
+        self.thread = self.resumers.pop().unwrap();
+        self.stack_mut().push_n(vals, -1);
+        result
+    }
+
+    /// Push a new table tracked by the collector
+    fn push_new_table(&mut self, narr: usize, nrec: usize) {
+        let t = Rc::new(RefCell::new(LuaTable::new(narr, nrec)));
+        self.gc.track_table(&t);
+        self.stack_mut().push(LuaValue::Table(t));
+    }
+
+    /// Push a new closure tracked by the collector
+    fn push_closure(&mut self, c: Closure) {
+        let c = Rc::new(c);
+        self.gc.track_closure(&c);
+        self.stack_mut().push(LuaValue::Function(c));
+    }
+
+    /// Mark the registry, the running thread with the ones resuming it, and the values of all frames
+    fn mark_roots(&mut self) {
+        self.gc.mark_value(&self.registry);
+        self.gc.mark_value(&LuaValue::Thread(self.thread.clone()));
+        for t in self.resumers.iter() {
+            self.gc.mark_value(&LuaValue::Thread(t.clone()));
+        }
+        for frame in self.frames.iter() {
+            frame.mark(&mut self.gc);
+        }
+    }
+
+    /// Perform a step of the collector, and return whether a cycle is finished
+    fn gc_step(&mut self) -> bool {
+        if !self.gc.is_marking() {
+            self.gc.start_cycle();
+            self.mark_roots();
+        }
+        let work = self.gc.step_work();
+        if self.gc.propagate(work) {
+            self.finish_cycle();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Mark the roots again, since they are changed without barriers, and sweep
+    fn finish_cycle(&mut self) {
+        self.mark_roots();
+        self.gc.atomic();
+        // the frames of the running thread are not tracked
+        let roots = self.frames.iter().map(LuaStack::size_estimate).sum();
+        self.gc.sweep(roots);
+    }
+
+    /// Finish the current cycle and perform a full one
+    fn full_gc(&mut self) {
+        if self.gc.is_marking() {
+            self.finish_cycle();
+        }
+        self.gc.start_cycle();
+        self.finish_cycle();
+    }
+
+    /// Call `__gc` of the objects found unreachable,
+    /// an error stops the calls and the rest are left to the next time
+    fn call_finalizers(&mut self) -> LuaResult<()> {
+        while let Some(obj) = self.gc.next_finalizer() {
+            let mm = self.get_metafield(&obj, "__gc");
+            if let LuaValue::Function(_) | LuaValue::RustFunction(_) = mm {
+                self.stack_mut().push(mm);
+                self.stack_mut().push(obj);
+                if self.pcall(1, 0, 0) != LUA_OK {
+                    let msg = match self.stack_mut().pop() {
+                        LuaValue::String(s) => bytes_to_string(s.to_vec()),
+                        _ => "no message".to_string(),
+                    };
+                    let msg = format!("error in __gc metamethod ({})", msg);
+                    return Err(LuaError::with_status(LUA_ERRGCMM, &msg));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Call all pending finalizers, ignoring their errors
+    fn call_all_finalizers(&mut self) {
+        while self.call_finalizers().is_err() {}
+    }
+
+    #[inline]
+    fn thread_is_main(&self) -> bool {
+        self.registry_get(LUA_RIDX_MAINTHREAD) == LuaValue::Thread(self.thread.clone())
+    }
+
+    /// The status of the coroutine at `idx` as a string of `coroutine.status`
+    pub(crate) fn co_status(&self, idx: isize) -> &'static str {
+        let co = match self.get_value(idx) {
+            LuaValue::Thread(t) => t,
+            _ => panic!("thread expected!"),
+        };
+        if Rc::ptr_eq(&co, &self.thread) {
+            return "running";
+        }
+        let t = co.borrow();
+        match t.status {
+            LUA_YIELD => "suspended",
+            LUA_OK if t.frames.is_empty() => "normal",
+            LUA_OK if t.frames.len() == 1 && t.frames[0].top() > 0 => "suspended",
+            _ => "dead",
+        }
+    }
+
+    /// Pop the function and its `nargs` arguments and start to call it
+    fn call_function(&mut self, nargs: isize, nresults: isize, fresh: bool, tail: bool) -> LuaResult<()> {
+        let mut args = self.stack_mut().pop_n(nargs as usize);
+        let mut f = self.stack_mut().pop();
+        // call the `__call` metamethod with the object as its first argument
+        while f.type_id() != LUA_TFUNCTION {
+            match self.get_metafield(&f, "__call") {
+                LuaValue::Nil => {
+                    let msg = format!("attempt to call a {} value", self.type_name(f.type_id()));
+                    return Err(self.runtime_error(&msg));
+                }
+                mm => {
+                    args.insert(0, f);
+                    f = mm;
+                }
+            }
+        }
+        match f {
+            LuaValue::Function(c) => match c.proto {
+                Some(ref proto) => {
+                    let proto = proto.clone();
+                    self.call_lua_closure(c, proto, args, nresults, fresh, tail)
+                }
+                None => self.call_rust_function(Some(c), None, args, nresults, fresh, tail),
+            },
+            LuaValue::RustFunction(f) => self.call_rust_function(None, Some(f), args, nresults, fresh, tail),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Push a new frame for the Lua closure, which runs in the loop of `execute`
+    fn call_lua_closure(&mut self, c: Rc<Closure>, proto: Rc<Prototype>, mut args: Vec<LuaValue>, nresults: isize, fresh: bool, tail: bool) -> LuaResult<()> {
+        let n_params = proto.num_params as usize;
+        let n_regs = proto.max_stack_size as usize;
+        let base = self.stack().slots();
+        if base + n_regs + LUA_MINSTACK > LUAI_MAXSTACK as usize {
+            return Err(self.runtime_error("stack overflow"));
+        }
+        let mut frame = LuaStack::new(n_regs + LUA_MINSTACK, Some(c));
+        frame.base = base;
+        frame.n_results = nresults;
+        frame.fresh = fresh;
+        frame.is_tail = tail;
+        if proto.is_vararg != 0 && args.len() > n_params {
+            frame.varargs = args.split_off(n_params);
+        }
+        frame.push_n(args, n_params as isize);
+        frame.set_top(n_regs);
+        self.frames.push(frame);
+        if self.hook_mask() & LUA_MASKCALL != 0 {
+            self.call_hook(if tail { LUA_HOOKTAILCALL } else { LUA_HOOKCALL }, -1)?;
+        }
+        Ok(())
+    }
+
+    /// Run the Rust function in a new frame and return its results at once,
+    /// the frame is left to be unwound if it raises an error
+    fn call_rust_function(&mut self, c: Option<Rc<Closure>>, f: Option<RustFn>, args: Vec<LuaValue>, nresults: isize, fresh: bool, tail: bool) -> LuaResult<()> {
+        let base = self.stack().slots();
+        if base + args.len() + LUA_MINSTACK > LUAI_MAXSTACK as usize {
+            return Err(self.runtime_error("stack overflow"));
+        }
+        let mut frame = LuaStack::new(args.len() + LUA_MINSTACK, c.clone());
+        frame.base = base;
+        frame.n_results = nresults;
+        frame.fresh = fresh;
+        frame.rust_fn = f;
+        frame.is_tail = tail;
+        frame.push_n(args, -1);
+        self.frames.push(frame);
+        if self.hook_mask() & LUA_MASKCALL != 0 {
+            self.call_hook(if tail { LUA_HOOKTAILCALL } else { LUA_HOOKCALL }, -1)?;
+        }
+
+        let n = match (f, c) {
+            (Some(f), _) => f(self)?,
+            (None, Some(c)) => (c.rust_fn.as_ref().unwrap())(self)?,
+            (None, None) => unreachable!(),
+        };
+        // the frame is kept until the thread is resumed
+        if self.yielded.is_none() {
+            self.post_call(n as isize)?;
+        }
+        Ok(())
+    }
+
+    /// push t[k], `__index` is used unless `raw`
+    fn get_table_val(&mut self, t: &LuaValue, k: &LuaValue, raw: bool) -> LuaResult<i8> {
+        let mut t = t.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            if let LuaValue::Table(ref tbl) = t {
+                let val = tbl.borrow().get(k);
+                if raw || !val.is_nil() {
+                    return Ok(self.push_type(val));
+                }
+            } else if raw {
+                let msg = format!("table expected, got {}", self.type_name(t.type_id()));
+                return Err(self.runtime_error(&msg));
+            }
+
+            match self.get_metafield(&t, "__index") {
+                LuaValue::Nil => match t {
+                    LuaValue::Table(_) => return Ok(self.push_type(LuaValue::Nil)),
+                    _ => return Err(self.operand_error(&t, "index")),
+                },
+                mm @ LuaValue::Function(_) | mm @ LuaValue::RustFunction(_) => {
+                    let val = self.call_metamethod(mm, &[t, k.clone()])?;
+                    return Ok(self.push_type(val));
+                }
+                mm => t = mm,
+            }
+        }
+        Err(self.runtime_error("'__index' chain too long; possibly a loop"))
+    }
+
+    /// t[k] = v, `__newindex` is used unless `raw`
+    fn set_table_val(&mut self, t: &LuaValue, k: LuaValue, v: LuaValue, raw: bool) -> LuaResult<()> {
+        let mut t = t.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            let mm = self.get_metafield(&t, "__newindex");
+            if let LuaValue::Table(ref tbl) = t {
+                if raw || mm.is_nil() || !tbl.borrow().get(&k).is_nil() {
+                    match k {
+                        LuaValue::Nil => return Err(self.runtime_error("table index is nil")),
+                        LuaValue::Number(n) if n.is_nan() => return Err(self.runtime_error("table index is NaN")),
+                        _ => {}
+                    }
+                    let mut bytes = k.heap_size() + v.heap_size();
+                    let growth = if v.is_nil() { 0 } else { tbl.borrow().growth(&k) };
+                    if self.gc.over_limit(bytes + growth) {
+                        // the key and the value are kept on the stack during the emergency collection
+                        self.stack_mut().push(k.clone());
+                        self.stack_mut().push(v.clone());
+                        let result = self.check_memory(bytes + growth);
+                        self.stack_mut().pop_n(2);
+                        result?;
+                    }
+                    self.gc.barrier_table(tbl);
+                    let mut tbl = tbl.borrow_mut();
+                    // a new entry and a string value take memory
+                    let entries = tbl.entries();
+                    tbl.put(k, v);
+                    if tbl.entries() > entries {
+                        bytes += 2 * std::mem::size_of::<LuaValue>();
+                    }
+                    self.gc.add_debt(bytes);
+                    return Ok(());
+                }
+            } else if raw {
+                let msg = format!("table expected, got {}", self.type_name(t.type_id()));
+                return Err(self.runtime_error(&msg));
+            }
+
+            match mm {
+                LuaValue::Nil => return Err(self.operand_error(&t, "index")),
+                LuaValue::Function(_) | LuaValue::RustFunction(_) => {
+                    self.call_metamethod(mm, &[t, k, v])?;
+                    return Ok(());
+                }
+                mm => t = mm,
+            }
+        }
+        Err(self.runtime_error("'__newindex' chain too long; possibly a loop"))
+    }
+
+    #[inline]
+    fn is_string_value(&self, val: &LuaValue) -> bool {
+        matches!(val, LuaValue::String(_) | LuaValue::Integer(_) | LuaValue::Number(_))
+    }
+
+    #[inline]
+    fn push_type(&mut self, val: LuaValue) -> i8 {
+        let tp = val.type_id();
+        self.stack_mut().push(val);
+        tp
+    }
+
+    /// The metatable of a table or userdata, or the shared one of its type
+    fn get_metatable_of(&self, val: &LuaValue) -> Option<Rc<RefCell<LuaTable>>> {
+        match val {
+            LuaValue::Table(t) => t.borrow().metatable.clone(),
+            LuaValue::UserData(u) => u.borrow().metatable.clone(),
+            _ => match self.registry {
+                LuaValue::Table(ref reg) => {
+                    let key = LuaValue::new_string(format!("_MT{}", val.type_id()).into_bytes());
+                    match reg.borrow().get(&key) {
+                        LuaValue::Table(mt) => Some(mt),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            },
+        }
+    }
+
+    fn get_metafield(&self, val: &LuaValue, event: &str) -> LuaValue {
+        match self.get_metatable_of(val) {
+            Some(mt) => mt.borrow().get(&LuaValue::new_string(event.into())),
+            None => LuaValue::Nil,
+        }
+    }
+
+    /// Call the metamethod with `args` and return its first result.
+    /// It may yield if it is called by an instruction of a yieldable thread, then an error of status
+    /// `LUA_YIELD` aborts the instruction, which is finished by `finish_op` after the thread is resumed.
+    fn call_metamethod(&mut self, mm: LuaValue, args: &[LuaValue]) -> LuaResult<LuaValue> {
+        self.stack_mut().push(mm);
+        for arg in args {
+            self.stack_mut().push(arg.clone());
+        }
+        let nargs = args.len() as isize;
+        let frame = self.stack();
+        if self.nny > 0 || frame.rust_fn.is_some() || frame.closure.as_ref().is_none_or(|c| c.proto.is_none()) {
+            // called by a Rust function
+            self.call(nargs, 1)?;
+            return Ok(self.stack_mut().pop());
+        }
+        let depth = self.frames.len();
+        self.call_yieldable(nargs, 1)?;
+        if self.yielded.is_some() {
+            self.frames[depth - 1].finish_op = true;
+            return Err(LuaError::with_status(LUA_YIELD, "yield in metamethod"));
+        }
+        Ok(self.stack_mut().pop())
+    }
+
+    /// Call the metamethod of the first operand or the second one for the binary event
+    fn call_binary_metamethod(&mut self, a: &LuaValue, b: &LuaValue, event: &str) -> LuaResult<Option<LuaValue>> {
+        let mm = match self.get_metafield(a, event) {
+            LuaValue::Nil => self.get_metafield(b, event),
+            mm => mm,
+        };
+        if mm.is_nil() {
+            Ok(None)
+        } else {
+            self.call_metamethod(mm, &[a.clone(), b.clone()]).map(Some)
+        }
+    }
+
+    /// Error of an operation on a value of wrong type, such as "attempt to index a nil value"
+    fn operand_error(&self, val: &LuaValue, op: &str) -> LuaError {
+        let msg = format!("attempt to {} a {} value", op, self.type_name(val.type_id()));
+        self.runtime_error(&msg)
+    }
+
+    fn compare_error(&self, a: &LuaValue, b: &LuaValue) -> LuaError {
+        let (t1, t2) = (self.type_name(a.type_id()), self.type_name(b.type_id()));
+        if t1 == t2 {
+            self.runtime_error(&format!("attempt to compare two {} values", t1))
+        } else {
+            self.runtime_error(&format!("attempt to compare {} with {}", t1, t2))
         }
     }
 }
@@ -21,102 +875,83 @@ impl LuaAPI for LuaState {
     /* basic stack manipulation */
 
     fn get_top(&self) -> isize {
-        self.stack.top()
+        self.stack().top()
     }
 
     fn abs_index(&self, idx: isize) -> isize {
-        self.stack.abs_index(idx)
+        self.stack().abs_index(idx)
     }
 
     fn check_stack(&mut self, n: usize) -> bool {
-        self.stack.check(n);
-        true
+        self.stack_mut().check(n)
     }
 
     fn pop(&mut self, n: usize) {
         for _ in 0..n {
-            self.stack.pop();
+            self.stack_mut().pop();
         }
     }
 
-    fn copy(&mut self, from_idx: isize, to_idx: isize) {
-        let val = self.stack.get(from_idx);
-        self.stack.set(to_idx, val);
+    fn copy(&mut self, from_idx: isize, to_idx: isize) -> LuaResult<()> {
+        let val = self.get_value(from_idx);
+        self.set_value(to_idx, val)
     }
 
     fn push_value(&mut self, idx: isize) {
-        let val = self.stack.get(idx);
-        self.stack.push(val);
+        let val = self.get_value(idx);
+        self.stack_mut().push(val);
     }
 
-    fn replace(&mut self, idx: isize) {
-        let val = self.stack.pop();
-        self.stack.set(idx, val);
+    fn replace(&mut self, idx: isize) -> LuaResult<()> {
+        let val = self.stack_mut().pop();
+        self.set_value(idx, val)
     }
 
-    fn insert(&mut self, idx: isize) {
-        self.rotate(idx, 1);
+    fn insert(&mut self, idx: isize) -> LuaResult<()> {
+        self.rotate(idx, 1)
     }
 
-    fn remove(&mut self, idx: isize) {
-        self.rotate(idx, -1);
+    fn remove(&mut self, idx: isize) -> LuaResult<()> {
+        self.rotate(idx, -1)?;
         self.pop(1);
+        Ok(())
     }
 
-    fn rotate(&mut self, idx: isize, n: isize) {
-        let abs_idx = self.stack.abs_index(idx);
-        if abs_idx < 0 || !self.stack.is_valid(abs_idx) {
-            panic!("invalid index!");
+    fn rotate(&mut self, idx: isize, n: isize) -> LuaResult<()> {
+        let abs_idx = self.stack().abs_index(idx);
+        if abs_idx < 0 || !self.stack().is_valid(abs_idx) {
+            return Err(LuaError::runtime(&format!("invalid index {}", idx), None));
         }
 
-        let t = self.stack.top() - 1; /* end of stack segment being rotated */
+        let t = self.stack().top() - 1; /* end of stack segment being rotated */
         let p = abs_idx - 1; /* start of segment */
         let m = if n >= 0 { t - n } else { p - n - 1 }; /* end of prefix */
-        self.stack.reverse(p as usize, m as usize); /* reverse the prefix with length 'n' */
-        self.stack.reverse(m as usize + 1, t as usize); /* reverse the suffix */
-        self.stack.reverse(p as usize, t as usize); /* reverse the entire segment */
+        self.stack_mut().reverse(p as usize, m as usize); /* reverse the prefix with length 'n' */
+        self.stack_mut().reverse(m as usize + 1, t as usize); /* reverse the suffix */
+        self.stack_mut().reverse(p as usize, t as usize); /* reverse the entire segment */
+        Ok(())
     }
 
-    fn set_top(&mut self, idx: isize) {
-        let new_top = self.stack.abs_index(idx);
+    fn set_top(&mut self, idx: isize) -> LuaResult<()> {
+        let new_top = self.stack().abs_index(idx);
         if new_top < 0 {
-            panic!("stack underflow!");
-        }
-
-        let n = self.stack.top() - new_top;
-        if n > 0 {
-            for _ in 0..n {
-                self.stack.pop();
-            }
-        } else if n < 0 {
-            for _ in n..0 {
-                self.stack.push(LuaValue::Nil);
-            }
+            return Err(LuaError::runtime("stack underflow", None));
         }
+        self.stack_mut().set_top(new_top as usize);
+        Ok(())
     }
 
     /* access functions (stack -> rust) */
 
+    #[inline]
     fn type_name(&self, tp: i8) -> &str {
-        match tp {
-            LUA_TNONE => "no value",
-            LUA_TNIL => "nil",
-            LUA_TBOOLEAN => "boolean",
-            LUA_TNUMBER => "number",
-            LUA_TSTRING => "string",
-            LUA_TTABLE => "table",
-            LUA_TFUNCTION => "function",
-            LUA_TTHREAD => "thread",
-            LUA_TLIGHTUSERDATA => "userdata",
-            LUA_TUSERDATA => "userdata",
-            _ => "?", // TODO
-        }
+        type_name(tp)
     }
 
     #[inline]
     fn type_id(&self, idx: isize) -> i8 {
-        if self.stack.is_valid(idx) {
-            self.stack.get(idx).type_id()
+        if idx == LUA_REGISTRYINDEX || self.stack().is_valid(idx) {
+            self.get_value(idx).type_id()
         } else {
             LUA_TNONE
         }
@@ -152,6 +987,20 @@ impl LuaAPI for LuaState {
         self.type_id(idx) == LUA_TFUNCTION
     }
 
+    #[inline]
+    fn is_rust_function(&self, idx: isize) -> bool {
+        match self.get_value(idx) {
+            LuaValue::RustFunction(_) => true,
+            LuaValue::Function(c) => c.rust_fn.is_some(),
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn is_userdata(&self, idx: isize) -> bool {
+        self.type_id(idx) == LUA_TUSERDATA
+    }
+
     #[inline]
     fn is_thread(&self, idx: isize) -> bool {
         self.type_id(idx) == LUA_TTHREAD
@@ -170,15 +1019,12 @@ impl LuaAPI for LuaState {
 
     #[inline]
     fn is_integer(&self, idx: isize) -> bool {
-        match self.stack.get(idx) {
-            LuaValue::Integer(_) => true,
-            _ => false,
-        }
+        matches!(self.get_value(idx), LuaValue::Integer(_))
     }
 
     #[inline]
     fn to_boolean(&self, idx: isize) -> bool {
-        self.stack.get(idx).to_boolean()
+        self.get_value(idx).to_boolean()
     }
 
     #[inline]
@@ -188,7 +1034,7 @@ impl LuaAPI for LuaState {
 
     #[inline]
     fn to_integerx(&self, idx: isize) -> Option<i64> {
-        self.stack.get(idx).to_integer()
+        self.get_value(idx).to_integer()
     }
 
     #[inline]
@@ -198,7 +1044,7 @@ impl LuaAPI for LuaState {
 
     #[inline]
     fn to_numberx(&self, idx: isize) -> Option<f64> {
-        self.stack.get(idx).to_number()
+        self.get_value(idx).to_number()
     }
 
     #[inline]
@@ -206,12 +1052,37 @@ impl LuaAPI for LuaState {
         self.to_stringx(idx).unwrap_or_default()
     }
 
+    /// A string or a number converted to string, where invalid UTF-8 sequences are replaced
     #[inline]
     fn to_stringx(&self, idx: isize) -> Option<String> {
-        match self.stack.get(idx) {
-            LuaValue::String(s) => Some(s),
-            LuaValue::Number(n) => Some(n.to_string()),
-            LuaValue::Integer(i) => Some(i.to_string()),
+        self.to_bytesx(idx).map(bytes_to_string)
+    }
+
+    #[inline]
+    fn to_bytes(&self, idx: isize) -> Vec<u8> {
+        self.to_bytesx(idx).unwrap_or_default()
+    }
+
+    /// The bytes of a string or a number converted to string
+    fn to_bytesx(&self, idx: isize) -> Option<Vec<u8>> {
+        value_bytes(&self.get_value(idx)).map(Cow::into_owned)
+    }
+
+    /// The address of a table, function, thread or userdata, or null for the other values
+    fn to_pointer(&self, idx: isize) -> *const () {
+        match self.get_value(idx) {
+            LuaValue::Table(t) => Rc::as_ptr(&t) as *const (),
+            LuaValue::Function(c) => Rc::as_ptr(&c) as *const (),
+            LuaValue::RustFunction(f) => f as *const (),
+            LuaValue::Thread(t) => Rc::as_ptr(&t) as *const (),
+            LuaValue::UserData(u) => Rc::as_ptr(&u) as *const (),
+            _ => std::ptr::null(),
+        }
+    }
+
+    fn to_userdata(&self, idx: isize) -> Option<Rc<RefCell<LuaUserData>>> {
+        match self.get_value(idx) {
+            LuaValue::UserData(u) => Some(u),
             _ => None,
         }
     }
@@ -220,91 +1091,1758 @@ impl LuaAPI for LuaState {
 
     #[inline]
     fn push_nil(&mut self) {
-        self.stack.push(LuaValue::Nil);
+        self.stack_mut().push(LuaValue::Nil);
     }
 
     #[inline]
     fn push_boolean(&mut self, b: bool) {
-        self.stack.push(LuaValue::Boolean(b));
+        self.stack_mut().push(LuaValue::Boolean(b));
     }
 
     #[inline]
     fn push_integer(&mut self, n: i64) {
-        self.stack.push(LuaValue::Integer(n));
+        self.stack_mut().push(LuaValue::Integer(n));
     }
 
     #[inline]
     fn push_number(&mut self, n: f64) {
-        self.stack.push(LuaValue::Number(n));
+        self.stack_mut().push(LuaValue::Number(n));
     }
 
     #[inline]
     fn push_string(&mut self, s: String) {
-        self.stack.push(LuaValue::String(s));
+        self.push_bytes(s.into_bytes());
     }
 
+    #[inline]
+    fn push_bytes(&mut self, s: Vec<u8>) {
+        self.gc.add_debt(s.len());
+        self.stack_mut().push(LuaValue::new_string(s));
+    }
 
-    /* comparison and arithmetic functions */
-
-    fn arith(&mut self, op: u8) {
-        if op != LUA_OPUNM && op != LUA_OPBNOT {
-            let b = self.stack.pop();
-            let a = self.stack.pop();
-            if let Some(result) = ops::arith(&a, &b, op) {
-                self.stack.push(result);
-                return;
-            }
-        } else {
-            let a = self.stack.pop();
-            if let Some(result) = ops::arith(&a, &a, op) {
-                self.stack.push(result);
-                return;
-            }
-        }
-        panic!("arithmetic error!");
+    #[inline]
+    fn push_rust_function(&mut self, f: RustFn) {
+        self.stack_mut().push(LuaValue::RustFunction(f));
     }
 
-    fn compare(&self, idx1: isize, idx2: isize, op: u8) -> bool {
-        if !self.stack.is_valid(idx1) || !self.stack.is_valid(idx2) {
-            false
-        } else {
-            let a = self.stack.get(idx1);
-            let b = self.stack.get(idx2);
-            if let Some(result) = ops::compare(&a, &b, op) {
-                return result;
-            }
-            panic!("comparison error!")
-        }
+    /// Push a Rust closure with the `n` values on the top of stack as its up values
+    fn push_rust_closure(&mut self, f: RustClosure, n: usize) {
+        let upvals = self.stack_mut().pop_n(n);
+        self.push_closure(Closure::new_rust(f, upvals));
     }
 
-    /* miscellaneous functions */
+    #[inline]
+    fn push_global_table(&mut self) {
+        let globals = self.registry_get(LUA_RIDX_GLOBALS);
+        self.stack_mut().push(globals);
+    }
+
+
+    /* get functions (Lua -> stack) */
+
+    #[inline]
+    fn create_table(&mut self, narr: usize, nrec: usize) {
+        self.push_new_table(narr, nrec);
+    }
+
+    #[inline]
+    fn new_table(&mut self) {
+        self.create_table(0, 0);
+    }
+
+    /// Push a new userdata holding `data`, which may be accessed by `to_userdata`
+    fn new_userdata(&mut self, data: Box<dyn std::any::Any>) {
+        let u = Rc::new(RefCell::new(LuaUserData::new(data)));
+        self.gc.track_userdata(&u);
+        self.stack_mut().push(LuaValue::UserData(u));
+    }
+
+    fn get_table(&mut self, idx: isize) -> LuaResult<i8> {
+        let t = self.get_value(idx);
+        let k = self.stack_mut().pop();
+        self.get_table_val(&t, &k, false)
+    }
+
+    fn get_field(&mut self, idx: isize, k: &str) -> LuaResult<i8> {
+        let t = self.get_value(idx);
+        self.get_table_val(&t, &LuaValue::new_string(k.into()), false)
+    }
+
+    fn get_i(&mut self, idx: isize, i: i64) -> LuaResult<i8> {
+        let t = self.get_value(idx);
+        self.get_table_val(&t, &LuaValue::Integer(i), false)
+    }
+
+    fn raw_get(&mut self, idx: isize) -> LuaResult<i8> {
+        let t = self.get_value(idx);
+        let k = self.stack_mut().pop();
+        self.get_table_val(&t, &k, true)
+    }
+
+    fn get_global(&mut self, name: &str) -> LuaResult<i8> {
+        let t = self.registry_get(LUA_RIDX_GLOBALS);
+        self.get_table_val(&t, &LuaValue::new_string(name.into()), false)
+    }
+
+    fn get_metatable(&mut self, idx: isize) -> bool {
+        let val = self.get_value(idx);
+        match self.get_metatable_of(&val) {
+            Some(mt) => {
+                self.stack_mut().push(LuaValue::Table(mt));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /* set functions (stack -> Lua) */
+
+    fn set_table(&mut self, idx: isize) -> LuaResult<()> {
+        let t = self.get_value(idx);
+        let v = self.stack_mut().pop();
+        let k = self.stack_mut().pop();
+        self.set_table_val(&t, k, v, false)
+    }
+
+    fn set_field(&mut self, idx: isize, k: &str) -> LuaResult<()> {
+        let t = self.get_value(idx);
+        let v = self.stack_mut().pop();
+        self.set_table_val(&t, LuaValue::new_string(k.into()), v, false)
+    }
+
+    fn set_i(&mut self, idx: isize, i: i64) -> LuaResult<()> {
+        let t = self.get_value(idx);
+        let v = self.stack_mut().pop();
+        self.set_table_val(&t, LuaValue::Integer(i), v, false)
+    }
+
+    fn raw_set(&mut self, idx: isize) -> LuaResult<()> {
+        let t = self.get_value(idx);
+        let v = self.stack_mut().pop();
+        let k = self.stack_mut().pop();
+        self.set_table_val(&t, k, v, true)
+    }
+
+    fn set_global(&mut self, name: &str) -> LuaResult<()> {
+        let t = self.registry_get(LUA_RIDX_GLOBALS);
+        let v = self.stack_mut().pop();
+        self.set_table_val(&t, LuaValue::new_string(name.into()), v, false)
+    }
+
+    /// Pop a table or nil as the metatable of the value at `idx`,
+    /// which is shared by all values of the same type except tables and userdata
+    fn set_metatable(&mut self, idx: isize) -> LuaResult<()> {
+        let val = self.get_value(idx);
+        let mt = match self.stack_mut().pop() {
+            LuaValue::Table(mt) => Some(mt),
+            LuaValue::Nil => None,
+            _ => return Err(LuaError::runtime("table expected", None)),
+        };
+
+        // only the objects with `__gc` at this point are finalized
+        let has_gc = mt.as_ref().is_some_and(|mt| !mt.borrow().get(&LuaValue::new_string(b"__gc".to_vec())).is_nil());
+        if let LuaValue::Table(ref t) = val {
+            self.gc.barrier_table(t);
+            if has_gc {
+                self.gc.check_finalizer(&val);
+            }
+            t.borrow_mut().metatable = mt;
+        } else if let LuaValue::UserData(ref u) = val {
+            if let Some(ref mt) = mt {
+                self.gc.barrier_value(&LuaValue::Table(mt.clone()));
+            }
+            if has_gc {
+                self.gc.check_finalizer(&val);
+            }
+            u.borrow_mut().metatable = mt;
+        } else if let LuaValue::Table(ref reg) = self.registry {
+            self.gc.barrier_table(reg);
+            let key = LuaValue::new_string(format!("_MT{}", val.type_id()).into_bytes());
+            let mt = mt.map_or(LuaValue::Nil, LuaValue::Table);
+            reg.borrow_mut().put(key, mt);
+        }
+        Ok(())
+    }
+
+    fn register(&mut self, name: &str, f: RustFn) -> LuaResult<()> {
+        self.push_rust_function(f);
+        self.set_global(name)
+    }
+
+    /* 'load' and 'call' functions (load and run Lua code) */
+
+    fn load(&mut self, chunk: Vec<u8>, chunk_name: &str, mode: &str) -> i8 {
+        let is_binary = chunk.starts_with(&LUA_SIGNATURE);
+        let (kind, allowed) = if is_binary { ("binary", "b") } else { ("text", "t") };
+        if !mode.contains(allowed) {
+            let msg = format!("attempt to load a {} chunk (mode is '{}')", kind, mode);
+            self.push_string(msg);
+            return LUA_ERRSYNTAX;
+        }
+
+        let result = if is_binary {
+            binary::decode(chunk).map_err(|err| err.to_string())
+        } else {
+            compiler::compile(chunk, chunk_name.to_string()).map_err(|err| err.to_string())
+        };
+        let proto = match result {
+            Ok(proto) => proto,
+            Err(msg) => {
+                self.push_string(format!("{}: {}", chunk_id(chunk_name), msg));
+                return LUA_ERRSYNTAX;
+            }
+        };
+        let c = Closure::new(proto);
+        // the first up value of main function is `_ENV`
+        if let Some(env) = c.upvals.borrow().first() {
+            let globals = self.registry_get(LUA_RIDX_GLOBALS);
+            *env.borrow_mut() = UpVal::Closed(globals);
+        }
+        self.push_closure(c);
+        LUA_OK
+    }
+
+    /// Call a function, which can not yield since the Rust caller can not be resumed.
+    /// The frames raising an error are left to the protected call catching it.
+    fn call(&mut self, nargs: isize, nresults: isize) -> LuaResult<()> {
+        self.nny += 1;
+        let result = self.call_yieldable(nargs, nresults);
+        self.nny -= 1;
+        result
+    }
+
+    /// Call a function in protected mode like `pcall`, which may yield if the running thread is yieldable.
+    /// Then `LUA_YIELD` is returned, and the Rust function should return at once. The call is finished
+    /// after the thread is resumed, and `k` is called in place of the Rust function to return its results,
+    /// with the status of the call and `ctx`, where the results or the error object are on the top of stack.
+    fn pcallk(&mut self, nargs: isize, nresults: isize, msgh: isize, ctx: isize, k: RustKFn) -> i8 {
+        let depth = self.frames.len();
+        let old_top = (self.get_top() - nargs - 1).max(0) as usize;
+        let msgh = if msgh != 0 { self.abs_index(msgh) } else { 0 };
+        let handler = if msgh != 0 { Some(self.get_value(msgh)) } else { None };
+        self.protected += 1;
+        let result = self.call_yieldable(nargs, nresults);
+        self.protected -= 1;
+        match result {
+            Ok(()) if self.yielded.is_some() => {
+                self.frames[depth - 1].pcallk = Some(PCallK { k, ctx, old_top, msgh });
+                LUA_YIELD
+            }
+            Ok(()) => LUA_OK,
+            Err(err) => self.recover(depth, old_top, handler, err),
+        }
+    }
+
+    /// Call a function in protected mode, and push the error object instead of its results on error,
+    /// which is the result of the message handler at `msgh` if it is not 0
+    fn pcall(&mut self, nargs: isize, nresults: isize, msgh: isize) -> i8 {
+        let depth = self.frames.len();
+        let old_top = (self.get_top() - nargs - 1).max(0) as usize;
+        let handler = if msgh != 0 { Some(self.get_value(msgh)) } else { None };
+        self.protected += 1;
+        let result = self.call(nargs, nresults);
+        self.protected -= 1;
+        match result {
+            Ok(()) => LUA_OK,
+            Err(err) => self.recover(depth, old_top, handler, err),
+        }
+    }
+
+    /* coroutine functions */
+
+    /// Push a new thread, whose body function should be moved to it by `xmove`
+    fn new_thread(&mut self) {
+        let mut t = LuaThread::new();
+        // the hook is inherited with a new count
+        t.hook = self.hook.clone().map(|h| HookState { count: h.base_count, ..h });
+        let t = Rc::new(RefCell::new(t));
+        self.gc.track_thread(&t);
+        self.stack_mut().push(LuaValue::Thread(t));
+    }
+
+    /// Push the running thread and return whether it is the main thread
+    fn push_thread(&mut self) -> bool {
+        let t = LuaValue::Thread(self.thread.clone());
+        self.stack_mut().push(t);
+        self.thread_is_main()
+    }
+
+    /// Pop `n` values and push them to the stack of the thread at `idx`
+    fn xmove(&mut self, idx: isize, n: usize) -> LuaResult<()> {
+        let t = match self.get_value(idx) {
+            LuaValue::Thread(t) => t,
+            _ => return Err(LuaError::runtime("thread expected", None)),
+        };
+        let vals = self.stack_mut().pop_n(n);
+        let mut t = t.borrow_mut();
+        match t.frames.last_mut() {
+            Some(frame) => {
+                frame.push_n(vals, -1);
+                Ok(())
+            }
+            None => Err(LuaError::runtime("cannot move values to a running thread", None)),
+        }
+    }
+
+    /// Resume the thread at `idx` with `nargs` arguments on the top of stack,
+    /// and push the values it yields or returns, whose number is returned with the status.
+    /// The error object is pushed instead if it raises an error, which kills the thread.
+    fn resume(&mut self, idx: isize, nargs: isize) -> (i8, usize) {
+        let co = match self.get_value(idx) {
+            LuaValue::Thread(t) => t,
+            _ => {
+                self.push_string("thread expected".to_string());
+                return (LUA_ERRRUN, 1);
+            }
+        };
+        let status = {
+            let t = co.borrow();
+            // the frames are empty while running or resuming another thread
+            let fresh = t.status == LUA_OK && t.frames.len() == 1 && t.frames[0].top() > 0;
+            if t.status != LUA_YIELD && !fresh {
+                let msg = if t.frames.is_empty() { "non-suspended" } else { "dead" };
+                self.push_string(format!("cannot resume {} coroutine", msg));
+                return (LUA_ERRRUN, 1);
+            }
+            t.status
+        };
+
+        if self.n_ccalls >= LUAI_MAXCCALLS {
+            self.push_string("C stack overflow".to_string());
+            return (LUA_ERRRUN, 1);
+        }
+        let args = self.stack_mut().pop_n(nargs as usize);
+        let prev = std::mem::replace(&mut self.thread, co.clone());
+        self.resumers.push(prev);
+        {
+            let mut t = co.borrow_mut();
+            std::mem::swap(&mut self.frames, &mut t.frames);
+            std::mem::swap(&mut self.nny, &mut t.nny);
+            std::mem::swap(&mut self.hook, &mut t.hook);
+            t.status = LUA_OK;
+        }
+        self.stack_mut().push_n(args, -1);
+        self.protected += 1;
+        self.n_ccalls += 1;
+        let result = if status == LUA_YIELD {
+            // the arguments are the results of `yield`
+            self.post_call(nargs)
+        } else {
+            let nargs = self.get_top() - 1;
+            self.call_function(nargs, LUA_MULTRET, true, false)
+        };
+        let result = self.execute_resumed(result);
+        self.n_ccalls -= 1;
+        self.protected -= 1;
 
-    fn len(&mut self, idx: isize) {
-        let val = self.stack.get(idx);
-        if let LuaValue::String(s) = val {
-            self.stack.push(LuaValue::Integer(s.len() as i64));
+        let (status, vals) = match (result, self.yielded.take()) {
+            (Err(err), _) => {
+                self.unwind(1);
+                let frame = self.frames.last_mut().unwrap();
+                frame.close_upvalues(0, &mut self.gc);
+                frame.set_top(0);
+                (err.status, vec![err.value])
+            }
+            (Ok(()), Some(n)) => (LUA_YIELD, self.stack_mut().pop_n(n)),
+            (Ok(()), None) => {
+                let n = self.get_top() as usize;
+                (LUA_OK, self.stack_mut().pop_n(n))
+            }
+        };
+        {
+            let mut t = co.borrow_mut();
+            std::mem::swap(&mut self.frames, &mut t.frames);
+            std::mem::swap(&mut self.nny, &mut t.nny);
+            std::mem::swap(&mut self.hook, &mut t.hook);
+            t.status = status;
+        }
+        self.thread = self.resumers.pop().unwrap();
+        if self.frames.len() == 1 {
+            // the resume from the host is finished
+            self.interrupted.store(false, Ordering::Relaxed);
+        }
+        let n = vals.len();
+        self.stack_mut().push_n(vals, -1);
+        (status, n)
+    }
+
+    /// Yield the `n` values on the top of stack, which should be returned by the Rust function.
+    /// It can not yield across the Rust functions calling Lua, except by `pcallk`.
+    fn yield_(&mut self, n: isize) -> LuaResult<usize> {
+        if self.nny > 0 {
+            let msg = if self.thread_is_main() {
+                "attempt to yield from outside a coroutine"
+            } else {
+                "attempt to yield across a Rust-call boundary"
+            };
+            return Err(LuaError::runtime(msg, None));
+        }
+        self.yielded = Some(n as usize);
+        Ok(n as usize)
+    }
+
+    /// The status of the thread at `idx`, `LUA_OK`, `LUA_YIELD` or an error status
+    fn status(&self, idx: isize) -> i8 {
+        match self.get_value(idx) {
+            LuaValue::Thread(t) => t.borrow().status,
+            _ => panic!("thread expected!"),
+        }
+    }
+
+    #[inline]
+    fn is_yieldable(&self) -> bool {
+        self.nny == 0
+    }
+
+    /* comparison and arithmetic functions */
+
+    fn arith(&mut self, op: u8) -> LuaResult<()> {
+        let b = self.stack_mut().pop();
+        let a = if op != LUA_OPUNM && op != LUA_OPBNOT {
+            self.stack_mut().pop()
+        } else {
+            b.clone()
+        };
+
+        if let (LuaValue::Integer(_), LuaValue::Integer(0)) = (&a, &b) {
+            match op {
+                LUA_OPMOD => return Err(self.runtime_error("attempt to perform 'n%0'")),
+                LUA_OPIDIV => return Err(self.runtime_error("attempt to perform 'n//0'")),
+                _ => {}
+            }
+        }
+        if let Some(result) = ops::arith(&a, &b, op) {
+            self.stack_mut().push(result);
+        } else if let Some(result) = self.call_binary_metamethod(&a, &b, ops::ARITH_EVENTS[op as usize])? {
+            self.stack_mut().push(result);
         } else {
-            panic!("length error!")
+            let bad = if a.to_number().is_none() { &a } else { &b };
+            if op >= LUA_OPBAND && op != LUA_OPUNM && bad.to_number().is_some() {
+                return Err(self.runtime_error("number has no integer representation"));
+            }
+            let what = if op >= LUA_OPBAND && op != LUA_OPUNM { "perform bitwise operation on" } else { "perform arithmetic on" };
+            return Err(self.operand_error(bad, what));
+        }
+        Ok(())
+    }
+
+    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> LuaResult<bool> {
+        if !self.stack().is_valid(idx1) || !self.stack().is_valid(idx2) {
+            return Ok(false);
+        }
+
+        let a = self.get_value(idx1);
+        let b = self.get_value(idx2);
+        if let Some(result) = ops::compare(&a, &b, op) {
+            if op != LUA_OPEQ || result {
+                return Ok(result);
+            }
+        }
+        match op {
+            LUA_OPEQ => match (&a, &b) {
+                (LuaValue::Table(_), LuaValue::Table(_)) => {
+                    Ok(self.call_binary_metamethod(&a, &b, "__eq")?.is_some_and(|v| v.to_boolean()))
+                }
+                _ => Ok(false),
+            },
+            LUA_OPLT => match self.call_binary_metamethod(&a, &b, "__lt")? {
+                Some(v) => Ok(v.to_boolean()),
+                None => Err(self.compare_error(&a, &b)),
+            },
+            LUA_OPLE => match self.call_binary_metamethod(&a, &b, "__le")? {
+                Some(v) => Ok(v.to_boolean()),
+                // a <= b is not (b < a) without `__le`
+                None => {
+                    let depth = self.frames.len();
+                    match self.call_binary_metamethod(&b, &a, "__lt") {
+                        Ok(Some(v)) => Ok(!v.to_boolean()),
+                        Ok(None) => Err(self.compare_error(&a, &b)),
+                        Err(err) => {
+                            if self.yielded.is_some() {
+                                // the result is negated by `finish_op`
+                                self.frames[depth - 1].le_by_lt = true;
+                            }
+                            Err(err)
+                        }
+                    }
+                }
+            },
+            _ => Err(LuaError::runtime("invalid comparison operator", None)),
+        }
+    }
+
+    /* miscellaneous functions */
+
+    /// Pop the error object as an error, which should be returned by the Rust function
+    fn error(&mut self) -> LuaError {
+        LuaError::new(self.stack_mut().pop())
+    }
+
+    fn len(&mut self, idx: isize) -> LuaResult<()> {
+        let val = self.get_value(idx);
+        if let LuaValue::String(ref s) = val {
+            self.stack_mut().push(LuaValue::Integer(s.len() as i64));
+            return Ok(());
+        }
+        match self.get_metafield(&val, "__len") {
+            LuaValue::Nil => match val {
+                LuaValue::Table(t) => self.stack_mut().push(LuaValue::Integer(t.borrow().len() as i64)),
+                _ => return Err(self.operand_error(&val, "get length of")),
+            },
+            mm => {
+                let result = self.call_metamethod(mm, &[val.clone(), val])?;
+                self.stack_mut().push(result);
+            }
         }
+        Ok(())
     }
 
-    fn concat(&mut self, n: isize) {
+    fn raw_len(&self, idx: isize) -> usize {
+        match self.get_value(idx) {
+            LuaValue::String(s) => s.len(),
+            LuaValue::Table(t) => t.borrow().len(),
+            _ => 0,
+        }
+    }
+
+    /// Primitive equality without `__eq`, false for non-valid indices
+    fn raw_equal(&self, idx1: isize, idx2: isize) -> bool {
+        if !self.stack().is_valid(idx1) || !self.stack().is_valid(idx2) {
+            return false;
+        }
+        ops::compare(&self.get_value(idx1), &self.get_value(idx2), LUA_OPEQ) == Some(true)
+    }
+
+    fn concat(&mut self, n: isize) -> LuaResult<()> {
         if n == 0 {
-            self.stack.push(LuaValue::String(String::new()))
+            self.stack_mut().push(LuaValue::new_string(Vec::new()))
         } else if n >= 2 {
             for _ in 1..n {
-                if self.is_string(-1) && self.is_string(-2) {
-                    let s2 = self.to_string(-1);
-                    let mut s1 = self.to_string(-2);
-                    s1.push_str(&s2);
-                    self.stack.pop();
-                    self.stack.pop();
-                    self.stack.push(LuaValue::String(s1));
-                } else {
-                    panic!("concatenation error!");
+                let b = self.stack_mut().pop();
+                let a = self.stack_mut().pop();
+                if let (Some(s1), Some(s2)) = (value_bytes(&a), value_bytes(&b)) {
+                    // the limit is checked before the new string is built
+                    self.check_memory(s1.len() + s2.len())?;
+                    self.push_bytes([&s1[..], &s2[..]].concat());
+                    continue;
+                }
+                match self.call_binary_metamethod(&a, &b, "__concat")? {
+                    Some(result) => self.stack_mut().push(result),
+                    None => {
+                        let bad = if self.is_string_value(&a) { &b } else { &a };
+                        return Err(self.operand_error(bad, "concatenate"));
+                    }
                 }
             }
         }
         // n == 1, do nothing
+        Ok(())
+    }
+
+    /// Pop a key and push the next key and value of the table at `idx`,
+    /// nothing is pushed at the end of the table
+    fn next(&mut self, idx: isize) -> LuaResult<bool> {
+        let t = match self.get_value(idx) {
+            LuaValue::Table(t) => t,
+            _ => return Err(LuaError::runtime("table expected", None)),
+        };
+        let key = self.stack_mut().pop();
+        let entry = t.borrow().next(&key);
+        match entry {
+            Ok(Some((k, v))) => {
+                self.stack_mut().push(k);
+                self.stack_mut().push(v);
+                Ok(true)
+            }
+            Ok(None) => Ok(false),
+            Err(msg) => Err(self.runtime_error(msg)),
+        }
+    }
+
+    /// Push the number converted from the numeral `s` and return true, or return false if it is not a numeral
+    fn string_to_number(&mut self, s: &str) -> bool {
+        match string_to_number(s) {
+            Some(n) => {
+                self.stack_mut().push(n);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /* garbage-collection function */
+
+    fn gc(&mut self, what: i32, data: i32) -> LuaResult<i32> {
+        let res = match what {
+            LUA_GCSTOP => {
+                self.gc.running = false;
+                0
+            }
+            LUA_GCRESTART => {
+                self.gc.running = true;
+                0
+            }
+            LUA_GCCOLLECT => {
+                self.full_gc();
+                self.call_finalizers()?;
+                0
+            }
+            LUA_GCCOUNT => (self.gc.total_bytes() >> 10) as i32,
+            LUA_GCCOUNTB => (self.gc.total_bytes() & 0x3ff) as i32,
+            LUA_GCSTEP => {
+                self.gc.request_step(data.max(0) as usize * 1024);
+                let done = self.gc_step();
+                self.call_finalizers()?;
+                done as i32
+            }
+            LUA_GCSETPAUSE => std::mem::replace(&mut self.gc.pause, data.max(0) as usize) as i32,
+            LUA_GCSETSTEPMUL => std::mem::replace(&mut self.gc.stepmul, data.max(0) as usize) as i32,
+            LUA_GCISRUNNING => self.gc.running as i32,
+            _ => -1,
+        };
+        Ok(res)
+    }
+
+    /* debug API */
+
+    /// The activation record of the function at `level`, 0 is the running one
+    fn get_stack(&self, level: usize) -> Option<LuaDebug> {
+        // the first frame belongs to the host
+        let frame = self.frames.len().checked_sub(level + 1).filter(|&i| i > 0)?;
+        Some(LuaDebug { frame, ..LuaDebug::default() })
+    }
+
+    /// Fill the fields of `ar` selected by the options in `what`, and push the function for 'f'
+    /// and the table of its lines for 'L'. The function is popped from the top of stack instead
+    /// of the activation record if `what` starts with '>'. Return false for an invalid option.
+    fn get_info(&mut self, what: &str, ar: &mut LuaDebug) -> bool {
+        let (func, frame, what) = match what.strip_prefix('>') {
+            Some(what) => (self.stack_mut().pop(), None, what),
+            None if ar.frame > 0 && ar.frame < self.frames.len() => (self.frame_function(ar.frame), Some(ar.frame), what),
+            None => return false,
+        };
+        let proto = match func {
+            LuaValue::Function(ref c) => c.proto.clone(),
+            _ => None,
+        };
+        let mut status = true;
+        for option in what.chars() {
+            match option {
+                'S' => {
+                    match proto {
+                        Some(ref p) => {
+                            ar.source = p.source.clone().unwrap_or_else(|| "=?".to_string());
+                            ar.line_defined = p.line_defined as i32;
+                            ar.last_line_defined = p.last_line_defined as i32;
+                            ar.what = if p.line_defined == 0 { "main" } else { "Lua" };
+                        }
+                        None => {
+                            ar.source = "=[C]".to_string();
+                            ar.line_defined = -1;
+                            ar.last_line_defined = -1;
+                            ar.what = "C";
+                        }
+                    }
+                    ar.short_src = chunk_id(&ar.source);
+                }
+                'l' => {
+                    ar.current_line = match (frame, &proto) {
+                        (Some(i), Some(p)) => func_line(p, current_pc(self.frames[i].pc)),
+                        _ => -1,
+                    }
+                }
+                'u' => {
+                    ar.nups = match func {
+                        LuaValue::Function(ref c) => c.upvals.borrow().len(),
+                        _ => 0,
+                    };
+                    ar.nparams = proto.as_ref().map_or(0, |p| p.num_params as usize);
+                    ar.is_vararg = proto.as_ref().is_none_or(|p| p.is_vararg != 0);
+                }
+                't' => ar.is_tail_call = frame.is_some_and(|i| self.frames[i].is_tail),
+                'n' => {
+                    let (namewhat, name) = match frame.and_then(|i| self.frame_func_name(i)) {
+                        Some((namewhat, name)) => (namewhat, Some(name)),
+                        None => ("", None),
+                    };
+                    ar.namewhat = namewhat;
+                    ar.name = name;
+                }
+                'L' | 'f' => {} // handled below
+                _ => status = false, // invalid option
+            }
+        }
+        if what.contains('f') {
+            self.stack_mut().push(func);
+        }
+        if what.contains('L') {
+            match proto {
+                Some(ref p) => {
+                    self.push_new_table(0, p.line_info.len());
+                    let t = self.stack_mut().pop();
+                    if let LuaValue::Table(ref tbl) = t {
+                        let mut tbl = tbl.borrow_mut();
+                        for &line in p.line_info.iter() {
+                            tbl.put(LuaValue::Integer(line as i64), LuaValue::Boolean(true));
+                        }
+                    }
+                    self.stack_mut().push(t);
+                }
+                None => self.push_nil(),
+            }
+        }
+        status
+    }
+
+    /// Push the local `n` of the activation record and return its name, or None if there is no
+    /// such local. Without a record, return the name of the parameter `n` of the function
+    /// on the top of stack without pushing anything.
+    fn get_local(&mut self, ar: Option<&LuaDebug>, n: isize) -> Option<String> {
+        let ar = match ar {
+            Some(ar) => ar,
+            None => {
+                return match self.stack().get(-1) {
+                    LuaValue::Function(c) => local_name(c.proto.as_ref()?, n, 0).map(|s| s.to_string()),
+                    _ => None,
+                };
+            }
+        };
+        if ar.frame == 0 || ar.frame >= self.frames.len() {
+            return None;
+        }
+        let (name, slot) = self.find_local(ar.frame, n)?;
+        let frame = &self.frames[ar.frame];
+        let val = match slot {
+            Ok(i) => frame.get(i),
+            Err(i) => frame.varargs[i].clone(),
+        };
+        self.stack_mut().push(val);
+        Some(name)
+    }
+
+    /// Pop a value into the local `n` of the activation record and return its name,
+    /// nothing is popped if there is no such local
+    fn set_local(&mut self, ar: &LuaDebug, n: isize) -> Option<String> {
+        if ar.frame == 0 || ar.frame >= self.frames.len() {
+            return None;
+        }
+        let (name, slot) = self.find_local(ar.frame, n)?;
+        let val = self.stack_mut().pop();
+        // the frame may belong to a thread already marked
+        self.gc.barrier_value(&val);
+        let frame = &mut self.frames[ar.frame];
+        match slot {
+            Ok(i) => frame.set(i, val).ok()?,
+            Err(i) => frame.varargs[i] = val,
+        }
+        Some(name)
+    }
+
+    /// Push the up value `n` of the closure at `func_idx`, and return its name,
+    /// which is empty for Rust closures, or None if there is no such up value
+    fn get_upvalue(&mut self, func_idx: isize, n: usize) -> Option<String> {
+        let (uv, name) = self.find_upvalue(func_idx, n)?;
+        let val = uv.borrow().get();
+        self.stack_mut().push(val);
+        Some(name)
+    }
+
+    /// Pop a value into the up value `n` of the closure at `func_idx`, and return its name,
+    /// which is empty for Rust closures, or None if there is no such up value
+    fn set_upvalue(&mut self, func_idx: isize, n: usize) -> Option<String> {
+        let (uv, name) = self.find_upvalue(func_idx, n)?;
+        let val = self.stack_mut().pop();
+        // the closure may be already marked
+        self.gc.barrier_value(&val);
+        uv.borrow_mut().set(val);
+        Some(name)
+    }
+
+    /// A unique identifier of the up value `n` of the closure at `func_idx`,
+    /// which is shared by the closures referring to the same variable
+    fn upvalue_id(&self, func_idx: isize, n: usize) -> Option<*const ()> {
+        let (uv, _) = self.find_upvalue(func_idx, n)?;
+        Some(Rc::as_ptr(&uv) as *const ())
+    }
+
+    /// Make the up value `n1` of the Lua closure at `func_idx1` refer to the up value `n2`
+    /// of the Lua closure at `func_idx2`
+    fn upvalue_join(&mut self, func_idx1: isize, n1: usize, func_idx2: isize, n2: usize) {
+        let uv = match self.find_upvalue(func_idx2, n2) {
+            Some((uv, _)) => uv,
+            None => return,
+        };
+        if let LuaValue::Function(c) = self.get_value(func_idx1) {
+            if let Some(slot) = c.upvals.borrow_mut().get_mut(n1.wrapping_sub(1)) {
+                self.gc.barrier_join(&uv);
+                *slot = uv;
+            }
+        }
+    }
+
+    /// Set the hook of the running thread for the events in `mask`, where `LUA_MASKCOUNT`
+    /// is for every `count` instructions. The hook is removed if `f` is None or `mask` is 0.
+    fn set_hook(&mut self, f: Option<LuaHook>, mut mask: i32, count: i32) {
+        if count <= 0 {
+            mask &= !LUA_MASKCOUNT;
+        }
+        self.hook = match f {
+            Some(hook) if mask != 0 => Some(HookState { hook, mask, base_count: count, count }),
+            _ => None,
+        };
+    }
+
+    fn get_hook(&self) -> Option<LuaHook> {
+        self.hook.as_ref().map(|h| h.hook.clone())
+    }
+
+    fn get_hook_mask(&self) -> i32 {
+        self.hook_mask()
+    }
+
+    fn get_hook_count(&self) -> i32 {
+        self.hook.as_ref().map_or(0, |h| h.base_count)
+    }
+}
+
+impl LuaVM for LuaState {
+    #[inline]
+    fn pc(&self) -> isize {
+        self.stack().pc
+    }
+
+    #[inline]
+    fn add_pc(&mut self, n: isize) {
+        self.stack_mut().pc += n;
+    }
+
+    fn fetch(&mut self) -> u32 {
+        let inst = self.proto().code[self.stack().pc as usize];
+        self.stack_mut().pc += 1;
+        inst
+    }
+
+    fn get_const(&mut self, idx: isize) {
+        let val = LuaValue::from(&self.proto().constants[idx as usize]);
+        self.stack_mut().push(val);
+    }
+
+    fn get_rk(&mut self, rk: isize) {
+        if rk > 0xFF {
+            // constant
+            self.get_const(rk & 0xFF);
+        } else {
+            // register
+            self.push_value(rk + 1);
+        }
+    }
+
+    #[inline]
+    fn register_count(&self) -> isize {
+        self.proto().max_stack_size as isize
+    }
+
+    fn load_vararg(&mut self, n: isize) {
+        let varargs = self.stack().varargs.clone();
+        self.stack_mut().push_n(varargs, n);
+    }
+
+    fn load_proto(&mut self, idx: usize) {
+        let proto = self.proto().prototypes[idx].clone();
+        let mut c = Closure::new(proto.clone());
+        for (i, uv) in proto.up_values.iter().enumerate() {
+            let idx = uv.idx as usize;
+            if uv.instack == 1 {
+                // a local variable of the enclosing function
+                c.upvals.get_mut()[i] = self.stack_mut().capture(idx);
+            } else {
+                // an up value of the enclosing function
+                let parent = self.stack().closure.as_ref().unwrap();
+                c.upvals.get_mut()[i] = parent.upvals.borrow()[idx].clone();
+            }
+        }
+        self.push_closure(c);
+    }
+
+    #[inline]
+    fn close_upvalues(&mut self, a: isize) {
+        let frame = self.frames.last_mut().unwrap();
+        frame.close_upvalues(a as usize - 1, &mut self.gc);
+    }
+
+    fn runtime_error(&self, msg: &str) -> LuaError {
+        LuaError::runtime(msg, self.where_(0))
+    }
+
+    #[inline]
+    fn pre_call(&mut self, nargs: isize, nresults: isize) -> LuaResult<()> {
+        self.call_function(nargs, nresults, false, false)
+    }
+
+    fn post_call(&mut self, n: isize) -> LuaResult<()> {
+        if self.hook_mask() & LUA_MASKRET != 0 {
+            self.call_hook(LUA_HOOKRET, -1)?;
+        }
+        let results = self.stack_mut().pop_n(n as usize);
+        let mut frame = self.frames.pop().unwrap();
+        frame.close_upvalues(0, &mut self.gc);
+        self.stack_mut().push_n(results, frame.n_results);
+        if !frame.fresh && frame.n_results >= 0 {
+            // the results have been moved to the registers of caller
+            let n_regs = self.register_count() as usize;
+            self.stack_mut().set_top(n_regs);
+        }
+        Ok(())
+    }
+
+    fn tail_call(&mut self, nargs: isize) -> LuaResult<()> {
+        let is_rust = match self.stack().get(-nargs - 1) {
+            LuaValue::Function(c) => c.proto.is_none(),
+            f => f.type_id() == LUA_TFUNCTION,
+        };
+        if is_rust {
+            // a Rust function runs above the frame, whose next instruction returns the results
+            return self.call_function(nargs, LUA_MULTRET, false, false);
+        }
+        let vals = self.stack_mut().pop_n(nargs as usize + 1);
+        let mut frame = self.frames.pop().unwrap();
+        frame.close_upvalues(0, &mut self.gc);
+        self.stack_mut().push_n(vals, -1);
+        self.call_function(nargs, frame.n_results, frame.fresh, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn execute(src: &str) -> (LuaState, usize) {
+        let mut state = LuaState::new();
+        assert_eq!(state.load(src.as_bytes().to_vec(), "test", "bt"), LUA_OK);
+        state.call(0, LUA_MULTRET).unwrap();
+        let n = state.get_top() as usize;
+        (state, n)
+    }
+
+    #[test]
+    fn test_arith() {
+        let (state, n) = execute(r#"
+        local a, b = 7, 2
+        return a + b, a - b, a * b, a / b, a // b, a % b, a ^ b, -a, -7 // 2.0, 3 | 4, 1 << 63, "10" + 1
+        "#);
+        assert_eq!(n, 12);
+        assert_eq!(state.to_integerx(1), Some(9));
+        assert_eq!(state.to_integerx(2), Some(5));
+        assert_eq!(state.to_integerx(3), Some(14));
+        assert_eq!(state.to_numberx(4), Some(3.5));
+        assert!(state.is_integer(5));
+        assert_eq!(state.to_integerx(5), Some(3));
+        assert_eq!(state.to_integerx(6), Some(1));
+        assert!(!state.is_integer(7));
+        assert_eq!(state.to_numberx(7), Some(49.0));
+        assert_eq!(state.to_integerx(8), Some(-7));
+        assert_eq!(state.to_numberx(9), Some(-4.0));
+        assert_eq!(state.to_integerx(10), Some(7));
+        assert_eq!(state.to_integerx(11), Some(i64::MIN));
+        assert!(!state.is_integer(12));
+        assert_eq!(state.to_numberx(12), Some(11.0));
+    }
+
+    #[test]
+    fn test_numerals() {
+        let (state, n) = execute(r#"
+        return 0x10, 0xff, 0XA, 0x1p4, 0xA.8p0, 0x.1, 0xffffffffffffffff, 9223372036854775808, 1e2, 3.
+        "#);
+        assert_eq!(n, 10);
+        assert!(state.is_integer(1) && state.is_integer(2) && state.is_integer(3));
+        assert_eq!(state.to_integerx(1), Some(16));
+        assert_eq!(state.to_integerx(2), Some(255));
+        assert_eq!(state.to_integerx(3), Some(10));
+        assert!(!state.is_integer(4));
+        assert_eq!(state.to_numberx(4), Some(16.0));
+        assert_eq!(state.to_numberx(5), Some(10.5));
+        assert_eq!(state.to_numberx(6), Some(0.0625));
+        // hexadecimal integers wrap around, and decimal ones overflowing are floats
+        assert_eq!(state.to_integerx(7), Some(-1));
+        assert!(!state.is_integer(8));
+        assert_eq!(state.to_numberx(8), Some(9223372036854775808.0));
+        assert_eq!(state.to_numberx(9), Some(100.0));
+        assert_eq!(state.to_numberx(10), Some(3.0));
+
+        let mut state = LuaState::new();
+        assert_eq!(state.load(b"return 0x".to_vec(), "=test", "t"), LUA_ERRSYNTAX);
+        assert_eq!(state.to_string(-1), "test: line: 1, error: IllegalNumLiteral");
+    }
+
+    #[test]
+    fn test_logic() {
+        let (state, n) = execute(r#"
+        local x = 3
+        return x < 4, x == 3.0, x ~= 3, not x, x and 5, nil or "s", false and x, x >= 4
+        "#);
+        assert_eq!(n, 8);
+        assert!(state.to_boolean(1));
+        assert!(state.to_boolean(2));
+        assert!(!state.to_boolean(3));
+        assert!(!state.to_boolean(4));
+        assert_eq!(state.to_integerx(5), Some(5));
+        assert_eq!(state.to_string(6), "s");
+        assert!(state.is_boolean(7) && !state.to_boolean(7));
+        assert!(!state.to_boolean(8));
+    }
+
+    #[test]
+    fn test_mixed_comparison() {
+        let (state, n) = execute(r#"
+        local max, min, nan = 9223372036854775807, -9223372036854775807 - 1, 0/0
+        return 2^63 > max, max < 9223372036854775808.0, 9007199254740993 == 9007199254740992.0,
+            -2^63 == min, 1 < 1.5, 2 <= 1.5, -1 > -1.5, 3 == 3.0, nan < 1, 1 <= nan, nan ~= 1
+        "#);
+        assert_eq!(n, 11);
+        let results: Vec<_> = (1..=11).map(|i| state.to_boolean(i)).collect();
+        let expected = [true, true, false, true, true, false, true, true, false, false, true];
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let (state, n) = execute(r#"
+        local sum = 0
+        for i = 1, 10 do sum = sum + i end
+        local j = 0
+        while j < 5 do j = j + 1 end
+        repeat j = j - 1 until j == 2
+        local k = 0
+        while true do
+            k = k + 1
+            if k == 3 then break end
+        end
+        local s
+        if sum > 100 then s = "big" elseif sum > 50 then s = "medium" else s = "small" end
+        return sum, j, k, s
+        "#);
+        assert_eq!(n, 4);
+        assert_eq!(state.to_integerx(1), Some(55));
+        assert_eq!(state.to_integerx(2), Some(2));
+        assert_eq!(state.to_integerx(3), Some(3));
+        assert_eq!(state.to_string(4), "medium");
+    }
+
+    #[test]
+    fn test_for_num() {
+        let (state, _) = execute(r#"
+        local a, b, c, d, e = 0, 0, 0, 0, 0
+        for i = 1, 2, 0.5 do a = a + 1 end
+        for i = 3, 1, -1 do b = b * 10 + i end
+        for i = 1, 3.5 do c = c + 1 end
+        for i = 1, 0 do d = d + 1 end
+        for i = 1, 2^63 do e = e + 1 if e == 5 then break end end
+        return a, b, c, d, e
+        "#);
+        assert_eq!(state.to_integerx(1), Some(3));
+        assert_eq!(state.to_integerx(2), Some(321));
+        assert_eq!(state.to_integerx(3), Some(3));
+        assert_eq!(state.to_integerx(4), Some(0));
+        assert_eq!(state.to_integerx(5), Some(5));
+    }
+
+    #[test]
+    fn test_goto() {
+        let (state, _) = execute(r#"
+        -- continue
+        local odd = 0
+        for i = 1, 10 do
+            if i % 2 == 0 then goto continue end
+            local x = i
+            odd = odd + x
+            ::continue::
+        end
+
+        -- backward jumps closing up values
+        local fs, i = {}, 1
+        ::top::
+        do
+            local j = i
+            fs[i] = function() return j end
+            i = i + 1
+            if i <= 3 then goto top end
+        end
+
+        -- out of nested loops
+        local found
+        for a = 1, 5 do
+            for b = 1, 5 do
+                if a * b == 12 then found = a * 10 + b goto done end
+            end
+        end
+        ::done::
+        return odd, fs[1](), fs[3](), found
+        "#);
+        assert_eq!(state.to_integerx(1), Some(25));
+        assert_eq!(state.to_integerx(2), Some(1));
+        assert_eq!(state.to_integerx(3), Some(3));
+        assert_eq!(state.to_integerx(4), Some(34));
+
+        let mut state = LuaState::new();
+        let errors = [
+            ("goto nowhere", "test: line: 1, error: no visible label 'nowhere' for goto"),
+            ("do ::l:: end goto l", "test: line: 1, error: no visible label 'l' for goto"),
+            ("::l:: do ::l:: end", "test: line: 1, error: label 'l' already defined on line 1"),
+            ("goto l local x ::l:: print(x)", "test: line: 1, error: <goto l> jumps into the scope of local 'x'"),
+            ("repeat goto l local x ::l:: until x", "test: line: 1, error: <goto l> jumps into the scope of local 'x'"),
+        ];
+        for (src, msg) in errors {
+            assert_eq!(state.load(src.as_bytes().to_vec(), "=test", "t"), LUA_ERRSYNTAX, "{}", src);
+            assert_eq!(state.to_string(-1), msg);
+            state.pop(1);
+        }
+    }
+
+    #[test]
+    fn test_concat_len() {
+        let (state, n) = execute(r#"
+        local s = "a" .. 1 .. "b"
+        return s, #s, #""
+        "#);
+        assert_eq!(n, 3);
+        assert_eq!(state.to_string(1), "a1b");
+        assert_eq!(state.to_integerx(2), Some(3));
+        assert_eq!(state.to_integerx(3), Some(0));
+    }
+    #[test]
+    fn test_table() {
+        let (mut state, n) = execute(r#"
+        local t = {10, 20, 30, x = "a", [2.5] = "f"}
+        t[2.0] = 22
+        t[4] = 40
+        t[t] = true
+        local s = 0
+        for i = 1, #t do s = s + t[i] end
+        return t, #t, t[2], t.x, t[5 / 2], t[t], s
+        "#);
+        assert_eq!(n, 7);
+        assert!(state.is_table(1));
+        assert_eq!(state.to_integerx(2), Some(4));
+        assert_eq!(state.to_integerx(3), Some(22));
+        assert_eq!(state.to_string(4), "a");
+        assert_eq!(state.to_string(5), "f");
+        assert!(state.to_boolean(6));
+        assert_eq!(state.to_integerx(7), Some(102));
+
+        assert_eq!(state.get_field(1, "x").unwrap(), LUA_TSTRING);
+        state.push_integer(1);
+        state.set_i(1, 5).unwrap();
+        assert_eq!(state.raw_len(1), 5);
+        assert_eq!(state.get_i(1, 3).unwrap(), LUA_TNUMBER);
+        assert_eq!(state.to_integerx(-1), Some(30));
+
+        state.new_table();
+        state.push_string("k".to_string());
+        state.push_boolean(false);
+        state.raw_set(-3).unwrap();
+        state.push_string("k".to_string());
+        assert_eq!(state.raw_get(-2).unwrap(), LUA_TBOOLEAN);
+    }
+    #[test]
+    fn test_call() {
+        let (state, n) = execute(r#"
+        local function fib(f, n)
+            if n < 2 then return n end
+            return f(f, n - 1) + f(f, n - 2)
+        end
+        local function multi() return 1, 2, 3 end
+        local a, b, c, d = multi()
+        local t = {multi(), multi()}
+        return fib(fib, 15), a + b + c, d, #t, (multi())
+        "#);
+        assert_eq!(n, 5);
+        assert_eq!(state.to_integerx(1), Some(610));
+        assert_eq!(state.to_integerx(2), Some(6));
+        assert!(state.is_nil(3));
+        assert_eq!(state.to_integerx(4), Some(4));
+        assert_eq!(state.to_integerx(5), Some(1));
+    }
+
+    #[test]
+    fn test_vararg_and_tail_call() {
+        let mut state = LuaState::new();
+        let src = r#"
+        local function pack(...) return {...}, ... end
+        local function f(a, ...) local b, c = ... return a, #{...}, c end
+        local function loop(f, n)
+            if n == 0 then return "done" end
+            return f(f, n - 1)
+        end
+        local t, x, y = pack(10, 20, 30)
+        return #t, x, y, loop(loop, 100000), ..., f(1, 2, 3, 4)
+        "#;
+        assert_eq!(state.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        state.push_string("arg".to_string());
+        state.push_nil();
+        state.call(2, 9).unwrap();
+        assert_eq!(state.get_top(), 9);
+        assert_eq!(state.to_integerx(1), Some(3));
+        assert_eq!(state.to_integerx(2), Some(10));
+        assert_eq!(state.to_integerx(3), Some(20));
+        assert_eq!(state.to_string(4), "done");
+        assert_eq!(state.to_string(5), "arg");
+        assert_eq!(state.to_integerx(6), Some(1));
+        assert_eq!(state.to_integerx(7), Some(3));
+        assert_eq!(state.to_integerx(8), Some(3));
+        assert!(state.is_nil(9));
+        assert_eq!(state.frames.len(), 1);
+    }
+
+    #[test]
+    fn test_generic_for_and_method() {
+        let (state, n) = execute(r#"
+        local function iter(t, i)
+            i = i + 1
+            local v = t[i]
+            if v then return i, v end
+        end
+        local s = 0
+        for i, v in iter, {10, 20, 30}, 0 do s = s + i * v end
+        local obj = {n = 5}
+        function obj.get(self, k) return self.n + k end
+        return s, obj:get(1)
+        "#);
+        assert_eq!(n, 2);
+        assert_eq!(state.to_integerx(1), Some(140));
+        assert_eq!(state.to_integerx(2), Some(6));
+    }
+
+    #[test]
+    fn test_load_mode() {
+        let mut state = LuaState::new();
+        assert_eq!(state.load(b"return 1".to_vec(), "test", "b"), LUA_ERRSYNTAX);
+        assert_eq!(state.to_string(-1), "attempt to load a text chunk (mode is 'b')");
+        assert_eq!(state.load(b"return +".to_vec(), "test", "t"), LUA_ERRSYNTAX);
+
+        // malformed binary chunks are errors
+        assert_eq!(state.load(b"\x1bLua".to_vec(), "=bin", "bt"), LUA_ERRSYNTAX);
+        assert_eq!(state.to_string(-1), "bin: truncated precompiled chunk");
+        let mut chunk = std::fs::read("./tests/luac.out").unwrap();
+        chunk.truncate(chunk.len() / 2);
+        assert_eq!(state.load(chunk, "=bin", "b"), LUA_ERRSYNTAX);
+        assert_eq!(state.to_string(-1), "bin: truncated precompiled chunk");
+        let mut chunk = std::fs::read("./tests/luac.out").unwrap();
+        chunk[5] = 1;
+        assert_eq!(state.load(chunk, "=bin", "b"), LUA_ERRSYNTAX);
+        assert_eq!(state.to_string(-1), "bin: bad binary format (format mismatch)");
+    }
+    #[test]
+    fn test_upvalue() {
+        let (state, n) = execute(r#"
+        local function counter()
+            local n = 0
+            return function() n = n + 1 return n end, function() return n end
+        end
+        local inc, get = counter()
+        inc() inc()
+        local inc2 = counter()
+        inc2()
+
+        local fs = {}
+        for i = 1, 3 do
+            local j = i * 10
+            fs[i] = function() return i + j end
+        end
+
+        local function fact(n)
+            if n <= 1 then return 1 end
+            return n * fact(n - 1)
+        end
+
+        g = 1
+        function add_g(x) g = g + x end
+        add_g(41)
+        return get(), inc2(), fs[1](), fs[3](), fact(10), g
+        "#);
+        assert_eq!(n, 6);
+        assert_eq!(state.to_integerx(1), Some(2));
+        assert_eq!(state.to_integerx(2), Some(2));
+        assert_eq!(state.to_integerx(3), Some(11));
+        assert_eq!(state.to_integerx(4), Some(33));
+        assert_eq!(state.to_integerx(5), Some(3628800));
+        assert_eq!(state.to_integerx(6), Some(42));
+    }
+    fn sum(state: &mut LuaState) -> LuaResult<usize> {
+        let n = state.get_top();
+        let mut s = 0;
+        for i in 1..=n {
+            s += state.to_integer(i);
+        }
+        state.push_integer(s);
+        state.push_integer(n as i64);
+        Ok(2)
+    }
+
+    #[test]
+    fn test_rust_function() {
+        use std::cell::Cell;
+        use crate::api::upvalue_index;
+
+        let mut state = LuaState::new();
+        state.register("sum", sum).unwrap();
+
+        // a counter keeping its state in the up value
+        state.push_integer(0);
+        state.push_rust_closure(Box::new(|state: &mut LuaState| {
+            let n = state.to_integer(upvalue_index(1)) + 1;
+            state.push_integer(n);
+            state.replace(upvalue_index(1))?;
+            state.push_integer(n);
+            Ok(1)
+        }), 1);
+        state.set_global("counter").unwrap();
+
+        // a closure capturing Rust state
+        let calls = Rc::new(Cell::new(0));
+        let c = calls.clone();
+        state.push_rust_closure(Box::new(move |_| {
+            c.set(c.get() + 1);
+            Ok(0)
+        }), 0);
+        state.set_global("touch").unwrap();
+
+        let src = r#"
+        touch() touch()
+        local s, n = sum(1, 2, 3)
+        counter()
+        local function f() return counter() end
+        return s, n, f(), (sum(10, 20)), sum()
+        "#;
+        assert_eq!(state.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        state.call(0, LUA_MULTRET).unwrap();
+        assert_eq!(state.get_top(), 6);
+        assert_eq!(state.to_integerx(1), Some(6));
+        assert_eq!(state.to_integerx(2), Some(3));
+        assert_eq!(state.to_integerx(3), Some(2));
+        assert_eq!(state.to_integerx(4), Some(30));
+        assert_eq!(state.to_integerx(5), Some(0));
+        assert_eq!(state.to_integerx(6), Some(0));
+        assert_eq!(calls.get(), 2);
+
+        assert_eq!(state.get_global("sum").unwrap(), LUA_TFUNCTION);
+        assert!(state.is_rust_function(-1));
+        state.push_integer(5);
+        state.call(1, 1).unwrap();
+        assert_eq!(state.to_integerx(-1), Some(5));
+    }
+    fn setmetatable(state: &mut LuaState) -> LuaResult<usize> {
+        state.set_top(2)?;
+        state.set_metatable(1)?;
+        Ok(1)
+    }
+
+    #[test]
+    fn test_metatable() {
+        use crate::api::aux_lib::LuaAuxLib;
+
+        let mut state = LuaState::new();
+        state.register("setmetatable", setmetatable).unwrap();
+        let src = r#"
+        local Vec = {}
+        Vec.__index = Vec
+        function Vec.new(x, y) return setmetatable({x = x, y = y}, Vec) end
+        function Vec:len2() return self.x * self.x + self.y * self.y end
+        Vec.__add = function(a, b) return Vec.new(a.x + b.x, a.y + b.y) end
+        Vec.__unm = function(a) return Vec.new(-a.x, -a.y) end
+        Vec.__eq = function(a, b) return a.x == b.x and a.y == b.y end
+        Vec.__lt = function(a, b) return a:len2() < b:len2() end
+        Vec.__len = function(a) return 2 end
+        Vec.__concat = function(a, b) return "vec" .. b end
+        Vec.__call = function(self, k) return self[k] end
+        Vec.__tostring = function(a) return "(" .. a.x .. ", " .. a.y .. ")" end
+
+        local log = {}
+        local proxy = setmetatable({}, {
+            __index = function(t, k) return k .. "!" end,
+            __newindex = function(t, k, v) log[#log + 1] = k end,
+        })
+        proxy.a = 1
+        proxy.b = 2
+
+        local v = Vec.new(1, 2) + Vec.new(3, 4)
+        local w = -v
+        return v.x, w.y, v == Vec.new(4, 6), v ~= w, w < v, w <= v, #v, v .. 1,
+            v("y"), proxy.z, #log, v
+        "#;
+        assert_eq!(state.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        state.call(0, LUA_MULTRET).unwrap();
+        assert_eq!(state.get_top(), 12);
+        assert_eq!(state.to_integerx(1), Some(4));
+        assert_eq!(state.to_integerx(2), Some(-6));
+        assert!(state.to_boolean(3));
+        assert!(state.to_boolean(4));
+        assert!(!state.to_boolean(5));
+        assert!(state.to_boolean(6));
+        assert_eq!(state.to_integerx(7), Some(2));
+        assert_eq!(state.to_string(8), "vec1");
+        assert_eq!(state.to_integerx(9), Some(6));
+        assert_eq!(state.to_string(10), "z!");
+        assert_eq!(state.to_integerx(11), Some(2));
+        assert_eq!(state.to_string2(12).unwrap(), "(4, 6)");
+
+        // metatable shared by all strings
+        state.push_string("s".to_string());
+        state.new_table();
+        state.new_table();
+        state.push_integer(42);
+        state.set_field(-2, "answer").unwrap();
+        state.set_field(-2, "__index").unwrap();
+        state.set_metatable(-2).unwrap();
+        assert_eq!(state.get_field(-1, "answer").unwrap(), LUA_TNUMBER);
+        assert_eq!(state.to_integerx(-1), Some(42));
+    }
+
+    #[test]
+    fn test_gc_cycles() {
+        let src = r#"
+        local t = {}
+        t.self = t
+        local f
+        f = function() return f, t end
+        cycle = {t, f}
+        keep = {}
+        keep.self = keep
+        keep.f = function() return keep end
+        "#;
+        let (mut state, _) = execute(src);
+        state.get_global("cycle").unwrap();
+        let (t, f) = match state.get_value(-1) {
+            LuaValue::Table(cycle) => {
+                let cycle = cycle.borrow();
+                match (cycle.get_int(1), cycle.get_int(2)) {
+                    (LuaValue::Table(t), LuaValue::Function(f)) => (Rc::downgrade(&t), Rc::downgrade(&f)),
+                    _ => panic!("unexpected values"),
+                }
+            }
+            _ => panic!("table expected"),
+        };
+        state.pop(1);
+        state.push_nil();
+        state.set_global("cycle").unwrap();
+
+        let before = state.gc(LUA_GCCOUNT, 0).unwrap() * 1024 + state.gc(LUA_GCCOUNTB, 0).unwrap();
+        state.gc(LUA_GCCOLLECT, 0).unwrap();
+        let after = state.gc(LUA_GCCOUNT, 0).unwrap() * 1024 + state.gc(LUA_GCCOUNTB, 0).unwrap();
+        assert!(t.upgrade().is_none());
+        assert!(f.upgrade().is_none());
+        assert!(after < before);
+
+        // reachable cycles survive
+        let src = "return keep.f() == keep and keep.self == keep";
+        assert_eq!(state.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        state.call(0, 1).unwrap();
+        assert!(state.to_boolean(-1));
+    }
+
+    #[test]
+    fn test_gc_incremental() {
+        let mut state = LuaState::new();
+        assert_eq!(state.gc(LUA_GCSETPAUSE, 150).unwrap(), 200);
+        assert_eq!(state.gc(LUA_GCISRUNNING, 0).unwrap(), 1);
+        let src = r#"
+        local live = {}
+        for i = 1, 20000 do
+            local t = {i = i}
+            t.self = t
+            t.f = function() return t end
+            if i % 100 == 0 then live[#live + 1] = t end
+        end
+        local sum = 0
+        for j = 1, #live do
+            local t = live[j]
+            if t.f() == t and t.self == t then sum = sum + t.i end
+        end
+        return sum
+        "#;
+        assert_eq!(state.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        state.call(0, 1).unwrap();
+        assert_eq!(state.to_integerx(-1), Some((1..=200).map(|i| i * 100).sum()));
+        // the garbage cycles have been collected in the steps
+        assert!(state.gc(LUA_GCCOUNT, 0).unwrap() < 1024);
+
+        state.gc(LUA_GCSTOP, 0).unwrap();
+        assert_eq!(state.gc(LUA_GCISRUNNING, 0).unwrap(), 0);
+    }
+
+    fn collectgarbage(state: &mut LuaState) -> LuaResult<usize> {
+        state.gc(LUA_GCCOLLECT, 0)?;
+        Ok(0)
+    }
+
+    #[test]
+    fn test_weak_tables() {
+        let mut state = LuaState::new();
+        state.register("setmetatable", setmetatable).unwrap();
+        state.register("collectgarbage", collectgarbage).unwrap();
+        let src = r#"
+        local keep = {}
+        local wk = setmetatable({}, {__mode = "k"})
+        local wv = setmetatable({}, {__mode = "v"})
+        local wkv = setmetatable({}, {__mode = "kv"})
+        wk[keep] = 1
+        wk[{}] = 2
+        local e = {}
+        wk[e] = {e} -- ephemeron: the value refers to its own key
+        wk[{}] = keep
+        wv[1] = keep
+        wv[2] = {}
+        wv.x = function() end
+        wv.s = "strings are not collected"
+        wkv[keep] = {}
+        wkv[{}] = keep
+        e = nil
+        collectgarbage()
+        return wk, wk[keep], wv[1] == keep, wv[2], wv.x, wv.s, wkv
+        "#;
+        assert_eq!(state.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        state.call(0, LUA_MULTRET).unwrap();
+        let entries = |val: LuaValue| match val {
+            LuaValue::Table(t) => t.borrow().hash_part().count(),
+            _ => panic!("table expected"),
+        };
+        assert_eq!(entries(state.get_value(1)), 1);
+        assert_eq!(state.to_integerx(2), Some(1));
+        assert!(state.to_boolean(3));
+        assert!(state.is_nil(4));
+        assert!(state.is_nil(5));
+        assert_eq!(state.to_string(6), "strings are not collected");
+        assert_eq!(entries(state.get_value(7)), 0);
+    }
+
+    #[test]
+    fn test_finalizers() {
+        let mut state = LuaState::new();
+        state.register("setmetatable", setmetatable).unwrap();
+        state.register("collectgarbage", collectgarbage).unwrap();
+        let src = r#"
+        log = ""
+        local mt = {__gc = function(o)
+            log = log .. o.name
+            if o.name == "b" then saved = o end
+        end}
+        local cache = setmetatable({}, {__mode = "k"})
+        local values = setmetatable({}, {__mode = "v"})
+        local a = setmetatable({name = "a"}, mt)
+        local b = setmetatable({name = "b"}, mt)
+        local c = setmetatable({name = "c"}, mt)
+        -- `__gc` added after the metatable is set is ignored
+        local dmt = {}
+        local d = setmetatable({name = "d"}, dmt)
+        dmt.__gc = mt.__gc
+        -- setting the metatable again does not finalize twice
+        local e = setmetatable({name = "e"}, mt)
+        setmetatable(e, mt)
+        cache[b] = true
+        values[1] = b
+        a, b, c, d, e = nil, nil, nil, nil, nil
+        collectgarbage()
+        local first = log
+        -- the resurrected object stays in weak keys but not in weak values
+        local cached = cache[saved]
+        local valued = values[1]
+        saved = nil
+        collectgarbage()
+        return first, cached, valued, log
+        "#;
+        assert_eq!(state.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        state.call(0, LUA_MULTRET).unwrap();
+        assert_eq!(state.to_string(1), "ecba");
+        assert!(state.to_boolean(2));
+        assert!(state.is_nil(3));
+        // finalizers are called only once
+        assert_eq!(state.to_string(4), "ecba");
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let cases = [
+            ("local t\nt.x = 1", "[string \"test\"]:2: attempt to index a nil value"),
+            ("local t = {}\nt[nil] = 1", "[string \"test\"]:2: table index is nil"),
+            ("local t = {}\nt[0/0] = 1", "[string \"test\"]:2: table index is NaN"),
+            ("return 1 % 0", "[string \"test\"]:1: attempt to perform 'n%0'"),
+            ("return {} .. 'x'", "[string \"test\"]:1: attempt to concatenate a table value"),
+            ("return -{}", "[string \"test\"]:1: attempt to perform arithmetic on a table value"),
+            ("return 1.5 | 1", "[string \"test\"]:1: number has no integer representation"),
+            ("return #5", "[string \"test\"]:1: attempt to get length of a number value"),
+            ("return {} < {}", "[string \"test\"]:1: attempt to compare two table values"),
+            ("\n\nundefined()", "[string \"test\"]:3: attempt to call a nil value"),
+            ("for i = 1, 'x' do end", "[string \"test\"]:1: 'for' limit must be a number"),
+        ];
+        for (src, msg) in cases.iter() {
+            let mut state = LuaState::new();
+            assert_eq!(state.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+            let err = state.call(0, 0).unwrap_err();
+            assert_eq!(err.to_string(), *msg);
+            assert_eq!(state.frames.len(), 1);
+            assert_eq!(state.get_top(), 0);
+        }
+    }
+
+    #[test]
+    fn test_finalizer_errors() {
+        let mut state = LuaState::new();
+        state.register("setmetatable", setmetatable).unwrap();
+        let src = r#"
+        setmetatable({}, {__gc = function() local t; t.x = 1 end})
+        for i = 1, 100000 do local t = {} end
+        "#;
+        assert_eq!(state.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        let err = state.call(0, 0).unwrap_err();
+        assert_eq!(err.status, LUA_ERRGCMM);
+        let msg = "error in __gc metamethod ([string \"test\"]:2: attempt to index a nil value)";
+        assert_eq!(err.to_string(), msg);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut state = LuaState::new();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let log = events.clone();
+        let hook: LuaHook = Rc::new(move |ls, ar| {
+            ls.get_info("Sl", ar);
+            log.borrow_mut().push(format!("{}@{}", ar.event, ar.current_line));
+            Ok(())
+        });
+        state.set_hook(Some(hook), LUA_MASKCALL | LUA_MASKRET | LUA_MASKLINE, 0);
+        assert_eq!(state.get_hook_mask(), LUA_MASKCALL | LUA_MASKRET | LUA_MASKLINE);
+        let src = "local function f()\n  return 1\nend\nlocal x = f()\nfor i = 1, 2 do\nend";
+        assert_eq!(state.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        state.call(0, 0).unwrap();
+        // a call event is at the first line, and a loop jumping back repeats the line event
+        let expected = ["0@3", "2@3", "2@4", "0@2", "2@2", "1@2", "2@5", "2@5", "2@5", "2@6", "1@6"];
+        assert_eq!(*events.borrow(), expected);
+
+        // a count hook stops an endless loop
+        let hook: LuaHook = Rc::new(|ls, ar| {
+            ls.get_info("Sl", ar);
+            let msg = format!("{}:{}: too many instructions", ar.short_src, ar.current_line);
+            Err(LuaError::runtime(&msg, None))
+        });
+        state.set_hook(Some(hook), LUA_MASKCOUNT, 1000);
+        assert_eq!(state.load(b"local n = 0\nwhile true do n = n + 1 end".to_vec(), "=loop", "t"), LUA_OK);
+        let err = state.call(0, 0).unwrap_err();
+        assert_eq!(err.to_string(), "loop:2: too many instructions");
+        assert_eq!(state.frames.len(), 1);
+        state.set_hook(None, 0, 0);
+        assert!(state.get_hook().is_none());
+    }
+
+    #[test]
+    fn test_interrupt() {
+        use crate::api::aux_lib::LuaAuxLib;
+
+        let mut state = LuaState::new();
+        state.open_libs().unwrap();
+        let handle = state.interrupt_handle();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            handle.interrupt();
+        });
+        // the error is raised again after it is caught
+        let src = "while true do pcall(function() while true do end end) end";
+        assert_eq!(state.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        let err = state.call(0, 0).unwrap_err();
+        t.join().unwrap();
+        assert_eq!(err.to_string(), "test:1: interrupted");
+        // the next call runs normally
+        assert_eq!(state.load(b"return 1".to_vec(), "=test", "t"), LUA_OK);
+        state.call(0, 1).unwrap();
+        assert_eq!(state.to_integerx(-1), Some(1));
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        use crate::api::aux_lib::LuaAuxLib;
+
+        let mut state = LuaState::new();
+        state.open_libs().unwrap();
+        state.set_memory_limit(Some(4 << 20));
+        let src = r#"
+        local t = {}
+        local ok1, err1 = pcall(function() for i = 1, 1e7 do t[i] = i end end)
+        t = nil
+        local ok2, err2 = pcall(string.rep, "x", 1e8)
+        local ok3, err3 = pcall(function() local s = {} for i = 1, 1e6 do s[i] = "item" .. i end end)
+        local ok4, err4 = pcall(function() local s = "x" for i = 1, 30 do s = s .. s end end)
+        -- the garbage is collected, so the state is still usable
+        local u = {}
+        for i = 1, 1000 do u[i] = string.rep("y", 100) end
+        return ok1, err1, ok2, err2, ok3, err3, ok4, err4, #u
+        "#;
+        assert_eq!(state.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        state.call(0, LUA_MULTRET).unwrap();
+        for i in 0..4 {
+            assert!(!state.to_boolean(i * 2 + 1));
+            assert_eq!(state.to_string(i * 2 + 2), "not enough memory");
+        }
+        assert_eq!(state.to_integerx(9), Some(1000));
+        assert!(state.memory_used() <= 4 << 20);
+
+        // an error not caught is of status `LUA_ERRMEM`
+        assert_eq!(state.load(b"local t = {} for i = 1, 1e7 do t[i] = {} end".to_vec(), "=test", "t"), LUA_OK);
+        assert_eq!(state.call(0, 0).unwrap_err().status, LUA_ERRMEM);
+        state.set_memory_limit(None);
+        assert_eq!(state.load(b"local t = {} for i = 1, 2e5 do t[i] = i end".to_vec(), "=test", "t"), LUA_OK);
+        state.call(0, 0).unwrap();
+    }
+
+    #[test]
+    fn test_memory_limit_before_allocation() {
+        use crate::api::aux_lib::LuaAuxLib;
+
+        let mut state = LuaState::new();
+        state.open_libs().unwrap();
+        state.set_memory_limit(Some(2 << 20));
+        // the memory in use is counted right after each error, before the garbage is collected
+        let src = r#"
+        local s = "x"
+        local ok1 = pcall(function() while true do s = s .. s end end)
+        local kb1 = collectgarbage("count")
+        s = nil
+        collectgarbage()
+        local t = {}
+        local ok2 = pcall(function() for i = 1, 1e7 do t[i] = i end end)
+        local kb2 = collectgarbage("count")
+        t = nil
+        collectgarbage()
+        local list = {}
+        for i = 1, 16 do list[i] = string.rep("y", 1 << 16) end
+        local ok3 = pcall(function() while true do list[1] = table.concat(list) end end)
+        local kb3 = collectgarbage("count")
+        return ok1, kb1, ok2, kb2, ok3, kb3
+        "#;
+        assert_eq!(state.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        state.call(0, LUA_MULTRET).unwrap();
+        for i in 0..3 {
+            assert!(!state.to_boolean(i * 2 + 1));
+            assert!(state.to_number(i * 2 + 2) <= 2048.0, "case {}: {} KB", i + 1, state.to_number(i * 2 + 2));
+        }
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        use crate::api::aux_lib::LuaAuxLib;
+
+        let mut state = LuaState::new();
+        state.open_libs().unwrap();
+        let src = r#"
+        local function f() return 1 + f() end
+        local function g() local ok, err = pcall(g) if not ok then error(err, 0) end end
+        local mt = {}
+        mt.__index = function(t, k) return t[k] end
+        return select(2, pcall(f)), select(2, pcall(g)), select(2, pcall(function() return setmetatable({}, mt).x end))
+        "#;
+        assert_eq!(state.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        state.call(0, LUA_MULTRET).unwrap();
+        assert_eq!(state.to_string(1), "test:2: stack overflow");
+        assert_eq!(state.to_string(2), "C stack overflow");
+        assert_eq!(state.to_string(3), "test:5: C stack overflow");
+        assert!(!state.check_stack(LUAI_MAXSTACK as usize));
+        assert!(state.check_stack(100));
     }
 }
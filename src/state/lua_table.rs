@@ -0,0 +1,346 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::rc::Rc;
+
+use crate::state::lua_value::{float_to_integer, LuaValue};
+
+/// Lua Table with an array part and a hash part
+pub struct LuaTable {
+    /// values of the keys 1..=n
+    arr: Vec<LuaValue>,
+    /// Entries of the other keys in the order of `next`, whose keys are never nil or NaN.
+    /// A removed entry is kept with a nil value as a dead key, so a traversal can go on from it,
+    /// until the slots are rehashed for a new key.
+    slots: Vec<(LuaValue, LuaValue)>,
+    /// Slots indexed by their keys
+    map: HashMap<LuaValue, usize>,
+    /// The number of dead keys in the slots
+    n_dead: usize,
+    pub metatable: Option<Rc<RefCell<LuaTable>>>,
+}
+
+impl LuaTable {
+    pub fn new(narr: usize, nrec: usize) -> LuaTable {
+        LuaTable {
+            arr: Vec::with_capacity(narr),
+            slots: Vec::with_capacity(nrec),
+            map: HashMap::with_capacity(nrec),
+            n_dead: 0,
+            metatable: None,
+        }
+    }
+
+    /// The length of the array part, which is a border of the table
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.arr.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.arr.is_empty() && self.hash_len() == 0
+    }
+
+    pub fn get(&self, key: &LuaValue) -> LuaValue {
+        let key = normalize_key(key);
+        if let LuaValue::Integer(i) = key {
+            if let Some(val) = self.get_array(i) {
+                return val.clone();
+            }
+        }
+        self.get_hash(&key)
+    }
+
+    #[inline]
+    pub fn get_int(&self, i: i64) -> LuaValue {
+        match self.get_array(i) {
+            Some(val) => val.clone(),
+            None => self.get_hash(&LuaValue::Integer(i)),
+        }
+    }
+
+    pub fn put(&mut self, key: LuaValue, val: LuaValue) {
+        let key = match key {
+            LuaValue::Nil => panic!("table index is nil"),
+            LuaValue::Number(n) if n.is_nan() => panic!("table index is NaN"),
+            key => normalize_key(&key),
+        };
+
+        if let LuaValue::Integer(i) = key {
+            if i >= 1 {
+                let len = self.arr.len() as i64;
+                if i <= len {
+                    let is_nil = val.is_nil();
+                    self.arr[i as usize - 1] = val;
+                    if i == len && is_nil {
+                        self.shrink_array();
+                    }
+                    return;
+                }
+                if i == len + 1 {
+                    self.remove_hash(&key);
+                    if !val.is_nil() {
+                        self.arr.push(val);
+                        self.expand_array();
+                    }
+                    return;
+                }
+            }
+        }
+
+        if val.is_nil() {
+            self.remove_hash(&key);
+        } else {
+            self.put_hash(key, val);
+        }
+    }
+
+    /// The entry following `key` in the traversal, the first one if it is nil,
+    /// and `None` at the end. The keys added during a traversal may be missed.
+    pub fn next(&self, key: &LuaValue) -> Result<Option<(LuaValue, LuaValue)>, &'static str> {
+        let key = normalize_key(key);
+        let pos = match key {
+            LuaValue::Nil => 0,
+            // the array part may be shrunk by clearing its fields during the traversal
+            LuaValue::Integer(i) if i >= 1 && (i as usize <= self.arr.len() || !self.map.contains_key(&key)) => i as usize,
+            _ => match self.map.get(&key) {
+                Some(&slot) => return Ok(self.next_in_hash(slot + 1)),
+                None => return Err("invalid key to 'next'"),
+            },
+        };
+
+        // the array part comes first
+        for i in pos..self.arr.len() {
+            if !self.arr[i].is_nil() {
+                return Ok(Some((LuaValue::Integer(i as i64 + 1), self.arr[i].clone())));
+            }
+        }
+        Ok(self.next_in_hash(0))
+    }
+
+    /// The first live entry from the slot `pos` of the hash part
+    fn next_in_hash(&self, pos: usize) -> Option<(LuaValue, LuaValue)> {
+        self.slots[pos..].iter().find(|(_, v)| !v.is_nil()).cloned()
+    }
+
+    /// The number of entries in the array and hash parts
+    #[inline]
+    pub fn entries(&self) -> usize {
+        self.arr.len() + self.hash_len()
+    }
+
+    /// Estimated bytes used by the table and its strings
+    pub fn size_estimate(&self) -> usize {
+        let strings: usize = self.arr.iter().map(LuaValue::heap_size).sum::<usize>()
+            + self.hash_part().map(|(k, v)| k.heap_size() + v.heap_size()).sum::<usize>();
+        size_of::<LuaTable>() + (self.arr.len() + self.slots.len() * 2) * size_of::<LuaValue>() + strings
+    }
+
+    /// Estimated bytes allocated when a value is put at a new `key`,
+    /// the array or the hash part is full and resized to twice its capacity
+    pub fn growth(&self, key: &LuaValue) -> usize {
+        match normalize_key(key) {
+            LuaValue::Integer(i) if i >= 1 && i as usize <= self.arr.len() => 0,
+            LuaValue::Integer(i) if i >= 1 && i as usize == self.arr.len() + 1 => {
+                if self.arr.len() < self.arr.capacity() {
+                    0
+                } else {
+                    self.arr.capacity().max(4) * size_of::<LuaValue>()
+                }
+            }
+            key if self.slots.len() < self.slots.capacity() || self.should_rehash() || self.map.contains_key(&key) => 0,
+            _ => self.slots.capacity().max(4) * 2 * size_of::<LuaValue>(),
+        }
+    }
+
+    /// Values of the keys 1..=n
+    #[inline]
+    pub fn array_part(&self) -> &[LuaValue] {
+        &self.arr
+    }
+
+    /// Live entries of the hash part
+    #[inline]
+    pub fn hash_part(&self) -> impl Iterator<Item = (&LuaValue, &LuaValue)> {
+        self.slots.iter().filter(|(_, v)| !v.is_nil()).map(|(k, v)| (k, v))
+    }
+
+    /// Remove the entries for which `f` returns false
+    pub fn retain<F: FnMut(&LuaValue, &LuaValue) -> bool>(&mut self, mut f: F) {
+        for (i, v) in self.arr.iter_mut().enumerate() {
+            if !v.is_nil() && !f(&LuaValue::Integer(i as i64 + 1), v) {
+                *v = LuaValue::Nil;
+            }
+        }
+        self.shrink_array();
+        for (k, v) in self.slots.iter_mut() {
+            if !v.is_nil() && !f(k, v) {
+                *v = LuaValue::Nil;
+                self.n_dead += 1;
+            }
+        }
+    }
+
+    /// Remove all entries and the metatable
+    pub fn clear(&mut self) {
+        self.arr = Vec::new();
+        self.slots = Vec::new();
+        self.map = HashMap::new();
+        self.n_dead = 0;
+        self.metatable = None;
+    }
+
+    #[inline]
+    fn get_array(&self, i: i64) -> Option<&LuaValue> {
+        if i >= 1 && i <= self.arr.len() as i64 {
+            Some(&self.arr[i as usize - 1])
+        } else {
+            None
+        }
+    }
+
+    /// The number of live entries in the hash part
+    #[inline]
+    fn hash_len(&self) -> usize {
+        self.slots.len() - self.n_dead
+    }
+
+    #[inline]
+    fn get_hash(&self, key: &LuaValue) -> LuaValue {
+        match self.map.get(key) {
+            Some(&slot) => self.slots[slot].1.clone(),
+            None => LuaValue::Nil,
+        }
+    }
+
+    fn put_hash(&mut self, key: LuaValue, val: LuaValue) {
+        if let Some(&slot) = self.map.get(&key) {
+            if self.slots[slot].1.is_nil() {
+                self.n_dead -= 1;
+            }
+            self.slots[slot].1 = val;
+            return;
+        }
+        if self.should_rehash() {
+            self.rehash();
+        }
+        self.map.insert(key.clone(), self.slots.len());
+        self.slots.push((key, val));
+    }
+
+    /// Remove the entry of `key` from the hash part and return its value, the key is left dead
+    fn remove_hash(&mut self, key: &LuaValue) -> Option<LuaValue> {
+        let &slot = self.map.get(key)?;
+        let val = std::mem::replace(&mut self.slots[slot].1, LuaValue::Nil);
+        if val.is_nil() {
+            return None;
+        }
+        self.n_dead += 1;
+        Some(val)
+    }
+
+    /// Whether the slots are full and half of them are dead, then they are rehashed instead of grown
+    #[inline]
+    fn should_rehash(&self) -> bool {
+        self.slots.len() == self.slots.capacity() && self.n_dead > 0 && self.n_dead * 2 >= self.slots.len()
+    }
+
+    /// Drop the dead keys to make room for a new key, the order of the others is kept
+    fn rehash(&mut self) {
+        self.slots.retain(|(_, v)| !v.is_nil());
+        self.map.clear();
+        for (slot, (k, _)) in self.slots.iter().enumerate() {
+            self.map.insert(k.clone(), slot);
+        }
+        self.n_dead = 0;
+    }
+
+    /// Remove the trailing nils of the array part
+    fn shrink_array(&mut self) {
+        while let Some(LuaValue::Nil) = self.arr.last() {
+            self.arr.pop();
+        }
+    }
+
+    /// Move the following integer keys from the hash part to the array part
+    fn expand_array(&mut self) {
+        let mut i = self.arr.len() as i64 + 1;
+        while let Some(val) = self.remove_hash(&LuaValue::Integer(i)) {
+            self.arr.push(val);
+            i += 1;
+        }
+    }
+}
+
+/// Floats with an exact integer value are converted to integers,
+/// so `t[2.0]` and `t[2]` refer to the same slot
+fn normalize_key(key: &LuaValue) -> LuaValue {
+    match key {
+        LuaValue::Number(n) => match float_to_integer(*n) {
+            Some(i) => LuaValue::Integer(i),
+            None => key.clone(),
+        },
+        _ => key.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_array_and_hash() {
+        let mut t = LuaTable::new(0, 0);
+        t.put(LuaValue::Integer(2), LuaValue::Integer(20));
+        assert_eq!(t.len(), 0);
+        t.put(LuaValue::Number(1.0), LuaValue::Integer(10));
+        assert_eq!(t.len(), 2);
+        assert!(matches!(t.get(&LuaValue::Number(2.0)), LuaValue::Integer(20)));
+        assert!(matches!(t.get_int(1), LuaValue::Integer(10)));
+
+        t.put(LuaValue::Number(1.5), LuaValue::Boolean(true));
+        assert!(matches!(t.get(&LuaValue::Number(1.5)), LuaValue::Boolean(true)));
+
+        t.put(LuaValue::Integer(2), LuaValue::Nil);
+        assert_eq!(t.len(), 1);
+        assert!(t.get_int(2).is_nil());
+    }
+
+    #[test]
+    #[should_panic(expected = "table index is NaN")]
+    fn test_nan_key() {
+        let mut t = LuaTable::new(0, 0);
+        t.put(LuaValue::Number(f64::NAN), LuaValue::Integer(1));
+    }
+
+    #[test]
+    fn test_next_with_dead_keys() {
+        let key = |i: i64| LuaValue::new_string(format!("k{}", i).into_bytes());
+        let mut t = LuaTable::new(0, 0);
+        for i in 0..100 {
+            t.put(key(i), LuaValue::Integer(i));
+        }
+        // clear the fields during the traversal
+        let mut k = LuaValue::Nil;
+        let mut n = 0;
+        while let Some((next, _)) = t.next(&k).unwrap() {
+            t.put(next.clone(), LuaValue::Nil);
+            k = next;
+            n += 1;
+        }
+        assert_eq!(n, 100);
+        assert!(t.is_empty());
+        assert!(t.next(&key(0)).unwrap().is_none());
+        assert!(matches!(t.next(&key(100)), Err("invalid key to 'next'")));
+
+        // the dead keys are dropped instead of growing the slots
+        for i in 100..10000 {
+            t.put(key(i), LuaValue::Integer(i));
+            t.put(key(i - 1), LuaValue::Nil);
+        }
+        assert_eq!(t.entries(), 1);
+        assert!(t.slots.len() <= 256);
+        assert!(matches!(t.next(&LuaValue::Nil), Ok(Some((_, LuaValue::Integer(9999))))));
+    }
+}
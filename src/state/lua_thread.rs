@@ -0,0 +1,42 @@
+use std::mem::size_of;
+
+use crate::api::consts::{LUA_MINSTACK, LUA_OK};
+use crate::state::lua_debug::HookState;
+use crate::state::lua_stack::LuaStack;
+
+/// Lua Thread, the call frames of a coroutine.
+///
+/// The frames of the running thread are moved into `LuaState`,
+/// so they are empty here while it is running or resuming another one.
+pub struct LuaThread {
+    /// Call frames, the first one holds the body function before the first resume
+    pub frames: Vec<LuaStack>,
+    /// `LUA_OK`, `LUA_YIELD` or the error status it died with
+    pub status: i8,
+    /// The number of non-yieldable calls in the running thread
+    pub nny: usize,
+    /// The hook of the thread, which is inherited by the threads it creates
+    pub(crate) hook: Option<HookState>,
+}
+
+impl Default for LuaThread {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LuaThread {
+    pub fn new() -> LuaThread {
+        LuaThread {
+            frames: vec![LuaStack::new(LUA_MINSTACK, None)],
+            status: LUA_OK,
+            nny: 0,
+            hook: None,
+        }
+    }
+
+    /// Estimated bytes used by the thread
+    pub fn size_estimate(&self) -> usize {
+        size_of::<LuaThread>() + self.frames.iter().map(LuaStack::size_estimate).sum::<usize>()
+    }
+}
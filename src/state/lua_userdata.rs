@@ -0,0 +1,37 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::mem::{size_of, size_of_val};
+use std::rc::Rc;
+
+use crate::state::lua_table::LuaTable;
+
+/// Full userdata, a Rust value with its own metatable
+pub struct LuaUserData {
+    data: Box<dyn Any>,
+    pub metatable: Option<Rc<RefCell<LuaTable>>>,
+}
+
+impl LuaUserData {
+    pub fn new(data: Box<dyn Any>) -> LuaUserData {
+        LuaUserData { data, metatable: None }
+    }
+
+    #[inline]
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.data.downcast_ref()
+    }
+
+    #[inline]
+    pub fn downcast_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.data.downcast_mut()
+    }
+
+    pub fn size_estimate(&self) -> usize {
+        size_of::<LuaUserData>() + size_of_val(&*self.data)
+    }
+
+    /// Remove the metatable, which breaks the cycles through it
+    pub fn clear(&mut self) {
+        self.metatable = None;
+    }
+}
@@ -1,4 +1,14 @@
+use std::cell::RefCell;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
 use crate::api::consts::*;
+use crate::api::RustFn;
+use crate::binary::chunk::Constant;
+use crate::state::closure::Closure;
+use crate::state::lua_table::LuaTable;
+use crate::state::lua_thread::LuaThread;
+use crate::state::lua_userdata::LuaUserData;
 
 /// Lua Basic Type Value
 #[derive(Clone)]
@@ -7,10 +17,27 @@ pub enum LuaValue {
     Boolean(bool),
     Number(f64),
     Integer(i64),
-    String(String),
+    /// The bytes of a string, which is not necessarily valid UTF-8 and shared by its copies
+    String(Rc<Vec<u8>>),
+    Table(Rc<RefCell<LuaTable>>),
+    Function(Rc<Closure>),
+    /// Rust function without up values
+    RustFunction(RustFn),
+    Thread(Rc<RefCell<LuaThread>>),
+    UserData(Rc<RefCell<LuaUserData>>),
 }
 
 impl LuaValue {
+    /// Estimated bytes owned by the value besides itself, which are the bytes of a string
+    /// divided among its copies, so a string is counted once
+    #[inline]
+    pub fn heap_size(&self) -> usize {
+        match self {
+            LuaValue::String(s) => s.len().div_ceil(Rc::strong_count(s)),
+            _ => 0,
+        }
+    }
+
     pub fn type_id(&self) -> i8 {
         match self {
             LuaValue::Nil => LUA_TNIL,
@@ -18,9 +45,29 @@ impl LuaValue {
             LuaValue::Number(_) => LUA_TNUMBER,
             LuaValue::Integer(_) => LUA_TNUMBER,
             LuaValue::String(_) => LUA_TSTRING,
+            LuaValue::Table(_) => LUA_TTABLE,
+            LuaValue::Function(_) => LUA_TFUNCTION,
+            LuaValue::RustFunction(_) => LUA_TFUNCTION,
+            LuaValue::Thread(_) => LUA_TTHREAD,
+            LuaValue::UserData(_) => LUA_TUSERDATA,
         }
     }
 
+    #[inline]
+    pub fn new_string(s: Vec<u8>) -> LuaValue {
+        LuaValue::String(Rc::new(s))
+    }
+
+    #[inline]
+    pub fn new_table(narr: usize, nrec: usize) -> LuaValue {
+        LuaValue::Table(Rc::new(RefCell::new(LuaTable::new(narr, nrec))))
+    }
+
+    #[inline]
+    pub fn is_nil(&self) -> bool {
+        matches!(self, LuaValue::Nil)
+    }
+
     pub fn to_boolean(&self) -> bool {
         match self {
             LuaValue::Nil => false,
@@ -34,7 +81,10 @@ impl LuaValue {
         match self {
             LuaValue::Integer(i) => Some(*i as f64),
             LuaValue::Number(n) => Some(*n),
-            LuaValue::String(s) => s.parse::<f64>().ok(), // TODO
+            LuaValue::String(s) => match bytes_to_number(s)? {
+                LuaValue::Integer(i) => Some(i as f64),
+                n => n.to_number(),
+            },
             _ => None,
         }
     }
@@ -50,21 +100,162 @@ impl LuaValue {
     }
 }
 
-fn float_to_integer(n: f64) -> Option<i64> {
-    let i = n as i64;
-    if i as f64 == n {
-        Some(i)
-    } else {
-        None
+/// The name of a basic type
+pub fn type_name(tp: i8) -> &'static str {
+    match tp {
+        LUA_TNONE => "no value",
+        LUA_TNIL => "nil",
+        LUA_TBOOLEAN => "boolean",
+        LUA_TNUMBER => "number",
+        LUA_TSTRING => "string",
+        LUA_TTABLE => "table",
+        LUA_TFUNCTION => "function",
+        LUA_TTHREAD => "thread",
+        LUA_TLIGHTUSERDATA => "userdata",
+        LUA_TUSERDATA => "userdata",
+        _ => "?", // TODO
+    }
+}
+
+/// Raw equality, used for table keys
+impl PartialEq for LuaValue {
+    fn eq(&self, other: &LuaValue) -> bool {
+        match (self, other) {
+            (LuaValue::Nil, LuaValue::Nil) => true,
+            (LuaValue::Boolean(x), LuaValue::Boolean(y)) => x == y,
+            (LuaValue::Integer(x), LuaValue::Integer(y)) => x == y,
+            (LuaValue::Number(x), LuaValue::Number(y)) => x == y,
+            (LuaValue::String(x), LuaValue::String(y)) => x == y,
+            (LuaValue::Table(x), LuaValue::Table(y)) => Rc::ptr_eq(x, y),
+            (LuaValue::Function(x), LuaValue::Function(y)) => Rc::ptr_eq(x, y),
+            (LuaValue::RustFunction(x), LuaValue::RustFunction(y)) => *x as usize == *y as usize,
+            (LuaValue::Thread(x), LuaValue::Thread(y)) => Rc::ptr_eq(x, y),
+            (LuaValue::UserData(x), LuaValue::UserData(y)) => Rc::ptr_eq(x, y),
+            _ => false,
+        }
+    }
+}
+
+// NaN can not be a table key
+impl Eq for LuaValue {}
+
+impl Hash for LuaValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            LuaValue::Nil => 0.hash(state),
+            LuaValue::Boolean(b) => b.hash(state),
+            LuaValue::Integer(i) => i.hash(state),
+            LuaValue::Number(n) => n.to_bits().hash(state),
+            LuaValue::String(s) => s.hash(state),
+            LuaValue::Table(t) => Rc::as_ptr(t).hash(state),
+            LuaValue::Function(f) => Rc::as_ptr(f).hash(state),
+            LuaValue::RustFunction(f) => (*f as usize).hash(state),
+            LuaValue::Thread(t) => Rc::as_ptr(t).hash(state),
+            LuaValue::UserData(u) => Rc::as_ptr(u).hash(state),
+        }
+    }
+}
+
+impl From<&Constant> for LuaValue {
+    fn from(cst: &Constant) -> Self {
+        match cst {
+            Constant::Nil => LuaValue::Nil,
+            Constant::Boolean(b) => LuaValue::Boolean(*b),
+            Constant::Number(n) => LuaValue::Number(*n),
+            Constant::Integer(i) => LuaValue::Integer(*i),
+            Constant::String(s) => LuaValue::new_string(s.clone()),
+        }
     }
 }
 
-fn string_to_integer(s: &String) -> Option<i64> {
-    if let Ok(i) = s.parse::<i64>() {
-        Some(i)
-    } else if let Ok(n) = s.parse::<f64>() {
-        float_to_integer(n)
+/// Convert a float with exact integral value in the range of i64
+pub fn float_to_integer(n: f64) -> Option<i64> {
+    // -2^63 <= n < 2^63
+    if n.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&n) {
+        Some(n as i64)
     } else {
         None
     }
 }
+
+fn string_to_integer(s: &[u8]) -> Option<i64> {
+    match bytes_to_number(s)? {
+        LuaValue::Integer(i) => Some(i),
+        LuaValue::Number(n) => float_to_integer(n),
+        _ => None,
+    }
+}
+
+/// Convert the bytes of a string to a string, where invalid UTF-8 sequences are replaced
+pub fn bytes_to_string(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
+/// Convert the bytes of a numeral to an integer or a float, see `string_to_number`
+pub fn bytes_to_number(s: &[u8]) -> Option<LuaValue> {
+    string_to_number(std::str::from_utf8(s).ok()?)
+}
+
+/// Whether the byte is a space of C's `isspace`, which includes '\v' unlike `u8::is_ascii_whitespace`
+pub fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t'..=b'\r')
+}
+
+/// Trim the spaces of C's `isspace` around a numeral
+pub fn trim_spaces(s: &str) -> &str {
+    s.trim_matches(|c: char| c.is_ascii() && is_space(c as u8))
+}
+
+/// Convert a numeral with optional surrounding spaces to an integer or a float,
+/// a decimal integer overflowing is converted to a float
+pub fn string_to_number(s: &str) -> Option<LuaValue> {
+    let s = trim_spaces(s);
+    let (neg, body) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    if let Some(hex) = body.strip_prefix("0x").or_else(|| body.strip_prefix("0X")) {
+        return hex_to_number(hex, neg);
+    }
+
+    // rust accepts "inf" and "nan" which are not numerals
+    if !body.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        || !body.bytes().all(|c| c.is_ascii_digit() || matches!(c, b'.' | b'e' | b'E' | b'+' | b'-'))
+    {
+        return None;
+    }
+    if body.bytes().all(|c| c.is_ascii_digit()) {
+        if let Ok(i) = s.parse::<i64>() {
+            return Some(LuaValue::Integer(i));
+        }
+    }
+    let n = body.parse::<f64>().ok()?;
+    Some(LuaValue::Number(if neg { -n } else { n }))
+}
+
+/// Hexadecimal integers wrap around, and floats may have a binary exponent after 'p'
+fn hex_to_number(hex: &str, neg: bool) -> Option<LuaValue> {
+    let (mantissa, exp) = match hex.find(['p', 'P']) {
+        Some(i) => (&hex[..i], Some(hex[i + 1..].parse::<i32>().ok()?)),
+        None => (hex, None),
+    };
+    let (int_part, frac_part) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], Some(&mantissa[i + 1..])),
+        None => (mantissa, None),
+    };
+    let digits = int_part.len() + frac_part.map_or(0, str::len);
+    if digits == 0 || !int_part.chars().chain(frac_part.unwrap_or("").chars()).all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    if frac_part.is_none() && exp.is_none() {
+        let i = int_part.chars().fold(0i64, |acc, c| acc.wrapping_mul(16).wrapping_add(c.to_digit(16).unwrap() as i64));
+        return Some(LuaValue::Integer(if neg { i.wrapping_neg() } else { i }));
+    }
+    let frac_part = frac_part.unwrap_or("");
+    let m = int_part.chars().chain(frac_part.chars()).fold(0f64, |acc, c| acc * 16.0 + c.to_digit(16).unwrap() as f64);
+    let e = exp.unwrap_or(0) - 4 * frac_part.len() as i32;
+    let n = m * 2f64.powi(e);
+    Some(LuaValue::Number(if neg { -n } else { n }))
+}
@@ -1,7 +1,7 @@
 // a % b == a - ((a // b) * b)
 #[inline]
 pub fn integer_mod(a: i64, b: i64) -> i64 {
-    a - integer_floor_div(a, b) * b
+    a.wrapping_sub(integer_floor_div(a, b).wrapping_mul(b))
 }
 
 // a % b == a - ((a // b) * b)
@@ -16,10 +16,10 @@ pub fn float_mod(a: f64, b: f64) -> f64 {
 }
 
 pub fn integer_floor_div(a: i64, b: i64) -> i64 {
-    if a > 0 && b > 0 || a < 0 && b < 0 || a % b == 0 {
-        a / b
+    if a > 0 && b > 0 || a < 0 && b < 0 || a.wrapping_rem(b) == 0 {
+        a.wrapping_div(b)
     } else {
-        a / b - 1
+        a.wrapping_div(b) - 1
     }
 }
 
@@ -29,7 +29,7 @@ pub fn float_floor_div(a: f64, b: f64) -> f64 {
 }
 
 pub fn shift_left(a: i64, n: i64) -> i64 {
-    if n >= 64 {
+    if n >= 64 || n <= -64 {
         0
     } else if n >= 0 {
         a << n
@@ -40,7 +40,7 @@ pub fn shift_left(a: i64, n: i64) -> i64 {
 
 // logical shift right
 pub fn shift_right(a: i64, n: i64) -> i64 {
-    if n >= 64 {
+    if n >= 64 || n <= -64 {
         0
     } else if n >= 0 {
         (a as u64 >> n) as i64
@@ -75,6 +75,7 @@ mod tests {
     fn floor_div() {
         assert_eq!(integer_floor_div(5, 3), 1);
         assert_eq!(integer_floor_div(-5, 3), -2);
+        assert_eq!(integer_floor_div(i64::MIN, -1), i64::MIN);
         assert_eq!(float_floor_div(5.0, -3.0), -2.0);
         assert_eq!(float_floor_div(-5.0, -3.0), 1.0);
     }
@@ -1,5 +1,13 @@
+mod aux_lib;
+pub mod closure;
+pub mod gc;
+pub mod lua_debug;
+pub mod lua_error;
 pub mod lua_value;
 pub mod lua_stack;
+pub mod lua_table;
 pub mod lua_state;
+pub mod lua_thread;
+pub mod lua_userdata;
 pub mod math;
 pub mod ops;
\ No newline at end of file
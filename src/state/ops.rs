@@ -1,9 +1,12 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
 use crate::api::consts::*;
 use crate::state::lua_value::LuaValue;
 use crate::state::math;
 
 fn iadd(a: i64, b: i64) -> i64 {
-    a + b
+    a.wrapping_add(b)
 }
 
 fn fadd(a: f64, b: f64) -> f64 {
@@ -11,7 +14,7 @@ fn fadd(a: f64, b: f64) -> f64 {
 }
 
 fn isub(a: i64, b: i64) -> i64 {
-    a - b
+    a.wrapping_sub(b)
 }
 
 fn fsub(a: f64, b: f64) -> f64 {
@@ -19,7 +22,7 @@ fn fsub(a: f64, b: f64) -> f64 {
 }
 
 fn imul(a: i64, b: i64) -> i64 {
-    a * b
+    a.wrapping_mul(b)
 }
 
 fn fmul(a: f64, b: f64) -> f64 {
@@ -27,6 +30,9 @@ fn fmul(a: f64, b: f64) -> f64 {
 }
 
 fn imod(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        panic!("attempt to perform 'n%0'");
+    }
     math::integer_mod(a, b)
 }
 
@@ -43,6 +49,9 @@ fn div(a: f64, b: f64) -> f64 {
 }
 
 fn iidiv(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        panic!("attempt to perform 'n//0'");
+    }
     math::integer_floor_div(a, b)
 }
 
@@ -71,7 +80,7 @@ fn shr(a: i64, b: i64) -> i64 {
 }
 
 fn iunm(a: i64, _: i64) -> i64 {
-    -a
+    a.wrapping_neg()
 }
 
 fn funm(a: f64, _: f64) -> f64 {
@@ -82,56 +91,54 @@ fn bnot(a: i64, _: i64) -> i64 {
     !a
 }
 
-fn inone(_: i64, _: i64) -> i64 {
-    0
-}
-
-fn fnone(_: f64, _: f64) -> f64 {
-    0.0
-}
+type IntegerOp = Option<fn(i64, i64) -> i64>;
+type FloatOp = Option<fn(f64, f64) -> f64>;
+
+/// (integer op, float op) pairs indexed by `LUA_OP*`,
+/// a missing float op marks a bitwise operator
+pub const OPS: &[(IntegerOp, FloatOp)] = &[
+    (Some(iadd), Some(fadd)),
+    (Some(isub), Some(fsub)),
+    (Some(imul), Some(fmul)),
+    (Some(imod), Some(fmod)),
+    (None, Some(pow)),
+    (None, Some(div)),
+    (Some(iidiv), Some(fidiv)),
+    (Some(band), None),
+    (Some(bor), None),
+    (Some(bxor), None),
+    (Some(shl), None),
+    (Some(shr), None),
+    (Some(iunm), Some(funm)),
+    (Some(bnot), None),
+];
 
-pub const OPS: &'static [(fn(i64, i64) -> i64, fn(f64, f64) -> f64)] = &[
-    (iadd, fadd),
-    (isub, fsub),
-    (imul, fmul),
-    (imod, fmod),
-    (inone, pow),
-    (inone, div),
-    (iidiv, fidiv),
-    (band, fnone),
-    (bor, fnone),
-    (bxor, fnone),
-    (shl, fnone),
-    (shr, fnone),
-    (iunm, funm),
-    (bnot, fnone),
+/// metamethod names indexed by `LUA_OP*`
+pub const ARITH_EVENTS: &[&str] = &[
+    "__add", "__sub", "__mul", "__mod", "__pow", "__div", "__idiv",
+    "__band", "__bor", "__bxor", "__shl", "__shr", "__unm", "__bnot",
 ];
 
 pub fn arith(a: &LuaValue, b: &LuaValue, op: u8) -> Option<LuaValue> {
-    let iop = OPS[op as usize].0;
-    let fop = OPS[op as usize].1;
-    if fop == fnone {
+    let (iop, fop) = OPS[op as usize];
+    match (iop, fop) {
         // bitwise
-        if let Some(x) = a.to_integer() {
-            if let Some(y) = b.to_integer() {
+        (Some(iop), None) => {
+            if let (Some(x), Some(y)) = (a.to_integer(), b.to_integer()) {
                 return Some(LuaValue::Integer(iop(x, y)));
             }
         }
-    } else {
         // arith
-        if iop != inone {
+        (iop, Some(fop)) => {
             // add,sub,mul,mod,idiv,unm
-            if let LuaValue::Integer(x) = a {
-                if let LuaValue::Integer(y) = b {
-                    return Some(LuaValue::Integer(iop(*x, *y)));
-                }
+            if let (Some(iop), LuaValue::Integer(x), LuaValue::Integer(y)) = (iop, a, b) {
+                return Some(LuaValue::Integer(iop(*x, *y)));
             }
-        }
-        if let Some(x) = a.to_number() {
-            if let Some(y) = b.to_number() {
+            if let (Some(x), Some(y)) = (a.to_number(), b.to_number()) {
                 return Some(LuaValue::Number(fop(x, y)));
             }
         }
+        (None, None) => unreachable!(),
     }
     None
 }
@@ -146,6 +153,25 @@ pub fn compare(a: &LuaValue, b: &LuaValue, op: u8) -> Option<bool> {
     }
 }
 
+/// Compare an integer with a float exactly, without converting the integer to a float,
+/// None if the float is NaN
+fn int_float_cmp(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= 9223372036854775808.0 {
+        Some(Ordering::Less)
+    } else if f < -9223372036854775808.0 {
+        Some(Ordering::Greater)
+    } else {
+        // the floor is in the range of i64
+        let floor = f.floor();
+        match i.cmp(&(floor as i64)) {
+            Ordering::Equal if f > floor => Some(Ordering::Less),
+            ord => Some(ord),
+        }
+    }
+}
+
 macro_rules! cmp {
     ($a:ident $op:tt $b:ident) => {
         match $a {
@@ -155,12 +181,12 @@ macro_rules! cmp {
             },
             LuaValue::Integer(x) => match $b {
                 LuaValue::Integer(y) => Some(x $op y),
-                LuaValue::Number(y) => Some((*x as f64) $op *y),
+                LuaValue::Number(y) => Some(int_float_cmp(*x, *y).is_some_and(|o| o $op Ordering::Equal)),
                 _ => None,
             },
             LuaValue::Number(x) => match $b {
                 LuaValue::Number(y) => Some(x $op y),
-                LuaValue::Integer(y) => Some(*x $op (*y as f64)),
+                LuaValue::Integer(y) => Some(int_float_cmp(*y, *x).is_some_and(|o| o.reverse() $op Ordering::Equal)),
                 _ => None,
             },
             _ => None,
@@ -173,14 +199,31 @@ fn eq(a: &LuaValue, b: &LuaValue) -> bool {
         x
     } else {
         match a {
-            LuaValue::Nil => match b {
-                LuaValue::Nil => true,
-                _ => false,
-            },
+            LuaValue::Nil => matches!(b, LuaValue::Nil),
             LuaValue::Boolean(x) => match b {
                 LuaValue::Boolean(y) => x == y,
                 _ => false,
             },
+            LuaValue::Table(x) => match b {
+                LuaValue::Table(y) => Rc::ptr_eq(x, y),
+                _ => false,
+            },
+            LuaValue::Function(x) => match b {
+                LuaValue::Function(y) => Rc::ptr_eq(x, y),
+                _ => false,
+            },
+            LuaValue::RustFunction(x) => match b {
+                LuaValue::RustFunction(y) => *x as usize == *y as usize,
+                _ => false,
+            },
+            LuaValue::Thread(x) => match b {
+                LuaValue::Thread(y) => Rc::ptr_eq(x, y),
+                _ => false,
+            },
+            LuaValue::UserData(x) => match b {
+                LuaValue::UserData(y) => Rc::ptr_eq(x, y),
+                _ => false,
+            },
             _ => false,
         }
     }
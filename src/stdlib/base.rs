@@ -0,0 +1,723 @@
+use std::io::Write;
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{LuaAPI, LuaError, LuaResult, RustFn};
+use crate::state::lua_state::LuaState;
+use crate::state::lua_value::trim_spaces;
+
+const BASE_FUNCS: &[(&str, RustFn)] = &[
+    ("assert", base_assert),
+    ("collectgarbage", base_collect_garbage),
+    ("dofile", base_dofile),
+    ("error", base_error),
+    ("getmetatable", base_get_metatable),
+    ("ipairs", base_ipairs),
+    ("loadfile", base_load_file),
+    ("load", base_load),
+    ("next", base_next),
+    ("pairs", base_pairs),
+    ("pcall", base_pcall),
+    ("print", base_print),
+    ("rawequal", base_raw_equal),
+    ("rawget", base_raw_get),
+    ("rawlen", base_raw_len),
+    ("rawset", base_raw_set),
+    ("select", base_select),
+    ("setmetatable", base_set_metatable),
+    ("tonumber", base_to_number),
+    ("tostring", base_to_string),
+    ("type", base_type),
+    ("xpcall", base_xpcall),
+];
+
+/// Set the base functions in the global table, which is returned as the module
+pub fn open_base(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.push_global_table();
+    for (name, f) in BASE_FUNCS {
+        ls.push_rust_function(*f);
+        ls.set_field(-2, name)?;
+    }
+    // set global _G
+    ls.push_value(-1);
+    ls.set_field(-2, "_G")?;
+    // set global _VERSION
+    ls.push_string(LUA_VERSION.to_string());
+    ls.set_field(-2, "_VERSION")?;
+    Ok(1)
+}
+
+// print (···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-print
+fn base_print(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top();
+    let mut line = Vec::new();
+    for i in 1..=n {
+        if i > 1 {
+            line.push(b'\t');
+        }
+        ls.to_string2(i)?;
+        line.extend_from_slice(&ls.to_bytes(-1));
+        ls.pop(1);
+    }
+    line.push(b'\n');
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    // printing is best effort like `fwrite` in the reference implementation
+    let _ = out.write_all(&line);
+    let _ = out.flush();
+    Ok(0)
+}
+
+// assert (v [, message])
+// http://www.lua.org/manual/5.3/manual.html#pdf-assert
+fn base_assert(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.to_boolean(1) {
+        // return all arguments
+        return Ok(ls.get_top() as usize);
+    }
+    ls.check_any(1)?;
+    ls.remove(1)?;
+    ls.push_string("assertion failed!".to_string()); // default message
+    ls.set_top(1)?; // leave only the message
+    base_error(ls)
+}
+
+// collectgarbage ([opt [, arg]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-collectgarbage
+fn base_collect_garbage(ls: &mut LuaState) -> LuaResult<usize> {
+    let opt = ls.opt_string(1, "collect")?;
+    let what = match opt.as_str() {
+        "stop" => LUA_GCSTOP,
+        "restart" => LUA_GCRESTART,
+        "collect" => LUA_GCCOLLECT,
+        "count" => LUA_GCCOUNT,
+        "step" => LUA_GCSTEP,
+        "setpause" => LUA_GCSETPAUSE,
+        "setstepmul" => LUA_GCSETSTEPMUL,
+        "isrunning" => LUA_GCISRUNNING,
+        _ => return Err(ls.arg_error(1, &format!("invalid option '{}'", opt))),
+    };
+    let data = ls.opt_integer(2, 0)? as i32;
+    let res = ls.gc(what, data)?;
+    match what {
+        LUA_GCCOUNT => {
+            let b = ls.gc(LUA_GCCOUNTB, 0)?;
+            ls.push_number(res as f64 + b as f64 / 1024.0);
+        }
+        LUA_GCSTEP | LUA_GCISRUNNING => ls.push_boolean(res != 0),
+        _ => ls.push_integer(res as i64),
+    }
+    Ok(1)
+}
+
+// error (message [, level])
+// http://www.lua.org/manual/5.3/manual.html#pdf-error
+fn base_error(ls: &mut LuaState) -> LuaResult<usize> {
+    let level = ls.opt_integer(2, 1)?;
+    ls.set_top(1)?;
+    if ls.type_id(1) == LUA_TSTRING && level > 0 {
+        // add the position of the function at `level`
+        if let Some(pos) = ls.where_(level as usize) {
+            let msg = format!("{}: {}", pos, ls.to_string(1));
+            ls.push_string(msg);
+            ls.replace(1)?;
+        }
+    }
+    Err(ls.error())
+}
+
+// getmetatable (object)
+// http://www.lua.org/manual/5.3/manual.html#pdf-getmetatable
+pub(crate) fn base_get_metatable(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    if !ls.get_metatable(1) {
+        ls.push_nil();
+        return Ok(1); // no metatable
+    }
+    ls.get_metafield(1, "__metatable")?;
+    Ok(1) // returns either __metatable field (if present) or metatable
+}
+
+// setmetatable (table, metatable)
+// http://www.lua.org/manual/5.3/manual.html#pdf-setmetatable
+fn base_set_metatable(ls: &mut LuaState) -> LuaResult<usize> {
+    let t = ls.type_id(2);
+    ls.check_type(1, LUA_TTABLE)?;
+    if t != LUA_TNIL && t != LUA_TTABLE {
+        return Err(ls.type_error(2, "nil or table"));
+    }
+    if ls.get_metafield(1, "__metatable")? != LUA_TNIL {
+        return Err(ls.error2("cannot change a protected metatable"));
+    }
+    ls.set_top(2)?;
+    ls.set_metatable(1)?;
+    Ok(1)
+}
+
+// next (table [, index])
+// http://www.lua.org/manual/5.3/manual.html#pdf-next
+fn base_next(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_type(1, LUA_TTABLE)?;
+    ls.set_top(2)?; // create a 2nd argument if there isn't one
+    if ls.next(1)? {
+        Ok(2)
+    } else {
+        ls.push_nil();
+        Ok(1)
+    }
+}
+
+// pairs (t)
+// http://www.lua.org/manual/5.3/manual.html#pdf-pairs
+fn base_pairs(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    if ls.get_metafield(1, "__pairs")? == LUA_TNIL {
+        // no metamethod
+        ls.push_rust_function(base_next); // will return generator,
+        ls.push_value(1); // state,
+        ls.push_nil(); // and initial value
+    } else {
+        ls.push_value(1); // argument 'self' to metamethod
+        ls.call(1, 3)?; // get 3 values from metamethod
+    }
+    Ok(3)
+}
+
+// ipairs (t)
+// http://www.lua.org/manual/5.3/manual.html#pdf-ipairs
+fn base_ipairs(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    ls.push_rust_function(ipairs_aux); // iteration function
+    ls.push_value(1); // state
+    ls.push_integer(0); // initial value
+    Ok(3)
+}
+
+/// Traversal function for 'ipairs', which respects `__index`
+fn ipairs_aux(ls: &mut LuaState) -> LuaResult<usize> {
+    let i = ls.check_integer(2)?.wrapping_add(1);
+    ls.push_integer(i);
+    if ls.get_i(1, i)? == LUA_TNIL {
+        Ok(1)
+    } else {
+        Ok(2)
+    }
+}
+
+// rawequal (v1, v2)
+// http://www.lua.org/manual/5.3/manual.html#pdf-rawequal
+fn base_raw_equal(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    ls.check_any(2)?;
+    let eq = ls.raw_equal(1, 2);
+    ls.push_boolean(eq);
+    Ok(1)
+}
+
+// rawlen (v)
+// http://www.lua.org/manual/5.3/manual.html#pdf-rawlen
+fn base_raw_len(ls: &mut LuaState) -> LuaResult<usize> {
+    let t = ls.type_id(1);
+    ls.arg_check(t == LUA_TTABLE || t == LUA_TSTRING, 1, "table or string expected")?;
+    let len = ls.raw_len(1);
+    ls.push_integer(len as i64);
+    Ok(1)
+}
+
+// rawget (table, index)
+// http://www.lua.org/manual/5.3/manual.html#pdf-rawget
+fn base_raw_get(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_type(1, LUA_TTABLE)?;
+    ls.check_any(2)?;
+    ls.set_top(2)?;
+    ls.raw_get(1)?;
+    Ok(1)
+}
+
+// rawset (table, index, value)
+// http://www.lua.org/manual/5.3/manual.html#pdf-rawset
+fn base_raw_set(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_type(1, LUA_TTABLE)?;
+    ls.check_any(2)?;
+    ls.check_any(3)?;
+    ls.set_top(3)?;
+    ls.raw_set(1)?;
+    Ok(1)
+}
+
+// select (index, ···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-select
+fn base_select(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top() as i64;
+    if ls.type_id(1) == LUA_TSTRING && ls.to_string(1) == "#" {
+        ls.push_integer(n - 1);
+        return Ok(1);
+    }
+    let mut i = ls.check_integer(1)?;
+    if i < 0 {
+        i += n;
+    } else if i > n {
+        i = n;
+    }
+    ls.arg_check(1 <= i, 1, "index out of range")?;
+    Ok((n - i) as usize)
+}
+
+// tonumber (e [, base])
+// http://www.lua.org/manual/5.3/manual.html#pdf-tonumber
+fn base_to_number(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.is_none_or_nil(2) {
+        // standard conversion
+        if ls.type_id(1) == LUA_TNUMBER {
+            ls.set_top(1)?; // yes; return it
+            return Ok(1);
+        }
+        if let Some(s) = ls.to_stringx(1) {
+            if ls.string_to_number(&s) {
+                return Ok(1); // successful conversion to number
+            }
+        }
+        ls.check_any(1)?; // (but there must be some parameter)
+    } else {
+        let base = ls.check_integer(2)?;
+        ls.check_type(1, LUA_TSTRING)?; // no numbers as strings
+        let s = ls.to_string(1);
+        ls.arg_check((2..=36).contains(&base), 2, "base out of range")?;
+        if let Some(n) = str_to_int(&s, base as u32) {
+            ls.push_integer(n);
+            return Ok(1);
+        }
+    }
+    ls.push_nil(); // not a number
+    Ok(1)
+}
+
+/// Convert a string of digits in `base` with optional spaces and minus sign, which may wrap around
+fn str_to_int(s: &str, base: u32) -> Option<i64> {
+    let s = trim_spaces(s);
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: i64 = 0;
+    for c in digits.chars() {
+        let digit = c.to_digit(base)?;
+        n = n.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Some(if neg { n.wrapping_neg() } else { n })
+}
+
+// tostring (v)
+// http://www.lua.org/manual/5.3/manual.html#pdf-tostring
+fn base_to_string(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    ls.to_string2(1)?;
+    Ok(1)
+}
+
+// type (v)
+// http://www.lua.org/manual/5.3/manual.html#pdf-type
+fn base_type(ls: &mut LuaState) -> LuaResult<usize> {
+    let t = ls.type_id(1);
+    ls.arg_check(t != LUA_TNONE, 1, "value expected")?;
+    ls.push_string(ls.type_name(t).to_string());
+    Ok(1)
+}
+
+/// The file name at `arg`, or None for the standard input
+fn opt_file_name(ls: &LuaState, arg: isize) -> LuaResult<Option<String>> {
+    if ls.is_none_or_nil(arg) {
+        Ok(None)
+    } else {
+        ls.check_string(arg).map(Some)
+    }
+}
+
+/// Return the loaded chunk whose first up value is set to the value at `env_idx` if it is not 0,
+/// or nil and the error message
+fn load_aux(ls: &mut LuaState, status: i8, env_idx: isize) -> LuaResult<usize> {
+    if status == LUA_OK {
+        if env_idx != 0 {
+            // 'env' parameter?
+            ls.push_value(env_idx); // environment for loaded function
+            if ls.set_upvalue(-2, 1).is_none() {
+                // set it as 1st upvalue
+                ls.pop(1); // remove 'env' if not used by previous call
+            }
+        }
+        Ok(1)
+    } else {
+        // error (message is on top of the stack)
+        ls.push_nil();
+        ls.insert(-2)?; // put before error message
+        Ok(2) // return fail plus error message
+    }
+}
+
+/// Concatenate the pieces returned by the reader function at index 1 until it returns nil
+/// or an empty string, or push the error message and return its status
+fn read_chunk(ls: &mut LuaState) -> LuaResult<Result<Vec<u8>, i8>> {
+    let mut chunk = Vec::new();
+    loop {
+        ls.push_value(1); // get function
+        let status = ls.pcall(0, 1, 0); // call it
+        if status == LUA_ERREXIT {
+            return Err(LuaError { status, ..ls.error() });
+        }
+        if status != LUA_OK {
+            return Ok(Err(status));
+        }
+        if ls.is_nil(-1) {
+            ls.pop(1); // pop result
+            return Ok(Ok(chunk));
+        }
+        if !ls.is_string(-1) {
+            ls.pop(1);
+            let msg = ls.error2("reader function must return a string").to_string();
+            ls.push_string(msg);
+            return Ok(Err(LUA_ERRSYNTAX));
+        }
+        let piece = ls.to_bytes(-1);
+        ls.pop(1);
+        if piece.is_empty() {
+            return Ok(Ok(chunk));
+        }
+        chunk.extend_from_slice(&piece);
+    }
+}
+
+// load (chunk [, chunkname [, mode [, env]]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-load
+pub(crate) fn base_load(ls: &mut LuaState) -> LuaResult<usize> {
+    let mode = ls.opt_string(3, "bt")?;
+    let env = if !ls.is_none(4) { 4 } else { 0 }; // 'env' index or 0 if no 'env'
+    let status = if ls.is_string(1) {
+        // loading a string?
+        let s = ls.to_bytes(1);
+        let chunk_name = ls.opt_string(2, &String::from_utf8_lossy(&s))?;
+        ls.load(s, &chunk_name, &mode)
+    } else {
+        // loading from a reader function
+        let chunk_name = ls.opt_string(2, "=(load)")?;
+        ls.check_type(1, LUA_TFUNCTION)?;
+        match read_chunk(ls)? {
+            Ok(chunk) => ls.load(chunk, &chunk_name, &mode),
+            Err(status) => status,
+        }
+    };
+    load_aux(ls, status, env)
+}
+
+// loadfile ([filename [, mode [, env]]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-loadfile
+fn base_load_file(ls: &mut LuaState) -> LuaResult<usize> {
+    let fname = opt_file_name(ls, 1)?;
+    let mode = ls.opt_string(2, "bt")?;
+    let env = if !ls.is_none(3) { 3 } else { 0 }; // 'env' index or 0 if no 'env'
+    let status = ls.load_file(fname.as_deref(), &mode);
+    load_aux(ls, status, env)
+}
+
+// dofile ([filename])
+// http://www.lua.org/manual/5.3/manual.html#pdf-dofile
+fn base_dofile(ls: &mut LuaState) -> LuaResult<usize> {
+    let fname = opt_file_name(ls, 1)?;
+    ls.set_top(1)?;
+    if ls.load_file(fname.as_deref(), "bt") != LUA_OK {
+        return Err(ls.error());
+    }
+    ls.call(0, LUA_MULTRET)?;
+    Ok((ls.get_top() - 1) as usize)
+}
+
+/// The results of a protected call following the `extra` values,
+/// or false and the error object, which is also the continuation after it yields
+fn finish_pcall(ls: &mut LuaState, status: i8, extra: isize) -> LuaResult<usize> {
+    if status == LUA_ERREXIT {
+        // an intercepted `os.exit` is not caught
+        return Err(LuaError { status, ..ls.error() });
+    }
+    if status != LUA_OK {
+        ls.push_boolean(false);
+        ls.push_value(-2);
+        Ok(2)
+    } else {
+        Ok((ls.get_top() - extra) as usize)
+    }
+}
+
+// pcall (f [, arg1, ···])
+// http://www.lua.org/manual/5.3/manual.html#pdf-pcall
+fn base_pcall(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    ls.push_boolean(true); // first result if no errors
+    ls.insert(1)?;
+    let nargs = ls.get_top() - 2;
+    let status = ls.pcallk(nargs, LUA_MULTRET, 0, 0, finish_pcall);
+    if status == LUA_YIELD {
+        return Ok(0); // finished by the continuation
+    }
+    finish_pcall(ls, status, 0)
+}
+
+// xpcall (f, msgh [, arg1, ···])
+// http://www.lua.org/manual/5.3/manual.html#pdf-xpcall
+fn base_xpcall(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top();
+    ls.check_type(2, LUA_TFUNCTION)?;
+    ls.push_boolean(true); // first result
+    ls.push_value(1); // function
+    ls.rotate(3, 2)?; // move them below the arguments
+    let status = ls.pcallk(n - 2, LUA_MULTRET, 2, 2, finish_pcall);
+    if status == LUA_YIELD {
+        return Ok(0); // finished by the continuation
+    }
+    finish_pcall(ls, status, 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stdlib::test_util::execute;
+
+    #[test]
+    fn test_pcall() {
+        let ls = execute(r#"
+        local ok1, e1 = pcall(error, "msg")
+        local ok2, e2 = pcall(function() local t = nil; return t.x end)
+        local ok3, e3 = pcall(error, {code = 42})
+        local ok4, a, b = pcall(function(x, y) return x + y, x * y end, 3, 4)
+        local ok5, e5 = pcall(function() error("deep", 2) end)
+        local ok6, e6 = pcall(function() error("no position", 0) end)
+        local ok7, e7 = pcall(pcall)
+        return ok1, e1, ok2, e2, ok3, e3.code, ok4, a, b, ok5, e5, ok6, e6, ok7, e7
+        "#);
+        assert!(!ls.to_boolean(1));
+        assert_eq!(ls.to_string(2), "msg");
+        assert!(!ls.to_boolean(3));
+        assert_eq!(ls.to_string(4), "test:3: attempt to index a nil value");
+        assert!(!ls.to_boolean(5));
+        assert_eq!(ls.to_integerx(6), Some(42));
+        assert!(ls.to_boolean(7));
+        assert_eq!(ls.to_integerx(8), Some(7));
+        assert_eq!(ls.to_integerx(9), Some(12));
+        assert!(!ls.to_boolean(10));
+        assert_eq!(ls.to_string(11), "deep");
+        assert!(!ls.to_boolean(12));
+        assert_eq!(ls.to_string(13), "no position");
+        assert!(!ls.to_boolean(14));
+        assert_eq!(ls.to_string(15), "bad argument #1 (value expected)");
+    }
+
+    #[test]
+    fn test_xpcall() {
+        let ls = execute(r#"
+        local function handler(e) return "handled: " .. e end
+        local ok1, e1 = xpcall(function(x) error("oops: " .. x) end, handler, 1)
+        local ok2, a, b = xpcall(function(...) return ... end, handler, 1, 2)
+        local ok3, e3 = xpcall(error, function() error("again") end, "x")
+        local closed
+        local ok4 = pcall(function()
+            local v = "captured"
+            closed = function() return v end
+            error()
+        end)
+        return ok1, e1, ok2, a, b, ok3, e3, ok4, closed()
+        "#);
+        assert!(!ls.to_boolean(1));
+        assert_eq!(ls.to_string(2), "handled: test:3: oops: 1");
+        assert!(ls.to_boolean(3));
+        assert_eq!(ls.to_integerx(4), Some(1));
+        assert_eq!(ls.to_integerx(5), Some(2));
+        assert!(!ls.to_boolean(6));
+        assert_eq!(ls.to_string(7), "error in error handling");
+        assert!(!ls.to_boolean(8));
+        assert_eq!(ls.to_string(9), "captured");
+    }
+
+    #[test]
+    fn test_errors_in_rust() {
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        let src = "local x = 1\nreturn x < {}";
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "@cmp.lua", "t"), LUA_OK);
+        let err = ls.call(0, 0).unwrap_err();
+        assert_eq!(err.status, LUA_ERRRUN);
+        assert_eq!(err.to_string(), "cmp.lua:2: attempt to compare number with table");
+        assert_eq!(err.position.as_deref(), Some("cmp.lua:2"));
+        // the state is still usable after the error
+        assert_eq!(ls.get_top(), 0);
+        ls.push_rust_function(base_error);
+        ls.push_integer(7);
+        assert_eq!(ls.pcall(1, 0, 0), LUA_ERRRUN);
+        assert_eq!(ls.to_integerx(-1), Some(7));
+        assert_eq!(ls.get_top(), 1);
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let ls = execute(r#"
+        setmetatable({}, {__gc = function() error("boom", 0) end})
+        local ok, err = pcall(collectgarbage)
+        return ok, err, collectgarbage(), collectgarbage("count") > 0, collectgarbage("isrunning")
+        "#);
+        assert!(!ls.to_boolean(1));
+        assert_eq!(ls.to_string(2), "error in __gc metamethod (boom)");
+        assert_eq!(ls.to_integerx(3), Some(0));
+        assert!(ls.to_boolean(4));
+        assert!(ls.to_boolean(5));
+    }
+
+    #[test]
+    fn test_load() {
+        let ls = execute(r#"
+        local f1 = load("local a, b = ... return a + b")
+        local parts, i = {"return ", "x ", "* 2"}, 0
+        local f2 = load(function() i = i + 1 return parts[i] end, "=parts", "t", {x = 21})
+        local f3, e3 = load("return +", "=bad")
+        local f4, e4 = load("\27Lua", "=bin", "t")
+        local f5, e5 = load(function() return {} end)
+        local f6, e6 = load(function() error("reader failed", 0) end)
+        local env = {}
+        load("y = 1", "=env", "bt", env)()
+        local ok, e7 = pcall(load, 42)
+        local f8, e8 = load("x = 1 end garbage (", "=eof")
+        return f1(1, 2), f2(), f3, e3, f4, e4, e5, e6, env.y, y, ok, f8, e8, load("return ...")(7)
+        "#);
+        assert_eq!(ls.to_integerx(1), Some(3));
+        assert_eq!(ls.to_integerx(2), Some(42));
+        assert!(ls.is_nil(3));
+        assert!(ls.to_string(4).starts_with("bad: "));
+        assert!(ls.is_nil(5));
+        assert_eq!(ls.to_string(6), "attempt to load a binary chunk (mode is 't')");
+        assert_eq!(ls.to_string(7), "test:7: reader function must return a string");
+        assert_eq!(ls.to_string(8), "reader failed");
+        assert_eq!(ls.to_integerx(9), Some(1));
+        assert!(ls.is_nil(10));
+        assert!(ls.to_boolean(11)); // the number is converted to a string
+        assert!(ls.is_nil(12));
+        assert_eq!(ls.to_string(13), "eof: line: 1, error: '<eof>' expected near 'end'");
+        assert_eq!(ls.to_integerx(14), Some(7));
+    }
+
+    #[test]
+    fn test_load_file() {
+        let path = std::env::temp_dir().join(format!("lua_rs_dofile_{}.lua", std::process::id()));
+        std::fs::write(&path, "#!/usr/bin/env lua\nlocal n = ... or 1\nreturn n * 10, x").unwrap();
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        ls.push_string(path.to_str().unwrap().to_string());
+        ls.set_global("PATH").unwrap();
+        let src = r#"
+        local a, b = dofile(PATH)
+        local fa, fb = loadfile(PATH, "t", {x = "env"})(5)
+        local g, e = loadfile(PATH .. ".missing")
+        local h, e2 = loadfile(PATH, "b")
+        return a, b, fa, fb, g, e, h, e2, select(2, pcall(dofile, PATH .. ".missing"))
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let path = path.display();
+        assert_eq!(ls.to_integerx(1), Some(10));
+        assert!(ls.is_nil(2));
+        assert_eq!(ls.to_integerx(3), Some(50));
+        assert_eq!(ls.to_string(4), "env");
+        assert!(ls.is_nil(5));
+        assert_eq!(ls.to_string(6), format!("cannot open {}.missing: No such file or directory", path));
+        assert!(ls.is_nil(7));
+        assert_eq!(ls.to_string(8), "attempt to load a text chunk (mode is 'b')");
+        assert_eq!(ls.to_string(9), format!("cannot open {}.missing: No such file or directory", path));
+    }
+
+    #[test]
+    fn test_iteration() {
+        let ls = execute(r#"
+        local t = {10, 20, 30, x = 1, y = 2, z = 3}
+        local sum, count = 0, 0
+        for k, v in pairs(t) do
+            sum = sum + v
+            count = count + 1
+            t[k] = nil -- clearing fields during traversal is allowed
+        end
+        local nested = 0
+        local u = {a = 1, b = 2, c = 3}
+        for _ in pairs(u) do
+            for _ in pairs(u) do nested = nested + 1 end
+        end
+        local isum = 0
+        for i, v in ipairs({1, 2, 3, nil, 5}) do isum = isum + i * v end
+        local proxy = setmetatable({}, {__index = function(_, i) if i <= 4 then return i end end})
+        local psum = 0
+        for _, v in ipairs(proxy) do psum = psum + v end
+        local k = next({}, nil)
+        return sum, count, next(t), nested, isum, psum, k
+        "#);
+        assert_eq!(ls.to_integerx(1), Some(66));
+        assert_eq!(ls.to_integerx(2), Some(6));
+        assert!(ls.is_nil(3));
+        assert_eq!(ls.to_integerx(4), Some(9));
+        assert_eq!(ls.to_integerx(5), Some(14));
+        assert_eq!(ls.to_integerx(6), Some(10));
+        assert!(ls.is_nil(7));
+    }
+
+    #[test]
+    fn test_conversions() {
+        let ls = execute(r##"
+        return tonumber("0x10"), tonumber(" \v12\f\n "), tonumber("1e2"), tonumber("z", 36),
+            tonumber("\v-ff ", 16), tonumber("8", 8), tonumber({}), tostring(nil), tostring(1.5),
+            type(print), select("#", 1, nil, 3), select(-1, 1, 2, 3), select(2, "a", "b", "c"),
+            _VERSION, _G == _G._G
+        "##);
+        assert_eq!(ls.to_integerx(1), Some(16));
+        assert_eq!(ls.to_integerx(2), Some(12));
+        assert_eq!(ls.to_numberx(3), Some(100.0));
+        assert!(ls.is_number(3) && !ls.is_integer(3));
+        assert_eq!(ls.to_integerx(4), Some(35));
+        assert_eq!(ls.to_integerx(5), Some(-255));
+        assert!(ls.is_nil(6));
+        assert!(ls.is_nil(7));
+        assert_eq!(ls.to_string(8), "nil");
+        assert_eq!(ls.to_string(9), "1.5");
+        assert_eq!(ls.to_string(10), "function");
+        assert_eq!(ls.to_integerx(11), Some(3));
+        assert_eq!(ls.to_integerx(12), Some(3));
+        assert_eq!(ls.to_string(13), "b");
+        assert_eq!(ls.to_string(14), "Lua 5.3");
+        assert!(ls.to_boolean(15));
+    }
+
+    #[test]
+    fn test_metatables_and_raw_access() {
+        let ls = execute(r#"
+        local t = setmetatable({}, {__index = function() return "meta" end, __metatable = "locked"})
+        local ok, e = pcall(setmetatable, t, {})
+        local tostr = tostring(setmetatable({}, {__tostring = function() return "custom" end}))
+        local named = tostring(setmetatable({}, {__name = "Point"}))
+        rawset(t, "k", "raw")
+        local ok2, e2 = pcall(assert, false)
+        local ok3, e3 = pcall(assert, nil, "message")
+        return getmetatable(t), ok, e, t.x, rawget(t, "x"), rawget(t, "k"), rawequal(t, t),
+            rawlen({1, 2}), rawlen("abc"), tostr, e2, e3, select(2, assert(1, 2)), named
+        "#);
+        assert_eq!(ls.to_string(1), "locked");
+        assert!(!ls.to_boolean(2));
+        assert_eq!(ls.to_string(3), "cannot change a protected metatable");
+        assert_eq!(ls.to_string(4), "meta");
+        assert!(ls.is_nil(5));
+        assert_eq!(ls.to_string(6), "raw");
+        assert!(ls.to_boolean(7));
+        assert_eq!(ls.to_integerx(8), Some(2));
+        assert_eq!(ls.to_integerx(9), Some(3));
+        assert_eq!(ls.to_string(10), "custom");
+        assert_eq!(ls.to_string(11), "assertion failed!");
+        assert_eq!(ls.to_string(12), "message");
+        assert_eq!(ls.to_integerx(13), Some(2));
+        assert!(ls.to_string(14).starts_with("Point: 0x"));
+    }
+}
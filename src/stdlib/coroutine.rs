@@ -0,0 +1,366 @@
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{upvalue_index, LuaAPI, LuaError, LuaResult, RustFn};
+use crate::state::lua_state::LuaState;
+
+const CO_FUNCS: &[(&str, RustFn)] = &[
+    ("create", co_create),
+    ("resume", co_resume),
+    ("running", co_running),
+    ("status", co_status),
+    ("wrap", co_wrap),
+    ("yield", co_yield),
+    ("isyieldable", co_is_yieldable),
+];
+
+pub fn open_coroutine(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.new_lib(CO_FUNCS)?;
+    Ok(1)
+}
+
+fn get_co(ls: &LuaState) -> LuaResult<()> {
+    if !ls.is_thread(1) {
+        return Err(ls.type_error(1, "coroutine"));
+    }
+    Ok(())
+}
+
+/// Resume the coroutine at `idx` with `nargs` arguments,
+/// and return the number of results or the error object on the top,
+/// an intercepted `os.exit` is propagated
+fn aux_resume(ls: &mut LuaState, idx: isize, nargs: isize) -> LuaResult<Result<usize, ()>> {
+    match ls.resume(idx, nargs) {
+        (LUA_OK, n) | (LUA_YIELD, n) => Ok(Ok(n)),
+        (LUA_ERREXIT, _) => Err(LuaError {
+            status: LUA_ERREXIT,
+            ..ls.error()
+        }),
+        _ => Ok(Err(())),
+    }
+}
+
+// coroutine.create (f)
+// http://www.lua.org/manual/5.3/manual.html#pdf-coroutine.create
+fn co_create(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_type(1, LUA_TFUNCTION)?;
+    ls.new_thread();
+    ls.push_value(1);
+    ls.xmove(-2, 1)?;
+    Ok(1)
+}
+
+// coroutine.resume (co [, val1, ···])
+// http://www.lua.org/manual/5.3/manual.html#pdf-coroutine.resume
+fn co_resume(ls: &mut LuaState) -> LuaResult<usize> {
+    get_co(ls)?;
+    let nargs = ls.get_top() - 1;
+    match aux_resume(ls, 1, nargs)? {
+        Ok(n) => {
+            ls.push_boolean(true);
+            ls.insert(-(n as isize) - 1)?;
+            Ok(n + 1)
+        }
+        Err(()) => {
+            ls.push_boolean(false);
+            ls.insert(-2)?;
+            Ok(2)
+        }
+    }
+}
+
+// coroutine.running ()
+// http://www.lua.org/manual/5.3/manual.html#pdf-coroutine.running
+fn co_running(ls: &mut LuaState) -> LuaResult<usize> {
+    let is_main = ls.push_thread();
+    ls.push_boolean(is_main);
+    Ok(2)
+}
+
+// coroutine.status (co)
+// http://www.lua.org/manual/5.3/manual.html#pdf-coroutine.status
+fn co_status(ls: &mut LuaState) -> LuaResult<usize> {
+    get_co(ls)?;
+    let status = ls.co_status(1);
+    ls.push_string(status.to_string());
+    Ok(1)
+}
+
+// coroutine.wrap (f)
+// http://www.lua.org/manual/5.3/manual.html#pdf-coroutine.wrap
+fn co_wrap(ls: &mut LuaState) -> LuaResult<usize> {
+    co_create(ls)?;
+    ls.push_rust_closure(Box::new(aux_wrap), 1);
+    Ok(1)
+}
+
+/// The function returned by `coroutine.wrap`, which propagates errors
+fn aux_wrap(ls: &mut LuaState) -> LuaResult<usize> {
+    let nargs = ls.get_top();
+    match aux_resume(ls, upvalue_index(1), nargs)? {
+        Ok(n) => Ok(n),
+        Err(()) => {
+            if ls.type_id(-1) == LUA_TSTRING {
+                // add the position of the caller to the message
+                let msg = ls.to_string(-1);
+                ls.pop(1);
+                return Err(ls.error2(&msg));
+            }
+            Err(ls.error())
+        }
+    }
+}
+
+// coroutine.yield (···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-coroutine.yield
+// which can yield across `pcall`, `xpcall` and the metamethods called by Lua code,
+// but not across the other Rust functions calling Lua, such as the comparator of `table.sort`
+fn co_yield(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top();
+    ls.yield_(n)
+}
+
+// coroutine.isyieldable ()
+// http://www.lua.org/manual/5.3/manual.html#pdf-coroutine.isyieldable
+fn co_is_yieldable(ls: &mut LuaState) -> LuaResult<usize> {
+    let yieldable = ls.is_yieldable();
+    ls.push_boolean(yieldable);
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coroutine() {
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        let src = r#"
+        local function walk(n)
+            -- yield across Lua frames
+            for i = 1, n do coroutine.yield(i) end
+            return "done"
+        end
+        local co = coroutine.create(function(a, b)
+            local c = coroutine.yield(a + b, coroutine.isyieldable())
+            local d, e = coroutine.yield(c * 2, coroutine.status(coroutine.running()))
+            return walk(d + e - 5)
+        end)
+        local log = {}
+        local function push(...)
+            local t = {...}
+            for i = 1, #t do log[#log + 1] = t[i] end
+        end
+        push(coroutine.resume(co, 1, 2))
+        push(coroutine.status(co))
+        push(coroutine.resume(co, 10))
+        push(coroutine.resume(co, 3, 4))
+        push(coroutine.resume(co))
+        push(coroutine.resume(co))
+        push(coroutine.status(co))
+        push(coroutine.resume(co))
+
+        local gen = coroutine.wrap(function() walk(3) end)
+        local sum = gen() + gen() + gen()
+        local main, is_main = coroutine.running()
+        return log, sum, is_main, coroutine.isyieldable()
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        let expected = [
+            "true", "3", "true", "suspended", "true", "20", "running", "true", "1", "true", "2",
+            "true", "done", "dead", "false", "cannot resume dead coroutine",
+        ];
+        for (i, s) in expected.iter().enumerate() {
+            ls.get_i(1, i as i64 + 1).unwrap();
+            let s2 = match ls.type_id(-1) {
+                LUA_TBOOLEAN => ls.to_boolean(-1).to_string(),
+                _ => ls.to_string(-1),
+            };
+            assert_eq!(&s2, s, "log[{}]", i + 1);
+            ls.pop(1);
+        }
+        assert_eq!(ls.raw_len(1), expected.len());
+        assert_eq!(ls.to_integerx(2), Some(6));
+        assert!(ls.to_boolean(3));
+        assert!(!ls.to_boolean(4));
+    }
+    #[test]
+    fn test_coroutine_errors() {
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        let src = r#"
+        local co = coroutine.create(function(x)
+            coroutine.yield(x)
+            error("boom")
+        end)
+        local r1, v1 = coroutine.resume(co, 1)
+        local r2, e2 = coroutine.resume(co)
+        local r3, e3 = coroutine.resume(co)
+        local gen = coroutine.wrap(function() error({}) end)
+        local ok, e4 = pcall(gen)
+        local ok5, e5 = pcall(coroutine.wrap(function() local t; return t.x end))
+        local ok6, e6 = pcall(coroutine.yield)
+        return r1, v1, r2, e2, coroutine.status(co), r3, e3, type(e4), e5, e6
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        assert!(ls.to_boolean(1));
+        assert_eq!(ls.to_integerx(2), Some(1));
+        assert!(!ls.to_boolean(3));
+        assert_eq!(ls.to_string(4), "test:4: boom");
+        assert_eq!(ls.to_string(5), "dead");
+        assert!(!ls.to_boolean(6));
+        assert_eq!(ls.to_string(7), "cannot resume dead coroutine");
+        assert_eq!(ls.to_string(8), "table");
+        assert_eq!(ls.to_string(9), "test:11: attempt to index a nil value");
+        assert_eq!(ls.to_string(10), "attempt to yield from outside a coroutine");
+    }
+
+    #[test]
+    fn test_yield_across_pcall() {
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        let src = r#"
+        local co = coroutine.wrap(function(a)
+            local ok, b, c = pcall(function(x)
+                local y = coroutine.yield(x + 1)
+                return y * 2, select(2, pcall(coroutine.yield, "inner"))
+            end, a)
+            local ok2, e = pcall(function() coroutine.yield("again"); error("boom") end)
+            local ok3, e3 = xpcall(function() coroutine.yield("handled"); error("oops", 0) end,
+                function(m) return "handler: " .. m end)
+            return tostring(ok) .. " " .. b .. " " .. c, tostring(ok2) .. " " .. e, tostring(ok3) .. " " .. e3
+        end)
+        return co(1), co(5), co("r"), co(), co()
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        let vals: Vec<_> = (1..=ls.get_top()).map(|i| ls.to_string(i)).collect();
+        assert_eq!(vals, ["2", "inner", "again", "handled", "true 10 r", "false test:7: boom", "false handler: oops"]);
+    }
+
+    #[test]
+    fn test_yield_in_metamethods() {
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        let src = r#"
+        local function name(v) return type(v) == "string" and v or "t" end
+        local mt = {
+            __index = function(t, k) return coroutine.yield("index " .. k) end,
+            __newindex = function(t, k, v) rawset(t, k, coroutine.yield("newindex " .. k) .. v) end,
+            __add = function(a, b) return coroutine.yield("add") end,
+            __unm = function(a) return coroutine.yield("unm") end,
+            __len = function(a) return coroutine.yield("len") end,
+            __concat = function(a, b) return coroutine.yield("concat " .. name(a) .. name(b)) end,
+            __eq = function(a, b) return coroutine.yield("eq") end,
+            __lt = function(a, b) return coroutine.yield("lt") end,
+        }
+        local t, u = setmetatable({}, mt), setmetatable({}, mt)
+        local obj = setmetatable({}, {__index = function(o, k)
+            coroutine.yield("method " .. k)
+            return function(self, x) return x * 2 end
+        end})
+        local co = coroutine.create(function()
+            setmetatable(_G, {
+                __index = function(g, k) return coroutine.yield("global " .. k) end,
+                __newindex = function(g, k, v) rawset(g, k, coroutine.yield("set global " .. k) + v) end,
+            })
+            local r = {}
+            r[1] = t.x
+            t.y = "!"
+            r[2] = t.y
+            r[3] = t + 1
+            r[4] = -t
+            r[5] = #t
+            r[6] = "a" .. t .. "b" .. t
+            r[7] = tostring(t == u)
+            r[8] = tostring(t < u)
+            r[9] = tostring(t <= u)
+            r[10] = obj:twice(21)
+            r[11] = select(2, pcall(function() return t.z end))
+            r[12] = missing
+            new_global = 1
+            r[13] = rawget(_G, "new_global")
+            setmetatable(_G, nil)
+            return table.concat(r, " ")
+        end)
+        local answers = {["index x"] = 1, ["newindex y"] = "v", add = 2, unm = 3, len = 4,
+            ["concat bt"] = "X", ["concat tX"] = "Y", eq = false, lt = true,
+            ["method twice"] = 0, ["index z"] = 5, ["global missing"] = "g", ["set global new_global"] = 10}
+        local tags, ok, v = {}, coroutine.resume(co)
+        while ok and coroutine.status(co) == "suspended" do
+            tags[#tags + 1] = v
+            ok, v = coroutine.resume(co, answers[v])
+        end
+        return v, table.concat(tags, ",")
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        assert_eq!(ls.to_string(1), "1 v! 2 3 4 aY false true false 42 5 g 11");
+        let tags = "index x,newindex y,add,unm,len,concat bt,concat tX,eq,lt,lt,method twice,index z,\
+            global missing,set global new_global";
+        assert_eq!(ls.to_string(2), tags);
+    }
+
+    #[test]
+    fn test_yield_across_rust_calls() {
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        let src = r#"
+        local function try(f)
+            local co = coroutine.create(f)
+            return select(2, coroutine.resume(co))
+        end
+        local t = setmetatable({}, {__index = function() return coroutine.yield(1) end})
+        return try(function() table.sort({1, 2}, function() return coroutine.yield(2) end) end),
+            try(function() return table.concat(t, "", 1, 1) end),
+            try(function() return tostring(setmetatable({}, {__tostring = coroutine.yield})) end)
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        let msg = "attempt to yield across a Rust-call boundary";
+        for i in 1..=3 {
+            assert_eq!(ls.to_string(i), msg);
+        }
+    }
+
+    #[test]
+    fn test_suspended_coroutine_cycles() {
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        let src = r#"
+        for i = 1, 2000 do
+            local co
+            co = coroutine.create(function()
+                local t = {co}
+                coroutine.yield(t)
+            end)
+            coroutine.resume(co)
+        end
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "test", "t"), LUA_OK);
+        ls.call(0, 0).unwrap();
+        ls.gc(LUA_GCCOLLECT, 0).unwrap();
+        assert!(ls.gc(LUA_GCCOUNT, 0).unwrap() < 64);
+    }
+
+    #[test]
+    fn test_collect_while_resuming() {
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        let src = r#"
+        local inner = coroutine.wrap(function()
+            for i = 1, 3 do collectgarbage() coroutine.yield(i) end
+        end)
+        local outer = coroutine.wrap(function()
+            for i = 1, 3 do coroutine.yield(inner()) end
+        end)
+        return outer(), outer(), outer()
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        let vals: Vec<_> = (1..=ls.get_top()).map(|i| ls.to_integer(i)).collect();
+        assert_eq!(vals, [1, 2, 3]);
+    }
+}
@@ -0,0 +1,597 @@
+use std::convert::TryFrom;
+use std::io;
+use std::rc::Rc;
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{LuaAPI, LuaDebug, LuaHook, LuaResult, RustFn};
+use crate::state::lua_state::LuaState;
+
+/// The key of the registry table holding the Lua hooks indexed by threads
+const HOOKKEY: &str = "_HKEY";
+
+const HOOK_NAMES: [&str; 5] = ["call", "return", "line", "count", "tail call"];
+
+const DB_FUNCS: &[(&str, RustFn)] = &[
+    ("debug", db_debug),
+    ("gethook", db_gethook),
+    ("getinfo", db_getinfo),
+    ("getlocal", db_getlocal),
+    ("getregistry", db_getregistry),
+    ("getmetatable", db_getmetatable),
+    ("getupvalue", db_getupvalue),
+    ("upvaluejoin", db_upvaluejoin),
+    ("upvalueid", db_upvalueid),
+    ("sethook", db_sethook),
+    ("setlocal", db_setlocal),
+    ("setmetatable", db_setmetatable),
+    ("setupvalue", db_setupvalue),
+    ("traceback", db_traceback),
+];
+
+thread_local! {
+    /// The hook calling the Lua hooks, which is told apart from the hooks set by the host
+    static HOOKF: LuaHook = Rc::new(hookf);
+}
+
+pub fn open_debug(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.new_lib(DB_FUNCS)?;
+    Ok(1)
+}
+
+/// The thread of the optional first argument, None if it is absent or the running one,
+/// and the offset of the other arguments
+fn get_thread(ls: &mut LuaState) -> (Option<isize>, isize) {
+    if !ls.is_thread(1) {
+        return (None, 0);
+    }
+    ls.push_thread();
+    let running = ls.raw_equal(1, -1);
+    ls.pop(1);
+    (if running { None } else { Some(1) }, 1)
+}
+
+/// Run `f` on the thread `co`, whose values left on the top of stack are moved back
+fn on_thread<T>(ls: &mut LuaState, co: Option<isize>, f: impl FnOnce(&mut LuaState) -> T) -> T {
+    match co {
+        Some(idx) => ls.with_thread(idx, f),
+        None => f(ls),
+    }
+}
+
+/// The activation record at `level`, None if the level is out of range
+fn stack_at(ls: &LuaState, level: i64) -> Option<LuaDebug> {
+    usize::try_from(level).ok().and_then(|level| ls.get_stack(level))
+}
+
+// debug.debug ()
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.debug
+fn db_debug(ls: &mut LuaState) -> LuaResult<usize> {
+    loop {
+        eprint!("lua_debug> ");
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(n) if n > 0 && line.trim_end_matches(&['\r', '\n'][..]) != "cont" => {}
+            _ => return Ok(0),
+        }
+        let mut status = ls.load(line.into_bytes(), "=(debug command)", "t");
+        if status == LUA_OK {
+            status = ls.pcall(0, 0, 0);
+        }
+        if status == LUA_ERREXIT {
+            return Err(ls.error());
+        }
+        if status != LUA_OK {
+            eprintln!("{}", ls.to_string2(-1)?);
+        }
+        ls.set_top(0)?; // remove eventual returns
+    }
+}
+
+// debug.getregistry ()
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.getregistry
+fn db_getregistry(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.push_value(LUA_REGISTRYINDEX);
+    Ok(1)
+}
+
+// debug.getmetatable (value)
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.getmetatable
+fn db_getmetatable(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    if !ls.get_metatable(1) {
+        ls.push_nil(); // no metatable
+    }
+    Ok(1)
+}
+
+// debug.setmetatable (value, table)
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.setmetatable
+fn db_setmetatable(ls: &mut LuaState) -> LuaResult<usize> {
+    let t = ls.type_id(2);
+    ls.arg_check(t == LUA_TNIL || t == LUA_TTABLE, 2, "nil or table expected")?;
+    ls.set_top(2)?;
+    ls.set_metatable(1)?;
+    Ok(1) // return 1st argument
+}
+
+// debug.getinfo ([thread,] f [, what])
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.getinfo
+fn db_getinfo(ls: &mut LuaState) -> LuaResult<usize> {
+    let (co, arg) = get_thread(ls);
+    let options = ls.opt_string(arg + 2, "flnStu")?;
+    ls.arg_check(!options.starts_with('>'), arg + 2, "invalid option '>'")?;
+    let top = ls.get_top();
+    let (valid, ar) = if ls.is_function(arg + 1) {
+        // info about a function
+        ls.push_value(arg + 1);
+        let mut ar = LuaDebug::default();
+        (ls.get_info(&format!(">{}", options), &mut ar), ar)
+    } else {
+        // stack level
+        let level = ls.check_integer(arg + 1)?;
+        let found = on_thread(ls, co, |ls| {
+            let mut ar = stack_at(ls, level)?;
+            Some((ls.get_info(&options, &mut ar), ar))
+        });
+        match found {
+            Some(found) => found,
+            None => {
+                ls.push_nil(); // level out of range
+                return Ok(1);
+            }
+        }
+    };
+    if !valid {
+        return Err(ls.arg_error(arg + 2, "invalid option"));
+    }
+    // the function and the active lines are pushed in order
+    let func_idx = top + 1;
+    let lines_idx = if options.contains('f') { top + 2 } else { top + 1 };
+    ls.create_table(0, 2);
+    if options.contains('S') {
+        ls.push_string(ar.source);
+        ls.set_field(-2, "source")?;
+        ls.push_string(ar.short_src);
+        ls.set_field(-2, "short_src")?;
+        ls.push_integer(ar.line_defined as i64);
+        ls.set_field(-2, "linedefined")?;
+        ls.push_integer(ar.last_line_defined as i64);
+        ls.set_field(-2, "lastlinedefined")?;
+        ls.push_string(ar.what.to_string());
+        ls.set_field(-2, "what")?;
+    }
+    if options.contains('l') {
+        ls.push_integer(ar.current_line as i64);
+        ls.set_field(-2, "currentline")?;
+    }
+    if options.contains('u') {
+        ls.push_integer(ar.nups as i64);
+        ls.set_field(-2, "nups")?;
+        ls.push_integer(ar.nparams as i64);
+        ls.set_field(-2, "nparams")?;
+        ls.push_boolean(ar.is_vararg);
+        ls.set_field(-2, "isvararg")?;
+    }
+    if options.contains('n') {
+        match ar.name {
+            Some(name) => ls.push_string(name),
+            None => ls.push_nil(),
+        }
+        ls.set_field(-2, "name")?;
+        ls.push_string(ar.namewhat.to_string());
+        ls.set_field(-2, "namewhat")?;
+    }
+    if options.contains('t') {
+        ls.push_boolean(ar.is_tail_call);
+        ls.set_field(-2, "istailcall")?;
+    }
+    if options.contains('L') {
+        ls.push_value(lines_idx);
+        ls.set_field(-2, "activelines")?;
+    }
+    if options.contains('f') {
+        ls.push_value(func_idx);
+        ls.set_field(-2, "func")?;
+    }
+    Ok(1) // return table
+}
+
+// debug.getlocal ([thread,] f, local)
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.getlocal
+fn db_getlocal(ls: &mut LuaState) -> LuaResult<usize> {
+    let (co, arg) = get_thread(ls);
+    let nvar = ls.check_integer(arg + 2)? as isize;
+    if ls.is_function(arg + 1) {
+        // function argument, the name of its parameter
+        ls.push_value(arg + 1);
+        match ls.get_local(None, nvar) {
+            Some(name) => ls.push_string(name),
+            None => ls.push_nil(),
+        }
+        return Ok(1);
+    }
+    // stack-level argument
+    let level = ls.check_integer(arg + 1)?;
+    let found = on_thread(ls, co, |ls| {
+        let ar = stack_at(ls, level)?;
+        Some(ls.get_local(Some(&ar), nvar))
+    });
+    match found {
+        None => Err(ls.arg_error(arg + 1, "level out of range")),
+        Some(Some(name)) => {
+            ls.push_string(name);
+            ls.rotate(-2, 1)?; // name, value
+            Ok(2)
+        }
+        Some(None) => {
+            ls.push_nil(); // no name (nor value)
+            Ok(1)
+        }
+    }
+}
+
+// debug.setlocal ([thread,] level, local, value)
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.setlocal
+fn db_setlocal(ls: &mut LuaState) -> LuaResult<usize> {
+    let (co, arg) = get_thread(ls);
+    let level = ls.check_integer(arg + 1)?;
+    let nvar = ls.check_integer(arg + 2)? as isize;
+    ls.check_any(arg + 3)?;
+    ls.set_top(arg + 3)?;
+    if let Some(idx) = co {
+        ls.xmove(idx, 1)?; // move value to the thread
+    }
+    let found = on_thread(ls, co, |ls| match stack_at(ls, level) {
+        Some(ar) => {
+            let name = ls.set_local(&ar, nvar);
+            if name.is_none() {
+                ls.pop(1); // pop value (if not popped by 'set_local')
+            }
+            Some(name)
+        }
+        None => {
+            ls.pop(1);
+            None
+        }
+    });
+    match found {
+        None => Err(ls.arg_error(arg + 1, "level out of range")),
+        Some(name) => {
+            match name {
+                Some(name) => ls.push_string(name),
+                None => ls.push_nil(),
+            }
+            Ok(1)
+        }
+    }
+}
+
+// debug.getupvalue (f, up)
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.getupvalue
+fn db_getupvalue(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.check_integer(2)?;
+    ls.check_type(1, LUA_TFUNCTION)?;
+    match usize::try_from(n).ok().and_then(|n| ls.get_upvalue(1, n)) {
+        Some(name) => {
+            ls.push_string(name);
+            ls.insert(-2)?;
+            Ok(2)
+        }
+        None => Ok(0),
+    }
+}
+
+// debug.setupvalue (f, up, value)
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.setupvalue
+fn db_setupvalue(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(3)?;
+    let n = ls.check_integer(2)?;
+    ls.check_type(1, LUA_TFUNCTION)?;
+    ls.set_top(3)?;
+    match usize::try_from(n).ok().and_then(|n| ls.set_upvalue(1, n)) {
+        Some(name) => {
+            ls.push_string(name);
+            Ok(1)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Check that the argument `argnup` is a valid up value index of the function at `argf`
+fn check_upval(ls: &mut LuaState, argf: isize, argnup: isize) -> LuaResult<usize> {
+    let nup = ls.check_integer(argnup)?;
+    ls.check_type(argf, LUA_TFUNCTION)?;
+    let valid = usize::try_from(nup).ok().and_then(|n| ls.upvalue_id(argf, n)).is_some();
+    ls.arg_check(valid, argnup, "invalid upvalue index")?;
+    Ok(nup as usize)
+}
+
+// debug.upvalueid (f, n)
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.upvalueid
+fn db_upvalueid(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = check_upval(ls, 1, 2)?;
+    // there are no light userdata, so the address is an integer
+    let id = ls.upvalue_id(1, n).unwrap();
+    ls.push_integer(id as usize as i64);
+    Ok(1)
+}
+
+// debug.upvaluejoin (f1, n1, f2, n2)
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.upvaluejoin
+fn db_upvaluejoin(ls: &mut LuaState) -> LuaResult<usize> {
+    let n1 = check_upval(ls, 1, 2)?;
+    let n2 = check_upval(ls, 3, 4)?;
+    ls.arg_check(!ls.is_rust_function(1), 1, "Lua function expected")?;
+    ls.arg_check(!ls.is_rust_function(3), 3, "Lua function expected")?;
+    ls.upvalue_join(1, n1, 3, n2);
+    Ok(0)
+}
+
+/// Call the Lua hook of the running thread with the event name and the current line
+fn hookf(ls: &mut LuaState, ar: &mut LuaDebug) -> LuaResult<()> {
+    let top = ls.get_top();
+    ls.get_field(LUA_REGISTRYINDEX, HOOKKEY)?;
+    if ls.is_table(-1) {
+        ls.push_thread();
+        ls.raw_get(-2)?;
+        if ls.is_function(-1) {
+            // is there a hook function?
+            ls.push_string(HOOK_NAMES[ar.event as usize].to_string()); // push event name
+            if ar.current_line >= 0 {
+                ls.push_integer(ar.current_line as i64); // push current line
+            } else {
+                ls.push_nil();
+            }
+            ls.call(2, 0)?; // call hook function
+        }
+    }
+    ls.set_top(top)
+}
+
+/// Convert a string mask of `debug.sethook` into a bit mask
+fn make_mask(smask: &str, count: i64) -> i32 {
+    let mut mask = 0;
+    if smask.contains('c') {
+        mask |= LUA_MASKCALL;
+    }
+    if smask.contains('r') {
+        mask |= LUA_MASKRET;
+    }
+    if smask.contains('l') {
+        mask |= LUA_MASKLINE;
+    }
+    if count > 0 {
+        mask |= LUA_MASKCOUNT;
+    }
+    mask
+}
+
+/// Convert a bit mask into a string mask of `debug.gethook`
+fn unmake_mask(mask: i32) -> String {
+    let mut smask = String::new();
+    if mask & LUA_MASKCALL != 0 {
+        smask.push('c');
+    }
+    if mask & LUA_MASKRET != 0 {
+        smask.push('r');
+    }
+    if mask & LUA_MASKLINE != 0 {
+        smask.push('l');
+    }
+    smask
+}
+
+/// Push the key of the thread `co` in the hook table
+fn push_thread_key(ls: &mut LuaState, co: Option<isize>) {
+    match co {
+        Some(idx) => ls.push_value(idx),
+        None => {
+            ls.push_thread();
+        }
+    }
+}
+
+// debug.sethook ([thread,] hook, mask [, count])
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.sethook
+fn db_sethook(ls: &mut LuaState) -> LuaResult<usize> {
+    let (co, arg) = get_thread(ls);
+    let (func, mask, count) = if ls.is_none_or_nil(arg + 1) {
+        // no hook, turn off hooks
+        ls.set_top(arg + 1)?;
+        (None, 0, 0)
+    } else {
+        let smask = ls.check_string(arg + 2)?;
+        ls.check_type(arg + 1, LUA_TFUNCTION)?;
+        let count = ls.opt_integer(arg + 3, 0)?;
+        (Some(HOOKF.with(|h| h.clone())), make_mask(&smask, count), count as i32)
+    };
+    if ls.get_field(LUA_REGISTRYINDEX, HOOKKEY)? == LUA_TNIL {
+        // create a hook table with weak keys
+        ls.pop(1);
+        ls.create_table(0, 2);
+        ls.push_value(-1);
+        ls.set_field(LUA_REGISTRYINDEX, HOOKKEY)?;
+        ls.push_string("k".to_string());
+        ls.set_field(-2, "__mode")?;
+        ls.push_value(-1);
+        ls.set_metatable(-2)?; // setmetatable(hooktable) = hooktable
+    }
+    push_thread_key(ls, co);
+    ls.push_value(arg + 1); // value (hook function)
+    ls.raw_set(-3)?; // hooktable[thread] = new Lua hook
+    on_thread(ls, co, |ls| ls.set_hook(func, mask, count));
+    Ok(0)
+}
+
+// debug.gethook ([thread])
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.gethook
+fn db_gethook(ls: &mut LuaState) -> LuaResult<usize> {
+    let (co, _) = get_thread(ls);
+    let (hook, mask, count) = on_thread(ls, co, |ls| (ls.get_hook(), ls.get_hook_mask(), ls.get_hook_count()));
+    match hook {
+        None => ls.push_nil(), // no hook
+        Some(hook) if !HOOKF.with(|h| Rc::ptr_eq(h, &hook)) => ls.push_string("external hook".to_string()),
+        Some(_) => {
+            ls.get_field(LUA_REGISTRYINDEX, HOOKKEY)?;
+            push_thread_key(ls, co);
+            ls.raw_get(-2)?; // 1st result = hooktable[thread]
+            ls.remove(-2)?; // remove hook table
+        }
+    }
+    ls.push_string(unmake_mask(mask)); // 2nd result = mask
+    ls.push_integer(count as i64); // 3rd result = count
+    Ok(3)
+}
+
+// debug.traceback ([thread,] [message [, level]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-debug.traceback
+fn db_traceback(ls: &mut LuaState) -> LuaResult<usize> {
+    let (co, arg) = get_thread(ls);
+    let msg = ls.to_stringx(arg + 1);
+    if msg.is_none() && !ls.is_none_or_nil(arg + 1) {
+        // non-string 'msg', return it untouched
+        ls.push_value(arg + 1);
+    } else {
+        let level = ls.opt_integer(arg + 2, if co.is_none() { 1 } else { 0 })?;
+        let level = level.max(0) as usize;
+        on_thread(ls, co, |ls| ls.traceback(msg.as_deref(), level))?;
+    }
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::test_util::{execute, results};
+
+    #[test]
+    fn test_getinfo() {
+        let ls = execute(r#"
+        local function f(a, b, ...)
+            local info = debug.getinfo(1)
+            local caller = debug.getinfo(2, "Sl")
+            return info, caller
+        end
+        local info, caller = f()
+        local c = debug.getinfo(print)
+        local t = {}
+        local function g() t.info = debug.getinfo(1, "n") end
+        t.g = g
+        t.g()
+        local lines = debug.getinfo(f, "L").activelines
+        return info.source, info.short_src, info.currentline, info.linedefined, info.lastlinedefined,
+            info.what, info.name, info.namewhat, info.nups, info.nparams, tostring(info.func == f),
+            caller.what, caller.currentline, c.what, c.short_src, c.currentline,
+            t.info.name .. " " .. t.info.namewhat, tostring(lines[3] and lines[6] and not lines[7]),
+            debug.getinfo(100), select(2, pcall(debug.getinfo, 1, "X"))
+        "#);
+        assert_eq!(
+            results(&ls),
+            vec![
+                "=test", "test", "3", "2", "6", "Lua", "f", "local", "1", "2", "true", "main", "7", "C", "[C]", "-1",
+                "g field", "true", "nil", "bad argument #2 (invalid option)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locals_upvalues() {
+        let ls = execute(r#"
+        local function f(a, b, ...)
+            local x = 10
+            local names = {}
+            for i = 1, 4 do names[i] = debug.getlocal(1, i) or "-" end
+            debug.setlocal(1, 3, 20)
+            local vname, vval = debug.getlocal(1, -2)
+            return table.concat(names, ","), x, vname, vval, debug.getlocal(f, 2)
+        end
+        local names, x, vname, vval, param = f(1, 2, "va1", "va2")
+        local u1, u2 = 1, 2
+        local function g() return u1 end
+        local function h() return u2 end
+        local gname = debug.getupvalue(g, 1)
+        debug.setupvalue(g, 1, 11)
+        local same = debug.upvalueid(g, 1) == debug.upvalueid(h, 1)
+        debug.upvaluejoin(g, 1, h, 1)
+        local joined = debug.upvalueid(g, 1) == debug.upvalueid(h, 1)
+        local mt = {}
+        debug.setmetatable(10, mt)
+        local nmt = debug.getmetatable(20) == mt
+        debug.setmetatable(10, nil)
+        return names, x, vname, vval, param, gname, u1, tostring(same), tostring(joined), g(), tostring(nmt),
+            select(2, pcall(debug.getlocal, 50, 1)), select(2, pcall(debug.upvalueid, g, 5))
+        "#);
+        assert_eq!(
+            results(&ls),
+            vec![
+                "a,b,x,names", "20", "(*vararg)", "va2", "b", "u1", "11", "false", "true", "2", "true",
+                "bad argument #1 (level out of range)", "bad argument #2 (invalid upvalue index)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hooks() {
+        let ls = execute(r#"
+        local events = {}
+        local function f(x)
+            return x + 1
+        end
+        debug.sethook(function(event, line)
+            events[#events + 1] = event .. (line and ":" .. line or "")
+        end, "crl")
+        f(1)
+        debug.sethook()
+        local count = 0
+        debug.sethook(function() count = count + 1 end, "", 10)
+        for i = 1, 100 do end
+        local hook, mask, n = debug.gethook()
+        debug.sethook()
+        local co = coroutine.create(function() coroutine.yield() end)
+        debug.sethook(co, print, "l")
+        local _, comask = debug.gethook(co)
+        return table.concat(events, " "), count > 20, type(hook), mask, n, debug.gethook(), comask
+        "#);
+        assert_eq!(
+            results(&ls),
+            vec![
+                "return line:9 call line:4 return line:10 call",
+                "nil",
+                "function",
+                "",
+                "10",
+                "nil",
+                "l",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_traceback() {
+        let ls = execute(r#"
+        local function f()
+            return debug.traceback("msg")
+        end
+        local t = {}
+        function t.g() local s = f() return s end
+        local s = t.g()
+        local co = coroutine.create(function() local x = 1; coroutine.yield() end)
+        coroutine.resume(co)
+        local function deep(n) if n == 0 then return debug.traceback() end return (deep(n - 1)) end
+        local _, lines = deep(30):gsub("\n", "")
+        return s, debug.traceback(co), debug.getinfo(co, 1, "l").currentline, debug.getlocal(co, 1, 1),
+            debug.traceback(t), lines
+        "#);
+        assert_eq!(
+            results(&ls),
+            vec![
+                "msg\nstack traceback:\n\ttest:3: in upvalue 'f'\n\ttest:6: in field 'g'\n\ttest:7: in main chunk",
+                "stack traceback:\n\t[C]: in function 'coroutine.yield'\n\ttest:8: in function <test:8>",
+                "8",
+                "x",
+                "nil",
+                "22",
+            ]
+        );
+    }
+}
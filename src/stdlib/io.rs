@@ -0,0 +1,1060 @@
+use std::cell::{RefCell, RefMut};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::rc::Rc;
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{upvalue_index, LuaAPI, LuaResult, RustFn};
+use crate::number::format::FormatSpec;
+use crate::state::lua_state::LuaState;
+use crate::state::lua_userdata::LuaUserData;
+use crate::state::lua_value::is_space;
+use crate::stdlib::os::make_temp_file;
+
+/// Name of the metatable of file handles in the registry
+const LUA_FILEHANDLE: &str = "FILE*";
+
+/// Keys of the default input and output files in the registry
+const IO_INPUT: &str = "_IO_input";
+const IO_OUTPUT: &str = "_IO_output";
+
+/// Default size of buffers
+const LUAL_BUFFERSIZE: usize = 8192;
+
+/// Maximum length of a numeral read by "n"
+const L_MAXLENNUM: usize = 200;
+
+/// Maximum number of formats of `lines`, which are up values of the iterator
+const MAXARGLINE: isize = 250;
+
+const IO_FUNCS: &[(&str, RustFn)] = &[
+    ("close", io_close),
+    ("flush", io_flush),
+    ("input", io_input),
+    ("lines", io_lines),
+    ("open", io_open),
+    ("output", io_output),
+    ("popen", io_popen),
+    ("read", io_read),
+    ("tmpfile", io_tmpfile),
+    ("type", io_type),
+    ("write", io_write),
+];
+
+/// Methods of file handles, which are in their metatable
+const FILE_METHODS: &[(&str, RustFn)] = &[
+    ("close", f_close),
+    ("flush", f_flush),
+    ("lines", f_lines),
+    ("read", f_read),
+    ("seek", f_seek),
+    ("setvbuf", f_setvbuf),
+    ("write", f_write),
+    ("__gc", f_gc),
+    ("__tostring", f_tostring),
+];
+
+pub fn open_io(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.new_lib(IO_FUNCS)?;
+    create_meta(ls)?;
+    // create (and set) default files
+    create_std_file(ls, Stream::Stdin, Some(IO_INPUT), "stdin")?;
+    create_std_file(ls, Stream::Stdout, Some(IO_OUTPUT), "stdout")?;
+    create_std_file(ls, Stream::Stderr, None, "stderr")?;
+    Ok(1)
+}
+
+fn create_meta(ls: &mut LuaState) -> LuaResult<()> {
+    ls.new_metatable(LUA_FILEHANDLE)?;
+    for (name, f) in FILE_METHODS {
+        ls.push_rust_function(*f);
+        ls.set_field(-2, name)?;
+    }
+    ls.push_value(-1);
+    ls.set_field(-2, "__index")?; // metatable.__index = metatable
+    ls.pop(1);
+    Ok(())
+}
+
+/// Set a standard file as the field `fname` of the library on the top,
+/// and the default file of registry key `k`
+fn create_std_file(ls: &mut LuaState, stream: Stream, k: Option<&str>, fname: &str) -> LuaResult<()> {
+    new_file(ls, stream)?;
+    if let Some(k) = k {
+        ls.push_value(-1);
+        ls.set_field(LUA_REGISTRYINDEX, k)?;
+    }
+    ls.set_field(-2, fname)
+}
+
+/// The underlying stream of a file handle
+enum Stream {
+    File(File),
+    Stdin,
+    Stdout,
+    Stderr,
+    /// The process of `io.popen`, whose output is read or input is written
+    Pipe(Child),
+}
+
+impl Stream {
+    fn is_standard(&self) -> bool {
+        matches!(self, Stream::Stdin | Stream::Stdout | Stream::Stderr)
+    }
+}
+
+fn bad_stream() -> io::Error {
+    io::Error::other("Bad file descriptor")
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::File(f) => f.read(buf),
+            Stream::Stdin => io::stdin().read(buf),
+            Stream::Pipe(child) => child.stdout.as_mut().ok_or_else(bad_stream)?.read(buf),
+            _ => Err(bad_stream()),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::File(f) => f.write(buf),
+            Stream::Stdout => io::stdout().write(buf),
+            Stream::Stderr => io::stderr().write(buf),
+            Stream::Pipe(child) => child.stdin.as_mut().ok_or_else(bad_stream)?.write(buf),
+            Stream::Stdin => Err(bad_stream()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::File(f) => f.flush(),
+            Stream::Stdout => io::stdout().flush(),
+            Stream::Stderr => io::stderr().flush(),
+            Stream::Pipe(child) => child.stdin.as_mut().map_or(Ok(()), |w| w.flush()),
+            Stream::Stdin => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum BufMode {
+    No,
+    Full,
+    Line,
+}
+
+/// The result of closing a file handle
+enum CloseResult {
+    /// Standard files are not closed
+    Standard,
+    File(io::Result<()>),
+    /// The exit status of the process of `io.popen`
+    Pipe(io::Result<ExitStatus>),
+}
+
+/// File handle of the io library, which is held by a userdata like C `FILE`
+struct LuaFile {
+    /// None if the file is closed
+    stream: Option<Stream>,
+    /// Bytes read ahead, which are consumed from `rpos`
+    rbuf: Vec<u8>,
+    rpos: usize,
+    /// Bytes written and not flushed yet
+    wbuf: Vec<u8>,
+    mode: BufMode,
+    bufsize: usize,
+}
+
+impl LuaFile {
+    fn new(stream: Stream) -> LuaFile {
+        // standard outputs are buffered by Rust already
+        let mode = match stream {
+            Stream::Stdout | Stream::Stderr => BufMode::No,
+            _ => BufMode::Full,
+        };
+        LuaFile {
+            stream: Some(stream),
+            rbuf: Vec::new(),
+            rpos: 0,
+            wbuf: Vec::new(),
+            mode,
+            bufsize: LUAL_BUFFERSIZE,
+        }
+    }
+
+    #[inline]
+    fn is_closed(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    fn stream(&mut self) -> io::Result<&mut Stream> {
+        self.stream.as_mut().ok_or_else(bad_stream)
+    }
+
+    /// Read ahead if all bytes read are consumed, and return whether there are bytes to consume
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.rpos < self.rbuf.len() {
+            return Ok(true);
+        }
+        self.flush()?;
+        let mut buf = vec![0; self.bufsize.max(1)];
+        let n = loop {
+            match self.stream()?.read(&mut buf) {
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                res => break res?,
+            }
+        };
+        buf.truncate(n);
+        self.rbuf = buf;
+        self.rpos = 0;
+        Ok(n > 0)
+    }
+
+    /// The next byte to read without consuming it
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(if self.fill()? { Some(self.rbuf[self.rpos]) } else { None })
+    }
+
+    /// Drop the bytes read ahead, the position of a file is moved back to the first one
+    fn discard_read_ahead(&mut self) -> io::Result<()> {
+        let unread = (self.rbuf.len() - self.rpos) as i64;
+        self.rbuf.clear();
+        self.rpos = 0;
+        if let Some(Stream::File(f)) = self.stream.as_mut() {
+            if unread > 0 {
+                f.seek(SeekFrom::Current(-unread))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.discard_read_ahead()?;
+        self.wbuf.extend_from_slice(data);
+        let full = match self.mode {
+            BufMode::No => true,
+            BufMode::Full => self.wbuf.len() >= self.bufsize,
+            BufMode::Line => data.contains(&b'\n') || self.wbuf.len() >= self.bufsize,
+        };
+        if full {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.stream.is_none() {
+            return Ok(());
+        }
+        let wbuf = std::mem::take(&mut self.wbuf);
+        let stream = self.stream()?;
+        stream.write_all(&wbuf)?;
+        stream.flush()
+    }
+
+    /// Seek like `fseek` and return the new position
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush()?;
+        let unread = (self.rbuf.len() - self.rpos) as i64;
+        self.rbuf.clear();
+        self.rpos = 0;
+        let pos = match pos {
+            SeekFrom::Current(off) => SeekFrom::Current(off - unread),
+            SeekFrom::Start(_) | SeekFrom::End(_) => pos,
+        };
+        match self.stream()? {
+            Stream::File(f) => f.seek(pos),
+            _ => Err(io::Error::other("Illegal seek")),
+        }
+    }
+
+    fn set_vbuf(&mut self, mode: BufMode, size: usize) -> io::Result<()> {
+        self.flush()?;
+        self.mode = mode;
+        self.bufsize = size;
+        Ok(())
+    }
+
+    fn close(&mut self) -> CloseResult {
+        match self.stream {
+            Some(ref s) if s.is_standard() => return CloseResult::Standard,
+            _ => {}
+        }
+        let res = self.flush();
+        match self.stream.take() {
+            Some(Stream::Pipe(mut child)) => {
+                drop(child.stdin.take()); // send EOF to the process
+                CloseResult::Pipe(res.and_then(|_| child.wait()))
+            }
+            _ => CloseResult::File(res),
+        }
+    }
+
+    /* reading functions */
+
+    /// Read a line, the newline is kept unless `chop`, return None at the end of file
+    fn read_line(&mut self, chop: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let mut found = false;
+        while self.fill()? {
+            let avail = &self.rbuf[self.rpos..];
+            match avail.iter().position(|&c| c == b'\n') {
+                Some(i) => {
+                    let end = if chop { i } else { i + 1 };
+                    line.extend_from_slice(&avail[..end]);
+                    self.rpos += i + 1;
+                    found = true;
+                    break;
+                }
+                None => {
+                    line.extend_from_slice(avail);
+                    self.rpos = self.rbuf.len();
+                }
+            }
+        }
+        // return ok if read something
+        Ok(if found || !line.is_empty() { Some(line) } else { None })
+    }
+
+    /// Read the bytes read ahead, which are filled if all are consumed, return None at the end of file
+    fn read_ahead(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !self.fill()? {
+            return Ok(None);
+        }
+        let chunk = self.rbuf[self.rpos..].to_vec();
+        self.rpos = self.rbuf.len();
+        Ok(Some(chunk))
+    }
+
+    /// Read at most `n` bytes, return None at the end of file
+    fn read_chars(&mut self, n: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut chars = Vec::new();
+        while chars.len() < n && self.fill()? {
+            let k = (n - chars.len()).min(self.rbuf.len() - self.rpos);
+            chars.extend_from_slice(&self.rbuf[self.rpos..self.rpos + k]);
+            self.rpos += k;
+        }
+        Ok(if chars.is_empty() { None } else { Some(chars) })
+    }
+
+    /// Read the longest prefix of a numeral, which is empty if it is too long
+    fn read_number(&mut self) -> io::Result<Vec<u8>> {
+        let mut rn = NumReader {
+            f: self,
+            buff: Vec::new(),
+            overflow: false,
+        };
+        while rn.f.peek()?.is_some_and(is_space) {
+            rn.f.rpos += 1; // skip spaces
+        }
+        rn.test2(b"-+")?; // optional sign
+        let mut count = 0;
+        let mut hex = false;
+        if rn.test2(b"00")? {
+            if rn.test2(b"xX")? {
+                hex = true; // numeral is hexadecimal
+            } else {
+                count = 1; // count initial '0' as a valid digit
+            }
+        }
+        count += rn.read_digits(hex)?; // integral part
+        if rn.test2(b"..")? {
+            count += rn.read_digits(hex)?; // fractional part
+        }
+        if count > 0 && rn.test2(if hex { b"pP" } else { b"eE" })? {
+            rn.test2(b"-+")?; // exponent sign
+            rn.read_digits(false)?; // exponent digits
+        }
+        Ok(if rn.overflow { Vec::new() } else { rn.buff })
+    }
+}
+
+impl Drop for LuaFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+        self.close();
+    }
+}
+
+/// Reader of numerals, whose look-ahead byte is peeked
+struct NumReader<'a> {
+    f: &'a mut LuaFile,
+    buff: Vec<u8>,
+    overflow: bool,
+}
+
+impl NumReader<'_> {
+    /// Add the current byte to the buffer if it is in `set`
+    fn test2(&mut self, set: &[u8]) -> io::Result<bool> {
+        match self.f.peek()? {
+            Some(c) if set.contains(&c) => Ok(self.next_c(c)),
+            _ => Ok(false),
+        }
+    }
+
+    fn next_c(&mut self, c: u8) -> bool {
+        if self.buff.len() >= L_MAXLENNUM {
+            self.overflow = true; // buffer overflow: invalidate result
+            return false;
+        }
+        self.buff.push(c);
+        self.f.rpos += 1;
+        true
+    }
+
+    fn read_digits(&mut self, hex: bool) -> io::Result<usize> {
+        let mut count = 0;
+        while let Some(c) = self.f.peek()? {
+            let is_digit = if hex { c.is_ascii_hexdigit() } else { c.is_ascii_digit() };
+            if !is_digit || !self.next_c(c) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/* helpers of file handles */
+
+/// Push a new file handle
+fn new_file(ls: &mut LuaState, stream: Stream) -> LuaResult<()> {
+    ls.new_userdata(Box::new(LuaFile::new(stream)));
+    ls.set_metatable2(LUA_FILEHANDLE)
+}
+
+/// Run `f` with the file of a file handle
+fn with_file<R>(u: &Rc<RefCell<LuaUserData>>, f: impl FnOnce(&mut LuaFile) -> R) -> R {
+    let mut u = u.borrow_mut();
+    f(u.downcast_mut::<LuaFile>().unwrap())
+}
+
+/// The file handle at `arg`, which must not be closed
+fn to_file(ls: &mut LuaState, arg: isize) -> LuaResult<Rc<RefCell<LuaUserData>>> {
+    let u = ls.check_udata(arg, LUA_FILEHANDLE)?;
+    if with_file(&u, |f| f.is_closed()) {
+        return Err(ls.error2("attempt to use a closed file"));
+    }
+    Ok(u)
+}
+
+/// The default file of registry key `findex`
+fn get_io_file(ls: &mut LuaState, findex: &str) -> LuaResult<Rc<RefCell<LuaUserData>>> {
+    ls.get_field(LUA_REGISTRYINDEX, findex)?;
+    let u = ls.to_userdata(-1).unwrap();
+    ls.pop(1);
+    if with_file(&u, |f| f.is_closed()) {
+        let msg = format!("standard {} file is closed", &findex["_IO_".len()..]);
+        return Err(ls.error2(&msg));
+    }
+    Ok(u)
+}
+
+/// Close the file handle and push the results
+fn aux_close(ls: &mut LuaState, u: &Rc<RefCell<LuaUserData>>) -> usize {
+    match with_file(u, |f| f.close()) {
+        CloseResult::Standard => {
+            ls.push_nil();
+            ls.push_string("cannot close standard file".to_string());
+            2
+        }
+        CloseResult::File(res) => ls.file_result(res, None),
+        CloseResult::Pipe(res) => ls.exec_result(res),
+    }
+}
+
+/// Whether the mode of `io.open` is valid, like "r", "w+" or "ab"
+fn check_mode(mode: &str) -> bool {
+    let m = mode.as_bytes();
+    match m.split_first() {
+        Some((c, rest)) if b"rwa".contains(c) => {
+            let rest = rest.strip_prefix(b"+").unwrap_or(rest);
+            rest.iter().all(|&c| c == b'b') // only binary extension
+        }
+        _ => false,
+    }
+}
+
+fn open_file(filename: &str, mode: &str) -> io::Result<File> {
+    let plus = mode.contains('+');
+    let mut opts = OpenOptions::new();
+    match mode.as_bytes()[0] {
+        b'r' => opts.read(true).write(plus),
+        b'w' => opts.write(true).create(true).truncate(true).read(plus),
+        _ => opts.append(true).create(true).read(plus),
+    };
+    opts.open(filename)
+}
+
+/// Open a file and push its handle, raise an error if it fails
+fn open_check_file(ls: &mut LuaState, filename: &str, mode: &str) -> LuaResult<()> {
+    match open_file(filename, mode) {
+        Ok(f) => new_file(ls, Stream::File(f)),
+        Err(err) => {
+            ls.file_result(Err(err), None);
+            let msg = format!("cannot open file '{}' ({})", filename, ls.to_string(-2));
+            Err(ls.error2(&msg))
+        }
+    }
+}
+
+/* reading and writing */
+
+/// Read the formats from `first` of the file, push the results and return their number,
+/// the last one is nil if it fails
+fn g_read(ls: &mut LuaState, u: &Rc<RefCell<LuaUserData>>, first: isize) -> LuaResult<usize> {
+    let nargs = ls.get_top() - first + 1;
+    // the file is borrowed by each read, since the collector may run to check the memory limit
+    let f = || RefMut::map(u.borrow_mut(), |u| u.downcast_mut::<LuaFile>().unwrap());
+    let mut n = first;
+    let mut success = true;
+    let res = (|| -> LuaResult<io::Result<()>> {
+        if nargs == 0 {
+            // no arguments, read a line
+            match f().read_line(true) {
+                Ok(line) => success = push_read(ls, line),
+                Err(err) => return Ok(Err(err)),
+            }
+            n += 1;
+            return Ok(Ok(()));
+        }
+        while n < first + nargs && success {
+            let read = if ls.type_id(n) == LUA_TNUMBER {
+                let l = ls.check_integer(n)?;
+                if l == 0 {
+                    // test end of file
+                    f().peek().map(|c| c.map(|_| Vec::new()))
+                } else {
+                    f().read_chars(l as usize)
+                }
+            } else {
+                let p = ls.check_string(n)?;
+                let p = p.strip_prefix('*').unwrap_or(&p); // skip optional '*' (for compatibility)
+                match p.bytes().next() {
+                    Some(b'n') => match f().read_number() {
+                        Ok(num) => {
+                            success = ls.string_to_number(&String::from_utf8_lossy(&num));
+                            if !success {
+                                ls.push_nil();
+                            }
+                            n += 1;
+                            continue;
+                        }
+                        Err(err) => Err(err),
+                    },
+                    Some(b'l') => f().read_line(true),
+                    Some(b'L') => f().read_line(false),
+                    Some(b'a') => read_all(ls, &f)?.map(Some),
+                    _ => return Err(ls.arg_error(n, "invalid format")),
+                }
+            };
+            match read {
+                Ok(s) => success = push_read(ls, s),
+                Err(err) => return Ok(Err(err)),
+            }
+            n += 1;
+        }
+        Ok(Ok(()))
+    })()?;
+    if let Err(err) = res {
+        return Ok(ls.file_result(Err(err), None));
+    }
+    Ok((n - first) as usize)
+}
+
+/// Read the rest of the file, the memory limit is checked before each chunk is added
+fn read_all<'a>(ls: &mut LuaState, f: &impl Fn() -> RefMut<'a, LuaFile>) -> LuaResult<io::Result<Vec<u8>>> {
+    let mut all = Vec::new();
+    loop {
+        match f().read_ahead() {
+            Ok(Some(chunk)) => {
+                ls.check_memory(all.len() + chunk.len())?;
+                all.extend_from_slice(&chunk);
+            }
+            Ok(None) => return Ok(Ok(all)),
+            Err(err) => return Ok(Err(err)),
+        }
+    }
+}
+
+/// Push a string read or nil
+fn push_read(ls: &mut LuaState, s: Option<Vec<u8>>) -> bool {
+    match s {
+        Some(s) => {
+            ls.push_bytes(s);
+            true
+        }
+        None => {
+            ls.push_nil();
+            false
+        }
+    }
+}
+
+/// Write the values from `arg` to the file
+fn g_write(ls: &mut LuaState, u: &Rc<RefCell<LuaUserData>>, arg: isize) -> LuaResult<io::Result<()>> {
+    let top = ls.get_top();
+    for i in arg..=top {
+        let s = if ls.type_id(i) == LUA_TNUMBER {
+            // optimization: could be done exactly as for strings
+            if ls.is_integer(i) {
+                ls.to_integer(i).to_string().into_bytes()
+            } else {
+                let spec = FormatSpec {
+                    precision: Some(14),
+                    ..FormatSpec::new(b'g')
+                };
+                spec.format_float(ls.to_number(i)).into_bytes()
+            }
+        } else {
+            ls.check_bytes(i)?
+        };
+        if let Err(err) = with_file(u, |f| f.write(&s)) {
+            return Ok(Err(err));
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Push a function reading the formats above the file handle at 1,
+/// which closes the file at the end if `to_close`
+fn aux_lines(ls: &mut LuaState, to_close: bool) -> LuaResult<()> {
+    let n = ls.get_top() - 1; // number of arguments to read
+    ls.arg_check(n <= MAXARGLINE, MAXARGLINE + 2, "too many arguments")?;
+    ls.push_integer(n as i64); // number of arguments to read
+    ls.push_boolean(to_close); // close/not close file when finished
+    ls.rotate(2, 2)?; // move 'n' and 'toclose' to their positions
+    ls.push_rust_closure(Box::new(io_readline), 3 + n as usize);
+    Ok(())
+}
+
+/// The iterator of `lines`
+fn io_readline(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = ls.to_userdata(upvalue_index(1)).unwrap();
+    let n = ls.to_integer(upvalue_index(2)) as isize;
+    if with_file(&u, |f| f.is_closed()) {
+        // file is already closed?
+        return Err(ls.error2("file is already closed"));
+    }
+    ls.set_top(1)?;
+    for i in 1..=n {
+        // push arguments to 'g_read'
+        ls.push_value(upvalue_index(3 + i));
+    }
+    let n = g_read(ls, &u, 2)? as isize; // 'n' is number of results
+    if ls.to_boolean(-n) {
+        // read at least one value?
+        return Ok(n as usize); // return them
+    }
+    // first result is nil: EOF or error
+    if n > 1 {
+        // is there error information?
+        let msg = ls.to_string(-n + 1);
+        return Err(ls.error2(&msg));
+    }
+    if ls.to_boolean(upvalue_index(3)) {
+        // generate error?
+        ls.set_top(0)?;
+        aux_close(ls, &u); // close it
+    }
+    Ok(0)
+}
+
+/* functions of the library */
+
+// io.close ([file])
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.close
+fn io_close(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.is_none(1) {
+        // use standard output
+        ls.get_field(LUA_REGISTRYINDEX, IO_OUTPUT)?;
+    }
+    f_close(ls)
+}
+
+// io.flush ()
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.flush
+fn io_flush(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = get_io_file(ls, IO_OUTPUT)?;
+    let res = with_file(&u, |f| f.flush());
+    Ok(ls.file_result(res, None))
+}
+
+/// Set the default file of registry key `f` if there is an argument, and push it
+fn g_io_file(ls: &mut LuaState, f: &str, mode: &str) -> LuaResult<usize> {
+    if !ls.is_none_or_nil(1) {
+        if let Some(filename) = ls.to_stringx(1) {
+            open_check_file(ls, &filename, mode)?;
+        } else {
+            to_file(ls, 1)?; // check that it's a valid file handle
+            ls.push_value(1);
+        }
+        ls.set_field(LUA_REGISTRYINDEX, f)?;
+    }
+    // return current value
+    ls.get_field(LUA_REGISTRYINDEX, f)?;
+    Ok(1)
+}
+
+// io.input ([file])
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.input
+fn io_input(ls: &mut LuaState) -> LuaResult<usize> {
+    g_io_file(ls, IO_INPUT, "r")
+}
+
+// io.output ([file])
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.output
+fn io_output(ls: &mut LuaState) -> LuaResult<usize> {
+    g_io_file(ls, IO_OUTPUT, "w")
+}
+
+// io.lines ([filename, ···])
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.lines
+fn io_lines(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.is_none(1) {
+        ls.push_nil(); // at least one argument
+    }
+    let to_close = if ls.is_nil(1) {
+        // no file name?
+        ls.get_field(LUA_REGISTRYINDEX, IO_INPUT)?; // get default input
+        ls.replace(1)?;
+        to_file(ls, 1)?;
+        false
+    } else {
+        // open a new file
+        let filename = ls.check_string(1)?;
+        open_check_file(ls, &filename, "r")?;
+        ls.replace(1)?;
+        true
+    };
+    aux_lines(ls, to_close)?;
+    Ok(1)
+}
+
+// io.open (filename [, mode])
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.open
+fn io_open(ls: &mut LuaState) -> LuaResult<usize> {
+    let filename = ls.check_string(1)?;
+    let mode = ls.opt_string(2, "r")?;
+    ls.arg_check(check_mode(&mode), 2, "invalid mode")?;
+    match open_file(&filename, &mode) {
+        Ok(f) => {
+            new_file(ls, Stream::File(f))?;
+            Ok(1)
+        }
+        Err(err) => Ok(ls.file_result(Err(err), Some(&filename))),
+    }
+}
+
+// io.popen (prog [, mode])
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.popen
+fn io_popen(ls: &mut LuaState) -> LuaResult<usize> {
+    let prog = ls.check_string(1)?;
+    let mode = ls.opt_string(2, "r")?;
+    ls.arg_check(mode == "r" || mode == "w", 2, "invalid mode")?;
+    let mut cmd = Command::new("/bin/sh");
+    cmd.arg("-c").arg(&prog);
+    if mode == "r" {
+        cmd.stdout(Stdio::piped());
+    } else {
+        cmd.stdin(Stdio::piped());
+    }
+    match cmd.spawn() {
+        Ok(child) => {
+            new_file(ls, Stream::Pipe(child))?;
+            Ok(1)
+        }
+        Err(err) => Ok(ls.file_result(Err(err), Some(&prog))),
+    }
+}
+
+// io.read (···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.read
+fn io_read(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = get_io_file(ls, IO_INPUT)?;
+    g_read(ls, &u, 1)
+}
+
+// io.tmpfile ()
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.tmpfile
+fn io_tmpfile(ls: &mut LuaState) -> LuaResult<usize> {
+    // the file is removed at once and kept open, so it is deleted when closed
+    let res = make_temp_file().and_then(|(path, f)| fs::remove_file(path).map(|_| f));
+    match res {
+        Ok(f) => {
+            new_file(ls, Stream::File(f))?;
+            Ok(1)
+        }
+        Err(err) => Ok(ls.file_result(Err(err), None)),
+    }
+}
+
+// io.type (obj)
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.type
+fn io_type(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    match ls.test_udata(1, LUA_FILEHANDLE)? {
+        None => ls.push_nil(), // not a file
+        Some(u) if with_file(&u, |f| f.is_closed()) => ls.push_string("closed file".to_string()),
+        Some(_) => ls.push_string("file".to_string()),
+    }
+    Ok(1)
+}
+
+// io.write (···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-io.write
+fn io_write(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = get_io_file(ls, IO_OUTPUT)?;
+    match g_write(ls, &u, 1)? {
+        Ok(()) => {
+            ls.get_field(LUA_REGISTRYINDEX, IO_OUTPUT)?; // file handle is the result
+            Ok(1)
+        }
+        Err(err) => Ok(ls.file_result(Err(err), None)),
+    }
+}
+
+/* methods of file handles */
+
+// file:close ()
+// http://www.lua.org/manual/5.3/manual.html#pdf-file:close
+fn f_close(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = to_file(ls, 1)?; // make sure argument is an open stream
+    Ok(aux_close(ls, &u))
+}
+
+// file:flush ()
+// http://www.lua.org/manual/5.3/manual.html#pdf-file:flush
+fn f_flush(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = to_file(ls, 1)?;
+    let res = with_file(&u, |f| f.flush());
+    Ok(ls.file_result(res, None))
+}
+
+// file:lines (···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-file:lines
+fn f_lines(ls: &mut LuaState) -> LuaResult<usize> {
+    to_file(ls, 1)?; // check that it's a valid file handle
+    aux_lines(ls, false)?;
+    Ok(1)
+}
+
+// file:read (···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-file:read
+fn f_read(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = to_file(ls, 1)?;
+    g_read(ls, &u, 2)
+}
+
+// file:seek ([whence [, offset]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-file:seek
+fn f_seek(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = to_file(ls, 1)?;
+    let whence = ls.opt_string(2, "cur")?;
+    let offset = ls.opt_integer(3, 0)?;
+    let pos = match whence.as_str() {
+        "set" if offset < 0 => None,
+        "set" => Some(SeekFrom::Start(offset as u64)),
+        "cur" => Some(SeekFrom::Current(offset)),
+        "end" => Some(SeekFrom::End(offset)),
+        _ => return Err(ls.arg_error(2, &format!("invalid option '{}'", whence))),
+    };
+    let res = match pos {
+        Some(pos) => with_file(&u, |f| f.seek(pos)),
+        None => Err(io::Error::new(ErrorKind::InvalidInput, "Invalid argument")),
+    };
+    match res {
+        Ok(pos) => {
+            ls.push_integer(pos as i64);
+            Ok(1)
+        }
+        Err(err) => Ok(ls.file_result(Err(err), None)),
+    }
+}
+
+// file:setvbuf (mode [, size])
+// http://www.lua.org/manual/5.3/manual.html#pdf-file:setvbuf
+fn f_setvbuf(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = to_file(ls, 1)?;
+    let mode = match ls.check_string(2)?.as_str() {
+        "no" => BufMode::No,
+        "full" => BufMode::Full,
+        "line" => BufMode::Line,
+        other => return Err(ls.arg_error(2, &format!("invalid option '{}'", other))),
+    };
+    let size = ls.opt_integer(3, LUAL_BUFFERSIZE as i64)?;
+    let res = with_file(&u, |f| f.set_vbuf(mode, size.max(0) as usize));
+    Ok(ls.file_result(res, None))
+}
+
+// file:write (···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-file:write
+fn f_write(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = to_file(ls, 1)?;
+    match g_write(ls, &u, 2)? {
+        Ok(()) => {
+            ls.push_value(1); // file handle is the result
+            Ok(1)
+        }
+        Err(err) => Ok(ls.file_result(Err(err), None)),
+    }
+}
+
+fn f_gc(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = ls.check_udata(1, LUA_FILEHANDLE)?;
+    if !with_file(&u, |f| f.is_closed()) {
+        aux_close(ls, &u); // ignore closed and incompletely open files
+    }
+    Ok(0)
+}
+
+fn f_tostring(ls: &mut LuaState) -> LuaResult<usize> {
+    let u = ls.check_udata(1, LUA_FILEHANDLE)?;
+    if with_file(&u, |f| f.is_closed()) {
+        ls.push_string("file (closed)".to_string());
+    } else {
+        let s = format!("file ({:p})", ls.to_pointer(1));
+        ls.push_string(s);
+    }
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::test_util::{execute, results};
+
+    #[test]
+    fn test_read_formats() {
+        let ls = execute(r#"
+        local name = os.tmpname()
+        local f = assert(io.open(name, "w"))
+        assert(f:write("  0x1F -2.5e1 12abc\n", 42, " ", 1.5, " ", 2^63, "\nsecond line\nrest") == f)
+        f:close()
+        f = io.open(name)
+        local a, b, c, d = f:read("n", "n", "*n", "n")
+        local l1 = f:read("L")
+        local l2, n = f:read("l", 2)
+        local c3, e, all, eof = f:read(3, 0, "a", 0)
+        f:close()
+        os.remove(name)
+        return a, b, c, tostring(d), l1, l2, n, c3, e, all, tostring(eof)
+        "#);
+        assert_eq!(
+            results(&ls),
+            vec!["31", "-25.0", "12", "nil", "abc\n", "42 1.5 9.2233720368548e+18", "se", "con", "", "d line\nrest", "nil"]
+        );
+    }
+
+    #[test]
+    fn test_lines_and_defaults() {
+        let ls = execute(r#"
+        local name = os.tmpname()
+        assert(io.output(name) ~= io.stdout)
+        io.write("a\n", "b\n", "\n", "c")
+        io.close()
+        io.output(io.stdout)
+        local t = {}
+        for l in io.lines(name) do t[#t + 1] = "[" .. l .. "]" end
+        for a, b in io.lines(name, 1, "l") do t[#t + 1] = a .. b end
+        io.input(name)
+        local first = io.read()
+        local f = io.input()
+        local rest = {}
+        for l in f:lines("L") do rest[#rest + 1] = l end
+        io.input():close()
+        local ok, msg = pcall(io.read)
+        os.remove(name)
+        return table.concat(t, ","), first, table.concat(rest), io.type(f), io.type(io.stdout), tostring(io.type(42)),
+            tostring(f), msg, select(2, pcall(f.read, f)), select(2, pcall(io.lines, name))
+        "#);
+        let mut r = results(&ls);
+        assert!(r.remove(9).starts_with("cannot open file '"));
+        assert_eq!(
+            r,
+            vec![
+                "[a],[b],[],[c],a,b,\nc",
+                "a",
+                "b\n\nc",
+                "closed file",
+                "file",
+                "nil",
+                "file (closed)",
+                "standard input file is closed",
+                "attempt to use a closed file",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_seek_and_buffering() {
+        let ls = execute(r#"
+        local f = io.tmpfile()
+        f:setvbuf("full", 4)
+        f:write("hello world")
+        local size = f:seek("end")
+        f:seek("set", 6)
+        local w = f:read(3)
+        f:write("LD")
+        f:seek("set")
+        local all = f:read("a")
+        local pos = f:seek("cur", -5)
+        local bad = select(2, pcall(f.seek, f, "top"))
+        f:close()
+        return size, w, all, pos, bad, select(2, io.stdout:close())
+        "#);
+        assert_eq!(
+            results(&ls),
+            vec!["11", "wor", "hello worLD", "6", "bad argument #2 (invalid option 'top')", "cannot close standard file"]
+        );
+    }
+
+    #[test]
+    fn test_popen_and_errors() {
+        let ls = execute(r#"
+        local p = io.popen("echo hello; exit 3")
+        local out = p:read("a")
+        local ok, what, code = p:close()
+        local nf, msg, errno = io.open("/nonexistent/file")
+        return out, tostring(ok), what, code, tostring(nf), msg, errno,
+            select(2, pcall(io.open, "x", "rw")), select(2, pcall(io.read, "x"))
+        "#);
+        assert_eq!(
+            results(&ls),
+            vec![
+                "hello\n",
+                "nil",
+                "exit",
+                "3",
+                "nil",
+                "/nonexistent/file: No such file or directory",
+                "2",
+                "bad argument #2 (invalid mode)",
+                "bad argument #1 (invalid format)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gc_closes_files() {
+        let ls = execute(r#"
+        local name = os.tmpname()
+        do
+            local f = io.open(name, "w")
+            f:write("flushed by __gc")
+        end
+        collectgarbage()
+        local f = io.open(name)
+        local s = f:read("a")
+        f:close()
+        os.remove(name)
+        return s
+        "#);
+        assert_eq!(results(&ls), vec!["flushed by __gc"]);
+    }
+}
@@ -0,0 +1,457 @@
+use std::cell::Cell;
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{LuaAPI, LuaResult, RustFn};
+use crate::state::lua_state::LuaState;
+use crate::state::lua_value::float_to_integer;
+
+const MATH_FUNCS: &[(&str, RustFn)] = &[
+    ("abs", math_abs),
+    ("ceil", math_ceil),
+    ("floor", math_floor),
+    ("fmod", math_fmod),
+    ("modf", math_modf),
+    ("sqrt", math_sqrt),
+    ("exp", math_exp),
+    ("log", math_log),
+    ("sin", math_sin),
+    ("cos", math_cos),
+    ("tan", math_tan),
+    ("asin", math_asin),
+    ("acos", math_acos),
+    ("atan", math_atan),
+    ("deg", math_deg),
+    ("rad", math_rad),
+    ("min", math_min),
+    ("max", math_max),
+    ("tointeger", math_to_integer),
+    ("type", math_type),
+    ("ult", math_ult),
+];
+
+pub fn open_math(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.new_lib(MATH_FUNCS)?;
+    ls.push_number(PI);
+    ls.set_field(-2, "pi")?;
+    ls.push_number(f64::INFINITY);
+    ls.set_field(-2, "huge")?;
+    ls.push_integer(i64::MAX);
+    ls.set_field(-2, "maxinteger")?;
+    ls.push_integer(i64::MIN);
+    ls.set_field(-2, "mininteger")?;
+    set_rand_funcs(ls)?;
+    Ok(1)
+}
+
+/// Push a float as an integer if it has an exact representation
+fn push_num_int(ls: &mut LuaState, d: f64) {
+    match float_to_integer(d) {
+        Some(n) => ls.push_integer(n), // result is integer
+        None => ls.push_number(d),     // result is float
+    }
+}
+
+// math.abs (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.abs
+fn math_abs(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.is_integer(1) {
+        let n = ls.to_integer(1);
+        ls.push_integer(n.wrapping_abs());
+    } else {
+        let n = ls.check_number(1)?;
+        ls.push_number(n.abs());
+    }
+    Ok(1)
+}
+
+// math.floor (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.floor
+fn math_floor(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.is_integer(1) {
+        ls.set_top(1)?; // integer is its own floor
+    } else {
+        let d = ls.check_number(1)?.floor();
+        push_num_int(ls, d);
+    }
+    Ok(1)
+}
+
+// math.ceil (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.ceil
+fn math_ceil(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.is_integer(1) {
+        ls.set_top(1)?; // integer is its own ceil
+    } else {
+        let d = ls.check_number(1)?.ceil();
+        push_num_int(ls, d);
+    }
+    Ok(1)
+}
+
+// math.fmod (x, y)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.fmod
+fn math_fmod(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.is_integer(1) && ls.is_integer(2) {
+        let d = ls.to_integer(2);
+        if (d as u64).wrapping_add(1) <= 1 {
+            // special cases: -1 or 0
+            ls.arg_check(d != 0, 2, "zero")?;
+            ls.push_integer(0); // avoid overflow with 0x80000... / -1
+        } else {
+            let m = ls.to_integer(1);
+            ls.push_integer(m % d);
+        }
+    } else {
+        let a = ls.check_number(1)?;
+        let b = ls.check_number(2)?;
+        ls.push_number(a % b);
+    }
+    Ok(1)
+}
+
+// math.modf (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.modf
+fn math_modf(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.is_integer(1) {
+        ls.set_top(1)?; // number is its own integer part
+        ls.push_number(0.0); // no fractional part
+    } else {
+        let n = ls.check_number(1)?;
+        // integer part (rounds toward zero)
+        let ip = if n < 0.0 { n.ceil() } else { n.floor() };
+        ls.push_number(ip);
+        // fractional part (test needed for inf/-inf)
+        ls.push_number(if n == ip { 0.0 } else { n - ip });
+    }
+    Ok(2)
+}
+
+// math.sqrt (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.sqrt
+fn math_sqrt(ls: &mut LuaState) -> LuaResult<usize> {
+    let x = ls.check_number(1)?;
+    ls.push_number(x.sqrt());
+    Ok(1)
+}
+
+// math.exp (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.exp
+fn math_exp(ls: &mut LuaState) -> LuaResult<usize> {
+    let x = ls.check_number(1)?;
+    ls.push_number(x.exp());
+    Ok(1)
+}
+
+// math.log (x [, base])
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.log
+fn math_log(ls: &mut LuaState) -> LuaResult<usize> {
+    let x = ls.check_number(1)?;
+    let res = if ls.is_none_or_nil(2) {
+        x.ln()
+    } else {
+        let base = ls.check_number(2)?;
+        if base == 2.0 {
+            x.log2()
+        } else if base == 10.0 {
+            x.log10()
+        } else {
+            x.ln() / base.ln()
+        }
+    };
+    ls.push_number(res);
+    Ok(1)
+}
+
+// math.sin (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.sin
+fn math_sin(ls: &mut LuaState) -> LuaResult<usize> {
+    let x = ls.check_number(1)?;
+    ls.push_number(x.sin());
+    Ok(1)
+}
+
+// math.cos (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.cos
+fn math_cos(ls: &mut LuaState) -> LuaResult<usize> {
+    let x = ls.check_number(1)?;
+    ls.push_number(x.cos());
+    Ok(1)
+}
+
+// math.tan (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.tan
+fn math_tan(ls: &mut LuaState) -> LuaResult<usize> {
+    let x = ls.check_number(1)?;
+    ls.push_number(x.tan());
+    Ok(1)
+}
+
+// math.asin (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.asin
+fn math_asin(ls: &mut LuaState) -> LuaResult<usize> {
+    let x = ls.check_number(1)?;
+    ls.push_number(x.asin());
+    Ok(1)
+}
+
+// math.acos (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.acos
+fn math_acos(ls: &mut LuaState) -> LuaResult<usize> {
+    let x = ls.check_number(1)?;
+    ls.push_number(x.acos());
+    Ok(1)
+}
+
+// math.atan (y [, x])
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.atan
+fn math_atan(ls: &mut LuaState) -> LuaResult<usize> {
+    let y = ls.check_number(1)?;
+    let x = ls.opt_number(2, 1.0)?;
+    ls.push_number(y.atan2(x));
+    Ok(1)
+}
+
+// math.deg (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.deg
+fn math_deg(ls: &mut LuaState) -> LuaResult<usize> {
+    let x = ls.check_number(1)?;
+    ls.push_number(x * (180.0 / PI));
+    Ok(1)
+}
+
+// math.rad (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.rad
+fn math_rad(ls: &mut LuaState) -> LuaResult<usize> {
+    let x = ls.check_number(1)?;
+    ls.push_number(x * (PI / 180.0));
+    Ok(1)
+}
+
+// math.min (x, ···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.min
+fn math_min(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top(); // number of arguments
+    let mut imin = 1; // index of current minimum value
+    ls.arg_check(n >= 1, 1, "value expected")?;
+    for i in 2..=n {
+        if ls.compare(i, imin, LUA_OPLT)? {
+            imin = i;
+        }
+    }
+    ls.push_value(imin);
+    Ok(1)
+}
+
+// math.max (x, ···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.max
+fn math_max(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top(); // number of arguments
+    let mut imax = 1; // index of current maximum value
+    ls.arg_check(n >= 1, 1, "value expected")?;
+    for i in 2..=n {
+        if ls.compare(imax, i, LUA_OPLT)? {
+            imax = i;
+        }
+    }
+    ls.push_value(imax);
+    Ok(1)
+}
+
+// math.tointeger (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.tointeger
+fn math_to_integer(ls: &mut LuaState) -> LuaResult<usize> {
+    match ls.to_integerx(1) {
+        Some(n) => ls.push_integer(n),
+        None => {
+            ls.check_any(1)?;
+            ls.push_nil(); // value is not convertible to integer
+        }
+    }
+    Ok(1)
+}
+
+// math.type (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.type
+fn math_type(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.type_id(1) == LUA_TNUMBER {
+        let t = if ls.is_integer(1) { "integer" } else { "float" };
+        ls.push_string(t.to_string());
+    } else {
+        ls.check_any(1)?;
+        ls.push_nil();
+    }
+    Ok(1)
+}
+
+// math.ult (m, n)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.ult
+fn math_ult(ls: &mut LuaState) -> LuaResult<usize> {
+    let a = ls.check_integer(1)?;
+    let b = ls.check_integer(2)?;
+    ls.push_boolean((a as u64) < (b as u64));
+    Ok(1)
+}
+
+/* pseudo-random numbers */
+
+/// The xoshiro256** generator, whose sequence only depends on the seed
+#[derive(Debug, Clone, Copy)]
+struct Xoshiro256 {
+    s: [u64; 4],
+}
+
+impl Xoshiro256 {
+    fn new(n1: i64, n2: i64) -> Self {
+        let mut rng = Xoshiro256 {
+            s: [n1 as u64, 0xff, n2 as u64, 0], // avoid a zero state
+        };
+        for _ in 0..16 {
+            rng.next(); // discard initial values to "spread" seed
+        }
+        rng
+    }
+
+    fn next(&mut self) -> u64 {
+        let s = &mut self.s;
+        let state2 = s[2] ^ s[0];
+        let state3 = s[3] ^ s[1];
+        let res = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        s[0] ^= state3;
+        s[1] ^= state2;
+        s[2] = state2 ^ (s[1] << 17);
+        s[3] = state3.rotate_left(45);
+        res
+    }
+
+    /// A float uniformly distributed in [0, 1)
+    fn next_float(&mut self) -> f64 {
+        (self.next() >> 11) as f64 * 0.5f64.powi(53)
+    }
+
+    /// An integer uniformly distributed in [0, n], computing the smallest `2^b - 1` not
+    /// smaller than `n` and discarding the random values larger than `n`
+    fn project(&mut self, n: u64) -> u64 {
+        let mut lim = n;
+        if lim & (lim.wrapping_add(1)) == 0 {
+            // 'lim + 1' is a power of 2?
+            return self.next() & lim; // no bias
+        }
+        // compute the smallest (2^b - 1) not smaller than n
+        lim |= lim >> 1;
+        lim |= lim >> 2;
+        lim |= lim >> 4;
+        lim |= lim >> 8;
+        lim |= lim >> 16;
+        lim |= lim >> 32;
+        loop {
+            let r = self.next() & lim; // project 'ran' into [0, lim]
+            if r <= n {
+                return r;
+            }
+        }
+    }
+}
+
+/// Set `random` and `randomseed`, which share the state of the generator
+fn set_rand_funcs(ls: &mut LuaState) -> LuaResult<()> {
+    let state = Rc::new(Cell::new(Xoshiro256::new(0, 0)));
+    let rng = state.clone();
+    ls.push_rust_closure(Box::new(move |ls| math_random(ls, &rng)), 0);
+    ls.set_field(-2, "random")?;
+    ls.push_rust_closure(Box::new(move |ls| math_random_seed(ls, &state)), 0);
+    ls.set_field(-2, "randomseed")?;
+    Ok(())
+}
+
+// math.random ([m [, n]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.random
+fn math_random(ls: &mut LuaState, state: &Cell<Xoshiro256>) -> LuaResult<usize> {
+    let mut rng = state.get();
+    let (low, up) = match ls.get_top() {
+        0 => {
+            // no arguments
+            ls.push_number(rng.next_float()); // Number between 0 and 1
+            state.set(rng);
+            return Ok(1);
+        }
+        1 => (1, ls.check_integer(1)?), // only upper limit
+        2 => (ls.check_integer(1)?, ls.check_integer(2)?), // lower and upper limits
+        _ => return Err(ls.error2("wrong number of arguments")),
+    };
+    // random integer in the interval [low, up]
+    ls.arg_check(low <= up, 1, "interval is empty")?;
+    let r = rng.project((up as u64).wrapping_sub(low as u64));
+    state.set(rng);
+    ls.push_integer((r.wrapping_add(low as u64)) as i64);
+    Ok(1)
+}
+
+// math.randomseed (x)
+// http://www.lua.org/manual/5.3/manual.html#pdf-math.randomseed
+fn math_random_seed(ls: &mut LuaState, state: &Cell<Xoshiro256>) -> LuaResult<usize> {
+    let n1 = if ls.is_integer(1) {
+        ls.to_integer(1)
+    } else {
+        ls.check_number(1)? as i64
+    };
+    let n2 = ls.opt_integer(2, 0)?;
+    state.set(Xoshiro256::new(n1, n2));
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stdlib::test_util::{execute, results};
+
+    #[test]
+    fn test_math_functions() {
+        let ls = execute(r#"
+        return math.floor(3.7), math.type(math.floor(3.7)), math.ceil(-3.5), math.floor(1e100),
+            math.abs(math.mininteger), math.fmod(7, -3), math.fmod(-7.5, 2), math.modf(-3.25),
+            math.max(3, 7.5, -1), math.min(3, 7.5, -1), math.tointeger(4.0), math.tointeger(4.5),
+            math.type("1"), math.ult(1, -1), math.log(8, 2), math.log(100, 10), math.sqrt(16),
+            math.huge, -math.huge, math.maxinteger + 1 == math.mininteger, math.pi
+        "#);
+        let expected = [
+            "3", "integer", "-3", "1e+100", "-9223372036854775808", "1", "-1.5", "-3.0", "7.5", "-1", "4", "nil",
+            "nil", "nil", "3.0", "2.0", "4.0", "inf", "-inf", "nil", "3.1415926535898",
+        ];
+        assert_eq!(results(&ls), expected);
+        assert!(ls.to_boolean(14));
+        assert!(ls.to_boolean(20));
+    }
+
+    #[test]
+    fn test_random() {
+        let ls = execute(r#"
+        local function sample()
+            local t = {}
+            for i = 1, 10 do t[i] = math.random(1, 6) end
+            return table.concat(t, ",")
+        end
+        math.randomseed(42)
+        local s1 = sample()
+        math.randomseed(42)
+        local s2 = sample()
+        math.randomseed(7)
+        local s3 = sample()
+        local in_range = true
+        for _ = 1, 1000 do
+            local f, i = math.random(), math.random(-3, 3)
+            in_range = in_range and f >= 0 and f < 1 and i >= -3 and i <= 3
+        end
+        local ok, e = pcall(math.random, 2, 1)
+        return s1 == s2, s1 ~= s3, in_range, math.random(5, 5), math.type(math.random(math.mininteger, math.maxinteger)), e
+        "#);
+        assert!(ls.to_boolean(1));
+        assert!(ls.to_boolean(2));
+        assert!(ls.to_boolean(3));
+        assert_eq!(ls.to_integerx(4), Some(5));
+        assert_eq!(ls.to_string(5), "integer");
+        assert_eq!(ls.to_string(6), "bad argument #1 (interval is empty)");
+    }
+}
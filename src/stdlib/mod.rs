@@ -0,0 +1,17 @@
+//! Lua standard libraries
+
+pub mod base;
+pub mod coroutine;
+pub mod debug;
+pub mod io;
+pub mod math;
+pub mod os;
+pub mod package;
+mod pattern;
+pub mod sandbox;
+pub mod string;
+pub mod table;
+#[cfg(test)]
+mod test_util;
+mod time;
+pub mod utf8;
@@ -0,0 +1,422 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{LuaAPI, LuaError, LuaResult, RustFn};
+use crate::state::lua_state::LuaState;
+use crate::stdlib::time::{make_time, Tm};
+
+const OS_FUNCS: &[(&str, RustFn)] = &[
+    ("clock", os_clock),
+    ("date", os_date),
+    ("difftime", os_difftime),
+    ("execute", os_execute),
+    ("exit", os_exit),
+    ("getenv", os_getenv),
+    ("remove", os_remove),
+    ("rename", os_rename),
+    ("setlocale", os_setlocale),
+    ("time", os_time),
+    ("tmpname", os_tmpname),
+];
+
+lazy_static! {
+    /// The time the library is opened, which approximates the start of the process
+    static ref START: Instant = Instant::now();
+}
+
+pub fn open_os(ls: &mut LuaState) -> LuaResult<usize> {
+    lazy_static::initialize(&START);
+    ls.new_lib(OS_FUNCS)?;
+    Ok(1)
+}
+
+/// The current time in seconds since the epoch
+fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+// os.clock ()
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.clock
+fn os_clock(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.push_number(START.elapsed().as_secs_f64());
+    Ok(1)
+}
+
+/* time/date operations */
+
+/// Set the fields of the date table on the top
+fn set_all_fields(ls: &mut LuaState, tm: &Tm) -> LuaResult<()> {
+    let fields = [
+        ("sec", tm.sec),
+        ("min", tm.min),
+        ("hour", tm.hour),
+        ("day", tm.day),
+        ("month", tm.month),
+        ("year", tm.year),
+        ("wday", tm.wday + 1),
+        ("yday", tm.yday + 1),
+    ];
+    for &(key, value) in fields.iter() {
+        ls.push_integer(value);
+        ls.set_field(-2, key)?;
+    }
+    ls.push_boolean(tm.isdst);
+    ls.set_field(-2, "isdst")
+}
+
+/// Get the field `key` of the date table on the top, which is required if there is no default `d`,
+/// its value minus `delta` must fit into a C int
+fn get_field(ls: &mut LuaState, key: &str, d: Option<i64>, delta: i64) -> LuaResult<i64> {
+    let t = ls.get_field(-1, key)?;
+    let res = match ls.to_integerx(-1) {
+        Some(res) => {
+            let fits = if res >= 0 {
+                res as u64 <= i32::MAX as u64 + delta as u64
+            } else {
+                i32::MIN as i64 + delta <= res
+            };
+            if !fits {
+                return Err(ls.error2(&format!("field '{}' is out-of-bound", key)));
+            }
+            res
+        }
+        None if t != LUA_TNIL => return Err(ls.error2(&format!("field '{}' is not an integer", key))),
+        None => match d {
+            Some(d) => d,
+            None => return Err(ls.error2(&format!("field '{}' missing in date table", key))),
+        },
+    };
+    ls.pop(1);
+    Ok(res)
+}
+
+/// Valid conversions of `strftime` in C99, which may have the modifier 'E' or 'O'
+const STRFTIME_OPTIONS: &[u8] = b"aAbBcCdDeFgGhHIjmMnprRStTuUVwWxXyYzZ%";
+const STRFTIME_E_OPTIONS: &[u8] = b"cCxXyY";
+const STRFTIME_O_OPTIONS: &[u8] = b"deHImMSuUVwWy";
+
+/// The length of the conversion at the start of `conv`
+fn check_option(ls: &LuaState, conv: &[u8]) -> LuaResult<usize> {
+    match conv {
+        [c, ..] if STRFTIME_OPTIONS.contains(c) => Ok(1),
+        [b'E', c, ..] if STRFTIME_E_OPTIONS.contains(c) => Ok(2),
+        [b'O', c, ..] if STRFTIME_O_OPTIONS.contains(c) => Ok(2),
+        _ => {
+            let msg = format!("invalid conversion specifier '%{}'", String::from_utf8_lossy(conv));
+            Err(ls.arg_error(1, &msg))
+        }
+    }
+}
+
+// os.date ([format [, time]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.date
+fn os_date(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = ls.opt_bytes(1, b"%c")?;
+    let t = if ls.is_none_or_nil(2) { now() } else { ls.check_integer(2)? };
+    let (utc, fmt) = match s.as_slice() {
+        [b'!', rest @ ..] => (true, rest), // UTC
+        fmt => (false, fmt),
+    };
+    let tm = match Tm::from_time(t, utc) {
+        Some(tm) => tm,
+        None => return Err(ls.error2("time result cannot be represented in this installation")),
+    };
+    if fmt == b"*t" {
+        ls.create_table(0, 9); // 9 = number of fields
+        set_all_fields(ls, &tm)?;
+        return Ok(1);
+    }
+
+    let mut out = Vec::with_capacity(fmt.len());
+    let mut i = 0;
+    while i < fmt.len() {
+        if fmt[i] != b'%' {
+            out.push(fmt[i]);
+            i += 1;
+        } else {
+            let len = check_option(ls, &fmt[i + 1..])?;
+            tm.format(&fmt[i + 1..i + 1 + len], &mut out);
+            i += 1 + len;
+        }
+    }
+    ls.push_bytes(out);
+    Ok(1)
+}
+
+// os.time ([table])
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.time
+fn os_time(ls: &mut LuaState) -> LuaResult<usize> {
+    let t = if ls.is_none_or_nil(1) {
+        now() // called without args, get current time
+    } else {
+        ls.check_type(1, LUA_TTABLE)?;
+        ls.set_top(1)?; // make sure table is at the top
+        let sec = get_field(ls, "sec", Some(0), 0)?;
+        let min = get_field(ls, "min", Some(0), 0)?;
+        let hour = get_field(ls, "hour", Some(12), 0)?;
+        let day = get_field(ls, "day", None, 0)?;
+        let month = get_field(ls, "month", None, 1)?;
+        let year = get_field(ls, "year", None, 1900)?;
+        let t = make_time(year, month, day, hour, min, sec);
+        // update the fields with the normalized values
+        match Tm::from_time(t, false) {
+            Some(tm) => set_all_fields(ls, &tm)?,
+            None => return Err(ls.error2("time result cannot be represented in this installation")),
+        }
+        t
+    };
+    ls.push_integer(t);
+    Ok(1)
+}
+
+// os.difftime (t2, t1)
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.difftime
+fn os_difftime(ls: &mut LuaState) -> LuaResult<usize> {
+    let t1 = ls.check_integer(1)?;
+    let t2 = ls.check_integer(2)?;
+    ls.push_number(t1 as f64 - t2 as f64);
+    Ok(1)
+}
+
+/* system operations */
+
+// os.execute ([command])
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.execute
+fn os_execute(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.is_none_or_nil(1) {
+        ls.push_boolean(true); // true if there is a shell
+        return Ok(1);
+    }
+    let cmd = ls.check_string(1)?;
+    let status = Command::new("/bin/sh").arg("-c").arg(&cmd).status();
+    Ok(ls.exec_result(status))
+}
+
+// os.exit ([code [, close]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.exit
+fn os_exit(ls: &mut LuaState) -> LuaResult<usize> {
+    let status = if ls.is_boolean(1) {
+        if ls.to_boolean(1) {
+            0
+        } else {
+            1
+        }
+    } else {
+        ls.opt_integer(1, 0)? as i32
+    };
+    let close = ls.to_boolean(2);
+    match ls.exit_handler() {
+        Some(handler) => {
+            handler(status, close);
+            ls.push_integer(status as i64);
+            Err(LuaError {
+                status: LUA_ERREXIT,
+                ..ls.error()
+            })
+        }
+        None => std::process::exit(status),
+    }
+}
+
+// os.getenv (varname)
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.getenv
+fn os_getenv(ls: &mut LuaState) -> LuaResult<usize> {
+    let name = ls.check_string(1)?;
+    let valid = !name.is_empty() && !name.contains('=') && !name.contains('\0');
+    match std::env::var_os(&name).filter(|_| valid) {
+        Some(value) => ls.push_string(value.to_string_lossy().into_owned()),
+        None => ls.push_nil(),
+    }
+    Ok(1)
+}
+
+// os.remove (filename)
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.remove
+fn os_remove(ls: &mut LuaState) -> LuaResult<usize> {
+    let filename = ls.check_string(1)?;
+    // like C `remove`, an empty directory is removed as well
+    let res = fs::remove_file(&filename).or_else(|err| match fs::metadata(&filename) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir(&filename),
+        _ => Err(err),
+    });
+    Ok(ls.file_result(res, Some(&filename)))
+}
+
+// os.rename (oldname, newname)
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.rename
+fn os_rename(ls: &mut LuaState) -> LuaResult<usize> {
+    let from_name = ls.check_string(1)?;
+    let to_name = ls.check_string(2)?;
+    let res = fs::rename(&from_name, &to_name);
+    Ok(ls.file_result(res, Some(&from_name)))
+}
+
+/// Create a new file with a unique name in the temporary directory like `mkstemp`
+pub(crate) fn make_temp_file() -> io::Result<(PathBuf, File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+    let seed = (std::process::id() as usize) ^ (nanos as usize);
+    for _ in 0..100 {
+        let n = seed.wrapping_add(COUNTER.fetch_add(1, Ordering::Relaxed).wrapping_mul(0x9e37_79b9));
+        let path = std::env::temp_dir().join(format!("lua_{:06x}", n & 0xff_ffff));
+        match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(ref err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(io::Error::new(ErrorKind::AlreadyExists, "unable to generate a unique filename"))
+}
+
+// os.tmpname ()
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.tmpname
+fn os_tmpname(ls: &mut LuaState) -> LuaResult<usize> {
+    // the file is created to reserve the name
+    match make_temp_file() {
+        Ok((path, _)) => {
+            ls.push_string(path.to_string_lossy().into_owned());
+            Ok(1)
+        }
+        Err(_) => Err(ls.error2("unable to generate a unique filename")),
+    }
+}
+
+// os.setlocale (locale [, category])
+// http://www.lua.org/manual/5.3/manual.html#pdf-os.setlocale
+fn os_setlocale(ls: &mut LuaState) -> LuaResult<usize> {
+    const CAT_NAMES: &[&str] = &["all", "collate", "ctype", "monetary", "numeric", "time"];
+    let cat = ls.opt_string(2, "all")?;
+    if !CAT_NAMES.contains(&cat.as_str()) {
+        return Err(ls.arg_error(2, &format!("invalid option '{}'", cat)));
+    }
+    // only the C locale is available
+    let locale = if ls.is_none_or_nil(1) {
+        "C".to_string()
+    } else {
+        ls.check_string(1)?
+    };
+    match locale.as_str() {
+        "" | "C" | "POSIX" => ls.push_string("C".to_string()),
+        _ => ls.push_nil(),
+    }
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stdlib::test_util::{execute, results};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_date() {
+        let ls = execute(r#"
+        local t = 951782400 + 13 * 3600 + 5 * 60 + 9
+        local d = os.date("!*t", t)
+        return os.date("!%Y-%m-%d %H:%M:%S", t), os.date("!%c|%x|%X|%p|%j|%%|%Ey", t),
+            d.year, d.month, d.day, d.hour, d.min, d.sec, d.wday, d.yday, tostring(d.isdst),
+            select(2, pcall(os.date, "%Q")), select(2, pcall(os.date, "%E")),
+            os.time(os.date("*t", t)) == t and "roundtrip"
+        "#);
+        assert_eq!(
+            results(&ls),
+            vec![
+                "2000-02-29 13:05:09",
+                "Tue Feb 29 13:05:09 2000|02/29/00|13:05:09|PM|060|%|00",
+                "2000",
+                "2",
+                "29",
+                "13",
+                "5",
+                "9",
+                "3",
+                "60",
+                "false",
+                "bad argument #1 (invalid conversion specifier '%Q')",
+                "bad argument #1 (invalid conversion specifier '%E')",
+                "roundtrip",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_time_normalization() {
+        let ls = execute(r#"
+        local t = {year = 2000, month = 14, day = 0, hour = 25, min = -1}
+        local n = os.time(t)
+        local d = os.time({year = 2001, month = 2, day = 1, hour = 0, min = 59})
+        return n - d, t.year, t.month, t.day, t.hour, t.min, t.sec, t.wday, t.yday,
+            select(2, pcall(os.time, {year = 2000, month = 1})),
+            select(2, pcall(os.time, {year = 2000, month = 1, day = 1.5})),
+            select(2, pcall(os.time, {year = 2000, month = 1, day = 2^31})),
+            os.difftime(n, d), math.type(os.time()), math.type(os.clock())
+        "#);
+        assert_eq!(
+            results(&ls),
+            vec![
+                "0",
+                "2001",
+                "2",
+                "1",
+                "0",
+                "59",
+                "0",
+                "5",
+                "32",
+                "field 'day' missing in date table",
+                "field 'day' is not an integer",
+                "field 'day' is out-of-bound",
+                "0.0",
+                "integer",
+                "float",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_files() {
+        let ls = execute(r#"
+        local name = os.tmpname()
+        local new_name = name .. ".renamed"
+        local r1 = os.rename(name, new_name)
+        local r2, msg, code = os.remove(name)
+        return tostring(r1), r2, msg == name .. ": No such file or directory", code,
+            tostring(os.remove(new_name)), os.getenv("LUA_RS_UNDEFINED_VARIABLE"),
+            os.setlocale(), os.setlocale("C", "numeric"), os.setlocale("fr_FR"),
+            tostring(os.execute()), select(2, os.execute("exit 3"))
+        "#);
+        assert_eq!(
+            results(&ls),
+            vec!["true", "nil", "nil", "2", "true", "nil", "C", "C", "nil", "true", "exit", "3"]
+        );
+    }
+
+    #[test]
+    fn test_exit_handler() {
+        let code = Rc::new(Cell::new(-1));
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        let c = code.clone();
+        ls.set_exit_handler(Some(Box::new(move |status, _| c.set(status))));
+        let src = r#"
+        local co = coroutine.wrap(function() pcall(os.exit, false) end)
+        pcall(co)
+        return "not reached"
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        let err = ls.call(0, LUA_MULTRET).unwrap_err();
+        assert_eq!(err.status, LUA_ERREXIT);
+        assert_eq!(err.to_string(), "1");
+        assert_eq!(code.get(), 1);
+    }
+}
@@ -0,0 +1,327 @@
+//! Package library, which loads Lua modules found along `package.path`
+
+use std::env;
+use std::fs::File;
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{upvalue_index, LuaAPI, LuaResult, RustFn};
+use crate::state::lua_state::LuaState;
+
+/// directory separator
+const LUA_DIRSEP: &str = "/";
+
+/// separator of the templates in a path
+const LUA_PATH_SEP: &str = ";";
+
+/// mark substituted by the module name in a template
+const LUA_PATH_MARK: &str = "?";
+
+/// mark ignored in `luaopen_` names, kept for `package.config`
+const LUA_IGMARK: &str = "-";
+
+/// mark replaced by the executable directory on Windows, kept for `package.config`
+const LUA_EXEC_DIR: &str = "!";
+
+/// temporary mark of the default path in an environment path
+const AUXMARK: &str = "\u{1}";
+
+const LUA_ROOT: &str = "/usr/local/";
+const LUA_VDIR: &str = "5.3";
+
+lazy_static! {
+    static ref LUA_PATH_DEFAULT: String = {
+        let ldir = format!("{}share/lua/{}/", LUA_ROOT, LUA_VDIR);
+        let cdir = format!("{}lib/lua/{}/", LUA_ROOT, LUA_VDIR);
+        format!("{0}?.lua;{0}?/init.lua;{1}?.lua;{1}?/init.lua;./?.lua;./?/init.lua", ldir, cdir)
+    };
+}
+
+const PK_FUNCS: &[(&str, RustFn)] = &[("searchpath", ll_searchpath)];
+
+pub fn open_package(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.new_lib(PK_FUNCS)?; // create 'package' table
+    create_searchers_table(ls)?;
+    set_path(ls, "path", "LUA_PATH_5_3", "LUA_PATH", &LUA_PATH_DEFAULT)?;
+    // store config information
+    let config = [LUA_DIRSEP, LUA_PATH_SEP, LUA_PATH_MARK, LUA_EXEC_DIR, LUA_IGMARK].join("\n") + "\n";
+    ls.push_string(config);
+    ls.set_field(-2, "config")?;
+    // set field 'loaded'
+    ls.get_subtable(LUA_REGISTRYINDEX, LUA_LOADED_TABLE)?;
+    ls.set_field(-2, "loaded")?;
+    // set field 'preload'
+    ls.get_subtable(LUA_REGISTRYINDEX, LUA_PRELOAD_TABLE)?;
+    ls.set_field(-2, "preload")?;
+    // set 'require' as a global with 'package' as its up value
+    ls.push_global_table();
+    ls.push_value(-2);
+    ls.push_rust_closure(Box::new(ll_require), 1);
+    ls.set_field(-2, "require")?;
+    ls.pop(1); // pop global table
+    Ok(1)
+}
+
+/// Set `package.searchers`, whose searchers share 'package' as their up value
+fn create_searchers_table(ls: &mut LuaState) -> LuaResult<()> {
+    let searchers: &[RustFn] = &[searcher_preload, searcher_lua];
+    ls.create_table(searchers.len(), 0);
+    for (i, searcher) in searchers.iter().enumerate() {
+        ls.push_value(-2); // set 'package' as up value for all searchers
+        ls.push_rust_closure(Box::new(*searcher), 1);
+        ls.set_i(-2, i as i64 + 1)?;
+    }
+    ls.set_field(-2, "searchers")
+}
+
+/// Set the path field from the first environment variable which is defined,
+/// where ";;" is replaced by the default path
+fn set_path(ls: &mut LuaState, field: &str, env1: &str, env2: &str, def: &str) -> LuaResult<()> {
+    let path = match env::var(env1).or_else(|_| env::var(env2)) {
+        Ok(path) => {
+            let sep2 = format!("{0}{0}", LUA_PATH_SEP);
+            let path = path.replace(&sep2, &format!("{0}{1}{0}", LUA_PATH_SEP, AUXMARK));
+            path.replace(AUXMARK, def)
+        }
+        Err(_) => def.to_string(),
+    };
+    ls.push_string(path);
+    ls.set_field(-2, field)
+}
+
+/// Search `name` along the templates of `path`, where `sep` in the name is replaced by `dirsep`,
+/// and return the first readable file or the list of the files tried
+fn search_path(name: &str, path: &str, sep: &str, dirsep: &str) -> Result<String, String> {
+    let name = if sep.is_empty() { name.to_string() } else { name.replace(sep, dirsep) };
+    let mut msg = String::new();
+    for template in path.split(LUA_PATH_SEP).filter(|t| !t.is_empty()) {
+        let filename = template.replace(LUA_PATH_MARK, &name);
+        if File::open(&filename).is_ok() {
+            return Ok(filename);
+        }
+        msg.push_str(&format!("\n\tno file '{}'", filename));
+    }
+    Err(msg)
+}
+
+// package.searchpath (name, path [, sep [, rep]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-package.searchpath
+fn ll_searchpath(ls: &mut LuaState) -> LuaResult<usize> {
+    let name = ls.check_string(1)?;
+    let path = ls.check_string(2)?;
+    let sep = ls.opt_string(3, ".")?;
+    let rep = ls.opt_string(4, LUA_DIRSEP)?;
+    match search_path(&name, &path, &sep, &rep) {
+        Ok(filename) => {
+            ls.push_string(filename);
+            Ok(1)
+        }
+        Err(msg) => {
+            // return fail and the files tried
+            ls.push_nil();
+            ls.push_string(msg);
+            Ok(2)
+        }
+    }
+}
+
+/// Search `name` along `package[pname]`
+fn find_file(ls: &mut LuaState, name: &str, pname: &str) -> LuaResult<Result<String, String>> {
+    ls.get_field(upvalue_index(1), pname)?;
+    let path = match ls.to_stringx(-1) {
+        Some(path) if ls.is_string(-1) => path,
+        _ => return Err(ls.error2(&format!("'package.{}' must be a string", pname))),
+    };
+    ls.pop(1);
+    Ok(search_path(name, &path, ".", LUA_DIRSEP))
+}
+
+/// Return the loader and the file name if the file was loaded
+fn check_load(ls: &mut LuaState, ok: bool, filename: String) -> LuaResult<usize> {
+    if ok {
+        // module loaded successfully, 2nd argument to module
+        ls.push_string(filename);
+        Ok(2)
+    } else {
+        let msg = format!(
+            "error loading module '{}' from file '{}':\n\t{}",
+            ls.to_string(1),
+            filename,
+            ls.to_string(-1)
+        );
+        Err(ls.error2(&msg))
+    }
+}
+
+/// Searcher for the Lua modules in `package.path`, which can be source or precompiled chunks
+fn searcher_lua(ls: &mut LuaState) -> LuaResult<usize> {
+    let name = ls.check_string(1)?;
+    match find_file(ls, &name, "path")? {
+        Ok(filename) => {
+            let ok = ls.load_file(Some(&filename), "bt") == LUA_OK;
+            check_load(ls, ok, filename)
+        }
+        Err(msg) => {
+            ls.push_string(msg); // module not found in this path
+            Ok(1)
+        }
+    }
+}
+
+/// Searcher for the loaders in `package.preload`
+fn searcher_preload(ls: &mut LuaState) -> LuaResult<usize> {
+    let name = ls.check_string(1)?;
+    ls.get_field(LUA_REGISTRYINDEX, LUA_PRELOAD_TABLE)?;
+    if ls.get_field(-1, &name)? == LUA_TNIL {
+        // not found?
+        ls.push_string(format!("\n\tno field package.preload['{}']", name));
+    }
+    Ok(1)
+}
+
+/// Push the loader of `name` and its extra value,
+/// or raise an error with the messages of all searchers
+fn find_loader(ls: &mut LuaState, name: &str) -> LuaResult<()> {
+    // will be at index 3
+    if ls.get_field(upvalue_index(1), "searchers")? != LUA_TTABLE {
+        return Err(ls.error2("'package.searchers' must be a table"));
+    }
+    // iterate over available searchers to find a loader
+    let mut msg = String::new();
+    let mut i = 0;
+    loop {
+        i += 1;
+        if ls.get_i(3, i)? == LUA_TNIL {
+            // no more searchers?
+            ls.pop(1); // remove nil
+            return Err(ls.error2(&format!("module '{}' not found:{}", name, msg)));
+        }
+        ls.push_string(name.to_string());
+        ls.call(1, 2)?; // call it
+        if ls.is_function(-2) {
+            return Ok(()); // module loader found
+        } else if ls.is_string(-2) {
+            // searcher returned error message?
+            ls.pop(1); // remove extra return
+            msg.push_str(&ls.to_string(-1));
+            ls.pop(1);
+        } else {
+            ls.pop(2); // remove both returns
+        }
+    }
+}
+
+// require (modname)
+// http://www.lua.org/manual/5.3/manual.html#pdf-require
+fn ll_require(ls: &mut LuaState) -> LuaResult<usize> {
+    let name = ls.check_string(1)?;
+    ls.set_top(1)?; // LOADED table will be at index 2
+    ls.get_field(LUA_REGISTRYINDEX, LUA_LOADED_TABLE)?;
+    ls.get_field(2, &name)?; // LOADED[name]
+    if ls.to_boolean(-1) {
+        return Ok(1); // package is already loaded
+    }
+    // else must load package
+    ls.pop(1); // remove 'get_field' result
+    find_loader(ls, &name)?;
+    ls.push_string(name.clone()); // pass name as argument to module loader
+    ls.insert(-2)?; // name is 1st argument (before search data)
+    ls.call(2, 1)?; // run loader to load module
+    if !ls.is_nil(-1) {
+        // non-nil return?
+        ls.set_field(2, &name)?; // LOADED[name] = returned value
+    }
+    if ls.get_field(2, &name)? == LUA_TNIL {
+        // module set no value?
+        ls.push_boolean(true); // use true as result
+        ls.push_value(-1); // extra copy to be returned
+        ls.set_field(2, &name)?; // LOADED[name] = true
+    }
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+    use crate::binary;
+    use crate::compiler;
+    use crate::stdlib::test_util::{results, run};
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lua_rs_{}_{}", tag, process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_search_path() {
+        let dir = temp_dir("searchpath");
+        fs::write(dir.join("sub").join("m.lua"), "").unwrap();
+        let path = format!("{0}/?.lua;;{0}/?/init.lua", dir.display());
+        assert_eq!(search_path("sub.m", &path, ".", "/"), Ok(format!("{}/sub/m.lua", dir.display())));
+        assert_eq!(
+            search_path("x_y", &path, "_", "/"),
+            Err(format!("\n\tno file '{0}/x/y.lua'\n\tno file '{0}/x/y/init.lua'", dir.display()))
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_require() {
+        let dir = temp_dir("require");
+        fs::write(dir.join("counter.lua"), "#!/usr/bin/lua\nCOUNT = (COUNT or 0) + 1\nreturn {name = ..., file = select(2, ...)}").unwrap();
+        fs::write(dir.join("sub").join("init.lua"), "x = 1").unwrap();
+        fs::write(dir.join("bad.lua"), "return return").unwrap();
+        let proto = compiler::compile(b"return 6 * 7".to_vec(), "@precompiled".to_string()).unwrap();
+        fs::write(dir.join("precompiled.luac"), binary::encode(proto, Some("@precompiled".to_string()))).unwrap();
+
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        let path = format!("{0}/?.lua;{0}/?/init.lua;{0}/?.luac", dir.display());
+        ls.push_string(path);
+        ls.set_global("PATH").unwrap();
+        run(&mut ls, r#"
+        package.path = PATH
+        package.preload.answer = function(name) return name .. "!" end
+        local c1, c2 = require "counter", require "counter"
+        local ok, err = pcall(require, "missing")
+        local ok2, err2 = pcall(require, "bad")
+        return c1 == c2 and c1.name, COUNT, c1.file == package.searchpath("counter", PATH),
+            package.loaded.counter == c1, require "answer", require "sub", require "precompiled",
+            package.loaded.string == string, ok, err, ok2, err2:match("^error loading module 'bad'")
+        "#);
+        let dir = dir.display();
+        assert_eq!(
+            results(&ls),
+            vec![
+                "counter".to_string(),
+                "1".to_string(),
+                "nil".to_string(), // true
+                "nil".to_string(), // true
+                "answer!".to_string(),
+                "nil".to_string(), // true
+                "42".to_string(),
+                "nil".to_string(), // true
+                "nil".to_string(), // false
+                format!(
+                    "module 'missing' not found:\n\tno field package.preload['missing']\n\
+                    \tno file '{0}/missing.lua'\n\tno file '{0}/missing/init.lua'\n\tno file '{0}/missing.luac'",
+                    dir
+                ),
+                "nil".to_string(), // false
+                "error loading module 'bad'".to_string(),
+            ]
+        );
+        for i in [3, 4, 6, 8] {
+            assert!(ls.to_boolean(i));
+        }
+        for i in [9, 11] {
+            assert!(!ls.to_boolean(i));
+        }
+        fs::remove_dir_all(format!("{}", dir)).unwrap();
+    }
+}
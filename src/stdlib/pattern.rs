@@ -0,0 +1,473 @@
+//! Lua pattern matching, which works on the bytes of strings
+//!
+//! http://www.lua.org/manual/5.3/manual.html#6.4.1
+
+/// Maximum number of captures that a pattern can do during pattern-matching
+pub const LUA_MAXCAPTURES: usize = 32;
+
+/// Maximum recursion depth for `do_match`
+const MAXCCALLS: usize = 200;
+
+/// Escape character in patterns
+const L_ESC: u8 = b'%';
+
+/// Special characters which make a pattern not plain
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
+
+/// A value captured by a successful match
+#[derive(Debug, Clone, PartialEq)]
+pub enum Capture<'a> {
+    Str(&'a [u8]),
+    /// Position capture `()`, which is 1-based
+    Position(usize),
+}
+
+pub type MatchResult<T> = Result<T, String>;
+
+pub struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    match_depth: usize,
+    level: usize,
+    /// Start and length of each capture
+    capture: [(usize, isize); LUA_MAXCAPTURES],
+}
+
+/// Check whether a pattern has no special characters and can be matched by a plain search
+pub fn no_specials(pat: &[u8]) -> bool {
+    !pat.iter().any(|c| SPECIALS.contains(c))
+}
+
+/// Find the first occurrence of `needle` in `haystack` starting from `init`
+pub fn find_plain(haystack: &[u8], needle: &[u8], init: usize) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(init);
+    }
+    haystack[init..].windows(needle.len()).position(|w| w == needle).map(|i| i + init)
+}
+
+impl<'a> MatchState<'a> {
+    pub fn new(src: &'a [u8], pat: &'a [u8]) -> Self {
+        MatchState {
+            src,
+            pat,
+            match_depth: MAXCCALLS,
+            level: 0,
+            capture: [(0, 0); LUA_MAXCAPTURES],
+        }
+    }
+
+    /// Reset the state before trying another match
+    pub fn reprep(&mut self) {
+        self.level = 0;
+        debug_assert_eq!(self.match_depth, MAXCCALLS);
+    }
+
+    /// Number of captures of the last match, where no capture means the whole match
+    pub fn capture_count(&self, whole: bool) -> usize {
+        if self.level == 0 && whole {
+            1
+        } else {
+            self.level
+        }
+    }
+
+    /// The subject from `s` to `e`
+    pub fn src_slice(&self, s: usize, e: usize) -> &'a [u8] {
+        &self.src[s..e]
+    }
+
+    /// The `i`-th capture of the last match of `src[s..e]`
+    pub fn get_capture(&self, i: usize, s: usize, e: usize) -> MatchResult<Capture<'a>> {
+        if i >= self.level {
+            if i != 0 {
+                return Err(format!("invalid capture index %{}", i + 1));
+            }
+            // ms->level == 0, too
+            return Ok(Capture::Str(&self.src[s..e])); // add whole match
+        }
+        let (init, len) = self.capture[i];
+        match len {
+            CAP_UNFINISHED => Err("unfinished capture".to_string()),
+            CAP_POSITION => Ok(Capture::Position(init + 1)),
+            _ => Ok(Capture::Str(&self.src[init..init + len as usize])),
+        }
+    }
+
+    /// Match the pattern from `p` against the subject from `s`,
+    /// and return the end of the match
+    pub fn do_match(&mut self, s: usize, p: usize) -> MatchResult<Option<usize>> {
+        if self.match_depth == 0 {
+            return Err("pattern too complex".to_string());
+        }
+        self.match_depth -= 1;
+        let res = self.match_aux(s, p);
+        self.match_depth += 1;
+        res
+    }
+
+    fn match_aux(&mut self, mut s: usize, mut p: usize) -> MatchResult<Option<usize>> {
+        let pat = self.pat;
+        loop {
+            if p == pat.len() {
+                // end of pattern
+                return Ok(Some(s));
+            }
+            match pat[p] {
+                b'(' => {
+                    // start capture
+                    return if pat.get(p + 1) == Some(&b')') {
+                        // position capture
+                        self.start_capture(s, p + 2, CAP_POSITION)
+                    } else {
+                        self.start_capture(s, p + 1, CAP_UNFINISHED)
+                    };
+                }
+                b')' => {
+                    // end capture
+                    return self.end_capture(s, p + 1);
+                }
+                b'$' if p + 1 == pat.len() => {
+                    // is the '$' the last char in pattern? then check end of string
+                    return Ok(if s == self.src.len() { Some(s) } else { None });
+                }
+                L_ESC if pat.get(p + 1) == Some(&b'b') => {
+                    // balanced string
+                    match self.match_balance(s, p + 2)? {
+                        Some(e) => {
+                            s = e;
+                            p += 4; // skip '%bxy'
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                L_ESC if pat.get(p + 1) == Some(&b'f') => {
+                    // frontier
+                    p += 2;
+                    if pat.get(p) != Some(&b'[') {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let ep = self.class_end(p)?; // points to what is next
+                    let prev = if s == 0 { 0 } else { self.src[s - 1] };
+                    let cur = self.src.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(prev, p, ep - 1) && self.match_bracket_class(cur, p, ep - 1) {
+                        p = ep;
+                        continue;
+                    }
+                    return Ok(None); // match failed
+                }
+                L_ESC if pat.get(p + 1).is_some_and(u8::is_ascii_digit) => {
+                    // capture results (%0-%9)
+                    match self.match_capture(s, pat[p + 1])? {
+                        Some(e) => {
+                            s = e;
+                            p += 2;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                _ => {
+                    // pattern class plus optional suffix
+                    let ep = self.class_end(p)?; // points to optional suffix
+                    if !self.single_match(s, p, ep) {
+                        if let Some(b'*') | Some(b'?') | Some(b'-') = pat.get(ep) {
+                            // accept empty?
+                            p = ep + 1;
+                            continue;
+                        }
+                        // '+' or no suffix
+                        return Ok(None); // fail
+                    }
+                    // matched single
+                    match pat.get(ep) {
+                        Some(b'?') => {
+                            // optional
+                            if let Some(res) = self.do_match(s + 1, ep + 1)? {
+                                return Ok(Some(res));
+                            }
+                            p = ep + 1;
+                        }
+                        Some(b'+') => return self.max_expand(s + 1, p, ep), // 1 or more repetitions
+                        Some(b'*') => return self.max_expand(s, p, ep),     // 0 or more repetitions
+                        Some(b'-') => return self.min_expand(s, p, ep),     // 0 or more repetitions (minimum)
+                        _ => {
+                            // no suffix
+                            s += 1;
+                            p = ep;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The end of the single character class at `p`
+    fn class_end(&self, mut p: usize) -> MatchResult<usize> {
+        let pat = self.pat;
+        let c = pat[p];
+        p += 1;
+        if c == L_ESC {
+            if p == pat.len() {
+                return Err("malformed pattern (ends with '%')".to_string());
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if pat.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // look for a ']'
+            loop {
+                if p >= pat.len() {
+                    return Err("malformed pattern (missing ']')".to_string());
+                }
+                let c = pat[p];
+                p += 1;
+                if c == L_ESC && p < pat.len() {
+                    p += 1; // skip escapes (e.g. '%]')
+                }
+                if pat.get(p) == Some(&b']') {
+                    return Ok(p + 1);
+                }
+            }
+        }
+        Ok(p)
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        match self.src.get(s) {
+            None => false,
+            Some(&c) => match self.pat[p] {
+                b'.' => true, // matches any char
+                L_ESC => match_class(c, self.pat[p + 1]),
+                b'[' => self.match_bracket_class(c, p, ep - 1),
+                pc => pc == c,
+            },
+        }
+    }
+
+    /// Check whether `c` is in the set `[...]` from `p` to the closing bracket at `ec`
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let pat = self.pat;
+        let mut sig = true;
+        if pat[p + 1] == b'^' {
+            sig = false;
+            p += 1; // skip the '^'
+        }
+        p += 1;
+        while p < ec {
+            if pat[p] == L_ESC {
+                p += 1;
+                if match_class(c, pat[p]) {
+                    return sig;
+                }
+            } else if pat[p + 1] == b'-' && p + 2 < ec {
+                if pat[p] <= c && c <= pat[p + 2] {
+                    return sig;
+                }
+                p += 2;
+            } else if pat[p] == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> MatchResult<Option<usize>> {
+        if p + 1 >= self.pat.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
+        }
+        let (b, e) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&b) {
+            return Ok(None);
+        }
+        let mut cont = 1;
+        for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+            if c == e {
+                cont -= 1;
+                if cont == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == b {
+                cont += 1;
+            }
+        }
+        Ok(None) // string ends out of balance
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> MatchResult<Option<usize>> {
+        let mut i = 0; // counts maximum expand for item
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        // keeps trying to match with the maximum repetitions
+        loop {
+            if let Some(res) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(res));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1; // else didn't match; reduce 1 repetition to try again
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> MatchResult<Option<usize>> {
+        loop {
+            if let Some(res) = self.do_match(s, ep + 1)? {
+                return Ok(Some(res));
+            } else if self.single_match(s, p, ep) {
+                s += 1; // try with one more repetition
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> MatchResult<Option<usize>> {
+        if self.level >= LUA_MAXCAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.capture[self.level] = (s, what);
+        self.level += 1;
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.level -= 1; // undo capture
+        }
+        Ok(res)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> MatchResult<Option<usize>> {
+        let l = self.capture_to_close()?;
+        self.capture[l].1 = (s - self.capture[l].0) as isize; // close capture
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.capture[l].1 = CAP_UNFINISHED; // undo capture
+        }
+        Ok(res)
+    }
+
+    fn capture_to_close(&self) -> MatchResult<usize> {
+        (0..self.level)
+            .rev()
+            .find(|&l| self.capture[l].1 == CAP_UNFINISHED)
+            .ok_or_else(|| "invalid pattern capture".to_string())
+    }
+
+    /// Match the previous capture `%l` at `s`
+    fn match_capture(&self, s: usize, l: u8) -> MatchResult<Option<usize>> {
+        let l = self.check_capture(l)?;
+        let (init, len) = self.capture[l];
+        if len < 0 {
+            // a position capture never matches
+            return Ok(None);
+        }
+        let len = len as usize;
+        if self.src.len() - s >= len && self.src[init..init + len] == self.src[s..s + len] {
+            Ok(Some(s + len))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn check_capture(&self, l: u8) -> MatchResult<usize> {
+        let l = l as isize - b'1' as isize;
+        if l < 0 || l as usize >= self.level || self.capture[l as usize].1 == CAP_UNFINISHED {
+            return Err(format!("invalid capture index %{}", l + 1));
+        }
+        Ok(l as usize)
+    }
+}
+
+/// Check whether `c` is in the class `%cl`, an upper case class is the complement
+fn match_class(c: u8, cl: u8) -> bool {
+    let res = match cl.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => c == b' ' || (b'\t'..=b'\r').contains(&c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return cl == c,
+    };
+    if cl.is_ascii_uppercase() {
+        !res
+    } else {
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(src: &str, pat: &str) -> Option<(usize, usize)> {
+        let (src, pat) = (src.as_bytes(), pat.as_bytes());
+        let (anchor, p) = if pat.first() == Some(&b'^') { (true, 1) } else { (false, 0) };
+        let mut ms = MatchState::new(src, pat);
+        for s in 0..=src.len() {
+            ms.reprep();
+            if let Some(e) = ms.do_match(s, p).unwrap() {
+                return Some((s, e));
+            }
+            if anchor {
+                break;
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_match() {
+        assert_eq!(find("hello world", "o w"), Some((4, 7)));
+        assert_eq!(find("hello", "l+"), Some((2, 4)));
+        assert_eq!(find("hello", "^h.-l"), Some((0, 3)));
+        assert_eq!(find("hello", "^e"), None);
+        assert_eq!(find("hello", "o$"), Some((4, 5)));
+        assert_eq!(find("key = value", "%w+%s*=%s*%w+"), Some((0, 11)));
+        assert_eq!(find("f(a(b)c)d", "%b()"), Some((1, 8)));
+        assert_eq!(find("THE (quick) fox", "%f[%a]%a+%f[%A]"), Some((0, 3)));
+        assert_eq!(find("x = [[y]]", "[%[%]]+"), Some((4, 6)));
+        assert_eq!(find("abc-123", "[^%a-]"), Some((4, 5)));
+        assert_eq!(find("abcabc", "(abc)%1"), Some((0, 6)));
+        assert_eq!(find("", "a?"), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_captures() {
+        let src = b"name=lua";
+        let mut ms = MatchState::new(src, b"()(%a+)=(%a+)()");
+        assert_eq!(ms.do_match(0, 0).unwrap(), Some(8));
+        assert_eq!(ms.capture_count(true), 4);
+        assert_eq!(ms.get_capture(0, 0, 8).unwrap(), Capture::Position(1));
+        assert_eq!(ms.get_capture(1, 0, 8).unwrap(), Capture::Str(b"name"));
+        assert_eq!(ms.get_capture(2, 0, 8).unwrap(), Capture::Str(b"lua"));
+        assert_eq!(ms.get_capture(3, 0, 8).unwrap(), Capture::Position(9));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let err = |pat: &str| MatchState::new(b"abc", pat.as_bytes()).do_match(0, 0).unwrap_err();
+        assert_eq!(err("%"), "malformed pattern (ends with '%')");
+        assert_eq!(err("[a"), "malformed pattern (missing ']')");
+        assert_eq!(err("%b"), "malformed pattern (missing arguments to '%b')");
+        assert_eq!(err("%f"), "missing '[' after '%f' in pattern");
+        assert_eq!(err("a)"), "invalid pattern capture");
+        assert_eq!(err("%1"), "invalid capture index %1");
+        assert_eq!(err(&"(".repeat(33)), "too many captures");
+        let src = "a".repeat(300);
+        let pat = "a?".repeat(300);
+        let res = MatchState::new(src.as_bytes(), pat.as_bytes()).do_match(0, 0);
+        assert_eq!(res.unwrap_err(), "pattern too complex");
+    }
+}
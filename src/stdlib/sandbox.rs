@@ -0,0 +1,227 @@
+//! Sandboxed environments, global tables holding an allow-listed subset of the standard libraries
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{upvalue_index, LuaAPI, LuaResult};
+use crate::state::lua_state::LuaState;
+use crate::stdlib::base::{base_get_metatable, base_load};
+
+/// The globals of a sandbox by default, which can not reach the files, the processes,
+/// the debug library or the modules of the host, "lib.f" is a function of a library
+pub const SANDBOX_GLOBALS: &[&str] = &[
+    "_G",
+    "_VERSION",
+    "assert",
+    "error",
+    "getmetatable",
+    "ipairs",
+    "load",
+    "next",
+    "pairs",
+    "pcall",
+    "print",
+    "rawequal",
+    "rawget",
+    "rawlen",
+    "rawset",
+    "select",
+    "setmetatable",
+    "tonumber",
+    "tostring",
+    "type",
+    "xpcall",
+    "coroutine",
+    "math",
+    "string",
+    "table",
+    "utf8",
+    "os.clock",
+    "os.date",
+    "os.difftime",
+    "os.time",
+];
+
+impl LuaState {
+    /// Push a new table to be the global table of a sandbox, with the globals named by `allow`
+    /// copied from the global table, see `SANDBOX_GLOBALS`. A library is copied into a new table,
+    /// so the sandbox can not change the one of the host, and "lib.f" copies only a function of it.
+    /// `_G` is the sandbox itself, and `load` loads only text chunks with the sandbox as default `_ENV`.
+    /// `getmetatable` refuses strings, since their metatable is shared with the host.
+    /// Names not found in the global table are ignored.
+    pub fn new_sandbox(&mut self, allow: &[&str]) -> LuaResult<()> {
+        self.create_table(0, allow.len());
+        let env = self.get_top();
+        for &name in allow {
+            match name.split_once('.') {
+                Some((lib, f)) => {
+                    if self.get_global(lib)? == LUA_TTABLE {
+                        self.get_field(-1, f)?;
+                        self.get_subtable(env, lib)?;
+                        self.insert(-2)?;
+                        self.set_field(-2, f)?;
+                        self.pop(1); // pop the library of the sandbox
+                    }
+                    self.pop(1); // pop the library of the host
+                }
+                None if name == "_G" => {
+                    self.push_value(env);
+                    self.set_field(env, name)?;
+                }
+                None if name == "getmetatable" => {
+                    self.push_rust_function(sandbox_get_metatable);
+                    self.set_field(env, name)?;
+                }
+                None if name == "load" => {
+                    self.push_value(env);
+                    self.push_rust_closure(Box::new(sandbox_load), 1);
+                    self.set_field(env, name)?;
+                }
+                None => {
+                    if self.get_global(name)? == LUA_TTABLE {
+                        self.get_subtable(env, name)?;
+                        self.push_nil(); // first key
+                        while self.next(-3)? {
+                            self.push_value(-2);
+                            self.insert(-2)?;
+                            self.raw_set(-4)?;
+                        }
+                        self.pop(2);
+                    } else {
+                        self.set_field(env, name)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Load a chunk like `LuaAPI::load` with the table at `env` as its `_ENV` up value,
+    /// binary chunks are refused unless `mode` allows them, since they are not verified
+    pub fn load_sandboxed(&mut self, chunk: Vec<u8>, chunk_name: &str, mode: Option<&str>, env: isize) -> i8 {
+        let env = self.abs_index(env);
+        let status = self.load(chunk, chunk_name, mode.unwrap_or("t"));
+        if status == LUA_OK {
+            self.push_value(env);
+            if self.set_upvalue(-2, 1).is_none() {
+                self.pop(1);
+            }
+        }
+        status
+    }
+}
+
+// load (chunk [, chunkname [, mode [, env]]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-load
+// without binary chunks, and the sandbox is the default 'env'
+fn sandbox_load(ls: &mut LuaState) -> LuaResult<usize> {
+    let mode = ls.opt_string(3, "bt")?.replace('b', "");
+    let no_env = ls.is_none(4);
+    ls.set_top(4)?;
+    ls.push_string(mode);
+    ls.replace(3)?;
+    if no_env {
+        ls.push_value(upvalue_index(1));
+        ls.replace(4)?;
+    }
+    base_load(ls)
+}
+
+// getmetatable (object)
+// http://www.lua.org/manual/5.3/manual.html#pdf-getmetatable
+// nil for strings, whose metatable has the `string` library of the host as `__index`
+fn sandbox_get_metatable(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.type_id(1) == LUA_TSTRING {
+        ls.push_nil();
+        return Ok(1);
+    }
+    base_get_metatable(ls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox() -> LuaState {
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        ls.new_sandbox(SANDBOX_GLOBALS).unwrap();
+        ls
+    }
+
+    #[test]
+    fn test_sandbox_globals() {
+        let mut ls = sandbox();
+        let src = r#"
+        string.upper = nil
+        x = 1
+        return type(io), type(debug), type(require), type(dofile), type(os.execute), type(os.time),
+            type(math.floor), tostring(_G == _ENV), ("x"):upper()
+        "#;
+        assert_eq!(ls.load_sandboxed(src.as_bytes().to_vec(), "=sandbox", None, 1), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        let types: Vec<_> = (2..=10).map(|i| ls.to_string(i)).collect();
+        assert_eq!(types, ["nil", "nil", "nil", "nil", "nil", "function", "function", "true", "X"]);
+        ls.set_top(1).unwrap();
+
+        // the globals and the libraries of the host are not changed
+        assert_eq!(ls.get_global("x").unwrap(), LUA_TNIL);
+        ls.get_global("string").unwrap();
+        assert_eq!(ls.get_field(-1, "upper").unwrap(), LUA_TFUNCTION);
+        ls.pop(3);
+        assert_eq!(ls.get_field(1, "x").unwrap(), LUA_TNUMBER);
+        ls.pop(1);
+
+        // only some functions of a library
+        ls.new_sandbox(&["os.time", "print", "type", "missing"]).unwrap();
+        let src = "return type(os.time), type(os.exit), type(print), type(pairs)";
+        assert_eq!(ls.load_sandboxed(src.as_bytes().to_vec(), "=s", None, -1), LUA_OK);
+        ls.call(0, 4).unwrap();
+        let types: Vec<_> = (3..=6).map(|i| ls.to_string(i)).collect();
+        assert_eq!(types, ["function", "nil", "function", "nil"]);
+    }
+
+    #[test]
+    fn test_sandbox_string_metatable() {
+        let mut ls = sandbox();
+        let src = r#"
+        local ok, err = pcall(function()
+            getmetatable('').__index.upper = function() return 'pwned' end
+            getmetatable('').__index.format = nil
+        end)
+        return tostring(ok), tostring(getmetatable('')), type(getmetatable(setmetatable({}, {})))
+        "#;
+        assert_eq!(ls.load_sandboxed(src.as_bytes().to_vec(), "=sandbox", None, 1), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        let vals: Vec<_> = (2..=4).map(|i| ls.to_string(i)).collect();
+        assert_eq!(vals, ["false", "nil", "table"]);
+
+        // the string library of the host is not changed
+        let src = "return ('host'):upper(), string.upper('host'), type(string.format)";
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=host", "t"), LUA_OK);
+        ls.call(0, 3).unwrap();
+        let vals: Vec<_> = (5..=7).map(|i| ls.to_string(i)).collect();
+        assert_eq!(vals, ["HOST", "HOST", "function"]);
+    }
+
+    #[test]
+    fn test_sandbox_load() {
+        let mut ls = sandbox();
+        let src = r#"
+        y = 2
+        local f = load("return y")
+        local g = load("return y", "=g", "bt", {y = 3})
+        local h, e = load("\27Lua", "=bin", "bt")
+        return f(), g(), h, e
+        "#;
+        assert_eq!(ls.load_sandboxed(src.as_bytes().to_vec(), "=sandbox", None, 1), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        assert_eq!(ls.to_integerx(2), Some(2));
+        assert_eq!(ls.to_integerx(3), Some(3));
+        assert!(ls.is_nil(4));
+        assert_eq!(ls.to_string(5), "attempt to load a binary chunk (mode is 't')");
+
+        // binary chunks are refused by default
+        assert_eq!(ls.load_sandboxed(b"\x1bLua".to_vec(), "=bin", None, 1), LUA_ERRSYNTAX);
+        assert_eq!(ls.to_string(-1), "attempt to load a binary chunk (mode is 't')");
+    }
+}
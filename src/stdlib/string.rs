@@ -0,0 +1,1105 @@
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{upvalue_index, LuaAPI, LuaResult, RustFn};
+use crate::binary::reader::Reader;
+use crate::binary::writer::Writer;
+use crate::number::format::FormatSpec;
+use crate::state::lua_state::LuaState;
+use crate::stdlib::pattern::{find_plain, no_specials, Capture, MatchState};
+
+/// Maximum size of the strings built by the library
+const MAX_SIZE: usize = i32::MAX as usize;
+
+/// Valid flags in a format specification
+const L_FMTFLAGS: &[u8] = b"-+ #0";
+
+const STR_FUNCS: &[(&str, RustFn)] = &[
+    ("byte", str_byte),
+    ("char", str_char),
+    ("find", str_find),
+    ("format", str_format),
+    ("gmatch", str_gmatch),
+    ("gsub", str_gsub),
+    ("len", str_len),
+    ("lower", str_lower),
+    ("match", str_match),
+    ("pack", str_pack),
+    ("packsize", str_packsize),
+    ("rep", str_rep),
+    ("reverse", str_reverse),
+    ("sub", str_sub),
+    ("unpack", str_unpack),
+    ("upper", str_upper),
+];
+
+pub fn open_string(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.new_lib(STR_FUNCS)?;
+    create_metatable(ls)?;
+    Ok(1)
+}
+
+/// Set the metatable of strings with the library as `__index`, which allows the method syntax
+fn create_metatable(ls: &mut LuaState) -> LuaResult<()> {
+    ls.create_table(0, 1); // table to be metatable for strings
+    ls.push_string(String::new()); // dummy string
+    ls.push_value(-2); // copy table
+    ls.set_metatable(-2)?; // set table as metatable for strings
+    ls.pop(1); // pop dummy string
+    ls.push_value(-2); // get string library
+    ls.set_field(-2, "__index")?; // metatable.__index = string
+    ls.pop(1); // pop metatable
+    Ok(())
+}
+
+/// Translate a relative string position, where negative means back from the end
+pub(crate) fn pos_relat(pos: i64, len: usize) -> i64 {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        len as i64 + pos + 1
+    }
+}
+
+// string.len (s)
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.len
+fn str_len(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = ls.check_bytes(1)?;
+    ls.push_integer(s.len() as i64);
+    Ok(1)
+}
+
+// string.sub (s, i [, j])
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.sub
+fn str_sub(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = ls.check_bytes(1)?;
+    let l = s.len();
+    let start = pos_relat(ls.check_integer(2)?, l).max(1);
+    let end = pos_relat(ls.opt_integer(3, -1)?, l).min(l as i64);
+    if start <= end {
+        ls.push_bytes(s[start as usize - 1..end as usize].to_vec());
+    } else {
+        ls.push_bytes(Vec::new());
+    }
+    Ok(1)
+}
+
+// string.reverse (s)
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.reverse
+fn str_reverse(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = ls.check_bytes(1)?;
+    let reversed = s.into_iter().rev().collect();
+    ls.push_bytes(reversed);
+    Ok(1)
+}
+
+// string.lower (s)
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.lower
+fn str_lower(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = ls.check_bytes(1)?;
+    ls.push_bytes(s.to_ascii_lowercase());
+    Ok(1)
+}
+
+// string.upper (s)
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.upper
+fn str_upper(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = ls.check_bytes(1)?;
+    ls.push_bytes(s.to_ascii_uppercase());
+    Ok(1)
+}
+
+// string.rep (s, n [, sep])
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.rep
+fn str_rep(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = ls.check_bytes(1)?;
+    let n = ls.check_integer(2)?;
+    let sep = ls.opt_bytes(3, b"")?;
+    if n <= 0 {
+        ls.push_bytes(Vec::new());
+        return Ok(1);
+    }
+    let n = n as usize;
+    let total = (s.len() + sep.len()).checked_mul(n).filter(|&total| total <= MAX_SIZE);
+    let total = match total {
+        Some(total) => total - sep.len(),
+        None => return Err(ls.error2("resulting string too large")),
+    };
+    ls.check_memory(total)?;
+    let mut buf = Vec::with_capacity(total);
+    for i in 0..n {
+        if i > 0 {
+            buf.extend_from_slice(&sep);
+        }
+        buf.extend_from_slice(&s);
+    }
+    ls.push_bytes(buf);
+    Ok(1)
+}
+
+// string.byte (s [, i [, j]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.byte
+fn str_byte(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = ls.check_bytes(1)?;
+    let l = s.len();
+    let posi = pos_relat(ls.opt_integer(2, 1)?, l);
+    let pose = pos_relat(ls.opt_integer(3, posi)?, l).min(l as i64);
+    let posi = posi.max(1);
+    if posi > pose {
+        return Ok(0); // empty interval; return no values
+    }
+    let n = (pose - posi + 1) as usize;
+    if n >= i32::MAX as usize || !ls.check_stack(n) {
+        return Err(ls.error2("string slice too long"));
+    }
+    for &b in &s[posi as usize - 1..pose as usize] {
+        ls.push_integer(b as i64);
+    }
+    Ok(n)
+}
+
+// string.char (···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.char
+fn str_char(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top();
+    let mut buf = Vec::with_capacity(n as usize);
+    for i in 1..=n {
+        let c = ls.check_integer(i)?;
+        ls.arg_check((0..=255).contains(&c), i, "value out of range")?;
+        buf.push(c as u8);
+    }
+    ls.push_bytes(buf);
+    Ok(1)
+}
+
+/// Add `piece` to the string being built in `buf`, the memory limit is checked at first
+fn add_bytes(ls: &mut LuaState, buf: &mut Vec<u8>, piece: &[u8]) -> LuaResult<()> {
+    ls.check_memory(buf.len() + piece.len())?;
+    buf.extend_from_slice(piece);
+    Ok(())
+}
+
+/* string.format */
+
+/// Add the string `s` quoted by `%q`, which can be read back by Lua
+fn add_quoted(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.push(b'"');
+    for (i, &c) in s.iter().enumerate() {
+        if c == b'"' || c == b'\\' || c == b'\n' {
+            buf.push(b'\\');
+            buf.push(c);
+        } else if c.is_ascii_control() {
+            let escape = if s.get(i + 1).is_some_and(u8::is_ascii_digit) {
+                format!("\\{:03}", c)
+            } else {
+                format!("\\{}", c)
+            };
+            buf.extend_from_slice(escape.as_bytes());
+        } else {
+            buf.push(c);
+        }
+    }
+    buf.push(b'"');
+}
+
+/// A float quoted by `%q`, in hexadecimal to preserve its precision
+fn quote_float(n: f64) -> String {
+    if n == f64::INFINITY {
+        "1e9999".to_string()
+    } else if n == f64::NEG_INFINITY {
+        "-1e9999".to_string()
+    } else if n.is_nan() {
+        "(0/0)".to_string()
+    } else {
+        FormatSpec::new(b'a').format_float(n)
+    }
+}
+
+/// Add the value at `arg` in a form which can be read back by Lua
+fn add_literal(ls: &mut LuaState, buf: &mut Vec<u8>, arg: isize) -> LuaResult<()> {
+    match ls.type_id(arg) {
+        LUA_TSTRING => {
+            let mut quoted = Vec::new();
+            add_quoted(&mut quoted, &ls.to_bytes(arg));
+            add_bytes(ls, buf, &quoted)?;
+        }
+        LUA_TNUMBER => {
+            let s = if !ls.is_integer(arg) {
+                quote_float(ls.to_number(arg))
+            } else if ls.to_integer(arg) == i64::MIN {
+                // corner case, which cannot be written in decimal
+                format!("0x{:x}", i64::MIN)
+            } else {
+                ls.to_integer(arg).to_string()
+            };
+            add_bytes(ls, buf, s.as_bytes())?;
+        }
+        LUA_TNIL | LUA_TBOOLEAN => {
+            buf.extend_from_slice(ls.to_string2(arg)?.as_bytes());
+            ls.pop(1);
+        }
+        _ => return Err(ls.arg_error(arg, "value has no literal form")),
+    }
+    Ok(())
+}
+
+/// Parse the conversion specification following a '%',
+/// and return it with the length of the text parsed
+fn scan_format(ls: &LuaState, strfrmt: &[u8]) -> LuaResult<(FormatSpec, usize)> {
+    let mut spec = FormatSpec::default();
+    let mut i = 0;
+    while let Some(&c) = strfrmt.get(i).filter(|c| L_FMTFLAGS.contains(c)) {
+        match c {
+            b'-' => spec.left = true,
+            b'+' => spec.plus = true,
+            b' ' => spec.space = true,
+            b'#' => spec.alt = true,
+            _ => spec.zero = true,
+        }
+        i += 1;
+    }
+    if i > L_FMTFLAGS.len() {
+        return Err(ls.error2("invalid format (repeated flags)"));
+    }
+    // at most 2 digits of width or precision
+    let digits = |i: &mut usize| {
+        let mut n = 0;
+        for _ in 0..2 {
+            match strfrmt.get(*i).filter(|c| c.is_ascii_digit()) {
+                Some(&c) => n = n * 10 + (c - b'0') as usize,
+                None => break,
+            }
+            *i += 1;
+        }
+        n
+    };
+    spec.width = digits(&mut i);
+    if strfrmt.get(i) == Some(&b'.') {
+        i += 1;
+        spec.precision = Some(digits(&mut i));
+    }
+    if strfrmt.get(i).is_some_and(u8::is_ascii_digit) {
+        return Err(ls.error2("invalid format (width or precision too long)"));
+    }
+    spec.conversion = strfrmt.get(i).copied().unwrap_or(b'\0');
+    Ok((spec, i + 1))
+}
+
+// string.format (formatstring, ···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.format
+fn str_format(ls: &mut LuaState) -> LuaResult<usize> {
+    let top = ls.get_top();
+    let mut arg = 1;
+    let strfrmt = &ls.check_bytes(arg)?;
+    let mut buf = Vec::with_capacity(strfrmt.len());
+    let mut i = 0;
+    while i < strfrmt.len() {
+        if strfrmt[i] != b'%' {
+            buf.push(strfrmt[i]);
+            i += 1;
+            continue;
+        }
+        if strfrmt.get(i + 1) == Some(&b'%') {
+            buf.push(b'%'); // %%
+            i += 2;
+            continue;
+        }
+        // format item
+        arg += 1;
+        if arg > top {
+            // too many format specifiers?
+            return Err(ls.arg_error(arg, "no value"));
+        }
+        let (spec, len) = scan_format(ls, &strfrmt[i + 1..])?;
+        i += len + 1;
+        let item = match spec.conversion {
+            b'c' => {
+                let c = ls.check_integer(arg)? as u8;
+                spec.pad_bytes(&[c])
+            }
+            b'd' | b'i' => spec.format_integer(ls.check_integer(arg)?).into_bytes(),
+            b'o' | b'u' | b'x' | b'X' => spec.format_unsigned(ls.check_integer(arg)? as u64).into_bytes(),
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'g' | b'G' => spec.format_float(ls.check_number(arg)?).into_bytes(),
+            b'q' => {
+                add_literal(ls, &mut buf, arg)?;
+                continue;
+            }
+            b's' => {
+                ls.to_string2(arg)?;
+                let mut s = ls.to_bytes(-1);
+                ls.pop(1);
+                if spec.precision.is_none() && s.len() >= 100 {
+                    // no precision and string is too long to be formatted, keep entire string
+                    s
+                } else {
+                    ls.arg_check(!s.contains(&b'\0'), arg, "string contains zeros")?;
+                    s.truncate(spec.precision.unwrap_or(usize::MAX));
+                    spec.pad_bytes(&s)
+                }
+            }
+            c => {
+                let msg = format!("invalid option '%{}' to 'format'", c as char);
+                return Err(ls.error2(&msg));
+            }
+        };
+        add_bytes(ls, &mut buf, &item)?;
+    }
+    ls.push_bytes(buf);
+    Ok(1)
+}
+
+/* pattern matching */
+
+/// Push a capture of the last match of `ms`
+fn push_capture(ls: &mut LuaState, cap: Capture) {
+    match cap {
+        Capture::Str(s) => ls.push_bytes(s.to_vec()),
+        Capture::Position(pos) => ls.push_integer(pos as i64),
+    }
+}
+
+/// Push the captures of the last match of `src[s..e]`, or the whole match if there is none
+/// and `whole` is set
+fn push_captures(ls: &mut LuaState, ms: &MatchState, s: usize, e: usize, whole: bool) -> LuaResult<usize> {
+    let nlevels = ms.capture_count(whole);
+    if !ls.check_stack(nlevels) {
+        return Err(ls.error2("too many captures"));
+    }
+    for i in 0..nlevels {
+        let cap = ms.get_capture(i, s, e).map_err(|msg| ls.error2(&msg))?;
+        push_capture(ls, cap);
+    }
+    Ok(nlevels) // number of strings pushed
+}
+
+/// Common part of `string.find` and `string.match`
+fn str_find_aux(ls: &mut LuaState, find: bool) -> LuaResult<usize> {
+    let (src, pat) = (&ls.check_bytes(1)?, &ls.check_bytes(2)?);
+    let init = pos_relat(ls.opt_integer(3, 1)?, src.len()).max(1);
+    if init > src.len() as i64 + 1 {
+        // start after string's end?
+        ls.push_nil(); // cannot find anything
+        return Ok(1);
+    }
+    let init = init as usize - 1;
+    // explicit request or no special characters?
+    if find && (ls.to_boolean(4) || no_specials(pat)) {
+        // do a plain search
+        if let Some(s1) = find_plain(src, pat, init) {
+            ls.push_integer(s1 as i64 + 1);
+            ls.push_integer((s1 + pat.len()) as i64);
+            return Ok(2);
+        }
+    } else {
+        let anchor = pat.first() == Some(&b'^');
+        let p = if anchor { 1 } else { 0 }; // skip anchor character
+        let mut ms = MatchState::new(src, pat);
+        for s1 in init..=src.len() {
+            ms.reprep();
+            if let Some(e) = ms.do_match(s1, p).map_err(|msg| ls.error2(&msg))? {
+                if find {
+                    ls.push_integer(s1 as i64 + 1); // start
+                    ls.push_integer(e as i64); // end
+                    return Ok(push_captures(ls, &ms, 0, 0, false)? + 2);
+                }
+                return push_captures(ls, &ms, s1, e, true);
+            }
+            if anchor {
+                break;
+            }
+        }
+    }
+    ls.push_nil(); // not found
+    Ok(1)
+}
+
+// string.find (s, pattern [, init [, plain]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.find
+fn str_find(ls: &mut LuaState) -> LuaResult<usize> {
+    str_find_aux(ls, true)
+}
+
+// string.match (s, pattern [, init])
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.match
+fn str_match(ls: &mut LuaState) -> LuaResult<usize> {
+    str_find_aux(ls, false)
+}
+
+// string.gmatch (s, pattern)
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.gmatch
+fn str_gmatch(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_bytes(1)?;
+    ls.check_bytes(2)?;
+    ls.set_top(2)?; // keep them on closure to avoid being collected
+    ls.push_integer(0); // current position
+    ls.push_nil(); // end of the last match
+    ls.push_rust_closure(Box::new(gmatch_aux), 4);
+    Ok(1)
+}
+
+/// The iterator returned by `string.gmatch`, with the subject, the pattern,
+/// the current position and the end of the last match as up values
+fn gmatch_aux(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = ls.to_bytes(upvalue_index(1));
+    let p = ls.to_bytes(upvalue_index(2));
+    let pos = ls.to_integer(upvalue_index(3)) as usize;
+    let last_match = ls.to_integerx(upvalue_index(4)).map(|e| e as usize);
+    let mut ms = MatchState::new(&s, &p);
+    for src in pos..=s.len() {
+        ms.reprep();
+        match ms.do_match(src, 0).map_err(|msg| ls.error2(&msg))? {
+            Some(e) if Some(e) != last_match => {
+                ls.push_integer(e as i64);
+                ls.copy(-1, upvalue_index(4))?;
+                ls.replace(upvalue_index(3))?;
+                return push_captures(ls, &ms, src, e, true);
+            }
+            _ => {}
+        }
+    }
+    Ok(0) // not found
+}
+
+/// Add the replacement of the match `src[s..e]` by a string to `buf`
+fn add_s(ls: &mut LuaState, ms: &MatchState, buf: &mut Vec<u8>, s: usize, e: usize) -> LuaResult<()> {
+    let repl = ls.to_bytes(3);
+    let mut chars = repl.into_iter();
+    while let Some(c) = chars.next() {
+        if c != b'%' {
+            buf.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(b'%') => buf.push(b'%'), // %%
+            Some(b'0') => add_bytes(ls, buf, ms.src_slice(s, e))?,
+            Some(d) if d.is_ascii_digit() => {
+                let cap = ms.get_capture((d - b'1') as usize, s, e).map_err(|msg| ls.error2(&msg))?;
+                match cap {
+                    Capture::Str(cap) => add_bytes(ls, buf, cap)?,
+                    Capture::Position(pos) => add_bytes(ls, buf, pos.to_string().as_bytes())?,
+                }
+            }
+            _ => return Err(ls.error2("invalid use of '%' in replacement string")),
+        }
+    }
+    Ok(())
+}
+
+/// Add the replacement of the match `src[s..e]` to `buf`
+fn add_value(ls: &mut LuaState, ms: &MatchState, buf: &mut Vec<u8>, s: usize, e: usize, tr: i8) -> LuaResult<()> {
+    match tr {
+        LUA_TFUNCTION => {
+            ls.push_value(3);
+            let n = push_captures(ls, ms, s, e, true)?;
+            ls.call(n as isize, 1)?; // call it
+        }
+        LUA_TTABLE => {
+            let cap = ms.get_capture(0, s, e).map_err(|msg| ls.error2(&msg))?;
+            push_capture(ls, cap);
+            ls.get_table(3)?;
+        }
+        _ => {
+            // LUA_TNUMBER or LUA_TSTRING
+            return add_s(ls, ms, buf, s, e);
+        }
+    }
+    if !ls.to_boolean(-1) {
+        // nil or false?
+        ls.pop(1);
+        add_bytes(ls, buf, ms.src_slice(s, e))?; // keep original text
+    } else if let Some(v) = ls.to_bytesx(-1) {
+        add_bytes(ls, buf, &v)?; // add result to accumulator
+        ls.pop(1);
+    } else {
+        let msg = format!("invalid replacement value (a {})", ls.type_name2(-1));
+        return Err(ls.error2(&msg));
+    }
+    Ok(())
+}
+
+// string.gsub (s, pattern, repl [, n])
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.gsub
+fn str_gsub(ls: &mut LuaState) -> LuaResult<usize> {
+    let (src, pat) = (&ls.check_bytes(1)?, &ls.check_bytes(2)?);
+    let tr = ls.type_id(3); // replacement type
+    let max_s = ls.opt_integer(4, src.len() as i64 + 1)?; // max replacements
+    ls.arg_check(
+        tr == LUA_TNUMBER || tr == LUA_TSTRING || tr == LUA_TFUNCTION || tr == LUA_TTABLE,
+        3,
+        "string/function/table expected",
+    )?;
+    let anchor = pat.first() == Some(&b'^');
+    let p = if anchor { 1 } else { 0 }; // skip anchor character
+    let mut ms = MatchState::new(src, pat);
+    let mut buf = Vec::with_capacity(src.len());
+    let mut s1 = 0;
+    let mut last_match = None;
+    let mut n = 0;
+    while n < max_s {
+        ms.reprep();
+        match ms.do_match(s1, p).map_err(|msg| ls.error2(&msg))? {
+            Some(e) if Some(e) != last_match => {
+                // match?
+                n += 1;
+                add_value(ls, &ms, &mut buf, s1, e, tr)?; // add replacement to buffer
+                s1 = e;
+                last_match = Some(e);
+            }
+            _ if s1 < src.len() => {
+                // otherwise, skip one character
+                add_bytes(ls, &mut buf, &src[s1..=s1])?;
+                s1 += 1;
+            }
+            _ => break, // end of subject
+        }
+        if anchor {
+            break;
+        }
+    }
+    add_bytes(ls, &mut buf, &src[s1..])?;
+    ls.push_bytes(buf);
+    ls.push_integer(n); // number of substitutions
+    Ok(2)
+}
+
+/* pack and unpack */
+
+/// Maximum size for the binary representation of an integer
+const MAXINTSIZE: usize = 16;
+
+/// Number of bits in a byte
+const NB: usize = 8;
+
+/// Size of a Lua integer
+const SZINT: usize = 8;
+
+/// Maximum alignment of native structures, which is the default for option '!'
+const MAXALIGN: usize = 8;
+
+/// Padding byte used by pack
+const LUAL_PACKPADBYTE: u8 = 0x00;
+
+/// Options for pack and unpack
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KOption {
+    /// signed integers
+    Int,
+    /// unsigned integers
+    Uint,
+    /// floating-point numbers
+    Float,
+    /// fixed-length strings
+    Char,
+    /// strings with prefixed length
+    String,
+    /// zero-terminated strings
+    Zstr,
+    /// padding
+    Padding,
+    /// padding for alignment
+    PaddAlign,
+    /// no-op (configuration or spaces)
+    Nop,
+}
+
+/// Reader of the format of pack and unpack with its configuration
+struct Header<'a> {
+    fmt: &'a [u8],
+    pos: usize,
+    little: bool,
+    max_align: usize,
+}
+
+impl<'a> Header<'a> {
+    fn new(fmt: &'a [u8]) -> Self {
+        Header {
+            fmt,
+            pos: 0,
+            little: cfg!(target_endian = "little"),
+            max_align: 1,
+        }
+    }
+
+    fn has_more(&self) -> bool {
+        self.pos < self.fmt.len()
+    }
+
+    /// Read an optional size, or return the default `df`
+    fn get_num(&mut self, df: usize) -> usize {
+        if !self.fmt.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            return df; // no number
+        }
+        let mut a = 0;
+        // avoid overflow
+        while a <= (MAX_SIZE - 9) / 10 {
+            match self.fmt.get(self.pos).filter(|c| c.is_ascii_digit()) {
+                Some(&c) => a = a * 10 + (c - b'0') as usize,
+                None => break,
+            }
+            self.pos += 1;
+        }
+        a
+    }
+
+    /// Read an optional integer size limited to `MAXINTSIZE`
+    fn get_num_limit(&mut self, ls: &LuaState, df: usize) -> LuaResult<usize> {
+        let sz = self.get_num(df);
+        if sz > MAXINTSIZE || sz == 0 {
+            let msg = format!("integral size ({}) out of limits [1,{}]", sz, MAXINTSIZE);
+            return Err(ls.error2(&msg));
+        }
+        Ok(sz)
+    }
+
+    /// Read the next option with its size
+    fn get_option(&mut self, ls: &LuaState) -> LuaResult<(KOption, usize)> {
+        let opt = self.fmt[self.pos];
+        self.pos += 1;
+        let res = match opt {
+            b'b' => (KOption::Int, 1),
+            b'B' => (KOption::Uint, 1),
+            b'h' => (KOption::Int, 2),
+            b'H' => (KOption::Uint, 2),
+            b'l' | b'j' => (KOption::Int, 8),
+            b'L' | b'J' | b'T' => (KOption::Uint, 8),
+            b'f' => (KOption::Float, 4),
+            b'd' | b'n' => (KOption::Float, 8),
+            b'i' => (KOption::Int, self.get_num_limit(ls, 4)?),
+            b'I' => (KOption::Uint, self.get_num_limit(ls, 4)?),
+            b's' => (KOption::String, self.get_num_limit(ls, 8)?),
+            b'c' => {
+                let size = self.get_num(usize::MAX);
+                if size == usize::MAX {
+                    return Err(ls.error2("missing size for format option 'c'"));
+                }
+                (KOption::Char, size)
+            }
+            b'z' => (KOption::Zstr, 0),
+            b'x' => (KOption::Padding, 1),
+            b'X' => (KOption::PaddAlign, 0),
+            b' ' => (KOption::Nop, 0),
+            b'<' | b'=' | b'>' => {
+                self.little = match opt {
+                    b'<' => true,
+                    b'>' => false,
+                    _ => cfg!(target_endian = "little"),
+                };
+                (KOption::Nop, 0)
+            }
+            b'!' => {
+                self.max_align = self.get_num_limit(ls, MAXALIGN)?;
+                (KOption::Nop, 0)
+            }
+            _ => {
+                let msg = format!("invalid format option '{}'", opt as char);
+                return Err(ls.error2(&msg));
+            }
+        };
+        Ok(res)
+    }
+
+    /// Read the next option with its size and the padding needed to align it,
+    /// at the current position `total_size`
+    fn get_details(&mut self, ls: &LuaState, total_size: usize) -> LuaResult<(KOption, usize, usize)> {
+        let (opt, size) = self.get_option(ls)?;
+        let mut align = size; // usually, alignment follows size
+        if opt == KOption::PaddAlign {
+            // 'X' gets alignment from following option
+            if !self.has_more() {
+                return Err(ls.arg_error(1, "invalid next option for option 'X'"));
+            }
+            let (next, next_size) = self.get_option(ls)?;
+            if next == KOption::Char || next_size == 0 {
+                return Err(ls.arg_error(1, "invalid next option for option 'X'"));
+            }
+            align = next_size;
+        }
+        if align <= 1 || opt == KOption::Char {
+            // need no alignment?
+            return Ok((opt, size, 0));
+        }
+        align = align.min(self.max_align); // enforce maximum alignment
+        if !align.is_power_of_two() {
+            // is 'align' not a power of 2?
+            return Err(ls.arg_error(1, "format asks for alignment not power of 2"));
+        }
+        let ntoalign = (align - (total_size & (align - 1))) & (align - 1);
+        Ok((opt, size, ntoalign))
+    }
+}
+
+// string.pack (fmt, v1, v2, ···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.pack
+fn str_pack(ls: &mut LuaState) -> LuaResult<usize> {
+    let fmt = ls.check_bytes(1)?;
+    let mut h = Header::new(&fmt);
+    let mut b = Writer::new();
+    let mut arg = 1; // current argument to pack
+    let mut total_size = 0; // accumulate total size of result
+    while h.has_more() {
+        let (opt, size, ntoalign) = h.get_details(ls, total_size)?;
+        total_size += ntoalign + size;
+        for _ in 0..ntoalign {
+            b.write_byte(LUAL_PACKPADBYTE); // fill alignment
+        }
+        arg += 1;
+        match opt {
+            KOption::Int => {
+                // signed integers
+                let n = ls.check_integer(arg)?;
+                if size < SZINT {
+                    // need overflow check?
+                    let lim = 1i64 << (size * NB - 1);
+                    ls.arg_check(-lim <= n && n < lim, arg, "integer overflow")?;
+                }
+                b.write_int(n as u64, size, h.little, n < 0);
+            }
+            KOption::Uint => {
+                // unsigned integers
+                let n = ls.check_integer(arg)?;
+                if size < SZINT {
+                    // need overflow check?
+                    ls.arg_check((n as u64) < 1u64 << (size * NB), arg, "unsigned overflow")?;
+                }
+                b.write_int(n as u64, size, h.little, false);
+            }
+            KOption::Float => {
+                // floating-point options
+                let n = ls.check_number(arg)?;
+                if size == 4 {
+                    b.write_int((n as f32).to_bits() as u64, size, h.little, false);
+                } else {
+                    b.write_int(n.to_bits(), size, h.little, false);
+                }
+            }
+            KOption::Char => {
+                // fixed-size string
+                let s = ls.check_bytes(arg)?;
+                ls.arg_check(s.len() <= size, arg, "string longer than given size")?;
+                ls.check_memory(total_size)?;
+                let len = s.len();
+                b.write_bytes(s); // add string
+                for _ in len..size {
+                    // pad extra space
+                    b.write_byte(LUAL_PACKPADBYTE);
+                }
+            }
+            KOption::String => {
+                // strings with length count
+                let s = ls.check_bytes(arg)?;
+                let len = s.len();
+                ls.arg_check(
+                    size >= SZINT || (len as u64) < 1u64 << (size * NB),
+                    arg,
+                    "string length does not fit in given size",
+                )?;
+                total_size += len;
+                ls.check_memory(total_size)?;
+                b.write_int(len as u64, size, h.little, false); // pack length
+                b.write_bytes(s);
+            }
+            KOption::Zstr => {
+                // zero-terminated string
+                let s = ls.check_bytes(arg)?;
+                ls.arg_check(!s.contains(&b'\0'), arg, "string contains zeros")?;
+                total_size += s.len() + 1;
+                ls.check_memory(total_size)?;
+                b.write_bytes(s);
+                b.write_byte(b'\0'); // add zero at the end
+            }
+            KOption::Padding => {
+                b.write_byte(LUAL_PACKPADBYTE);
+                arg -= 1; // undo increment
+            }
+            KOption::PaddAlign | KOption::Nop => arg -= 1, // undo increment
+        }
+    }
+    ls.push_bytes(b.as_bytes());
+    Ok(1)
+}
+
+// string.packsize (fmt)
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.packsize
+fn str_packsize(ls: &mut LuaState) -> LuaResult<usize> {
+    let fmt = ls.check_bytes(1)?;
+    let mut h = Header::new(&fmt);
+    let mut total_size: usize = 0; // accumulate total size of result
+    while h.has_more() {
+        let (opt, size, ntoalign) = h.get_details(ls, total_size)?;
+        ls.arg_check(opt != KOption::String && opt != KOption::Zstr, 1, "variable-length format")?;
+        let size = size + ntoalign; // total space used by option
+        ls.arg_check(total_size <= MAX_SIZE - size.min(MAX_SIZE), 1, "format result too large")?;
+        total_size += size;
+    }
+    ls.push_integer(total_size as i64);
+    Ok(1)
+}
+
+// string.unpack (fmt, s [, pos])
+// http://www.lua.org/manual/5.3/manual.html#pdf-string.unpack
+fn str_unpack(ls: &mut LuaState) -> LuaResult<usize> {
+    let fmt = ls.check_bytes(1)?;
+    let data = ls.check_bytes(2)?;
+    let ld = data.len();
+    let pos = pos_relat(ls.opt_integer(3, 1)?, ld) - 1;
+    ls.arg_check(0 <= pos && pos as usize <= ld, 3, "initial position out of string")?;
+    let mut h = Header::new(&fmt);
+    let mut r = Reader::new(data.clone());
+    r.seek(pos as usize);
+    let mut n = 0; // number of results
+    while h.has_more() {
+        let (opt, size, ntoalign) = h.get_details(ls, r.pos())?;
+        if ntoalign.saturating_add(size) > ld - r.pos() {
+            return Err(ls.arg_error(2, "data string too short"));
+        }
+        r.seek(r.pos() + ntoalign); // skip alignment
+        // stack space for item + next position
+        if !ls.check_stack(2) {
+            return Err(ls.error2("too many results"));
+        }
+        n += 1;
+        match opt {
+            KOption::Int | KOption::Uint => match r.read_int(size, h.little, opt == KOption::Int) {
+                Some(res) => ls.push_integer(res),
+                None => {
+                    let msg = format!("{}-byte integer does not fit into Lua Integer", size);
+                    return Err(ls.error2(&msg));
+                }
+            },
+            KOption::Float => {
+                let bits = r.read_int(size, h.little, false).unwrap_or_default() as u64;
+                if size == 4 {
+                    ls.push_number(f32::from_bits(bits as u32) as f64);
+                } else {
+                    ls.push_number(f64::from_bits(bits));
+                }
+            }
+            KOption::Char => {
+                let s = r.read_bytes(size).unwrap_or_default();
+                ls.push_bytes(s);
+            }
+            KOption::String => {
+                let len = r.read_int(size, h.little, false).unwrap_or(-1) as u64;
+                ls.arg_check(len <= (ld - r.pos()) as u64, 2, "data string too short")?;
+                let s = r.read_bytes(len as usize).unwrap_or_default();
+                ls.push_bytes(s);
+            }
+            KOption::Zstr => {
+                let start = r.pos();
+                let len = data[start..].iter().position(|&c| c == 0);
+                let len = match len {
+                    Some(len) => len,
+                    None => return Err(ls.arg_error(2, "unfinished string for format 'z'")),
+                };
+                let s = r.read_bytes(len).unwrap_or_default();
+                r.seek(start + len + 1); // skip string plus final '\0'
+                ls.push_bytes(s);
+            }
+            KOption::PaddAlign | KOption::Padding | KOption::Nop => {
+                r.seek(r.pos() + size);
+                n -= 1; // undo increment
+            }
+        }
+    }
+    ls.push_integer(r.pos() as i64 + 1); // next position
+    Ok(n + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stdlib::test_util::{execute, results, run};
+
+    #[test]
+    fn test_basic_functions() {
+        let ls = execute(r#"
+        local s = "Hello"
+        return s:len(), s:upper(), ("x"):rep(3, ","), s:reverse(), s:sub(2, -2), s:sub(-100, 100),
+            s:sub(4, 2), string.char(72, 105), #string.rep("ab", 0), s:lower(), s:byte(-1)
+        "#);
+        let expected = ["5", "HELLO", "x,x,x", "olleH", "ell", "Hello", "", "Hi", "0", "hello", "111"];
+        assert_eq!(results(&ls), expected);
+    }
+
+    #[test]
+    fn test_binary_strings() {
+        let ls = execute(r#"
+        local s = string.char(255, 0, 200)
+        return #s, ("\xff"):byte(), #("é"):reverse(), tostring(("é"):reverse():reverse() == "é"),
+            ("%s|%-4c|"):format("\xff\200", 255), tostring(string.unpack("s1", string.pack("s1", s)) == s),
+            s:byte(1, -1)
+        "#);
+        assert_eq!(results(&ls)[..4], ["3", "255", "2", "true"]);
+        assert_eq!(ls.to_bytes(5), b"\xff\xc8|\xff   |");
+        assert_eq!(results(&ls)[5..], ["true", "255", "0", "200"]);
+    }
+
+    #[test]
+    fn test_find_and_match() {
+        let ls = execute(r#"
+        local s = "hello world from lua"
+        local i1, j1 = s:find("o w")
+        local i2, j2, cap = s:find("(%a+)", 7)
+        local i3 = s:find(".", 1, true)
+        local k, v = string.match("key = value", "(%w+)%s*=%s*(%w+)")
+        return i1, j1, i2, j2, cap, i3, k, v, s:match("^(h%a*)"), s:match("()lua()"),
+            s:find("xyz"), string.find("abc", "", 10)
+        "#);
+        let expected = ["5", "7", "7", "11", "world", "nil", "key", "value", "hello", "18", "nil", "nil"];
+        assert_eq!(results(&ls), expected);
+    }
+
+    #[test]
+    fn test_gmatch_and_gsub() {
+        let ls = execute(r#"
+        local words = {}
+        for w in string.gmatch("one two  three", "%a+") do words[#words + 1] = w end
+        local pairs_ = {}
+        for k, v in string.gmatch("a=1, b=2", "(%w+)=(%w+)") do pairs_[#pairs_ + 1] = k .. v end
+        local empty = 0
+        for _ in ("abc"):gmatch("x*") do empty = empty + 1 end
+        local r1, n1 = string.gsub("hello world", "o", "0")
+        local r2 = string.gsub("hello world", "(%w+)", "<%1>")
+        local r3 = string.gsub("$name is $age", "%$(%w+)", {name = "lua", age = 25})
+        local r4 = string.gsub("abc", "%w", function(c) return c:upper() .. "." end)
+        local r5, n5 = string.gsub("abc", "", "-")
+        local r6 = string.gsub("hello world", "o", "0", 1)
+        local r7 = string.gsub("abc", "^a", "x")
+        return words[1], words[3], #words, pairs_[1], pairs_[2], empty,
+            r1, n1, r2, r3, r4, r5, n5, r6, r7
+        "#);
+        let expected = [
+            "one", "three", "3", "a1", "b2", "4", "hell0 w0rld", "2", "<hello> <world>", "lua is 25",
+            "A.B.C.", "-a-b-c-", "4", "hell0 world", "xbc",
+        ];
+        assert_eq!(results(&ls), expected);
+    }
+
+    #[test]
+    fn test_format() {
+        let ls = execute(r#"
+        return string.format("%5d|%-5d|%05.1f|%x|%X|%#o|%c%c", 42, 42, 3.14159, 255, 255, 8, 76, 117),
+            string.format("%s %s %s %.3s %10s", nil, true, {} ~= nil, "abcdef", "right"),
+            string.format("%e|%.3g|%g|%a", 12345.6789, 0.0001234, 1e20, 1.0),
+            string.format("%q", 'a "quoted"\n\0001 line\r'),
+            string.format("%q|%q|%q|%q", 1 / 3, 1e400, 0 / 0 ~= 0 / 0, -1 << 63),
+            string.format("%5.1s|%%|%i", "xyz", -7),
+            tostring(1.0), tostring(10 / 2), tostring(1e100), tostring(-0.0), 2^53 .. "", 0.1 + 0.2
+        "#);
+        let expected = [
+            "   42|42   |003.1|ff|FF|010|Lu",
+            "nil true true abc      right",
+            "1.234568e+04|0.000123|1e+20|0x1p+0",
+            "\"a \\\"quoted\\\"\\\n\\0001 line\\13\"",
+            "0x1.5555555555555p-2|1e9999|true|0x8000000000000000",
+            "    x|%|-7",
+            "1.0",
+            "5.0",
+            "1e+100",
+            "-0.0",
+            "9.007199254741e+15",
+            "0.3",
+        ];
+        assert_eq!(results(&ls), expected);
+    }
+
+    #[test]
+    fn test_pack() {
+        let ls = execute(r#"
+        local s = string.pack("<i4>i2", 1, -2)
+        local b = {s:byte(1, -1)}
+        local a1, a2, next1 = string.unpack("<i4>i2", s)
+        local p = string.pack("!<bi8", 1, 2)
+        local s2 = string.pack("z s1 c5 d f", "zero", "len", "fix", 1.5, 0.25)
+        local z, l, c, d, f, next2 = string.unpack("z s1 c5 d f", s2)
+        local big = string.pack(">i16", -3)
+        local u = string.unpack("<I3", "\1\2\3")
+        return #s, b[1], b[5], b[6], a1, a2, next1, #p, string.packsize("!<bi8"),
+            z, l, c, d, f, next2, #big, string.unpack(">i16", big), u, string.unpack("<i12", string.pack("<i12", -5)),
+            string.packsize("!bXi4h"), string.unpack("B", "\255\1", 2)
+        "#);
+        let expected = [
+            "6", "1", "255", "254", "1", "-2", "7", "16", "16", "zero", "len", "fix\0\0", "1.5", "0.25",
+            "27", "16", "-3", "197121", "-5", "6", "1", "3",
+        ];
+        assert_eq!(results(&ls), expected);
+    }
+
+    #[test]
+    fn test_pack_errors() {
+        let ls = execute(r#"
+        local _, e1 = pcall(string.pack, "i17", 1)
+        local _, e2 = pcall(string.pack, "b", 200)
+        local _, e3 = pcall(string.unpack, "i4", "abc")
+        local _, e4 = pcall(string.packsize, "s")
+        local _, e5 = pcall(string.unpack, "i9", string.rep("\255", 8) .. "\1")
+        local _, e6 = pcall(string.pack, "!3i4", 1)
+        local _, e7 = pcall(string.pack, "y", 1)
+        return e1, e2, e3, e4, e5, e6, e7
+        "#);
+        let expected = [
+            "integral size (17) out of limits [1,16]",
+            "bad argument #2 (integer overflow)",
+            "bad argument #2 (data string too short)",
+            "bad argument #1 (variable-length format)",
+            "9-byte integer does not fit into Lua Integer",
+            "bad argument #1 (format asks for alignment not power of 2)",
+            "invalid format option 'y'",
+        ];
+        assert_eq!(results(&ls), expected);
+    }
+
+    #[test]
+    fn test_pattern_errors() {
+        let ls = execute(r#"
+        local _, e1 = pcall(string.find, "abc", "[a")
+        local _, e2 = pcall(string.gsub, "abc", "a", "%2")
+        local _, e3 = pcall(string.gsub, "abc", "a", {a = {}})
+        local _, e4 = pcall(string.rep, "x", 1 << 40)
+        local _, e5 = pcall(string.char, 256)
+        local _, e6 = pcall(string.format, "%d %d", 1)
+        local _, e7 = pcall(string.format, "%y", 1)
+        local _, e8 = pcall(string.format, "%100d", 1)
+        local _, e9 = pcall(string.format, "%d", 1.5)
+        local _, e10 = pcall(string.format, "%q", {})
+        return e1, e2, e3, e4, e5, e6, e7, e8, e9, e10
+        "#);
+        let expected = [
+            "malformed pattern (missing ']')",
+            "invalid capture index %2",
+            "invalid replacement value (a table)",
+            "resulting string too large",
+            "bad argument #1 (value out of range)",
+            "bad argument #3 (no value)",
+            "invalid option '%y' to 'format'",
+            "invalid format (width or precision too long)",
+            "bad argument #2 (number has no integer representation)",
+            "bad argument #2 (value has no literal form)",
+        ];
+        assert_eq!(results(&ls), expected);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut ls = LuaState::new();
+        ls.open_libs().unwrap();
+        ls.set_memory_limit(Some(1 << 20));
+        // each result would be 4 MB, the memory in use is counted right after the error
+        run(&mut ls, r#"
+        local big, list = string.rep("y", 1 << 16), {}
+        for i = 1, 64 do list[i] = big end
+        local ok1 = pcall(string.gsub, string.rep("x", 64), "x", function() return big end)
+        local kb1 = collectgarbage("count")
+        local ok2 = pcall(string.format, string.rep("%s", 64), table.unpack(list))
+        local kb2 = collectgarbage("count")
+        local ok3 = pcall(string.pack, string.rep("z", 64), table.unpack(list))
+        local kb3 = collectgarbage("count")
+        return ok1, kb1, ok2, kb2, ok3, kb3
+        "#);
+        for i in 0..3 {
+            assert!(!ls.to_boolean(i * 2 + 1));
+            assert!(ls.to_number(i * 2 + 2) <= 1024.0, "case {}: {} KB", i + 1, ls.to_number(i * 2 + 2));
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::{LuaAPI, LuaResult, RustFn};
+use crate::state::lua_state::LuaState;
+
+/* operations that an object must define to mimic a table
+   (some functions only need some of them) */
+const TAB_R: u8 = 1; // read
+const TAB_W: u8 = 2; // write
+const TAB_L: u8 = 4; // length
+const TAB_RW: u8 = TAB_R | TAB_W; // read/write
+
+/// Partitions of sort larger than this use a randomized pivot
+const RANLIMIT: i64 = 100;
+
+const TAB_FUNCS: &[(&str, RustFn)] = &[
+    ("concat", tab_concat),
+    ("insert", tab_insert),
+    ("pack", tab_pack),
+    ("unpack", tab_unpack),
+    ("remove", tab_remove),
+    ("move", tab_move),
+    ("sort", tab_sort),
+];
+
+pub fn open_table(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.new_lib(TAB_FUNCS)?;
+    Ok(1)
+}
+
+/// Check that `arg` either is a table or can behave like one,
+/// with the metamethods for the operations in `what`
+fn check_tab(ls: &mut LuaState, arg: isize, what: u8) -> LuaResult<()> {
+    if ls.type_id(arg) == LUA_TTABLE {
+        return Ok(());
+    }
+    // is it not a table? must have metatable with the required fields
+    if ls.get_metatable(arg) {
+        let fields = [(TAB_R, "__index"), (TAB_W, "__newindex"), (TAB_L, "__len")];
+        let mut ok = true;
+        for (op, field) in fields.iter() {
+            if what & op != 0 {
+                ls.push_string(field.to_string());
+                ok = ok && ls.raw_get(-2)? != LUA_TNIL;
+                ls.pop(1);
+            }
+        }
+        ls.pop(1); // pop metatable
+        if ok {
+            return Ok(());
+        }
+    }
+    ls.check_type(arg, LUA_TTABLE) // force an error
+}
+
+/// The length of the table like object at `arg`
+fn aux_getn(ls: &mut LuaState, arg: isize, what: u8) -> LuaResult<i64> {
+    check_tab(ls, arg, what | TAB_L)?;
+    ls.len2(arg)
+}
+
+// table.insert (list, [pos,] value)
+// http://www.lua.org/manual/5.3/manual.html#pdf-table.insert
+fn tab_insert(ls: &mut LuaState) -> LuaResult<usize> {
+    let e = aux_getn(ls, 1, TAB_RW)?.wrapping_add(1); // first empty element
+    let pos = match ls.get_top() {
+        2 => e, // only 2 arguments, insert new element at the end
+        3 => {
+            let pos = ls.check_integer(2)?; // 2nd argument is the position
+            // check whether 'pos' is in [1, e]
+            ls.arg_check((pos as u64).wrapping_sub(1) < e as u64, 2, "position out of bounds")?;
+            let mut i = e;
+            while i > pos {
+                // move up elements
+                ls.get_i(1, i - 1)?;
+                ls.set_i(1, i)?; // t[i] = t[i - 1]
+                i -= 1;
+            }
+            pos
+        }
+        _ => return Err(ls.error2("wrong number of arguments to 'insert'")),
+    };
+    ls.set_i(1, pos)?; // t[pos] = v
+    Ok(0)
+}
+
+// table.remove (list [, pos])
+// http://www.lua.org/manual/5.3/manual.html#pdf-table.remove
+fn tab_remove(ls: &mut LuaState) -> LuaResult<usize> {
+    let size = aux_getn(ls, 1, TAB_RW)?;
+    let mut pos = ls.opt_integer(2, size)?;
+    if pos != size {
+        // validate 'pos' if given
+        ls.arg_check((pos as u64).wrapping_sub(1) <= size as u64, 1, "position out of bounds")?;
+    }
+    ls.get_i(1, pos)?; // result = t[pos]
+    while pos < size {
+        ls.get_i(1, pos + 1)?;
+        ls.set_i(1, pos)?; // t[pos] = t[pos + 1]
+        pos += 1;
+    }
+    ls.push_nil();
+    ls.set_i(1, pos)?; // t[pos] = nil
+    Ok(1)
+}
+
+// table.move (a1, f, e, t [,a2])
+// http://www.lua.org/manual/5.3/manual.html#pdf-table.move
+fn tab_move(ls: &mut LuaState) -> LuaResult<usize> {
+    let f = ls.check_integer(2)?;
+    let e = ls.check_integer(3)?;
+    let t = ls.check_integer(4)?;
+    let tt = if !ls.is_none_or_nil(5) { 5 } else { 1 }; // destination table
+    check_tab(ls, 1, TAB_R)?;
+    check_tab(ls, tt, TAB_W)?;
+    if e >= f {
+        // otherwise, nothing to move
+        ls.arg_check(f > 0 || e < i64::MAX + f, 3, "too many elements to move")?;
+        let n = e - f + 1; // number of elements to move
+        ls.arg_check(t <= i64::MAX - n + 1, 4, "destination wrap around")?;
+        if t > e || t <= f || (tt != 1 && !ls.compare(1, tt, LUA_OPEQ)?) {
+            for i in 0..n {
+                ls.get_i(1, f + i)?;
+                ls.set_i(tt, t + i)?;
+            }
+        } else {
+            for i in (0..n).rev() {
+                ls.get_i(1, f + i)?;
+                ls.set_i(tt, t + i)?;
+            }
+        }
+    }
+    ls.push_value(tt); // return destination table
+    Ok(1)
+}
+
+// table.concat (list [, sep [, i [, j]]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-table.concat
+fn tab_concat(ls: &mut LuaState) -> LuaResult<usize> {
+    let last = aux_getn(ls, 1, TAB_R)?;
+    let sep = ls.opt_bytes(2, b"")?;
+    let mut i = ls.opt_integer(3, 1)?;
+    let last = ls.opt_integer(4, last)?;
+    let mut buf = Vec::new();
+    while i <= last {
+        ls.get_i(1, i)?;
+        match ls.to_bytesx(-1) {
+            Some(s) if ls.is_string(-1) => {
+                ls.check_memory(buf.len() + s.len() + sep.len())?;
+                buf.extend_from_slice(&s);
+            }
+            _ => {
+                let msg = format!("invalid value (at index {}) in table for 'concat'", i);
+                return Err(ls.error2(&msg));
+            }
+        }
+        ls.pop(1);
+        if i != last {
+            // add separator
+            buf.extend_from_slice(&sep);
+        }
+        i += 1;
+    }
+    ls.push_bytes(buf);
+    Ok(1)
+}
+
+// table.pack (···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-table.pack
+fn tab_pack(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top(); // number of elements to pack
+    ls.create_table(n as usize, 1); // create result table
+    ls.insert(1)?; // put it at index 1
+    for i in (1..=n).rev() {
+        // assign elements
+        ls.set_i(1, i as i64)?;
+    }
+    ls.push_integer(n as i64);
+    ls.set_field(1, "n")?; // t.n = number of elements
+    Ok(1) // return table
+}
+
+// table.unpack (list [, i [, j]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-table.unpack
+fn tab_unpack(ls: &mut LuaState) -> LuaResult<usize> {
+    let mut i = ls.opt_integer(2, 1)?;
+    let e = if ls.is_none_or_nil(3) { ls.len2(1)? } else { ls.check_integer(3)? };
+    if i > e {
+        return Ok(0); // empty range
+    }
+    let n = (e as u64).wrapping_sub(i as u64); // number of elements minus 1 (avoid overflows)
+    if n >= i32::MAX as u64 || !ls.check_stack(n as usize + 1) {
+        return Err(ls.error2("too many results to unpack"));
+    }
+    while i < e {
+        // push arg[i..e - 1] (to avoid overflows)
+        ls.get_i(1, i)?;
+        i += 1;
+    }
+    ls.get_i(1, e)?; // push last element
+    Ok(n as usize + 1)
+}
+
+/* sort */
+
+/// A seed for the random pivots of large partitions
+fn randomize_pivot() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs().wrapping_add(now.subsec_nanos() as u64)
+}
+
+/// Pop the values on the top of stack to `t[i]` and `t[j]`
+fn set2(ls: &mut LuaState, i: i64, j: i64) -> LuaResult<()> {
+    ls.set_i(1, i)?;
+    ls.set_i(1, j)
+}
+
+/// Return true iff value at stack index `a` is less than the value at index `b`
+/// (according to the order of the sort)
+fn sort_comp(ls: &mut LuaState, a: isize, b: isize) -> LuaResult<bool> {
+    if ls.is_nil(2) {
+        // no function?
+        return ls.compare(a, b, LUA_OPLT); // a < b
+    }
+    // function
+    ls.push_value(2); // push function
+    ls.push_value(a - 1); // -1 to compensate function
+    ls.push_value(b - 2); // -2 to compensate function and 'a'
+    ls.call(2, 1)?; // call function
+    let res = ls.to_boolean(-1); // get result
+    ls.pop(1); // pop result
+    Ok(res)
+}
+
+/// Partition the interval [lo, up] with the pivot `P == t[up - 1]` on the top of stack,
+/// and return the final position of the pivot
+fn partition(ls: &mut LuaState, lo: i64, up: i64) -> LuaResult<i64> {
+    let mut i = lo; // will be incremented before first use
+    let mut j = up - 1; // will be decremented before first use
+    // loop invariant: a[lo .. i] <= P <= a[j .. up], a[up - 1] == P
+    loop {
+        // next loop: repeat ++i while a[i] < P
+        i += 1;
+        ls.get_i(1, i)?;
+        while sort_comp(ls, -1, -2)? {
+            if i == up - 1 {
+                // a[i] < P  but a[up - 1] == P  ??
+                return Err(ls.error2("invalid order function for sorting"));
+            }
+            ls.pop(1); // remove a[i]
+            i += 1;
+            ls.get_i(1, i)?;
+        }
+        // after the loop, a[i] >= P and a[lo .. i - 1] < P
+        // next loop: repeat --j while P < a[j]
+        j -= 1;
+        ls.get_i(1, j)?;
+        while sort_comp(ls, -3, -1)? {
+            if j < i {
+                // j < i  but  a[j] > P ??
+                return Err(ls.error2("invalid order function for sorting"));
+            }
+            ls.pop(1); // remove a[j]
+            j -= 1;
+            ls.get_i(1, j)?;
+        }
+        // after the loop, a[j] <= P and a[j + 1 .. up] >= P
+        if j < i {
+            // no elements to be exchanged?
+            ls.pop(1); // pop a[j]
+            // swap pivot (a[up - 1]) with a[i] to satisfy pred.
+            set2(ls, up - 1, i)?;
+            return Ok(i);
+        }
+        // otherwise, swap a[i] - a[j] to restore invariant and repeat
+        set2(ls, i, j)?;
+    }
+}
+
+/// Choose an element in the middle (2nd-3th quarters) of [lo,up] "randomized" by `rnd`
+fn choose_pivot(lo: i64, up: i64, rnd: u64) -> i64 {
+    let r4 = (up - lo) / 4; // range/4
+    (rnd % (r4 as u64 * 2)) as i64 + lo + r4
+}
+
+/// Quicksort of the interval [lo, up]
+fn aux_sort(ls: &mut LuaState, mut lo: i64, mut up: i64, mut rnd: u64) -> LuaResult<()> {
+    while lo < up {
+        // loop for tail recursion
+        // sort elements 'lo', 'p', and 'up'
+        ls.get_i(1, lo)?;
+        ls.get_i(1, up)?;
+        if sort_comp(ls, -1, -2)? {
+            // a[up] < a[lo]?
+            set2(ls, lo, up)?; // swap a[lo] - a[up]
+        } else {
+            ls.pop(2); // remove both values
+        }
+        if up - lo == 1 {
+            // only 2 elements?
+            break; // already sorted
+        }
+        let p = if up - lo < RANLIMIT || rnd == 0 {
+            // small interval or no randomize?
+            (lo + up) / 2 // middle element is a good pivot
+        } else {
+            // for larger intervals, it is expensive to compute a good pivot
+            choose_pivot(lo, up, rnd)
+        };
+        ls.get_i(1, p)?;
+        ls.get_i(1, lo)?;
+        if sort_comp(ls, -2, -1)? {
+            // a[p] < a[lo]?
+            set2(ls, p, lo)?; // swap a[p] - a[lo]
+        } else {
+            ls.pop(1); // remove second element
+            ls.get_i(1, up)?;
+            if sort_comp(ls, -1, -2)? {
+                // a[up] < a[p]?
+                set2(ls, p, up)?; // swap up - p
+            } else {
+                ls.pop(2); // clean stack
+            }
+        }
+        if up - lo == 2 {
+            // only 3 elements?
+            break; // already sorted
+        }
+        ls.get_i(1, p)?; // get median (Pivot)
+        ls.push_value(-1); // push Pivot
+        ls.get_i(1, up - 1)?; // push a[up - 1]
+        set2(ls, p, up - 1)?; // a[p] = a[up - 1]; a[up - 1] = a[p]
+        let p = partition(ls, lo, up)?;
+        // a[lo .. p - 1] <= a[p] == P <= a[p + 1 .. up]
+        let n;
+        if p - lo < up - p {
+            // lower interval is shorter?
+            aux_sort(ls, lo, p - 1, rnd)?; // call recursively for lower interval
+            n = p - lo; // size of smaller interval
+            lo = p + 1; // tail call for [p + 1 .. up] (upper interval)
+        } else {
+            aux_sort(ls, p + 1, up, rnd)?; // call recursively for upper interval
+            n = up - p; // size of smaller interval
+            up = p - 1; // tail call for [lo .. p - 1]  (lower interval)
+        }
+        if (up - lo) / 128 > n {
+            // partition too imbalanced?
+            rnd = randomize_pivot(); // try a new randomization
+        }
+    }
+    Ok(())
+}
+
+// table.sort (list [, comp])
+// http://www.lua.org/manual/5.3/manual.html#pdf-table.sort
+fn tab_sort(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = aux_getn(ls, 1, TAB_RW)?;
+    if n > 1 {
+        // non-trivial interval?
+        ls.arg_check(n < i32::MAX as i64, 1, "array too big")?;
+        if !ls.is_none_or_nil(2) {
+            // is there a 2nd argument?
+            ls.check_type(2, LUA_TFUNCTION)?; // must be a function
+        }
+        ls.set_top(2)?; // make sure there are two arguments
+        aux_sort(ls, 1, n, 0)?;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::test_util::{execute, results};
+
+    #[test]
+    fn test_table_functions() {
+        let ls = execute(r#"
+        local t = {1, 2, 3}
+        table.insert(t, 4)
+        table.insert(t, 1, 0)
+        local r = table.remove(t, 2)
+        local last = table.remove(t)
+        local p = table.pack(1, nil, 3)
+        local m = table.move({1, 2, 3, 4, 5}, 2, 4, 1)
+        local m2 = table.move({1, 2, 3}, 1, 3, 3)
+        local m3 = table.move({1, 2}, 1, 2, 2, {})
+        return table.concat(t, ","), r, last, p.n, p[3], table.concat(m, ""), table.concat(m2, ""),
+            m3[1], m3[3], table.concat({1, 2.5, "x"}, "-", 2, 3), table.unpack({1, 2, 3}, 2)
+        "#);
+        let expected = ["0,2,3", "1", "4", "3", "3", "23445", "12123", "nil", "2", "2.5-x", "2", "3"];
+        assert_eq!(results(&ls), expected);
+    }
+
+    #[test]
+    fn test_sort() {
+        let ls = execute(r#"
+        local t = {}
+        for i = 1, 500 do t[i] = (i * 7919) % 1009 end
+        table.sort(t)
+        local sorted = true
+        for i = 2, #t do sorted = sorted and t[i - 1] <= t[i] end
+        local words = {"pear", "apple", "fig", "banana"}
+        table.sort(words, function(a, b) return #a > #b end)
+        local ok, e = pcall(table.sort, {5, 4, 3, 2, 1, 6, 7, 8, 9, 10}, function() return true end)
+        local ok2, e2 = pcall(table.sort, {1, "x"})
+        return tostring(sorted), words[1], words[4], tostring(ok), e, tostring(ok2), e2
+        "#);
+        let expected = [
+            "true",
+            "banana",
+            "fig",
+            "false",
+            "invalid order function for sorting",
+            "false",
+            "attempt to compare string with number",
+        ];
+        assert_eq!(results(&ls), expected);
+    }
+
+    #[test]
+    fn test_metamethods() {
+        let ls = execute(r#"
+        local log = {}
+        local store = {10, 20, 30}
+        local proxy = setmetatable({}, {
+            __index = function(_, k) return store[k] end,
+            __newindex = function(_, k, v) log[#log + 1] = k; store[k] = v end,
+            __len = function() return #store end,
+        })
+        table.insert(proxy, 40)
+        table.insert(proxy, 1, 5)
+        local removed = table.remove(proxy, 2)
+        local a, b = table.unpack(proxy, 1, 2)
+        local ok, e = pcall(table.insert, 1, 2)
+        local ok2, e2 = pcall(table.concat, {1, {}, 3})
+        return table.concat(store, ","), removed, #log, a, b, e, e2
+        "#);
+        let expected = [
+            "5,20,30,40",
+            "10",
+            "10",
+            "5",
+            "20",
+            "bad argument #1 (table expected, got number)",
+            "invalid value (at index 2) in table for 'concat'",
+        ];
+        assert_eq!(results(&ls), expected);
+    }
+}
@@ -0,0 +1,26 @@
+//! Fixtures shared by the tests of the standard libraries
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::consts::*;
+use crate::api::LuaAPI;
+use crate::state::lua_state::LuaState;
+
+/// Run `src` in a new state with the standard libraries, whose results are left on the stack
+pub fn execute(src: &str) -> LuaState {
+    let mut ls = LuaState::new();
+    ls.open_libs().unwrap();
+    run(&mut ls, src);
+    ls
+}
+
+/// Run `src` in `ls`, whose results are left on the stack
+pub fn run(ls: &mut LuaState, src: &str) {
+    let status = ls.load(src.as_bytes().to_vec(), "=test", "t");
+    assert_eq!(status, LUA_OK, "{:?}", ls.to_stringx(-1));
+    ls.call(0, LUA_MULTRET).unwrap();
+}
+
+/// The values on the stack converted to strings, "nil" for the ones which are not strings or numbers
+pub fn results(ls: &LuaState) -> Vec<String> {
+    (1..=ls.get_top()).map(|i| ls.to_stringx(i).unwrap_or_else(|| "nil".to_string())).collect()
+}
@@ -0,0 +1,371 @@
+//! Broken-down time of `os.date` and `os.time` like C `struct tm`,
+//! the local time zone is read from the TZif file of `TZ` or `/etc/localtime`
+
+use std::fs;
+
+use crate::binary::reader::Reader;
+
+const SECS_PER_DAY: i64 = 86400;
+
+const WEEK_DAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Broken-down time, all fields are normalized
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tm {
+    pub year: i64,
+    /// 1 to 12
+    pub month: i64,
+    /// 1 to 31
+    pub day: i64,
+    pub hour: i64,
+    pub min: i64,
+    pub sec: i64,
+    /// Days since Sunday, 0 to 6
+    pub wday: i64,
+    /// Days since January 1, 0 to 365
+    pub yday: i64,
+    pub isdst: bool,
+    /// Offset from UTC in seconds
+    pub utoff: i64,
+    /// Abbreviation of the time zone
+    pub zone: String,
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400; // [0, 399]
+    let mp = (month + 9) % 12; // March is 0
+    let doy = (153 * mp + 2) / 5 + day - 1; // [0, 365]
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy; // [0, 146096]
+    era * 146097 + doe - 719468
+}
+
+/// The date (year, month, day) of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097; // [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365; // [0, 399]
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
+    let mp = (5 * doy + 2) / 153; // March is 0
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl Tm {
+    /// Convert the time in seconds since the epoch, like `gmtime` if `utc` else `localtime`,
+    /// return None if the year does not fit into a C int
+    pub fn from_time(t: i64, utc: bool) -> Option<Tm> {
+        let (utoff, isdst, zone) = if utc {
+            (0, false, "GMT".to_string())
+        } else {
+            local_type(t)
+        };
+        let t = t.checked_add(utoff)?;
+        let days = t.div_euclid(SECS_PER_DAY);
+        let secs = t.rem_euclid(SECS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        if year - 1900 > i32::MAX as i64 || year - 1900 < i32::MIN as i64 {
+            return None;
+        }
+        Some(Tm {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            min: secs % 3600 / 60,
+            sec: secs % 60,
+            wday: (days + 4).rem_euclid(7), // 1970-01-01 is a Thursday
+            yday: days - days_from_civil(year, 1, 1),
+            isdst,
+            utoff,
+            zone,
+        })
+    }
+
+    /// Write the conversion `conv` of `strftime` in the C locale,
+    /// which is one character optionally preceded by 'E' or 'O'
+    pub fn format(&self, conv: &[u8], out: &mut Vec<u8>) {
+        let mut push = |s: String| out.extend_from_slice(s.as_bytes());
+        match conv[conv.len() - 1] {
+            b'a' => push(WEEK_DAYS[self.wday as usize][..3].to_string()),
+            b'A' => push(WEEK_DAYS[self.wday as usize].to_string()),
+            b'b' | b'h' => push(MONTHS[self.month as usize - 1][..3].to_string()),
+            b'B' => push(MONTHS[self.month as usize - 1].to_string()),
+            b'c' => {
+                self.format(b"a", out);
+                out.push(b' ');
+                self.format(b"b", out);
+                out.push(b' ');
+                self.format(b"e", out);
+                out.push(b' ');
+                self.format(b"T", out);
+                out.push(b' ');
+                self.format(b"Y", out);
+            }
+            b'C' => push(format!("{:02}", self.year.div_euclid(100))),
+            b'd' => push(format!("{:02}", self.day)),
+            b'D' | b'x' => push(format!("{:02}/{:02}/{:02}", self.month, self.day, self.year.rem_euclid(100))),
+            b'e' => push(format!("{:2}", self.day)),
+            b'F' => push(format!("{}-{:02}-{:02}", self.year, self.month, self.day)),
+            b'g' => push(format!("{:02}", self.iso_week().0.rem_euclid(100))),
+            b'G' => push(self.iso_week().0.to_string()),
+            b'H' => push(format!("{:02}", self.hour)),
+            b'I' => push(format!("{:02}", (self.hour + 11) % 12 + 1)),
+            b'j' => push(format!("{:03}", self.yday + 1)),
+            b'm' => push(format!("{:02}", self.month)),
+            b'M' => push(format!("{:02}", self.min)),
+            b'n' => push("\n".to_string()),
+            b'p' => push(if self.hour < 12 { "AM" } else { "PM" }.to_string()),
+            b'r' => {
+                push(format!("{:02}:{:02}:{:02} ", (self.hour + 11) % 12 + 1, self.min, self.sec));
+                self.format(b"p", out);
+            }
+            b'R' => push(format!("{:02}:{:02}", self.hour, self.min)),
+            b'S' => push(format!("{:02}", self.sec)),
+            b't' => push("\t".to_string()),
+            b'T' | b'X' => push(format!("{:02}:{:02}:{:02}", self.hour, self.min, self.sec)),
+            b'u' => push(if self.wday == 0 { 7 } else { self.wday }.to_string()),
+            b'U' => push(format!("{:02}", (self.yday + 7 - self.wday) / 7)),
+            b'V' => push(format!("{:02}", self.iso_week().1)),
+            b'w' => push(self.wday.to_string()),
+            b'W' => push(format!("{:02}", (self.yday + 7 - (self.wday + 6) % 7) / 7)),
+            b'y' => push(format!("{:02}", self.year.rem_euclid(100))),
+            b'Y' => push(self.year.to_string()),
+            b'z' => {
+                let sign = if self.utoff < 0 { '-' } else { '+' };
+                let off = self.utoff.abs() / 60;
+                push(format!("{}{:02}{:02}", sign, off / 60, off % 60));
+            }
+            b'Z' => push(self.zone.clone()),
+            _ => push("%".to_string()),
+        }
+    }
+
+    /// The ISO 8601 week-based year and week number
+    fn iso_week(&self) -> (i64, i64) {
+        // the number of weeks of a year, which has 53 if it starts on a Thursday
+        // or it is a leap year starting on a Wednesday
+        let weeks = |y: i64| {
+            let p = |y: i64| (y + y.div_euclid(4) - y.div_euclid(100) + y.div_euclid(400)).rem_euclid(7);
+            if p(y) == 4 || p(y - 1) == 3 {
+                53
+            } else {
+                52
+            }
+        };
+        let week = (self.yday - (self.wday + 6) % 7 + 10) / 7;
+        if week < 1 {
+            (self.year - 1, weeks(self.year - 1))
+        } else if week > weeks(self.year) {
+            (self.year + 1, 1)
+        } else {
+            (self.year, week)
+        }
+    }
+}
+
+/// Convert a local time like `mktime`, the fields may be out of their ranges,
+/// e.g. day 0 is the last day of the previous month
+pub fn make_time(year: i64, month: i64, day: i64, hour: i64, min: i64, sec: i64) -> i64 {
+    let m = month - 1;
+    let days = days_from_civil(year + m.div_euclid(12), m.rem_euclid(12) + 1, 1) + day - 1;
+    let local = days * SECS_PER_DAY + hour * 3600 + min * 60 + sec;
+    // the offset at the guessed time is right unless it is near a transition
+    let guess = local - local_type(local).0;
+    local - local_type(guess).0
+}
+
+/// The local time type (offset, is DST, abbreviation) at time `t`
+fn local_type(t: i64) -> (i64, bool, String) {
+    let zone = match *LOCAL_ZONE {
+        Some(ref zone) => zone,
+        None => return (0, false, "UTC".to_string()),
+    };
+    // the first standard time type is used before the first transition,
+    // and the last one is kept after the last transition
+    let idx = match zone.transitions.binary_search_by_key(&t, |&(at, _)| at) {
+        Ok(i) => zone.transitions[i].1,
+        Err(0) => zone.types.iter().position(|ty| !ty.1).unwrap_or(0),
+        Err(i) => zone.transitions[i - 1].1,
+    };
+    zone.types[idx].clone()
+}
+
+/// Time zone loaded from a TZif file
+struct Zone {
+    /// Sorted transition times and the indices of their types
+    transitions: Vec<(i64, usize)>,
+    /// Local time types (offset, is DST, abbreviation)
+    types: Vec<(i64, bool, String)>,
+}
+
+lazy_static! {
+    static ref LOCAL_ZONE: Option<Zone> = load_local_zone();
+}
+
+fn load_local_zone() -> Option<Zone> {
+    let path = match std::env::var("TZ") {
+        Ok(tz) => {
+            let tz = tz.strip_prefix(':').unwrap_or(&tz);
+            if tz.is_empty() || tz == "UTC" || tz == "UTC0" || tz == "GMT" || tz == "GMT0" {
+                return None;
+            }
+            if tz.starts_with('/') {
+                tz.to_string()
+            } else {
+                format!("/usr/share/zoneinfo/{}", tz)
+            }
+        }
+        Err(_) => "/etc/localtime".to_string(),
+    };
+    parse_tzif(fs::read(path).ok()?)
+}
+
+/// Parse a TZif file, the 64-bit data of version 2 or later is preferred
+fn parse_tzif(data: Vec<u8>) -> Option<Zone> {
+    const HEADER_SIZE: usize = 44;
+    // the counts of the header: isutcnt, isstdcnt, leapcnt, timecnt, typecnt, charcnt
+    let counts = |data: &[u8], start: usize| -> Option<[usize; 6]> {
+        if data.len() < start + HEADER_SIZE || &data[start..start + 4] != b"TZif" {
+            return None;
+        }
+        let mut r = Reader::new(data[start + 20..start + HEADER_SIZE].to_vec());
+        let mut counts = [0; 6];
+        for c in counts.iter_mut() {
+            *c = r.read_int(4, false, false)? as usize;
+        }
+        Some(counts)
+    };
+    let block_size = |c: [usize; 6], time_size: usize| {
+        c[3] * time_size + c[3] + c[4] * 6 + c[5] + c[2] * (time_size + 4) + c[1] + c[0]
+    };
+
+    let mut c = counts(&data, 0)?;
+    let mut start = HEADER_SIZE;
+    let mut time_size = 4;
+    if data[4] >= b'2' {
+        start += block_size(c, 4);
+        c = counts(&data, start)?;
+        start += HEADER_SIZE;
+        time_size = 8;
+    }
+    if data.len() < start + block_size(c, time_size) || c[4] == 0 {
+        return None;
+    }
+
+    let [_, _, _, timecnt, typecnt, charcnt] = c;
+    let mut r = Reader::new(data);
+    r.seek(start);
+    let times = (0..timecnt)
+        .map(|_| r.read_int(time_size, false, true))
+        .collect::<Option<Vec<_>>>()?;
+    let mut transitions = Vec::with_capacity(timecnt);
+    for at in times {
+        let idx = r.read_byte().ok()? as usize;
+        if idx >= typecnt {
+            return None;
+        }
+        transitions.push((at, idx));
+    }
+    let raw_types = (0..typecnt)
+        .map(|_| Some((r.read_int(4, false, true)?, r.read_byte().ok()? != 0, r.read_byte().ok()? as usize)))
+        .collect::<Option<Vec<_>>>()?;
+    let chars = r.read_bytes(charcnt).ok()?;
+    let types = raw_types
+        .into_iter()
+        .map(|(utoff, isdst, idx)| {
+            let abbr = chars.get(idx..).unwrap_or_default();
+            let end = abbr.iter().position(|&c| c == 0).unwrap_or(abbr.len());
+            (utoff, isdst, String::from_utf8_lossy(&abbr[..end]).into_owned())
+        })
+        .collect();
+    Some(Zone { transitions, types })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        for &days in &[-719468, -1, 0, 59, 11016, 11017, 2932896] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn test_format() {
+        let tm = Tm::from_time(951782400 + 13 * 3600 + 5 * 60 + 9, true).unwrap(); // 2000-02-29
+        let fmt = |conv: &str| {
+            let mut out = Vec::new();
+            tm.format(conv.as_bytes(), &mut out);
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!((tm.wday, tm.yday), (2, 59));
+        assert_eq!(fmt("c"), "Tue Feb 29 13:05:09 2000");
+        assert_eq!(fmt("F"), "2000-02-29");
+        assert_eq!(fmt("D"), "02/29/00");
+        assert_eq!(fmt("r"), "01:05:09 PM");
+        assert_eq!(fmt("j"), "060");
+        assert_eq!(fmt("U"), "09");
+        assert_eq!(fmt("W"), "09");
+        assert_eq!(fmt("V"), "09");
+        assert_eq!(fmt("u"), "2");
+        assert_eq!(fmt("z"), "+0000");
+        assert_eq!(fmt("Z"), "GMT");
+        assert_eq!(fmt("Ey"), "00");
+
+        // 2005-01-01 is in the last week of 2004
+        let tm = Tm::from_time(days_from_civil(2005, 1, 1) * SECS_PER_DAY, true).unwrap();
+        assert_eq!(tm.iso_week(), (2004, 53));
+        let tm = Tm::from_time(days_from_civil(2008, 12, 29) * SECS_PER_DAY, true).unwrap();
+        assert_eq!(tm.iso_week(), (2009, 1));
+    }
+
+    #[test]
+    fn test_parse_tzif() {
+        // version 1 file with a standard and a DST type
+        let mut data = b"TZif".to_vec();
+        data.extend_from_slice(&[0; 16]);
+        for &n in &[0u32, 0, 0, 2, 2, 8] {
+            data.extend_from_slice(&n.to_be_bytes());
+        }
+        data.extend_from_slice(&1000i32.to_be_bytes());
+        data.extend_from_slice(&2000i32.to_be_bytes());
+        data.extend_from_slice(&[1, 0]);
+        data.extend_from_slice(&3600i32.to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&7200i32.to_be_bytes());
+        data.extend_from_slice(&[1, 4]);
+        data.extend_from_slice(b"CET\0CEST");
+        let zone = parse_tzif(data).unwrap();
+        assert_eq!(zone.transitions, vec![(1000, 1), (2000, 0)]);
+        assert_eq!(zone.types[1], (7200, true, "CEST".to_string()));
+        assert!(parse_tzif(b"TZif".to_vec()).is_none());
+    }
+}
@@ -0,0 +1,275 @@
+//! UTF-8 library, where code points go up to 0x7FFFFFFF like the `\u{XXX}` escape,
+//! so sequences have up to 6 bytes
+
+use crate::api::aux_lib::LuaAuxLib;
+use crate::api::{LuaAPI, LuaResult, RustFn};
+use crate::compiler::lexer::utf8_encode;
+use crate::state::lua_state::LuaState;
+use crate::stdlib::string::pos_relat;
+
+/// Maximum code point
+const MAXUTF: u64 = 0x7FFF_FFFF;
+
+/// Pattern matching exactly one UTF-8 byte sequence
+const UTF8PATT: &[u8] = b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*";
+
+const UTF8_FUNCS: &[(&str, RustFn)] = &[
+    ("offset", byte_offset),
+    ("codepoint", codepoint),
+    ("char", utf_char),
+    ("len", utf_len),
+    ("codes", iter_codes),
+];
+
+pub fn open_utf8(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.new_lib(UTF8_FUNCS)?;
+    ls.push_bytes(UTF8PATT.to_vec());
+    ls.set_field(-2, "charpattern")?;
+    Ok(1)
+}
+
+#[inline]
+fn is_cont(s: &[u8], i: usize) -> bool {
+    s.get(i).is_some_and(|&c| c & 0xC0 == 0x80)
+}
+
+/// Decode the sequence at the start of `s` and return the code point and its length,
+/// None if it is invalid or overlong
+fn utf8_decode(s: &[u8]) -> Option<(u32, usize)> {
+    const LIMITS: [u64; 6] = [!0, 0x80, 0x800, 0x1_0000, 0x20_0000, 0x400_0000];
+    let mut c = *s.first()? as u64;
+    if c < 0x80 {
+        return Some((c as u32, 1)); // ascii
+    }
+    let mut count = 0;
+    let mut res: u64 = 0;
+    while c & 0x40 != 0 {
+        // while it needs continuation bytes
+        count += 1;
+        if count > 5 {
+            return None;
+        }
+        let cc = *s.get(count)? as u64;
+        if cc & 0xC0 != 0x80 {
+            return None; // not a continuation byte
+        }
+        res = (res << 6) | (cc & 0x3F); // add lower 6 bits from cont. byte
+        c <<= 1;
+    }
+    res |= (c & 0x7F) << (count * 5); // add first byte
+    if count == 0 || res > MAXUTF || res < LIMITS[count] {
+        return None; // a lone continuation byte, too large or overlong
+    }
+    Some((res as u32, count + 1))
+}
+
+// utf8.len (s [, i [, j]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-utf8.len
+fn utf_len(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = &ls.check_bytes(1)?;
+    let len = s.len() as i64;
+    let posi = pos_relat(ls.opt_integer(2, 1)?, s.len());
+    let posj = pos_relat(ls.opt_integer(3, -1)?, s.len());
+    ls.arg_check(1 <= posi && posi - 1 <= len, 2, "initial position out of string")?;
+    ls.arg_check(posj - 1 < len, 3, "final position out of string")?;
+    let (mut posi, posj) = (posi - 1, posj - 1);
+    let mut n = 0;
+    while posi <= posj {
+        match utf8_decode(&s[posi as usize..]) {
+            Some((_, l)) => posi += l as i64,
+            None => {
+                // conversion error, return fail and the position
+                ls.push_nil();
+                ls.push_integer(posi + 1);
+                return Ok(2);
+            }
+        }
+        n += 1;
+    }
+    ls.push_integer(n);
+    Ok(1)
+}
+
+// utf8.codepoint (s [, i [, j]])
+// http://www.lua.org/manual/5.3/manual.html#pdf-utf8.codepoint
+fn codepoint(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = &ls.check_bytes(1)?;
+    let posi = pos_relat(ls.opt_integer(2, 1)?, s.len());
+    let pose = pos_relat(ls.opt_integer(3, posi)?, s.len());
+    ls.arg_check(posi >= 1, 2, "out of range")?;
+    ls.arg_check(pose <= s.len() as i64, 3, "out of range")?;
+    if posi > pose {
+        return Ok(0); // empty interval; return no values
+    }
+    if pose - posi >= i32::MAX as i64 || !ls.check_stack((pose - posi + 1) as usize) {
+        return Err(ls.error2("string slice too long"));
+    }
+    let mut n = 0;
+    let mut i = posi as usize - 1;
+    while i < pose as usize {
+        match utf8_decode(&s[i..]) {
+            Some((code, l)) => {
+                ls.push_integer(code as i64);
+                i += l;
+                n += 1;
+            }
+            None => return Err(ls.error2("invalid UTF-8 code")),
+        }
+    }
+    Ok(n)
+}
+
+// utf8.char (···)
+// http://www.lua.org/manual/5.3/manual.html#pdf-utf8.char
+fn utf_char(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top();
+    let mut buf = Vec::new();
+    for i in 1..=n {
+        let code = ls.check_integer(i)?;
+        ls.arg_check(code >= 0 && code as u64 <= MAXUTF, i, "value out of range")?;
+        buf.extend(utf8_encode(code as u32));
+    }
+    ls.push_bytes(buf);
+    Ok(1)
+}
+
+// utf8.offset (s, n [, i])
+// http://www.lua.org/manual/5.3/manual.html#pdf-utf8.offset
+fn byte_offset(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = &ls.check_bytes(1)?;
+    let len = s.len() as i64;
+    let mut n = ls.check_integer(2)?;
+    let def = if n >= 0 { 1 } else { len + 1 };
+    let posi = pos_relat(ls.opt_integer(3, def)?, s.len());
+    ls.arg_check(1 <= posi && posi - 1 <= len, 3, "position out of range")?;
+    let mut posi = (posi - 1) as usize;
+    if n == 0 {
+        // find beginning of current byte sequence
+        while posi > 0 && is_cont(s, posi) {
+            posi -= 1;
+        }
+    } else {
+        if is_cont(s, posi) {
+            return Err(ls.error2("initial position is a continuation byte"));
+        }
+        if n < 0 {
+            while n < 0 && posi > 0 {
+                // move back
+                posi -= 1; // find beginning of previous character
+                while posi > 0 && is_cont(s, posi) {
+                    posi -= 1;
+                }
+                n += 1;
+            }
+        } else {
+            n -= 1; // do not move for 1st character
+            while n > 0 && posi < s.len() {
+                posi += 1; // find beginning of next character
+                while is_cont(s, posi) {
+                    posi += 1; // (cannot pass final '\0')
+                }
+                n -= 1;
+            }
+        }
+    }
+    if n == 0 {
+        // did it find given character?
+        ls.push_integer(posi as i64 + 1);
+    } else {
+        // no such character
+        ls.push_nil();
+    }
+    Ok(1)
+}
+
+/// The iterator of `utf8.codes`
+fn iter_aux(ls: &mut LuaState) -> LuaResult<usize> {
+    let s = &ls.check_bytes(1)?;
+    let mut n = ls.to_integer(2) - 1;
+    if n < 0 {
+        // first iteration?
+        n = 0; // start from here
+    } else if n < s.len() as i64 {
+        n += 1; // skip current byte
+        while is_cont(s, n as usize) {
+            n += 1; // and its continuations
+        }
+    }
+    if n >= s.len() as i64 {
+        return Ok(0); // no more codepoints
+    }
+    match utf8_decode(&s[n as usize..]) {
+        Some((code, l)) if !is_cont(s, n as usize + l) => {
+            ls.push_integer(n + 1);
+            ls.push_integer(code as i64);
+            Ok(2)
+        }
+        _ => Err(ls.error2("invalid UTF-8 code")),
+    }
+}
+
+// utf8.codes (s)
+// http://www.lua.org/manual/5.3/manual.html#pdf-utf8.codes
+fn iter_codes(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_bytes(1)?;
+    ls.push_rust_function(iter_aux);
+    ls.push_value(1);
+    ls.push_integer(0);
+    Ok(3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stdlib::test_util::{execute, results};
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(utf8_decode(b"A"), Some((0x41, 1)));
+        assert_eq!(utf8_decode("é".as_bytes()), Some((0xE9, 2)));
+        assert_eq!(utf8_decode(&utf8_encode(0x7FFF_FFFF)), Some((0x7FFF_FFFF, 6)));
+        assert_eq!(utf8_decode(b"\xC0\x80"), None); // overlong
+        assert_eq!(utf8_decode(b"\x80"), None);
+        assert_eq!(utf8_decode(b"\xE4\xB8"), None);
+        assert_eq!(utf8_decode(b"\xFE\x80\x80\x80\x80\x80\x80"), None);
+    }
+
+    #[test]
+    fn test_utf8() {
+        let ls = execute(r##"
+        local s = "héllo, 世界"
+        local t = {}
+        for p, c in utf8.codes(s) do t[#t + 1] = p .. ":" .. c end
+        return utf8.char(72, 233, 19990, 1114111) == "H\u{E9}\u{4E16}\u{10FFFF}",
+            utf8.char(2147483647) == "\u{7FFFFFFF}" and #utf8.char(2147483647),
+            utf8.len(s), utf8.len(s, 3), utf8.len("\xFFabc"), utf8.len("abc", 4),
+            utf8.offset(s, 3), utf8.offset(s, -1), utf8.offset(s, 0, 3), tostring(utf8.offset(s, 20)),
+            table.concat(t, " "), select("#", utf8.codepoint(s, 1, -1)),
+            string.match(s, utf8.charpattern, 2), select(2, pcall(utf8.char, -1)),
+            select(2, pcall(utf8.codepoint, "\xFF")), select(2, pcall(utf8.offset, s, 1, 3)),
+            utf8.codepoint(s, 2)
+        "##);
+        assert_eq!(
+            results(&ls),
+            vec![
+                "nil", // true
+                "6",
+                "9",
+                "nil",
+                "nil",
+                "0",
+                "4",
+                "12",
+                "2",
+                "nil",
+                "1:104 2:233 4:108 5:108 6:111 7:44 8:32 9:19990 12:30028",
+                "9",
+                "é",
+                "bad argument #1 (value out of range)",
+                "invalid UTF-8 code",
+                "initial position is a continuation byte",
+                "233",
+            ]
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use crate::api::{LuaResult, LuaVM};
+use crate::vm::instruction::Instruction;
+
+/// R(A) := closure(KPROTO[Bx])
+pub fn closure(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, bx) = i.a_bx();
+    vm.load_proto(bx as usize);
+    vm.replace(a + 1)
+}
+
+/// R(A), ... ,R(A+C-2) := R(A)(R(A+1), ... ,R(A+B-1))
+pub fn call(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    let nargs = func_and_args(a + 1, b, vm)?;
+    vm.pre_call(nargs, c - 1)
+}
+
+/// return R(A)(R(A+1), ... ,R(A+B-1))
+pub fn tail_call(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, _) = i.abc();
+    let nargs = func_and_args(a + 1, b, vm)?;
+    vm.tail_call(nargs)
+}
+
+/// return R(A), ... ,R(A+B-2)
+pub fn return_(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, _) = i.abc();
+    let a = a + 1;
+    // close the up values before the registers are dropped
+    vm.close_upvalues(1);
+    // B == 0 means returning all values up to the top
+    if b != 0 {
+        vm.set_top(a + b - 2)?;
+    }
+    let n = vm.get_top() - a + 1;
+    vm.post_call(n)
+}
+
+/// R(A), R(A+1), ..., R(A+B-2) = vararg
+pub fn vararg(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, _) = i.abc();
+    if b != 1 {
+        vm.set_top(a)?;
+        vm.load_vararg(b - 1);
+        // B == 0 means leaving all of them on the top
+        if b != 0 {
+            let n = vm.register_count();
+            vm.set_top(n)?;
+        }
+    }
+    Ok(())
+}
+
+/// R(A+1) := R(B); R(A) := R(B)[RK(C)]
+pub fn self_(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    let (a, b) = (a + 1, b + 1);
+    vm.copy(b, a + 1)?;
+    vm.get_rk(c);
+    vm.get_table(b)?;
+    vm.replace(a)
+}
+
+/// R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2))
+pub fn t_for_call(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, _, c) = i.abc();
+    let a = a + 1;
+    vm.set_top(a + 2)?;
+    for j in a..a + 3 {
+        vm.push_value(j);
+    }
+    vm.pre_call(2, c)
+}
+
+/// Leave the function R(A) and its arguments on the top of stack,
+/// and return the number of arguments
+fn func_and_args(a: isize, b: isize, vm: &mut dyn LuaVM) -> LuaResult<isize> {
+    // B == 0 means the arguments are up to the top
+    if b != 0 {
+        vm.set_top(a + b - 1)?;
+    }
+    Ok(vm.get_top() - a)
+}
@@ -0,0 +1,101 @@
+use crate::api::{LuaResult, LuaVM};
+use crate::state::lua_value::float_to_integer;
+use crate::vm::instruction::Instruction;
+
+/// R(A)-=R(A+2); pc+=sBx
+pub fn for_prep(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, sbx) = i.a_sbx();
+    let (init, limit, step) = (a + 1, a + 2, a + 3);
+
+    if vm.is_integer(init) && vm.is_integer(step) {
+        let step_v = vm.to_integer(step);
+        if let Some((limit_v, stop_now)) = for_limit(vm, limit, step_v) {
+            // when the loop should not run at all, start from 0 to avoid overflow
+            let init_v = if stop_now { 0 } else { vm.to_integer(init) };
+            vm.push_integer(limit_v);
+            vm.replace(limit)?;
+            vm.push_integer(init_v.wrapping_sub(step_v));
+            vm.replace(init)?;
+            vm.add_pc(sbx);
+            return Ok(());
+        }
+    }
+
+    let limit_v = for_number(vm, limit, "limit")?;
+    vm.push_number(limit_v);
+    vm.replace(limit)?;
+    let step_v = for_number(vm, step, "step")?;
+    vm.push_number(step_v);
+    vm.replace(step)?;
+    let init_v = for_number(vm, init, "initial value")?;
+    vm.push_number(init_v - step_v);
+    vm.replace(init)?;
+    vm.add_pc(sbx);
+    Ok(())
+}
+
+/// R(A)+=R(A+2); if R(A) <?= R(A+1) then { pc+=sBx; R(A+3)=R(A) }
+pub fn for_loop(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, sbx) = i.a_sbx();
+    let (index, limit, step) = (a + 1, a + 2, a + 3);
+
+    let go_on = if vm.is_integer(index) {
+        let step_v = vm.to_integer(step);
+        let index_v = vm.to_integer(index).wrapping_add(step_v);
+        let limit_v = vm.to_integer(limit);
+        vm.push_integer(index_v);
+        if step_v > 0 { index_v <= limit_v } else { limit_v <= index_v }
+    } else {
+        let step_v = vm.to_number(step);
+        let index_v = vm.to_number(index) + step_v;
+        let limit_v = vm.to_number(limit);
+        vm.push_number(index_v);
+        if step_v > 0.0 { index_v <= limit_v } else { limit_v <= index_v }
+    };
+
+    if go_on {
+        vm.add_pc(sbx);
+        vm.copy(-1, index)?;
+        vm.replace(a + 4)?;
+    } else {
+        vm.pop(1);
+    }
+    Ok(())
+}
+
+/// if R(A+1) ~= nil then { R(A)=R(A+1); pc += sBx }
+pub fn t_for_loop(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, sbx) = i.a_sbx();
+    let a = a + 1;
+    if !vm.is_nil(a + 1) {
+        vm.copy(a + 1, a)?;
+        vm.add_pc(sbx);
+    }
+    Ok(())
+}
+
+/// Convert a control value of a float loop to number
+fn for_number(vm: &mut dyn LuaVM, idx: isize, what: &str) -> LuaResult<f64> {
+    vm.to_numberx(idx)
+        .ok_or_else(|| vm.runtime_error(&format!("'for' {} must be a number", what)))
+}
+
+/// Convert the limit of an integer loop to integer, clipping it when it is out of the range of integer.
+/// The flag returned is true if the loop must not run.
+fn for_limit(vm: &mut dyn LuaVM, idx: isize, step: i64) -> Option<(i64, bool)> {
+    if let Some(limit) = vm.to_integerx(idx) {
+        return Some((limit, false));
+    }
+
+    let limit = vm.to_numberx(idx)?;
+    let rounded = if step < 0 { limit.ceil() } else { limit.floor() };
+    if let Some(limit) = float_to_integer(rounded) {
+        Some((limit, false))
+    } else if limit > 0.0 {
+        // the float is larger than max integer
+        Some((i64::MAX, step < 0))
+    } else {
+        // the float is smaller than min integer, or NaN
+        Some((i64::MIN, step >= 0))
+    }
+}
@@ -0,0 +1,40 @@
+use crate::api::{LuaResult, LuaVM};
+use crate::vm::instruction::Instruction;
+
+/// R(A), R(A+1), ..., R(A+B) := nil
+pub fn load_nil(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, _) = i.abc();
+    let a = a + 1;
+    vm.push_nil();
+    for i in a..=a + b {
+        vm.copy(-1, i)?;
+    }
+    vm.pop(1);
+    Ok(())
+}
+
+/// R(A) := (bool)B; if (C) pc++
+pub fn load_bool(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    vm.push_boolean(b != 0);
+    vm.replace(a + 1)?;
+    if c != 0 {
+        vm.add_pc(1);
+    }
+    Ok(())
+}
+
+/// R(A) := Kst(Bx)
+pub fn load_k(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, bx) = i.a_bx();
+    vm.get_const(bx);
+    vm.replace(a + 1)
+}
+
+/// R(A) := Kst(extra arg)
+pub fn load_kx(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, _) = i.a_bx();
+    let ax = vm.fetch().ax();
+    vm.get_const(ax);
+    vm.replace(a + 1)
+}
@@ -0,0 +1,18 @@
+use crate::api::{LuaResult, LuaVM};
+use crate::vm::instruction::Instruction;
+
+/// R(A) := R(B)
+pub fn move_(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, _) = i.abc();
+    vm.copy(b + 1, a + 1)
+}
+
+/// pc+=sBx; if (A) close all upvalues >= R(A - 1)
+pub fn jmp(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, sbx) = i.a_sbx();
+    vm.add_pc(sbx);
+    if a != 0 {
+        vm.close_upvalues(a);
+    }
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use crate::api::consts::*;
+use crate::api::{LuaResult, LuaVM};
+use crate::vm::instruction::Instruction;
+
+/// R(A) := RK(B) op RK(C)
+fn binary_arith(i: u32, vm: &mut dyn LuaVM, op: u8) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    vm.get_rk(b);
+    vm.get_rk(c);
+    vm.arith(op)?;
+    vm.replace(a + 1)
+}
+
+/// R(A) := op R(B)
+fn unary_arith(i: u32, vm: &mut dyn LuaVM, op: u8) -> LuaResult<()> {
+    let (a, b, _) = i.abc();
+    vm.push_value(b + 1);
+    vm.arith(op)?;
+    vm.replace(a + 1)
+}
+
+pub fn add(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPADD) }
+
+pub fn sub(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPSUB) }
+
+pub fn mul(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPMUL) }
+
+pub fn mod_(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPMOD) }
+
+pub fn pow(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPPOW) }
+
+pub fn div(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPDIV) }
+
+pub fn idiv(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPIDIV) }
+
+pub fn band(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPBAND) }
+
+pub fn bor(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPBOR) }
+
+pub fn bxor(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPBXOR) }
+
+pub fn shl(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPSHL) }
+
+pub fn shr(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { binary_arith(i, vm, LUA_OPSHR) }
+
+pub fn unm(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { unary_arith(i, vm, LUA_OPUNM) }
+
+pub fn bnot(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { unary_arith(i, vm, LUA_OPBNOT) }
+
+/// R(A) := not R(B)
+pub fn not(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, _) = i.abc();
+    let res = !vm.to_boolean(b + 1);
+    vm.push_boolean(res);
+    vm.replace(a + 1)
+}
+
+/// R(A) := length of R(B)
+pub fn len(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, _) = i.abc();
+    vm.len(b + 1)?;
+    vm.replace(a + 1)
+}
+
+/// R(A) := R(B).. ... ..R(C)
+pub fn concat(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    let (a, b, c) = (a + 1, b + 1, c + 1);
+    let n = c - b + 1;
+    vm.check_stack(n as usize);
+    for i in b..=c {
+        vm.push_value(i);
+    }
+    vm.concat(n)?;
+    vm.replace(a)
+}
+
+/// if ((RK(B) op RK(C)) ~= A) then pc++
+fn compare(i: u32, vm: &mut dyn LuaVM, op: u8) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    vm.get_rk(b);
+    vm.get_rk(c);
+    if vm.compare(-2, -1, op)? != (a != 0) {
+        vm.add_pc(1);
+    }
+    vm.pop(2);
+    Ok(())
+}
+
+pub fn eq(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { compare(i, vm, LUA_OPEQ) }
+
+pub fn lt(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { compare(i, vm, LUA_OPLT) }
+
+pub fn le(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> { compare(i, vm, LUA_OPLE) }
+
+/// if not (R(A) <=> C) then pc++
+pub fn test(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, _, c) = i.abc();
+    if vm.to_boolean(a + 1) != (c != 0) {
+        vm.add_pc(1);
+    }
+    Ok(())
+}
+
+/// if (R(B) <=> C) then R(A) := R(B) else pc++
+pub fn test_set(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    if vm.to_boolean(b + 1) == (c != 0) {
+        vm.copy(b + 1, a + 1)?;
+    } else {
+        vm.add_pc(1);
+    }
+    Ok(())
+}
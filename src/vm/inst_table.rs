@@ -0,0 +1,52 @@
+use crate::api::{LuaResult, LuaVM};
+use crate::number::parser::float_byte_to_int;
+use crate::vm::instruction::Instruction;
+
+/// number of list items to accumulate before a SETLIST instruction
+const LFIELDS_PER_FLUSH: isize = 50;
+
+/// R(A) := {} (size = B,C)
+pub fn new_table(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    vm.create_table(float_byte_to_int(b) as usize, float_byte_to_int(c) as usize);
+    vm.replace(a + 1)
+}
+
+/// R(A) := R(B)[RK(C)]
+pub fn get_table(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    vm.get_rk(c);
+    vm.get_table(b + 1)?;
+    vm.replace(a + 1)
+}
+
+/// R(A)[RK(B)] := RK(C)
+pub fn set_table(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    vm.get_rk(b);
+    vm.get_rk(c);
+    vm.set_table(a + 1)
+}
+
+/// R(A)[(C-1)*FPF+i] := R(A+i), 1 <= i <= B
+pub fn set_list(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    let a = a + 1;
+    // C == 0 means the real C is stored in the following EXTRAARG
+    let c = if c > 0 { c - 1 } else { vm.fetch().ax() };
+    // B == 0 means the values are up to the top
+    let n = if b != 0 { b } else { vm.get_top() - a };
+
+    let mut idx = (c * LFIELDS_PER_FLUSH) as i64;
+    for j in 1..=n {
+        idx += 1;
+        vm.push_value(a + j);
+        vm.set_i(a, idx)?;
+    }
+
+    if b == 0 {
+        let n = vm.register_count();
+        vm.set_top(n)?;
+    }
+    Ok(())
+}
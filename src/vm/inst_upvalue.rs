@@ -0,0 +1,30 @@
+use crate::api::{upvalue_index, LuaResult, LuaVM};
+use crate::vm::instruction::Instruction;
+
+/// R(A) := UpValue[B]
+pub fn get_upval(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, _) = i.abc();
+    vm.copy(upvalue_index(b + 1), a + 1)
+}
+
+/// UpValue[B] := R(A)
+pub fn set_upval(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, _) = i.abc();
+    vm.copy(a + 1, upvalue_index(b + 1))
+}
+
+/// R(A) := UpValue[B][RK(C)]
+pub fn get_tab_up(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    vm.get_rk(c);
+    vm.get_table(upvalue_index(b + 1))?;
+    vm.replace(a + 1)
+}
+
+/// UpValue[A][RK(B)] := RK(C)
+pub fn set_tab_up(i: u32, vm: &mut dyn LuaVM) -> LuaResult<()> {
+    let (a, b, c) = i.abc();
+    vm.get_rk(b);
+    vm.get_rk(c);
+    vm.set_table(upvalue_index(a + 1))
+}
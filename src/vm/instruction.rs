@@ -1,4 +1,12 @@
+use super::inst_call::*;
+use super::inst_for::*;
+use super::inst_load::*;
+use super::inst_misc::*;
+use super::inst_operators::*;
+use super::inst_table::*;
+use super::inst_upvalue::*;
 use super::opcode::*;
+use crate::api::{LuaResult, LuaVM};
 
 /// Value: 262143
 const MAXARG_BX: isize = (1 << 18) - 1;
@@ -16,6 +24,7 @@ pub trait Instruction {
     fn a_bx(self) -> (isize, isize);
     fn a_sbx(self) -> (isize, isize);
     fn ax(self) -> isize;
+    fn execute(self, vm: &mut dyn LuaVM) -> LuaResult<()>;
 }
 
 impl Instruction for u32 {
@@ -60,4 +69,58 @@ impl Instruction for u32 {
     fn ax(self) -> isize {
         (self >> 6) as isize
     }
+
+    fn execute(self, vm: &mut dyn LuaVM) -> LuaResult<()> {
+        match self.opcode() {
+            OP_MOVE => move_(self, vm),
+            OP_LOADK => load_k(self, vm),
+            OP_LOADKX => load_kx(self, vm),
+            OP_LOADBOOL => load_bool(self, vm),
+            OP_LOADNIL => load_nil(self, vm),
+            OP_ADD => add(self, vm),
+            OP_SUB => sub(self, vm),
+            OP_MUL => mul(self, vm),
+            OP_MOD => mod_(self, vm),
+            OP_POW => pow(self, vm),
+            OP_DIV => div(self, vm),
+            OP_IDIV => idiv(self, vm),
+            OP_BAND => band(self, vm),
+            OP_BOR => bor(self, vm),
+            OP_BXOR => bxor(self, vm),
+            OP_SHL => shl(self, vm),
+            OP_SHR => shr(self, vm),
+            OP_UNM => unm(self, vm),
+            OP_BNOT => bnot(self, vm),
+            OP_NOT => not(self, vm),
+            OP_LEN => len(self, vm),
+            OP_CONCAT => concat(self, vm),
+            OP_JMP => jmp(self, vm),
+            OP_EQ => eq(self, vm),
+            OP_LT => lt(self, vm),
+            OP_LE => le(self, vm),
+            OP_TEST => test(self, vm),
+            OP_TESTSET => test_set(self, vm),
+            OP_FORLOOP => for_loop(self, vm),
+            OP_FORPREP => for_prep(self, vm),
+            OP_TFORLOOP => t_for_loop(self, vm),
+            OP_NEWTABLE => new_table(self, vm),
+            OP_GETTABLE => get_table(self, vm),
+            OP_SETTABLE => set_table(self, vm),
+            OP_SETLIST => set_list(self, vm),
+            OP_SELF => self_(self, vm),
+            OP_CALL => call(self, vm),
+            OP_TAILCALL => tail_call(self, vm),
+            OP_RETURN => return_(self, vm),
+            OP_VARARG => vararg(self, vm),
+            OP_CLOSURE => closure(self, vm),
+            OP_TFORCALL => t_for_call(self, vm),
+            OP_GETUPVAL => get_upval(self, vm),
+            OP_SETUPVAL => set_upval(self, vm),
+            OP_GETTABUP => get_tab_up(self, vm),
+            OP_SETTABUP => set_tab_up(self, vm),
+            // consumed by the previous instruction
+            OP_EXTRAARG => Ok(()),
+            op => panic!("invalid opcode: {}", op),
+        }
+    }
 }
@@ -1,2 +1,9 @@
 pub mod opcode;
 pub mod instruction;
+mod inst_misc;
+mod inst_load;
+mod inst_operators;
+mod inst_for;
+mod inst_call;
+mod inst_table;
+mod inst_upvalue;
@@ -137,7 +137,7 @@ pub enum OpArgMask {
 }
 
 /// B, C, mode, name
-pub const OPCODES: &'static [OpCode] = &[
+pub const OPCODES: &[OpCode] = &[
     opcode(OpArgMask::R, OpArgMask::N, OpMode::ABC, "MOVE    "),
     opcode(OpArgMask::K, OpArgMask::N, OpMode::ABx, "LOADK   "),
     opcode(OpArgMask::N, OpArgMask::N, OpMode::ABx, "LOADKX  "),